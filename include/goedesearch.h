@@ -0,0 +1,25 @@
+#ifndef GOEDESEARCH_H
+#define GOEDESEARCH_H
+
+/* Generated by cbindgen from src/ffi.rs, which documents each function */
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct GoedeIndex GoedeIndex;
+
+struct GoedeIndex *goede_index_create(const char *path);
+
+struct GoedeIndex *goede_index_load(const char *path);
+
+uint64_t goede_index_size(const struct GoedeIndex *index);
+
+char *goede_search(const struct GoedeIndex *index, const char *query, size_t limit);
+
+const char *goede_last_error(void);
+
+void goede_string_free(char *s);
+
+void goede_index_free(struct GoedeIndex *index);
+
+#endif  /* GOEDESEARCH_H */
@@ -0,0 +1,205 @@
+/*
+ * The Python bindings of goedesearch, built into a `goedesearch` module with maturin, e.g.
+ *
+ *   import goedesearch
+ *   index = goedesearch.Index.from_dump("data/simple.xml.gz")
+ *   for article in index.query("anarchism"):
+ *       print(article.title, article.url)
+ */
+// The #[pymethods] expansion converts every PyResult error into a PyErr again
+#![allow(clippy::useless_conversion)]
+
+use goedesearch::engine::{self, IndexReader};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+fn io_error(e: impl std::fmt::Display) -> PyErr {
+    PyIOError::new_err(e.to_string())
+}
+
+/**
+ * A document in the index
+ */
+#[pyclass(name = "Article", module = "goedesearch", frozen)]
+#[derive(Clone)]
+pub struct PyArticle {
+    article: engine::Article,
+}
+
+#[pymethods]
+impl PyArticle {
+    #[new]
+    #[pyo3(signature = (title, r#abstract, url, metadata = None))]
+    fn new(
+        title: &str,
+        r#abstract: &str,
+        url: &str,
+        metadata: Option<HashMap<String, String>>,
+    ) -> PyResult<Self> {
+        let mut article = engine::Article::new(title, r#abstract, url)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        for (key, value) in metadata.unwrap_or_default() {
+            article = article.with_metadata(&key, &value);
+        }
+        Ok(Self { article })
+    }
+
+    #[getter]
+    fn id(&self) -> u64 {
+        self.article.id()
+    }
+
+    #[getter]
+    fn title(&self) -> &str {
+        self.article.title()
+    }
+
+    #[getter]
+    fn url(&self) -> Option<&str> {
+        self.article.url().map(|url| url.as_str())
+    }
+
+    #[getter(r#abstract)]
+    fn abstract_text(&self) -> &str {
+        self.article.abstract_text()
+    }
+
+    #[getter]
+    fn metadata(&self) -> HashMap<String, String> {
+        self.article.metadata().clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Article({:?}, id={})",
+            self.article.title(),
+            self.article.id()
+        )
+    }
+}
+
+/**
+ * An index held in memory, which documents can be added to and searched
+ */
+#[pyclass(name = "Index", module = "goedesearch")]
+pub struct PyIndex {
+    index: engine::Index,
+}
+
+#[pymethods]
+impl PyIndex {
+    #[new]
+    fn new() -> Self {
+        Self {
+            index: engine::Index::new(),
+        }
+    }
+
+    /**
+     * Build an index from the Wikipedia XML dump at the path
+     */
+    #[staticmethod]
+    fn from_dump(path: PathBuf) -> PyResult<Self> {
+        Ok(Self {
+            index: engine::Index::from_file(&path).map_err(io_error)?,
+        })
+    }
+
+    /**
+     * Load an index file written by `save` or the goedesearch command line
+     */
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        Ok(Self {
+            index: engine::Index::open(&path).map_err(io_error)?,
+        })
+    }
+
+    fn save(&self, path: PathBuf) -> PyResult<()> {
+        self.index.save(&path).map_err(io_error)
+    }
+
+    fn add(&mut self, article: &PyArticle) -> PyResult<()> {
+        self.index
+            .index_document(article.article.clone())
+            .map_err(io_error)
+    }
+
+    /**
+     * Compute the statistics used for scoring once everything has been added, so that they
+     * are not recomputed on every query
+     */
+    fn finalize(&mut self) {
+        self.index.finalize();
+    }
+
+    /**
+     * The articles matching the query, best first
+     */
+    fn query(&self, query: &str) -> Vec<PyArticle> {
+        self.index
+            .query_index(query)
+            .iter()
+            .filter_map(|id| self.get(*id))
+            .collect()
+    }
+
+    /**
+     * The `limit` highest scoring articles for the query, along with their scores
+     */
+    #[pyo3(signature = (query, limit = 10))]
+    fn search(&self, query: &str, limit: usize) -> Vec<(PyArticle, f64)> {
+        self.index
+            .search(query, limit)
+            .into_iter()
+            .filter_map(|(id, score)| self.get(id).map(|article| (article, score)))
+            .collect()
+    }
+
+    fn get(&self, id: u64) -> Option<PyArticle> {
+        self.index.document(&id).map(|article| PyArticle {
+            article: article.into_owned(),
+        })
+    }
+
+    fn __len__(&self) -> usize {
+        self.index.size() as usize
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "goedesearch")]
+fn goedesearch_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyArticle>()?;
+    m.add_class::<PyIndex>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_and_query() -> PyResult<()> {
+        let mut index = PyIndex::new();
+        let mut metadata = HashMap::new();
+        metadata.insert("category".to_string(), "Fruit".to_string());
+        index.add(&PyArticle::new(
+            "Banana",
+            "A long yellow fruit",
+            "https://example.com/banana",
+            Some(metadata),
+        )?)?;
+        index.finalize();
+
+        let found = index.query("yellow fruit");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].title(), "Banana");
+        assert_eq!(found[0].metadata()["category"], "Fruit");
+        assert_eq!(index.search("banana", 10)[0].0.url(), found[0].url());
+        assert_eq!(index.__len__(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+/**
+ * The bench module replays queries, such as those recorded in a query log, against an index
+ * from a number of threads at once, measuring the throughput and the latency of each query
+ */
+use crate::engine::IndexReader;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/**
+ * The measurements of a replay
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub threads: usize,
+    /**
+     * How long replaying every query took, from start to finish
+     */
+    pub elapsed: Duration,
+    /**
+     * The latency of every query, sorted from fastest to slowest
+     */
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    pub fn queries(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /**
+     * The number of queries answered per second
+     */
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.queries() as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.queries() {
+            0 => Duration::default(),
+            n => self.latencies.iter().sum::<Duration>() / n as u32,
+        }
+    }
+
+    /**
+     * The latency which `percentile` percent of the queries were at least as fast as, by the
+     * nearest rank
+     */
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let rank = (percentile / 100.0 * self.queries() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.queries()) - 1]
+    }
+}
+
+/**
+ * Run every query against the reader for its `limit` highest scoring documents, spread across
+ * the given number of threads
+ */
+pub fn replay<R: IndexReader + ?Sized>(
+    reader: &R,
+    queries: &[String],
+    limit: usize,
+    threads: usize,
+) -> Report {
+    let threads = threads.max(1);
+    let next = AtomicUsize::new(0);
+    let start = Instant::now();
+    let mut latencies: Vec<Duration> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut latencies = vec![];
+                    while let Some(query) = queries.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let started = Instant::now();
+                        reader.search(query, limit);
+                        latencies.push(started.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("A benchmark thread panicked"))
+            .collect()
+    });
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+
+    Report {
+        threads,
+        elapsed,
+        latencies,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+
+    #[test]
+    fn test_replay() -> Result<(), std::io::Error> {
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let queries: Vec<String> = ["history", "anarchism", "language", "war"]
+            .iter()
+            .cycle()
+            .take(20)
+            .map(|q| q.to_string())
+            .collect();
+        let report = replay(&index, &queries, 10, 3);
+        assert_eq!(report.queries(), 20);
+        assert_eq!(report.threads, 3);
+        assert!(report.latencies.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(report.percentile(100.0), report.latencies[19]);
+        assert_eq!(report.percentile(50.0), report.latencies[9]);
+        assert_eq!(report.percentile(0.0), report.latencies[0]);
+        assert!(report.throughput() > 0.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+/**
+ * The bloom module contains a plain bloom filter, which the segments of a SegmentedIndex keep
+ * over their terms so that looking a term up can skip every segment which cannot contain it
+ *
+ * A bloom filter can say a key is there when it is not, at roughly FALSE_POSITIVE_RATE, but
+ * never that a key is not there when it is. The filters are only ever held in memory, so the
+ * hashes do not need to be stable from one build to the next.
+ */
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/**
+ * How often a filter sized with `with_capacity()` says a key it has never seen might be there
+ */
+pub const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/**
+ * A fixed size set of bits which keys are hashed into
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /**
+     * An empty filter sized for `keys` keys at the FALSE_POSITIVE_RATE
+     */
+    pub fn with_capacity(keys: usize) -> Self {
+        let keys = keys.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-keys * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((bits as f64 / keys) * ln2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes,
+        }
+    }
+
+    pub fn insert<K: Hash + ?Sized>(&mut self, key: &K) {
+        for bit in self.bits_of(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /**
+     * Whether the key might have been inserted, false only if it certainly was not
+     */
+    pub fn might_contain<K: Hash + ?Sized>(&self, key: &K) -> bool {
+        self.bits_of(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /**
+     * The bits of the key, from two halves of a single hash combined `hashes` different ways
+     */
+    fn bits_of<K: Hash + ?Sized>(&self, key: &K) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (first, second) = (hash & 0xffff_ffff, hash >> 32);
+        let len = (self.bits.len() * 64) as u64;
+        (0..self.hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+    }
+}
+
+impl<K: Hash> std::iter::FromIterator<K> for BloomFilter {
+    /**
+     * A filter sized for and holding exactly the given keys
+     */
+    fn from_iter<I: IntoIterator<Item = K>>(keys: I) -> Self {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let mut filter = Self::with_capacity(keys.len());
+        for key in keys.iter() {
+            filter.insert(key);
+        }
+        filter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let filter: BloomFilter = (0..1000).map(|n| format!("term{}", n)).collect();
+        assert_eq!(filter.hashes, 7);
+        assert!((0..1000).all(|n| filter.might_contain(&format!("term{}", n))));
+
+        let false_positives = (1000..11_000)
+            .filter(|n| filter.might_contain(&format!("term{}", n)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let empty = BloomFilter::with_capacity(0);
+        assert!(!empty.might_contain("term"));
+    }
+}
@@ -0,0 +1,375 @@
+/**
+ * The boost module multiplies the scores of documents by how much they should be favored
+ * regardless of the query, such as for being popular or recent
+ *
+ * Each kind of boost implements the Boost trait, and a BoostedReader applies any number of them
+ * to the documents of the reader it wraps.
+ */
+use crate::engine::{Article, DocumentId, FieldLengths, IndexReader, TermStats};
+use crate::postings::PostingsRef;
+use crate::query::Clause;
+use crate::schema::{Field, Schema};
+use crate::scoring::Ranking;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/**
+ * Something which the scores of documents are multiplied by
+ */
+pub trait Boost: Send + Sync {
+    /**
+     * What the scores of the document in the reader are multiplied by, one to leave them be
+     */
+    fn boost(&self, reader: &dyn IndexReader, id: DocumentId) -> f64;
+}
+
+/**
+ * The metadata key which the dates of documents are read from by default
+ */
+pub const DEFAULT_DATE_KEY: &str = "date";
+
+/**
+ * Favor documents by how recent the date in their metadata is, decaying exponentially with age
+ *
+ * The newest documents have their scores multiplied by up to `1 + weight`, which halves every
+ * `half_life` after that. Documents without a date, or with one which cannot be parsed, are left
+ * alone.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recency {
+    key: String,
+    half_life: Duration,
+    weight: f64,
+    now: Option<DateTime<Utc>>,
+}
+
+impl Recency {
+    pub fn new(key: &str, half_life: Duration) -> Self {
+        Self {
+            key: key.to_string(),
+            half_life,
+            weight: 1.0,
+            now: None,
+        }
+    }
+
+    pub fn weight(mut self, weight: f64) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /**
+     * Measure the age of documents from this time, rather than from whenever they are scored
+     */
+    pub fn now(mut self, now: DateTime<Utc>) -> Self {
+        self.now = Some(now);
+        self
+    }
+
+    /**
+     * What the scores of a document from the date are multiplied by, dates in the future being
+     * as good as the present
+     */
+    pub fn boost_at(&self, date: DateTime<Utc>) -> f64 {
+        let now = self.now.unwrap_or_else(Utc::now);
+        let age = (now - date).num_seconds().max(0) as f64;
+        let half_life = self.half_life.as_secs_f64();
+        match half_life > 0.0 {
+            true => 1.0 + self.weight * 0.5f64.powf(age / half_life),
+            false => 1.0,
+        }
+    }
+}
+
+impl Boost for Recency {
+    fn boost(&self, reader: &dyn IndexReader, id: DocumentId) -> f64 {
+        reader
+            .document(&id)
+            .and_then(|article| {
+                article
+                    .metadata()
+                    .get(&self.key)
+                    .and_then(|d| parse_date(d))
+            })
+            .map(|date| self.boost_at(date))
+            .unwrap_or(1.0)
+    }
+}
+
+/**
+ * The metadata key which the editorial boosts of documents are read from by default
+ */
+pub const DEFAULT_BOOST_KEY: &str = "boost";
+
+/**
+ * Multiply the scores of documents by the number in their metadata, giving whoever owns the
+ * documents a simple knob for ranking them
+ *
+ * Documents without the key, or with something other than a number which is not negative, are
+ * left alone.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldBoost {
+    key: String,
+}
+
+impl FieldBoost {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+        }
+    }
+}
+
+impl Default for FieldBoost {
+    fn default() -> Self {
+        Self::new(DEFAULT_BOOST_KEY)
+    }
+}
+
+impl Boost for FieldBoost {
+    fn boost(&self, reader: &dyn IndexReader, id: DocumentId) -> f64 {
+        reader
+            .document(&id)
+            .and_then(|article| article.metadata().get(&self.key)?.trim().parse().ok())
+            .filter(|boost: &f64| boost.is_finite() && *boost >= 0.0)
+            .unwrap_or(1.0)
+    }
+}
+
+/**
+ * Parse a date in RFC 3339 format, or a plain `YYYY-MM-DD` which is taken as midnight UTC
+ */
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+                .map(|midnight| midnight.and_utc())
+        })
+}
+
+/**
+ * A reader which applies Boosts, and optionally its own Ranking, to the scores of the reader it
+ * wraps
+ *
+ * Queries are evaluated by this reader itself, rather than by the wrapped one, so that the
+ * boosts are applied whichever reader is wrapped.
+ */
+pub struct BoostedReader {
+    reader: Box<dyn IndexReader>,
+    boosts: Vec<Box<dyn Boost>>,
+    ranking: Option<Ranking>,
+}
+
+impl BoostedReader {
+    pub fn new(reader: Box<dyn IndexReader>) -> Self {
+        Self {
+            reader,
+            boosts: vec![],
+            ranking: None,
+        }
+    }
+
+    /**
+     * Score the documents as the Ranking says to, instead of as the wrapped reader does
+     */
+    pub fn ranking(mut self, ranking: Ranking) -> Self {
+        self.ranking = Some(ranking);
+        self
+    }
+
+    /**
+     * Apply the boost as well as those already added
+     */
+    pub fn with<B: Boost + 'static>(mut self, boost: B) -> Self {
+        self.boosts.push(Box::new(boost));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boosts.is_empty() && self.ranking.is_none()
+    }
+
+    /**
+     * The wrapped reader, or this one when there is anything to apply
+     */
+    pub fn into_reader(self) -> Box<dyn IndexReader> {
+        match self.is_empty() {
+            true => self.reader,
+            false => Box::new(self),
+        }
+    }
+}
+
+impl IndexReader for BoostedReader {
+    fn schema(&self) -> &Schema {
+        self.reader.schema()
+    }
+
+    fn size(&self) -> u64 {
+        self.reader.size()
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.reader.document_ids()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.reader.document(id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.reader.terms()
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.reader.postings(term)
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.reader.field_terms(field)
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.reader.field_postings(field, term)
+    }
+
+    fn postings_ref(&self, term: &str) -> Option<PostingsRef<'_>> {
+        self.reader.postings_ref(term)
+    }
+
+    fn field_postings_ref(&self, field: Field, term: &str) -> Option<PostingsRef<'_>> {
+        self.reader.field_postings_ref(field, term)
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.reader.term_frequency(id, term)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.reader.positions(id, term)
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        self.reader.idf(term)
+    }
+
+    fn term_stats(&self, term: &str) -> Option<TermStats> {
+        self.reader.term_stats(term)
+    }
+
+    fn document_length(&self, id: DocumentId) -> Option<f64> {
+        self.reader.document_length(id)
+    }
+
+    fn average_document_length(&self) -> f64 {
+        self.reader.average_document_length()
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.reader.field_lengths(id)
+    }
+
+    fn average_field_lengths(&self) -> (f64, f64) {
+        self.reader.average_field_lengths()
+    }
+
+    fn ranking(&self) -> Ranking {
+        self.ranking.unwrap_or_else(|| self.reader.ranking())
+    }
+
+    fn boost(&self, id: DocumentId) -> f64 {
+        self.boosts
+            .iter()
+            .map(|boost| boost.boost(self.reader.as_ref(), id))
+            .product::<f64>()
+            * self.reader.boost(id)
+    }
+
+    fn related_terms(&self, term: &str, n: usize) -> Vec<(String, f64)> {
+        self.reader.related_terms(term, n)
+    }
+
+    fn substring_candidates(&self, needle: &str) -> Option<HashSet<DocumentId>> {
+        self.reader.substring_candidates(needle)
+    }
+
+    fn terms_with_suffix(&self, suffix: &str) -> Option<Vec<String>> {
+        self.reader.terms_with_suffix(suffix)
+    }
+
+    fn rewrite(&self, clauses: Vec<Clause>) -> Vec<Clause> {
+        self.reader.rewrite(clauses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+
+    #[test]
+    fn test_recency() -> Result<(), std::io::Error> {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = parse_date("2024-03-11T00:00:00Z").unwrap();
+        assert_eq!(parse_date("2024-03-11"), Some(now));
+        assert_eq!(parse_date("yesterday"), None);
+
+        let recency = Recency::new(DEFAULT_DATE_KEY, day * 7).now(now);
+        assert_eq!(recency.boost_at(now), 2.0);
+        assert_eq!(recency.boost_at(now - chrono::Duration::days(7)), 1.5);
+        assert_eq!(recency.boost_at(now + chrono::Duration::days(1)), 2.0);
+
+        let mut index = Index::new();
+        let old = Article::new("Old news", "rain expected", "https://example.com/old")?
+            .with_metadata("date", "2023-01-01");
+        let new = Article::new("New news", "rain expected", "https://example.com/new")?
+            .with_metadata("date", "2024-03-10");
+        let undated = Article::new("News", "rain expected", "https://example.com/undated")?;
+        let other = Article::new("Sports", "a win", "https://example.com/sports")?;
+        let ids = [old.id(), new.id(), undated.id()];
+        for article in [old, new, undated, other] {
+            index.index_document(article)?;
+        }
+
+        let reader = BoostedReader::new(Box::new(index)).with(recency);
+        let results: Vec<DocumentId> = reader
+            .search("rain", 10)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(results[0], ids[1]);
+        assert!((reader.boost(ids[0]) - 1.0).abs() < 1e-9);
+        assert_eq!(reader.boost(ids[2]), 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_field_boost() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let plain = Article::new("Showers", "rain expected", "https://example.com/plain")?;
+        let promoted = Article::new("Drizzle", "rain expected", "https://example.com/promoted")?
+            .with_metadata("boost", "2.5");
+        let broken = Article::new("Storm", "rain expected", "https://example.com/broken")?
+            .with_metadata("boost", "-1");
+        let other = Article::new("Sports", "a win", "https://example.com/sports")?;
+        let ids = [plain.id(), promoted.id(), broken.id()];
+        for article in [plain, promoted, broken, other] {
+            index.index_document(article)?;
+        }
+
+        let reader = BoostedReader::new(Box::new(index)).with(FieldBoost::default());
+        assert_eq!(reader.boost(ids[0]), 1.0);
+        assert_eq!(reader.boost(ids[1]), 2.5);
+        assert_eq!(reader.boost(ids[2]), 1.0);
+        assert_eq!(reader.search("rain", 1)[0].0, ids[1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,496 @@
+/**
+ * The builder module contains the IndexBuilder, which gathers up everything an Index can be
+ * configured with before it is created or loaded from a Wikipedia XML dump
+ *
+ * A dump can also be indexed straight into a persisted index with `write()`, which with a
+ * memory budget flushes the documents and postings to disk as sorted runs in the persisted index
+ * format whenever they grow past it, and merges the runs on disk once every document has been
+ * indexed.
+ */
+use crate::disk::{self, DiskIndex};
+use crate::engine::{read_articles_with, Article, Index, IngestOptions};
+use crate::filters::Analyzer;
+use crate::schema::Schema;
+use crate::store::{lock, FileStorage, Storage};
+use crossbeam::channel::Receiver;
+use log::*;
+use std::collections::HashSet;
+use std::io::Error;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/**
+ * The number of parsed articles which can be waiting for a thread to index them
+ */
+const QUEUE_LEN: usize = 1024;
+
+/**
+ * The number of runs flushed by every IndexBuilder of the process, so that each gets a file of
+ * its own
+ */
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * The number of files documents were spilled to by every IndexBuilder of the process, so that
+ * each gets a file of its own
+ */
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * Documents and their postings flushed to a temporary file, which is removed once the runs have
+ * been merged or the build has failed
+ */
+struct Run {
+    path: PathBuf,
+}
+
+impl Run {
+    /**
+     * Write everything in the index to a new run
+     */
+    fn flush(index: &Index) -> Result<Self, Error> {
+        let path = std::env::temp_dir().join(format!(
+            "goedesearch-{}-{}.run",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        debug!(
+            "Flushing {} documents and about {} bytes of postings to {:?}",
+            index.size(),
+            index.postings_size(),
+            path
+        );
+        let run = Self { path };
+        disk::write(index, &run.path)?;
+        Ok(run)
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove the run {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexBuilder {
+    schema: Schema,
+    limit: Option<usize>,
+    cache_size: Option<NonZeroUsize>,
+    trigrams: bool,
+    reversed_terms: bool,
+    cooccurrences: bool,
+    documents: Option<Arc<dyn Storage>>,
+    memory_budget: Option<usize>,
+    parallelism: usize,
+    ingest: IngestOptions,
+}
+
+impl Default for IndexBuilder {
+    fn default() -> Self {
+        Self {
+            schema: Schema::default(),
+            limit: None,
+            cache_size: None,
+            trigrams: false,
+            reversed_terms: false,
+            cooccurrences: false,
+            documents: None,
+            memory_budget: None,
+            parallelism: 1,
+            ingest: IngestOptions::default(),
+        }
+    }
+}
+
+impl IndexBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Analyze documents and queries with the given Schema
+     */
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /**
+     * Analyze the full text with the given Analyzer, leaving the rest of the Schema as it is
+     */
+    pub fn analyzer(mut self, analyzer: Analyzer) -> Self {
+        self.schema = self.schema.text(analyzer);
+        self
+    }
+
+    /**
+     * Return at most this many of the highest scoring results from every query
+     */
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /**
+     * Cache the results of up to this many distinct queries
+     */
+    pub fn cache_size(mut self, capacity: NonZeroUsize) -> Self {
+        self.cache_size = Some(capacity);
+        self
+    }
+
+    /**
+     * Build the trigram index which speeds up `contains:` queries
+     */
+    pub fn trigrams(mut self, trigrams: bool) -> Self {
+        self.trigrams = trigrams;
+        self
+    }
+
+    /**
+     * Build the reversed term index which speeds up `*suffix` wildcard queries, at the cost of
+     * keeping every term a second time
+     */
+    pub fn reversed_terms(mut self, reversed_terms: bool) -> Self {
+        self.reversed_terms = reversed_terms;
+        self
+    }
+
+    /**
+     * Count which terms occur near each other, so that `related_terms` can suggest related
+     * searches, at the cost of a count for every pair of nearby terms
+     */
+    pub fn cooccurrences(mut self, cooccurrences: bool) -> Self {
+        self.cooccurrences = cooccurrences;
+        self
+    }
+
+    /**
+     * Keep the documents in the Storage, rather than in memory
+     *
+     * With a memory budget, the documents are only moved into the Storage once the budget has
+     * been used up.
+     */
+    pub fn documents(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.documents = Some(storage);
+        self
+    }
+
+    /**
+     * Keep roughly this many bytes of documents in memory, after which they are moved into the
+     * document Storage, or a temporary file if none was given
+     *
+     * An Index holds all of its postings in memory, so only `write()` keeps them to the budget
+     * as well, by flushing them to disk along with the documents.
+     */
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /**
+     * Index the documents of a dump with this many threads, each of which builds part of the
+     * index before the parts are combined
+     */
+    pub fn parallelism(mut self, threads: usize) -> Self {
+        self.parallelism = threads.max(1);
+        self
+    }
+
+    /**
+     * Deal with malformed entries in a dump as the options say, instead of skipping them
+     */
+    pub fn ingest(mut self, options: IngestOptions) -> Self {
+        self.ingest = options;
+        self
+    }
+
+    /**
+     * Create an empty Index with the configured options
+     */
+    pub fn build(&self) -> Result<Index, Error> {
+        let mut index = Index::with_schema(self.schema.clone());
+        if let (Some(storage), None) = (&self.documents, self.memory_budget) {
+            index.store_documents(storage.clone())?;
+        }
+        self.configure(&mut index);
+        Ok(index)
+    }
+
+    fn configure(&self, index: &mut Index) {
+        if self.trigrams {
+            index.enable_trigrams();
+        }
+        if self.reversed_terms {
+            index.enable_reversed_terms();
+        }
+        if self.cooccurrences {
+            index.enable_cooccurrences();
+        }
+        if let Some(capacity) = self.cache_size {
+            index.enable_query_cache(capacity);
+        }
+        if let Some(limit) = self.limit {
+            index.limit_results(limit);
+        }
+    }
+
+    /**
+     * The Storage documents are moved into once a memory budget has been used up, which is a
+     * temporary file of its own unless a document Storage was given
+     */
+    fn spill_storage(&self) -> Result<Option<Arc<dyn Storage>>, Error> {
+        if self.memory_budget.is_none() {
+            return Ok(None);
+        }
+        if let Some(storage) = &self.documents {
+            return Ok(Some(storage.clone()));
+        }
+        let path = std::env::temp_dir().join(format!(
+            "goedesearch-{}-{}.docs",
+            std::process::id(),
+            SPILLS.fetch_add(1, Ordering::Relaxed)
+        ));
+        debug!(
+            "Documents over the memory budget will be kept in {:?}",
+            path
+        );
+        // The file goes away along with the last index keeping its documents in it
+        Ok(Some(Arc::new(FileStorage::temporary(&path)?)))
+    }
+
+    /**
+     * Load the Wikipedia XML dump at the given path into a new Index with the configured
+     * options
+     */
+    pub fn from_file(&self, path: &Path) -> Result<Index, Error> {
+        let spill = self.spill_storage()?;
+        let budget = self.memory_budget.map(|bytes| bytes / self.parallelism);
+
+        let parts = self.index_dump(path, |queue| {
+            let mut part = Index::with_schema(self.schema.clone());
+            if let (Some(storage), None) = (&self.documents, budget) {
+                part.store_documents(storage.clone())?;
+            }
+            let mut used = 0;
+            let mut spilled = false;
+            for article in queue {
+                used += article.memory_size();
+                part.index_document(article)?;
+                if let (Some(budget), Some(storage)) = (budget, &spill) {
+                    if !spilled && used > budget {
+                        debug!("Spilling documents after {} bytes", used);
+                        part.store_documents(storage.clone())?;
+                        spilled = true;
+                    }
+                }
+            }
+            Ok((part, spilled))
+        })?;
+
+        // Each part is dropped as soon as it has been copied into the first
+        let mut parts = parts.into_iter();
+        let (mut index, mut spilled) = parts
+            .next()
+            .unwrap_or_else(|| (Index::with_schema(self.schema.clone()), false));
+        for (part, part_spilled) in parts {
+            if let (Some(storage), true, false) = (&spill, part_spilled, spilled) {
+                index.store_documents(storage.clone())?;
+                spilled = true;
+            }
+            index.copy_from(&part)?;
+        }
+        index.finalize();
+        self.configure(&mut index);
+        debug!("Built an index of {} documents", index.size());
+        Ok(index)
+    }
+
+    /**
+     * Index the Wikipedia XML dump at `dump` into a persisted index at `path`, returning it
+     * opened as a DiskIndex
+     *
+     * With a memory budget, each thread writes the documents and postings it has indexed to a
+     * temporary run whenever they grow past its share of the budget, and the runs are merged
+     * into the persisted index term by term at the end. Neither ever holds the whole index in
+     * memory, which is how dumps larger than the memory of the machine are indexed. The options
+     * only an in-memory Index has, such as trigrams, a cache or a document Storage, do not apply
+     * to the runs.
+     */
+    pub fn write(&self, dump: &Path, path: &Path) -> Result<DiskIndex, Error> {
+        let budget = match self.memory_budget {
+            Some(bytes) => bytes / self.parallelism,
+            None => {
+                self.from_file(dump)?.save(path)?;
+                return DiskIndex::open(path);
+            }
+        };
+
+        // Runs must not have documents in common, even when they are from different threads
+        let seen = Mutex::new(HashSet::new());
+        let runs = self.index_dump(dump, |queue| {
+            let mut runs = vec![];
+            let mut part = Index::with_schema(self.schema.clone());
+            let mut used = 0;
+            for article in queue {
+                if !lock(&seen)?.insert(article.id()) {
+                    continue;
+                }
+                used += article.memory_size();
+                part.index_document(article)?;
+                if used + part.postings_size() > budget {
+                    runs.push(Run::flush(&part)?);
+                    part = Index::with_schema(self.schema.clone());
+                    used = 0;
+                }
+            }
+            if part.size() > 0 {
+                runs.push(Run::flush(&part)?);
+            }
+            Ok(runs)
+        })?;
+
+        let runs: Vec<Run> = runs.into_iter().flatten().collect();
+        debug!("Merging {} runs into {:?}", runs.len(), path);
+        let indexes = runs
+            .iter()
+            .map(|run| DiskIndex::open(&run.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        disk::merge(&indexes, path)?;
+        drop(indexes);
+        drop(runs);
+
+        let index = DiskIndex::open(path)?;
+        debug!("Wrote an index of {} documents", index.metadata().documents);
+        Ok(index)
+    }
+
+    /**
+     * Parse the dump on this thread while `parallelism` threads each do the work with the
+     * articles they are handed, returning what each of them came up with
+     */
+    fn index_dump<T, W>(&self, path: &Path, work: W) -> Result<Vec<T>, Error>
+    where
+        T: Send,
+        W: Fn(Receiver<Article>) -> Result<T, Error> + Sync,
+    {
+        let (articles, queue) = crossbeam::channel::bounded::<Article>(QUEUE_LEN);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.parallelism)
+                .map(|_| {
+                    let queue = queue.clone();
+                    let work = &work;
+                    scope.spawn(move || work(queue))
+                })
+                .collect();
+            drop(queue);
+
+            let parsed = read_articles_with(path, &self.ingest, |article| {
+                articles
+                    .send(article)
+                    .map_err(|_| Error::other("an indexing thread stopped early"))
+            });
+            // Hanging up lets the workers know that every article has been sent
+            drop(articles);
+
+            let parts = workers
+                .into_iter()
+                .map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|_| Err(Error::other("an indexing thread panicked")))
+                })
+                .collect::<Result<Vec<_>, _>>();
+            // A worker failing makes the parsing fail as well, so its error is the useful one
+            let parts = parts?;
+            parsed?;
+            Ok(parts)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::IndexReader;
+    use crate::store::MemoryStorage;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_build_with_options() -> Result<(), Error> {
+        let path = PathBuf::from("data/simple.xml.gz");
+        let expected = Index::from_file(&path)?;
+
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let index = IndexBuilder::new()
+            .parallelism(4)
+            .memory_budget(64 * 1024)
+            .documents(storage.clone())
+            .limit(3)
+            .from_file(&path)?;
+        assert_eq!(index.size(), expected.size());
+        assert!(!storage.document_ids()?.is_empty());
+        for query in &["history", "\"political philosophy\"", "title:history"] {
+            let mut all = expected.query_index(query);
+            all.truncate(3);
+            assert_eq!(
+                index.query_index(query),
+                all,
+                "results differ for {}",
+                query
+            );
+        }
+        let id = expected.query_index("anarchism")[0];
+        assert_eq!(IndexReader::document(&index, &id), expected.document(&id));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spilled_documents() -> Result<(), Error> {
+        let path = PathBuf::from("data/simple.xml.gz");
+        let expected = Index::from_file(&path)?;
+
+        // Each builder spills to a file of its own, so the second does not clobber the first
+        let first = IndexBuilder::new().memory_budget(1).from_file(&path)?;
+        let second = IndexBuilder::new()
+            .memory_budget(1)
+            .parallelism(4)
+            .from_file(&path)?;
+        for id in expected.document_ids() {
+            assert_eq!(IndexReader::document(&first, &id), expected.document(&id));
+            assert_eq!(IndexReader::document(&second, &id), expected.document(&id));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_runs() -> Result<(), Error> {
+        let path = PathBuf::from("data/simple.xml.gz");
+        let output = std::env::temp_dir().join(format!("goede-runs-{}.idx", std::process::id()));
+        let expected = Index::from_file(&path)?;
+
+        // Every document goes over a budget this small, so each is flushed to a run of its own
+        let index = IndexBuilder::new()
+            .memory_budget(1)
+            .parallelism(2)
+            .write(&path, &output)?;
+        assert_eq!(index.size(), expected.size());
+        for query in &["history", "\"political philosophy\"", "title:history"] {
+            assert_eq!(
+                index.search(query, 5),
+                expected.search(query, 5),
+                "results differ for {}",
+                query
+            );
+        }
+        // Merging the runs lays everything out just as writing the whole index at once does
+        assert_eq!(std::fs::read(&output)?, disk::to_bytes(&expected)?);
+        std::fs::remove_file(&output)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+/**
+ * The bulk module runs a whole file of queries against an index at once, writing the results of
+ * each as a line of JSON, so that the results of an index can be compared over time or fed into
+ * an evaluation
+ */
+use crate::engine::{DocumentId, IndexReader};
+use crate::spell;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Error, Write};
+
+/**
+ * A single hit of a query, without the rest of its document
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BulkHit {
+    pub id: DocumentId,
+    pub score: f64,
+    pub title: String,
+}
+
+/**
+ * The terms which a term of a query found in no document might have been meant to be
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Suggestions {
+    pub term: String,
+    pub suggestions: Vec<String>,
+}
+
+/**
+ * The most suggestions for each term of a query found in no document
+ */
+pub const MAX_SUGGESTIONS: usize = 3;
+
+/**
+ * The results of one of the queries, which is written as a line of JSON
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct QueryResults {
+    pub query: String,
+    pub hits: Vec<BulkHit>,
+    /**
+     * Suggestions for each of the (analyzed) terms of the query which are in no document, so
+     * that a "did you mean" can be shown along with the results
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<Suggestions>,
+}
+
+/**
+ * Search the reader for the `limit` highest scoring hits of the query
+ */
+pub fn search<R: IndexReader + ?Sized>(reader: &R, query: &str, limit: usize) -> QueryResults {
+    let hits = reader
+        .search(query, limit)
+        .into_iter()
+        .map(|(id, score)| BulkHit {
+            id,
+            score,
+            title: reader
+                .document(&id)
+                .map(|article| article.title().to_string())
+                .unwrap_or_default(),
+        })
+        .collect();
+    QueryResults {
+        query: query.to_string(),
+        hits,
+        suggestions: suggest(reader, query),
+    }
+}
+
+/**
+ * Suggest terms for each of the (analyzed) terms of the query which are in no document of the
+ * reader, leaving out those for which nothing close enough is
+ */
+pub fn suggest<R: IndexReader + ?Sized>(reader: &R, query: &str) -> Vec<Suggestions> {
+    reader
+        .normalize(query)
+        .terms
+        .into_iter()
+        .filter(|term| reader.postings_ref(term).is_none_or(|docs| docs.is_empty()))
+        .map(|term| Suggestions {
+            suggestions: spell::suggestions(reader, &term, MAX_SUGGESTIONS),
+            term,
+        })
+        .filter(|suggestions| !suggestions.suggestions.is_empty())
+        .collect()
+}
+
+/**
+ * Run every line of the queries against the reader, skipping blank ones, and write the results
+ * of each as a line of JSON, returning how many were run
+ */
+pub fn run<R, I, W>(reader: &R, queries: I, limit: usize, out: &mut W) -> Result<usize, Error>
+where
+    R: IndexReader + ?Sized,
+    I: BufRead,
+    W: Write + ?Sized,
+{
+    let mut count = 0;
+    for line in queries.lines() {
+        let line = line?;
+        let query = line.trim();
+        if query.is_empty() {
+            continue;
+        }
+        serde_json::to_writer(&mut *out, &search(reader, query, limit))?;
+        writeln!(out)?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+
+    #[test]
+    fn test_run() -> Result<(), Error> {
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let mut out = vec![];
+        let count = run(&index, "history\n\n  anarchism \n".as_bytes(), 3, &mut out)?;
+        assert_eq!(count, 2);
+
+        let lines: Vec<QueryResults> = out
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].query, "history");
+        assert_eq!(lines[1].query, "anarchism");
+        let ids: Vec<_> = lines[0]
+            .hits
+            .iter()
+            .map(|hit| (hit.id, hit.score))
+            .collect();
+        assert_eq!(ids, index.search("history", 3));
+        assert!(lines[0].suggestions.is_empty());
+
+        let misspelled = search(&index, "histry", 3);
+        assert!(misspelled.hits.is_empty());
+        assert_eq!(misspelled.suggestions[0].suggestions[0], "histori");
+        Ok(())
+    }
+}
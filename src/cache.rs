@@ -0,0 +1,107 @@
+/**
+ * The cache module contains the optional cache of query results, which lets repeated queries
+ * skip evaluation entirely
+ */
+use crate::engine::DocumentId;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+/**
+ * A least-recently-used cache of query results keyed by the normalized query
+ *
+ * The cache is only ever valid for the exact contents of the index it belongs to, so the index
+ * must clear it whenever it is mutated.
+ */
+pub struct QueryCache {
+    capacity: NonZeroUsize,
+    entries: Mutex<LruCache<String, Vec<DocumentId>>>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /**
+     * Fetch the cached results for the key, marking them as recently used
+     */
+    pub fn get(&self, key: &str) -> Option<Vec<DocumentId>> {
+        self.entries
+            .lock()
+            .ok()
+            .and_then(|mut entries| entries.get(key).cloned())
+    }
+
+    pub fn put(&self, key: String, results: Vec<DocumentId>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.put(key, results);
+        }
+    }
+
+    /**
+     * Drop every cached result
+     */
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().map(|e| e.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Clone for QueryCache {
+    /**
+     * Cloning a cache produces an empty cache of the same capacity, since the results cannot be
+     * trusted to stay valid for the clone of the index
+     */
+    fn clone(&self) -> Self {
+        Self::new(self.capacity)
+    }
+}
+
+impl std::fmt::Debug for QueryCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "QueryCache {{ capacity: {}, len: {} }}",
+            self.capacity,
+            self.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = QueryCache::new(NonZeroUsize::new(2).unwrap());
+        cache.put("a".into(), vec![1]);
+        cache.put("b".into(), vec![2]);
+        assert_eq!(cache.get("a"), Some(vec![1]));
+
+        cache.put("c".into(), vec![3]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(vec![1]));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_clone_is_empty() {
+        let cache = QueryCache::new(NonZeroUsize::new(2).unwrap());
+        cache.put("a".into(), vec![1]);
+        assert!(cache.clone().is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+/**
+ * The cluster module groups the top hits of a query by how similar their documents are, so that
+ * a broad query can be shown as a handful of topics rather than one long list
+ *
+ * Each hit is compared with the clusters found so far in rank order, and joins the most similar
+ * one if it is similar enough, otherwise it starts a new cluster. This only looks at the hits
+ * once, which is plenty for the few dozen results a person will read.
+ */
+use crate::engine::{cosine, tfidf_vector, DocumentId, IndexReader};
+use std::collections::HashMap;
+
+/**
+ * The number of top hits which are clustered unless told otherwise
+ */
+pub const DEFAULT_HITS: usize = 50;
+
+#[derive(Clone, Debug)]
+pub struct ClusterOptions {
+    /**
+     * How similar a document has to be to a cluster to join it, from zero to one
+     */
+    pub threshold: f64,
+    /**
+     * How many terms each cluster is labelled with
+     */
+    pub labels: usize,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.2,
+            labels: 3,
+        }
+    }
+}
+
+/**
+ * A group of similar hits
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cluster {
+    /**
+     * The terms which set the cluster apart from the others, most significant first
+     */
+    pub label: Vec<String>,
+    /**
+     * The documents in the cluster, in the order they were ranked
+     */
+    pub documents: Vec<DocumentId>,
+}
+
+/**
+ * Group the hits, which should be in rank order, into clusters of similar documents
+ *
+ * The clusters are ordered by their best ranked document, and hits which are no longer in the
+ * index are left out.
+ */
+pub fn cluster<R: IndexReader + ?Sized>(
+    reader: &R,
+    hits: &[DocumentId],
+    options: &ClusterOptions,
+) -> Vec<Cluster> {
+    let mut centroids: Vec<HashMap<String, f64>> = vec![];
+    let mut members: Vec<Vec<DocumentId>> = vec![];
+
+    for id in hits.iter() {
+        let vector = match tfidf_vector(reader, id) {
+            Some(vector) => vector,
+            None => continue,
+        };
+        let best = centroids
+            .iter()
+            .map(|centroid| cosine(centroid, &vector))
+            .enumerate()
+            .filter(|(_, similarity)| *similarity >= options.threshold)
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Less));
+
+        match best {
+            Some((i, _)) => {
+                for (term, weight) in vector {
+                    *centroids[i].entry(term).or_default() += weight;
+                }
+                members[i].push(*id);
+            }
+            None => {
+                centroids.push(vector);
+                members.push(vec![*id]);
+            }
+        }
+    }
+
+    let labels: Vec<Vec<String>> = centroids
+        .iter()
+        .map(|centroid| label(centroid, &centroids, options.labels))
+        .collect();
+    labels
+        .into_iter()
+        .zip(members)
+        .map(|(label, documents)| Cluster { label, documents })
+        .collect()
+}
+
+/**
+ * The heaviest terms of the centroid, leaving out those which every cluster has in common
+ * (such as the terms of the query itself) since they do not tell the clusters apart
+ */
+fn label(centroid: &HashMap<String, f64>, all: &[HashMap<String, f64>], n: usize) -> Vec<String> {
+    let mut scored: Vec<(String, f64)> = centroid
+        .iter()
+        .filter(|(term, _)| all.len() == 1 || !all.iter().all(|other| other.contains_key(*term)))
+        .map(|(term, weight)| (term.clone(), *weight))
+        .collect();
+    crate::query::sort_scored(&mut scored);
+    scored.into_iter().take(n).map(|(term, _)| term).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Article, Index};
+
+    #[test]
+    fn test_cluster_hits() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let articles = [
+            ("Statue of Liberty", "A statue of a woman in New York"),
+            ("Liberty Island", "The island with the statue of a woman"),
+            ("Green bananas", "Banana fruit which is not yet ripe"),
+            ("Banana bread", "Bread baked with ripe banana fruit"),
+        ];
+        let mut ids = vec![];
+        for (i, (title, text)) in articles.iter().enumerate() {
+            let article = Article::new(title, text, &format!("https://example.com/{}", i))?;
+            ids.push(article.id());
+            index.index_document(article)?;
+        }
+        index.finalize();
+
+        let clusters = cluster(&index, &ids, &ClusterOptions::default());
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].documents, &ids[0..2]);
+        assert_eq!(clusters[1].documents, &ids[2..4]);
+        assert!(clusters[0].label.contains(&"statu".to_string()));
+        assert!(clusters[1].label.contains(&"banana".to_string()));
+        Ok(())
+    }
+}
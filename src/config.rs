@@ -0,0 +1,735 @@
+/**
+ * The config module contains the declarative configuration which can be loaded from a TOML file
+ * to describe how the engine should behave
+ */
+use crate::filters::*;
+use crate::schema::{Field, Schema};
+use crate::scoring::{EarlyExit, ProximityBonus, Ranking, Scorer, TitleBonus};
+use crate::vectors::Fusion;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/**
+ * The top-level configuration file
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub analysis: AnalysisConfig,
+    pub ranking: RankingConfig,
+}
+
+impl Config {
+    /**
+     * Load the configuration from the TOML file at the given path
+     */
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_toml(&contents)
+    }
+
+    /**
+     * Parse the configuration, reading any stopword files it refers to
+     */
+    pub fn from_toml(contents: &str) -> Result<Self, Error> {
+        let mut config: Self =
+            toml::from_str(contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        config.analysis.read_stopwords()?;
+        config.ranking.validate()?;
+        Ok(config)
+    }
+}
+
+/**
+ * The description of how every part of a document is analyzed
+ *
+ * ```toml
+ * metadata = ["category"]
+ *
+ * [analyzer]
+ * tokenizer = "unicode"
+ * stemmer = "english"
+ * stopwords = "stopwords.txt"
+ *
+ * [fields.url]
+ * tokenizer = "keyword"
+ * ```
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct AnalysisConfig {
+    /**
+     * The pipeline used for the full text of every document
+     */
+    pub analyzer: AnalyzerConfig,
+    /**
+     * Pipelines for individually searchable fields, keyed by the field name. Fields which are
+     * not listed keep their analyzer from the default Schema
+     */
+    pub fields: HashMap<String, AnalyzerConfig>,
+    /**
+     * The metadata keys whose values are indexed as keywords
+     */
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<String>,
+}
+
+impl AnalysisConfig {
+    /**
+     * Build the Schema described by this configuration
+     */
+    pub fn schema(&self) -> Result<Schema, Error> {
+        let mut schema = Schema::default().text(self.analyzer.build()?);
+
+        for (name, analyzer) in self.fields.iter() {
+            let field: Field = name
+                .parse()
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            schema = schema.field(field, analyzer.build()?);
+        }
+        for key in self.metadata.iter() {
+            schema = schema.metadata(key);
+        }
+        Ok(schema.with_config(self.clone()))
+    }
+
+    /**
+     * Read the words of every stopword file into the configuration, so that an index built
+     * with it records the words rather than a path which only exists where it was built
+     */
+    pub fn read_stopwords(&mut self) -> Result<(), Error> {
+        self.analyzer.read_stopwords()?;
+        for analyzer in self.fields.values_mut() {
+            analyzer.read_stopwords()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * A short digest of everything which affects how text is analyzed, for telling whether an
+     * index was built with the same configuration
+     *
+     * Stopwords read from a file are digested as part of the configuration. Configurations
+     * from before the words were recorded only have the path, so the file is digested by its
+     * contents instead.
+     */
+    pub fn fingerprint(&self) -> Result<String, Error> {
+        use crc::{crc64, Hasher64};
+
+        let fields: BTreeMap<&String, &AnalyzerConfig> = self.fields.iter().collect();
+        let mut digest = crc64::Digest::new(crc64::ECMA);
+        digest.write(&serde_json::to_vec(&(&self.analyzer, &fields))?);
+        // Only digested when there are any, so that older fingerprints stay the same
+        if !self.metadata.is_empty() {
+            let mut keys = self.metadata.clone();
+            keys.sort();
+            digest.write(&serde_json::to_vec(&keys)?);
+        }
+
+        for analyzer in std::iter::once(&self.analyzer).chain(fields.values().copied()) {
+            if analyzer.is_stopword_file() && analyzer.stopword_list.is_none() {
+                digest.write(&std::fs::read(&analyzer.stopwords)?);
+            }
+        }
+        Ok(format!("{:016x}", digest.sum64()))
+    }
+}
+
+/**
+ * How the documents matching a query are scored, which unlike the analysis can be changed
+ * without rebuilding the index
+ *
+ * The parameters are all optional, leaving out one keeps its usual value, and those which do
+ * not apply to the scorer are ignored. An index records the configuration it was built with, so
+ * that it is searched the same way wherever it is served.
+ *
+ * ```toml
+ * [ranking]
+ * scorer = "bm25"
+ * k1 = 1.5
+ * b = 0.6
+ * recency_half_life = 30
+ * ```
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RankingConfig {
+    /**
+     * One of `tfidf`, `pivoted`, `bm25`, `bm25f` or `dirichlet`
+     */
+    pub scorer: String,
+    /**
+     * The saturation of term frequencies by BM25 and BM25F
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k1: Option<f64>,
+    /**
+     * How much BM25 and BM25F normalize by the length of the document or field
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b: Option<f64>,
+    /**
+     * The Dirichlet prior of query likelihood
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mu: Option<f64>,
+    /**
+     * The slope of the pivoted length normalization of TF-IDF
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slope: Option<f64>,
+    /**
+     * How much the title counts for with BM25F
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title_weight: Option<f64>,
+    /**
+     * How much the abstract counts for with BM25F
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abstract_weight: Option<f64>,
+    /**
+     * The bonus for documents whose title has every term of the query
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title_bonus: Option<f64>,
+    /**
+     * The further bonus for documents whose title is the query
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exact_title_bonus: Option<f64>,
+    /**
+     * The bonus for documents with the terms of the query next to each other
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proximity_bonus: Option<f64>,
+    /**
+     * How strongly the priors of documents affect their scores
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prior_weight: Option<f64>,
+    /**
+     * The number of days it takes the boost of recent documents to halve
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recency_half_life: Option<f64>,
+    /**
+     * How much the newest documents are boosted by
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recency_weight: Option<f64>,
+    /**
+     * How much the lexical scores count for in a hybrid search, which fuses the two rankings by
+     * their weighted scores rather than their ranks when it is set
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lexical_weight: Option<f64>,
+    /**
+     * The `k` of reciprocal rank fusion in a hybrid search
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rrf_k: Option<f64>,
+    /**
+     * Stop scoring the documents matching a query once this many are within
+     * `early_exit_ratio` of the best score so far, for approximate but faster results
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub early_exit_hits: Option<usize>,
+    /**
+     * How close to the best score so far a document has to be to count towards
+     * `early_exit_hits`, 0.5 by default
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub early_exit_ratio: Option<f64>,
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            scorer: "tfidf".to_string(),
+            k1: None,
+            b: None,
+            mu: None,
+            slope: None,
+            title_weight: None,
+            abstract_weight: None,
+            title_bonus: None,
+            exact_title_bonus: None,
+            proximity_bonus: None,
+            prior_weight: None,
+            recency_half_life: None,
+            recency_weight: None,
+            lexical_weight: None,
+            rrf_k: None,
+            early_exit_hits: None,
+            early_exit_ratio: None,
+        }
+    }
+}
+
+impl RankingConfig {
+    /**
+     * Build the Ranking described by this configuration
+     */
+    pub fn ranking(&self) -> Result<Ranking, Error> {
+        let mut scorer: Scorer = self
+            .scorer
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        match &mut scorer {
+            Scorer::TfIdf => {}
+            Scorer::PivotedTfIdf { slope } => *slope = self.slope.unwrap_or(*slope),
+            Scorer::Bm25 { k1, b } => {
+                *k1 = self.k1.unwrap_or(*k1);
+                *b = self.b.unwrap_or(*b);
+            }
+            Scorer::Bm25F {
+                k1,
+                title,
+                r#abstract,
+            } => {
+                *k1 = self.k1.unwrap_or(*k1);
+                title.weight = self.title_weight.unwrap_or(title.weight);
+                title.b = self.b.unwrap_or(title.b);
+                r#abstract.weight = self.abstract_weight.unwrap_or(r#abstract.weight);
+                r#abstract.b = self.b.unwrap_or(r#abstract.b);
+            }
+            Scorer::Dirichlet { mu } => *mu = self.mu.unwrap_or(*mu),
+        }
+        let mut title = TitleBonus::default();
+        title.matched = self.title_bonus.unwrap_or(title.matched);
+        title.exact = self.exact_title_bonus.unwrap_or(title.exact);
+        let mut proximity = ProximityBonus::default();
+        proximity.weight = self.proximity_bonus.unwrap_or(proximity.weight);
+        let early_exit = match self.early_exit_hits {
+            Some(hits) => EarlyExit::new(hits, self.early_exit_ratio.unwrap_or(0.5)),
+            None => EarlyExit::default(),
+        };
+        Ok(Ranking {
+            scorer,
+            title,
+            proximity,
+            early_exit,
+        })
+    }
+
+    /**
+     * How the rankings of a hybrid search are fused
+     */
+    pub fn fusion(&self) -> Fusion {
+        match (self.lexical_weight, self.rrf_k) {
+            (Some(lexical_weight), _) => Fusion::Weighted { lexical_weight },
+            (None, Some(k)) => Fusion::ReciprocalRank { k },
+            (None, None) => Fusion::default(),
+        }
+    }
+
+    /**
+     * Override a single setting from a `KEY=VALUE` string, e.g. `k1=1.2`
+     */
+    pub fn set(&mut self, setting: &str) -> Result<(), Error> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidInput, message);
+        let (key, value) = setting
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| invalid(format!("`{}` is not KEY=VALUE", setting)))?;
+        if key == "scorer" {
+            self.scorer = value.to_string();
+            return self.ranking().map(|_| ());
+        }
+        if key == "early_exit_hits" {
+            let hits = value
+                .parse()
+                .map_err(|_| invalid(format!("the value of `{}` is not a count", key)))?;
+            self.early_exit_hits = Some(hits);
+            return Ok(());
+        }
+        let number: f64 = value
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite())
+            .ok_or_else(|| invalid(format!("the value of `{}` is not a number", key)))?;
+        if key == "recency_half_life" {
+            check_half_life(number)?;
+        }
+        let parameter = match key {
+            "k1" => &mut self.k1,
+            "b" => &mut self.b,
+            "mu" => &mut self.mu,
+            "slope" => &mut self.slope,
+            "title_weight" => &mut self.title_weight,
+            "abstract_weight" => &mut self.abstract_weight,
+            "title_bonus" => &mut self.title_bonus,
+            "exact_title_bonus" => &mut self.exact_title_bonus,
+            "proximity_bonus" => &mut self.proximity_bonus,
+            "prior_weight" => &mut self.prior_weight,
+            "recency_half_life" => &mut self.recency_half_life,
+            "recency_weight" => &mut self.recency_weight,
+            "lexical_weight" => &mut self.lexical_weight,
+            "rrf_k" => &mut self.rrf_k,
+            "early_exit_ratio" => &mut self.early_exit_ratio,
+            _ => return Err(invalid(format!("unknown ranking setting `{}`", key))),
+        };
+        *parameter = Some(number);
+        Ok(())
+    }
+
+    /**
+     * Refuse settings which no ranking can be built from, which parsing alone lets through
+     */
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.recency_half_life {
+            Some(days) => check_half_life(days),
+            None => Ok(()),
+        }
+    }
+}
+
+fn check_half_life(days: f64) -> Result<(), Error> {
+    match days > 0.0 && days.is_finite() {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("a recency half-life of {} days is not positive", days),
+        )),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenizerKind {
+    Whitespace,
+    Unicode,
+    Keyword,
+}
+
+/**
+ * The description of a single Analyzer pipeline
+ *
+ * The stages are always applied in the same order: tokenizing, lowercasing, stripping
+ * punctuation, ascii folding, length limits, stopwords and finally stemming. The defaults
+ * describe the same pipeline as the default Analyzer.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct AnalyzerConfig {
+    pub tokenizer: TokenizerKind,
+    pub lowercase: bool,
+    pub strip_punctuation: bool,
+    pub ascii_folding: bool,
+    /**
+     * Tokens shorter than this many characters are dropped
+     */
+    pub min_length: usize,
+    /**
+     * Tokens longer than this many characters are dropped
+     */
+    pub max_length: Option<usize>,
+    /**
+     * Either `english` for the built-in list, `none`, or the path to a file with one stopword
+     * per line
+     */
+    pub stopwords: String,
+    /**
+     * The words of the stopword file, which are read when the configuration is loaded and used
+     * instead of the file from then on
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopword_list: Option<Vec<String>>,
+    /**
+     * The snowball stemmer language, or `none` to disable stemming
+     */
+    pub stemmer: String,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        Self {
+            tokenizer: TokenizerKind::Whitespace,
+            lowercase: true,
+            strip_punctuation: true,
+            ascii_folding: false,
+            min_length: 0,
+            max_length: None,
+            stopwords: "english".to_string(),
+            stopword_list: None,
+            stemmer: "english".to_string(),
+        }
+    }
+}
+
+impl AnalyzerConfig {
+    fn is_stopword_file(&self) -> bool {
+        !["none", "english"].contains(&self.stopwords.as_str())
+    }
+
+    /**
+     * Read the words of the stopword file into `stopword_list`, unless they already have been
+     */
+    pub fn read_stopwords(&mut self) -> Result<(), Error> {
+        if self.is_stopword_file() && self.stopword_list.is_none() {
+            self.stopword_list = Some(read_stopwords(&PathBuf::from(&self.stopwords))?);
+        }
+        Ok(())
+    }
+
+    /**
+     * Build the Analyzer described by this configuration
+     */
+    pub fn build(&self) -> Result<Analyzer, Error> {
+        let mut builder = match self.tokenizer {
+            TokenizerKind::Whitespace => Analyzer::builder().tokenizer(WhitespaceTokenizer),
+            TokenizerKind::Unicode => Analyzer::builder().tokenizer(UnicodeTokenizer),
+            TokenizerKind::Keyword => Analyzer::builder().tokenizer(KeywordTokenizer),
+        };
+
+        if self.lowercase {
+            builder = builder.filter(LowercaseFilter);
+        }
+        if self.strip_punctuation {
+            builder = builder.filter(PunctuationFilter);
+        }
+        if self.ascii_folding {
+            builder = builder.filter(AsciiFoldingFilter);
+        }
+        if self.min_length > 0 || self.max_length.is_some() {
+            builder = builder.filter(LengthFilter::new(
+                self.min_length,
+                self.max_length.unwrap_or(usize::MAX),
+            ));
+        }
+
+        builder = match self.stopwords.as_str() {
+            "none" => builder,
+            "english" => builder.filter(StopwordFilter::default()),
+            path => match &self.stopword_list {
+                Some(words) => builder.filter(StopwordFilter::from_words(words.iter().cloned())),
+                None => builder.filter(StopwordFilter::from_file(&PathBuf::from(path))?),
+            },
+        };
+
+        if self.stemmer != "none" {
+            builder = builder.filter(StemmerFilter::new(stemmer_algorithm(&self.stemmer)?));
+        }
+        Ok(builder.build())
+    }
+}
+
+/**
+ * Look up the snowball stemmer algorithm by its (lowercase) language name
+ */
+fn stemmer_algorithm(language: &str) -> Result<rust_stemmers::Algorithm, Error> {
+    use rust_stemmers::Algorithm::*;
+
+    Ok(match language {
+        "arabic" => Arabic,
+        "danish" => Danish,
+        "dutch" => Dutch,
+        "english" => English,
+        "finnish" => Finnish,
+        "french" => French,
+        "german" => German,
+        "greek" => Greek,
+        "hungarian" => Hungarian,
+        "italian" => Italian,
+        "norwegian" => Norwegian,
+        "portuguese" => Portuguese,
+        "romanian" => Romanian,
+        "russian" => Russian,
+        "spanish" => Spanish,
+        "swedish" => Swedish,
+        "tamil" => Tamil,
+        "turkish" => Turkish,
+        other => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown stemmer language `{}`", other),
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_matches_default_analyzer() -> Result<(), Error> {
+        let text = "The History of Anarchism, briefly";
+        assert_eq!(
+            AnalyzerConfig::default().build()?.terms(text),
+            Analyzer::default().terms(text)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_toml() -> Result<(), Error> {
+        let config = Config::from_toml(
+            r#"
+            [analyzer]
+            tokenizer = "unicode"
+            stemmer = "none"
+            stopwords = "none"
+            ascii_folding = true
+            max_length = 6
+
+            [fields.title]
+            tokenizer = "keyword"
+            "#,
+        )?;
+        assert_eq!(config.analysis.analyzer.tokenizer, TokenizerKind::Unicode);
+
+        let schema = config.analysis.schema()?;
+        assert_eq!(
+            schema
+                .text_analyzer()
+                .terms("Café of the Zürich metropolis"),
+            vec!["cafe", "of", "the", "zurich"]
+        );
+        assert_eq!(
+            schema.analyzer(Field::Title).unwrap().terms("Hello World"),
+            vec!["hello world"]
+        );
+        assert_eq!(schema.config(), Some(&config.analysis));
+        assert_eq!(config.ranking, RankingConfig::default());
+
+        let config = Config::from_toml("[ranking]\nscorer = \"dirichlet\"\n")?;
+        assert_eq!(config.ranking.ranking()?.scorer, Scorer::dirichlet());
+        assert_eq!(config.analysis, AnalysisConfig::default());
+        assert!(Config::from_toml("[ranking]\nscorer = \"magic\"\n")?
+            .ranking
+            .ranking()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranking_parameters() -> Result<(), Error> {
+        let mut config = Config::from_toml(
+            "[ranking]\nscorer = \"bm25f\"\nk1 = 2.0\ntitle_weight = 3\nlexical_weight = 0.7\n",
+        )?
+        .ranking;
+        config.set("b = 0.5")?;
+        config.set("exact_title_bonus=0")?;
+        let ranking = config.ranking()?;
+        match ranking.scorer {
+            Scorer::Bm25F {
+                k1,
+                title,
+                r#abstract,
+            } => {
+                assert_eq!((k1, title.weight, title.b), (2.0, 3.0, 0.5));
+                assert_eq!((r#abstract.weight, r#abstract.b), (1.0, 0.5));
+            }
+            other => panic!("expected BM25F, not {:?}", other),
+        }
+        assert_eq!(ranking.title.exact, 0.0);
+        assert_eq!(ranking.title.matched, TitleBonus::default().matched);
+        assert_eq!(
+            config.fusion(),
+            Fusion::Weighted {
+                lexical_weight: 0.7
+            }
+        );
+
+        config.set("scorer=dirichlet")?;
+        config.set("mu=500")?;
+        assert_eq!(config.ranking()?.scorer, Scorer::Dirichlet { mu: 500.0 });
+        assert!(config.set("k2=1").is_err());
+        assert!(config.set("k1=lots").is_err());
+        assert!(config.set("k1").is_err());
+        config.set("early_exit_hits=10")?;
+        config.set("early_exit_ratio=0.8")?;
+        assert_eq!(config.ranking()?.early_exit, EarlyExit::new(10, 0.8));
+        assert!(config.set("early_exit_hits=0.5").is_err());
+        assert!(config.set("recency_half_life=-3").is_err());
+        assert!(config.set("recency_half_life=0").is_err());
+        assert_eq!(config.recency_half_life, None);
+        assert!(Config::from_toml("[ranking]\nrecency_half_life = -1.0\n").is_err());
+        assert!(Config::from_toml("[ranking]\nrecency_half_life = nan\n").is_err());
+        assert!(config.set("scorer=magic").is_err());
+        assert_eq!(RankingConfig::default().ranking()?, Ranking::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint() -> Result<(), Error> {
+        let default = AnalysisConfig::default();
+        assert_eq!(
+            Schema::default().fingerprint()?,
+            Some(default.fingerprint()?)
+        );
+
+        let mut unstemmed = default.clone();
+        unstemmed.analyzer.stemmer = "none".to_string();
+        assert_ne!(unstemmed.fingerprint()?, default.fingerprint()?);
+
+        let mut keyword = default.clone();
+        keyword
+            .fields
+            .insert("domain".to_string(), AnalyzerConfig::default());
+        keyword
+            .fields
+            .insert("url".to_string(), AnalyzerConfig::default());
+        let mut reordered = default;
+        reordered
+            .fields
+            .insert("url".to_string(), AnalyzerConfig::default());
+        reordered
+            .fields
+            .insert("domain".to_string(), AnalyzerConfig::default());
+        assert_eq!(keyword.fingerprint()?, reordered.fingerprint()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stopword_file() -> Result<(), Error> {
+        use crate::engine::{Article, Index, IndexReader};
+
+        let path = std::env::temp_dir().join(format!("goede-stop-{}.txt", std::process::id()));
+        std::fs::write(&path, "# very common here\nhistory\n")?;
+        let config = Config::from_toml(&format!("[analyzer]\nstopwords = {:?}\n", path))?;
+        assert_eq!(
+            config.analysis.analyzer.stopword_list,
+            Some(vec!["history".to_string()])
+        );
+        let mut index = Index::with_schema(config.analysis.schema()?);
+        index.index_document(Article::new(
+            "History of Rome",
+            "An empire",
+            "https://example.com/rome",
+        )?)?;
+        let bytes = crate::disk::to_bytes(&index)?;
+
+        // The index opens and analyzes the same wherever the file is gone, or has changed
+        std::fs::write(&path, "empire\n")?;
+        let fingerprint = config.analysis.fingerprint()?;
+        std::fs::remove_file(&path)?;
+        let disk = crate::disk::DiskIndex::from_bytes(bytes)?;
+        assert_eq!(disk.schema().fingerprint()?, Some(fingerprint));
+        assert_eq!(
+            disk.schema().text_analyzer().terms("history empire"),
+            vec!["empir"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_stemmer() {
+        let config = AnalyzerConfig {
+            stemmer: "klingon".to_string(),
+            ..Default::default()
+        };
+        assert!(config.build().is_err());
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let config = Config::from_toml("[fields.titel]\nstemmer = \"none\"").unwrap();
+        assert!(config.analysis.schema().is_err());
+    }
+}
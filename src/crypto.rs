@@ -0,0 +1,227 @@
+/**
+ * The crypto module encrypts persisted indexes and stored values at rest with AES-256-GCM, for
+ * corpora which contain sensitive text
+ *
+ * Encrypted data is the magic bytes and a format version, followed by a random 96-bit nonce and
+ * then the ciphertext with its authentication tag. The header is authenticated along with the
+ * ciphertext, so tampering with any of it is detected when decrypting.
+ */
+use crate::store::Storage;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"GOEDEENC";
+const VERSION: u32 = 1;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_LEN;
+
+/**
+ * A 256-bit key, which is written down as 64 hexadecimal characters
+ */
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Key(..)")
+    }
+}
+
+impl Key {
+    /**
+     * Generate a new random key
+     */
+    pub fn generate() -> Self {
+        Self(Aes256Gcm::generate_key(OsRng).into())
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let hex = hex.trim();
+        let bad = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "a key must be 64 hexadecimal characters",
+            )
+        };
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(bad());
+        }
+
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| bad())?;
+        }
+        Ok(Self(key))
+    }
+
+    /**
+     * Read the key from a file holding its hexadecimal form, e.g. from `openssl rand -hex 32`
+     */
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Self::from_hex(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+/**
+ * Whether the bytes were encrypted by `encrypt()`
+ */
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/**
+ * Encrypt the bytes, with any associated data which must be given again to decrypt them
+ */
+pub fn encrypt(key: &Key, plaintext: &[u8], associated: &[u8]) -> Result<Vec<u8>, Error> {
+    let nonce = Aes256Gcm::generate_nonce(OsRng);
+    let mut out = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&nonce);
+
+    let aad = [&out[..], associated].concat();
+    let ciphertext = key
+        .cipher()
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| Error::other("failed to encrypt"))?;
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/**
+ * Decrypt bytes from `encrypt()`, failing if the key or associated data are not the same or
+ * the bytes have been tampered with
+ */
+pub fn decrypt(key: &Key, bytes: &[u8], associated: &[u8]) -> Result<Vec<u8>, Error> {
+    if bytes.len() < HEADER_LEN || !is_encrypted(bytes) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the data is not encrypted",
+        ));
+    }
+    let version = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported encryption version {}", version),
+        ));
+    }
+
+    let nonce = Nonce::from_slice(&bytes[MAGIC.len() + 4..HEADER_LEN]);
+    let aad = [&bytes[..HEADER_LEN], associated].concat();
+    key.cipher()
+        .decrypt(
+            nonce,
+            Payload {
+                msg: &bytes[HEADER_LEN..],
+                aad: &aad,
+            },
+        )
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "decryption failed, either the key is wrong or the data is corrupt",
+            )
+        })
+}
+
+/**
+ * A Storage which encrypts every value before handing it to another Storage
+ *
+ * The keys are left as they are so that they can still be listed in order, which means the
+ * names of segments and the ids of documents are visible, but nothing of their contents. Each
+ * value is bound to its key, so values cannot be swapped between keys unnoticed.
+ */
+#[derive(Debug)]
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    key: Key,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, key: Key) -> Self {
+        Self { inner, key }
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.inner
+            .get(key)?
+            .map(|value| decrypt(&self.key, &value, key))
+            .transpose()
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.inner.put(key, &encrypt(&self.key, value, key)?)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        self.inner.delete(key)
+    }
+
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        self.inner.keys(prefix)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStorage;
+
+    #[test]
+    fn test_round_trip() -> Result<(), Error> {
+        let key = Key::generate();
+        let encrypted = encrypt(&key, b"hello world", b"")?;
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.windows(5).any(|w| w == b"hello"));
+        assert_eq!(decrypt(&key, &encrypted, b"")?, b"hello world");
+
+        assert!(decrypt(&Key::generate(), &encrypted, b"").is_err());
+        assert!(decrypt(&key, &encrypted, b"other").is_err());
+        let mut tampered = encrypted;
+        tampered[HEADER_LEN] ^= 1;
+        assert!(decrypt(&key, &tampered, b"").is_err());
+
+        assert_eq!(Key::from_hex(&key.to_hex())?.0, key.0);
+        assert!(Key::from_hex("abc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_storage() -> Result<(), Error> {
+        let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::new(inner.clone(), Key::generate());
+        storage.put(b"a", b"secret")?;
+        storage.put(b"b", b"other")?;
+        assert_eq!(storage.get(b"a")?, Some(b"secret".to_vec()));
+        assert_ne!(inner.get(b"a")?, Some(b"secret".to_vec()));
+
+        // A value moved to another key no longer decrypts
+        inner.put(b"b", &inner.get(b"a")?.unwrap())?;
+        assert!(storage.get(b"b").is_err());
+        Ok(())
+    }
+}
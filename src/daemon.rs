@@ -0,0 +1,379 @@
+/**
+ * The daemon module answers searches over a unix domain socket, so that shell tools and editors
+ * on the same machine can query an index which is already loaded without the overhead of HTTP
+ *
+ * Every message in either direction is a little endian u32 length followed by that many bytes
+ * of JSON. A client sends a Request and gets back a Reply, and can keep sending requests over
+ * the same connection until it has been idle for IDLE_TIMEOUT. Every connection has a thread of
+ * its own, so that clients such as editors can keep one open between searches, but only so many
+ * searches run at once and only MAX_CONNECTIONS clients are served at a time.
+ */
+use crate::server::{self, SearchResponse, DEDUPE_OVERFETCH, DEFAULT_LIMIT};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/**
+ * Messages longer than this are rejected rather than buffered, which is plenty for a Request
+ */
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/**
+ * Connections beyond this many at once are hung up on straight away, since each of them has a
+ * thread of its own
+ */
+pub const MAX_CONNECTIONS: usize = 256;
+
+/**
+ * How long a connection can go without sending anything before it is hung up on
+ */
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/**
+ * A search, e.g. `{"q": "history", "limit": 3}`
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Request {
+    pub q: String,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /**
+     * Collapse the hits with the same title into the best of them
+     */
+    #[serde(default)]
+    pub dedupe: bool,
+}
+
+fn default_limit() -> usize {
+    DEFAULT_LIMIT
+}
+
+impl Request {
+    pub fn new(q: &str, limit: usize) -> Self {
+        Self {
+            q: q.to_string(),
+            limit,
+            dedupe: false,
+        }
+    }
+}
+
+/**
+ * The answer to a Request, which is `{"error": "..."}` when it could not be searched
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Reply {
+    Hits(SearchResponse),
+    Error { error: String },
+}
+
+/**
+ * Read a single message, or None if the other end hung up instead of sending one
+ */
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("a message of {} bytes is too long", len),
+        ));
+    }
+    let mut message = vec![0; len];
+    reader.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> Result<(), Error> {
+    let len = u32::try_from(message.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "the message is too long"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(message)?;
+    writer.flush()
+}
+
+/**
+ * Listen on a socket at the path, replacing one left behind by a daemon which is no longer
+ * running
+ *
+ * Anything else already at the path is left alone, and binding fails with `AddrInUse`.
+ */
+pub fn bind(path: &Path) -> Result<UnixListener, Error> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("{:?} already exists and is not a socket", path),
+            ));
+        }
+        if UnixStream::connect(path).is_err() {
+            warn!("Removing the stale socket {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/**
+ * Removes the socket at the path once the daemon stops listening on it
+ *
+ * Nothing is dropped when the process is killed by a signal such as SIGINT or SIGTERM, which is
+ * how a daemon usually stops, so the socket is left behind then and it is `bind()` which
+ * replaces it the next time.
+ */
+struct Socket(PathBuf);
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove the socket {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/**
+ * Answer requests on the listener, with the given function doing the searching for up to
+ * `workers` requests at once
+ */
+pub fn serve<F>(listener: UnixListener, workers: usize, search: F) -> Result<(), Error>
+where
+    F: Fn(&str, usize) -> Result<SearchResponse, Error> + Sync,
+{
+    serve_capped(listener, workers, MAX_CONNECTIONS, search)
+}
+
+fn serve_capped<F>(
+    listener: UnixListener,
+    workers: usize,
+    max_connections: usize,
+    search: F,
+) -> Result<(), Error>
+where
+    F: Fn(&str, usize) -> Result<SearchResponse, Error> + Sync,
+{
+    let workers = workers.max(1);
+    let _socket = listener
+        .local_addr()?
+        .as_pathname()
+        .map(|path| Socket(path.to_path_buf()));
+    info!(
+        "Listening for searches on {:?} with {} workers",
+        listener.local_addr()?,
+        workers
+    );
+
+    // A search takes one of the permits and hands it back once it is done
+    let (release, permits) = crossbeam::channel::bounded(workers);
+    for _ in 0..workers {
+        release
+            .send(())
+            .expect("the permits were sized for every worker");
+    }
+    let search = |q: &str, limit: usize| {
+        permits
+            .recv()
+            .map_err(|_| Error::other("the permits were dropped"))?;
+        let response = search(q, limit);
+        let _ = release.send(());
+        response
+    };
+
+    let connections = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for stream in server::accepted(listener.incoming()) {
+            if connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                warn!("Hanging up on a client, {} are connected", max_connections);
+                continue;
+            }
+            let (search, connections) = (&search, &connections);
+            scope.spawn(move || {
+                if let Err(e) = handle(stream, search) {
+                    warn!("Failed to answer a request over the socket: {}", e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle<F>(stream: UnixStream, search: &F) -> Result<(), Error>
+where
+    F: Fn(&str, usize) -> Result<SearchResponse, Error>,
+{
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let message = match read_message(&mut reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                debug!(
+                    "Hanging up on a client which has been idle for {:?}",
+                    IDLE_TIMEOUT
+                );
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let reply = match serde_json::from_slice::<Request>(&message) {
+            Ok(request) => answer(&request, search),
+            Err(e) => Reply::Error {
+                error: format!("invalid request: {}", e),
+            },
+        };
+        write_message(&mut writer, &serde_json::to_vec(&reply)?)?;
+    }
+    Ok(())
+}
+
+fn answer<F>(request: &Request, search: &F) -> Reply
+where
+    F: Fn(&str, usize) -> Result<SearchResponse, Error>,
+{
+    if let Err(e) = crate::query::validate(&request.q) {
+        return Reply::Error {
+            error: e.to_string(),
+        };
+    }
+    debug!(
+        "Searching for `{}` with a limit of {}",
+        request.q, request.limit
+    );
+    let results = match request.dedupe {
+        true => search(&request.q, request.limit.saturating_mul(DEDUPE_OVERFETCH))
+            .map(|response| response.dedupe(request.limit)),
+        false => search(&request.q, request.limit),
+    };
+    match results {
+        Ok(response) => Reply::Hits(response),
+        Err(e) => {
+            error!("Failed to search for `{}`: {}", request.q, e);
+            Reply::Error {
+                error: e.to_string(),
+            }
+        }
+    }
+}
+
+/**
+ * Send the request to the daemon listening at the path, and wait for its reply
+ */
+pub fn query(path: &Path, request: &Request) -> Result<Reply, Error> {
+    let mut stream = UnixStream::connect(path)?;
+    write_message(&mut stream, &serde_json::to_vec(request)?)?;
+    match read_message(&mut stream)? {
+        Some(reply) => Ok(serde_json::from_slice(&reply)?),
+        None => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "the daemon hung up without replying",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+    use crate::server::search;
+
+    #[test]
+    fn test_daemon() -> Result<(), Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let expected = search(&index, "history", 3);
+        let path = std::env::temp_dir().join(format!("goedesearch-{}.sock", std::process::id()));
+        // Left behind by a daemon which is gone
+        drop(UnixListener::bind(&path)?);
+        let listener = bind(&path)?;
+        std::thread::spawn(move || serve(listener, 2, |q, limit| Ok(search(&index, q, limit))));
+
+        // Clients which keep a connection open without searching do not hold up anyone else
+        let _idle = (UnixStream::connect(&path)?, UnixStream::connect(&path)?);
+
+        match query(&path, &Request::new("history", 3))? {
+            Reply::Hits(response) => assert_eq!(
+                SearchResponse {
+                    timings: expected.timings,
+                    ..response
+                },
+                expected
+            ),
+            other => panic!("expected hits, not {:?}", other),
+        }
+
+        // Several requests can be sent over the same connection
+        let mut stream = UnixStream::connect(&path)?;
+        write_message(&mut stream, br#"{"q": "\"history"}"#)?;
+        write_message(&mut stream, b"not json")?;
+        write_message(&mut stream, br#"{"q": "history"}"#)?;
+        let mut replies = vec![];
+        for _ in 0..3 {
+            let reply = read_message(&mut stream)?.unwrap();
+            replies.push(serde_json::from_slice::<Reply>(&reply)?);
+        }
+        assert_eq!(
+            replies[0],
+            Reply::Error {
+                error: "unbalanced quote at column 1".to_string()
+            }
+        );
+        assert!(matches!(&replies[1], Reply::Error { error } if error.starts_with("invalid")));
+        assert!(matches!(&replies[2], Reply::Hits(response) if !response.hits.is_empty()));
+
+        std::fs::remove_file(&path)?;
+
+        // Whatever else is at the path is not mistaken for a stale socket
+        std::fs::write(&path, b"precious")?;
+        assert_eq!(bind(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read(&path)?, b"precious");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_limit() -> Result<(), Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let path =
+            std::env::temp_dir().join(format!("goedesearch-{}-cap.sock", std::process::id()));
+        let listener = bind(&path)?;
+        std::thread::spawn(move || {
+            serve_capped(listener, 1, 1, |q, limit| Ok(search(&index, q, limit)))
+        });
+
+        let idle = UnixStream::connect(&path)?;
+        assert!(query(&path, &Request::new("history", 3)).is_err());
+
+        // Hanging up makes room for the next client, once the daemon has noticed
+        drop(idle);
+        let mut answered = false;
+        for _ in 0..200 {
+            if let Ok(Reply::Hits(_)) = query(&path, &Request::new("history", 3)) {
+                answered = true;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(answered);
+
+        let mut stream = UnixStream::connect(&path)?;
+        stream.write_all(&(MAX_MESSAGE_LEN as u32 + 1).to_le_bytes())?;
+        assert!(read_message(&mut stream).map_or(true, |reply| reply.is_none()));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1537 @@
+/**
+ * The disk module contains the persisted index format, and the DiskIndex which can query it
+ * directly from a memory-mapped file without deserializing it into HashMaps first
+ *
+ * The file is a fixed size header followed by a number of sections, all integers are little
+ * endian. The header is the magic bytes, a format version, and then the offset and length of
+ * each section:
+ *
+ *  - metadata: JSON describing the index, including the analysis and ranking configurations it
+ *    was built with
+ *  - term index: one u64 per term dictionary entry, the offset of that entry in the dictionary
+ *  - term dictionary: entries sorted by (field, term), each of which is a u8 field name length,
+ *    the field name (empty for the full text), a u32 term length, the term, and then the u64
+ *    index of the term's first posting and the u64 number of postings
+ *  - postings: fixed size entries sorted by document id within each term, a u64 document id,
+ *    a u32 term frequency and the u64 index of the first of that many positions
+ *  - positions: u32 token positions
+ *  - document table: fixed size entries sorted by document id, a u64 document id, the u64
+ *    offset and u32 length of the block the document is stored in, the u32 number of terms in
+ *    its title, in its abstract, and the u32 position its abstract starts at, and then the u32
+ *    slot of the document within its block
+ *  - document store: the stored documents serialized as JSON, in order, compressed together
+ *    as LZ4 blocks of about `store::BLOCK_LEN` bytes
+ *
+ * The metadata also records a CRC-64 of every other section, which is only checked by
+ * `verify()` so that opening an index does not have to read all of it, and the total lengths
+ * of the titles and abstracts which the average lengths are worked out from.
+ *
+ * Version 1 stored the documents uncompressed, version 2 did not record the fingerprint of the
+ * analysis configuration, version 3 did not record the checksums, and version 4 did not record
+ * the lengths of the documents, and version 5 compressed every document as a block of its own.
+ * Versions 2 to 5 can still be read as they are, analyzing the documents again for their
+ * lengths before version 5, version 1 files have to be rewritten with `upgrade()` first.
+ */
+use crate::config::{AnalysisConfig, RankingConfig};
+use crate::crypto::{self, Key};
+use crate::engine::{Article, DocumentId, FieldLengths, IndexReader};
+use crate::schema::{Field, Schema};
+use crate::store::Storage;
+use crc::{crc64, Hasher64};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"GOEDEIDX";
+pub const VERSION: u32 = 6;
+/**
+ * The oldest version which can be opened without being upgraded first
+ */
+pub const OLDEST_READABLE: u32 = 2;
+/**
+ * The oldest version which `upgrade()` knows how to rewrite
+ */
+const OLDEST_UPGRADABLE: u32 = 1;
+
+const SECTIONS: usize = 7;
+const HEADER_LEN: usize = 16 + SECTIONS * 16;
+
+const META: usize = 0;
+const TERM_INDEX: usize = 1;
+const TERMS: usize = 2;
+const POSTINGS: usize = 3;
+const POSITIONS: usize = 4;
+const DOC_TABLE: usize = 5;
+const STORE: usize = 6;
+
+const POSTING_LEN: usize = 20;
+const DOC_ENTRY_LEN: usize = 36;
+/**
+ * The length of a document table entry before version 6 added the slot of the document in its
+ * block
+ */
+const DOC_ENTRY_LEN_V5: usize = 32;
+/**
+ * The length of a document table entry before version 5 added the lengths of the document
+ */
+const DOC_ENTRY_LEN_V4: usize = 20;
+
+/**
+ * The block of documents which was decompressed last, and where it is in the document store
+ */
+type LastBlock = Option<(Range<usize>, Vec<u8>)>;
+
+/**
+ * The metadata section of a persisted index
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Metadata {
+    pub version: u32,
+    pub documents: u64,
+    /**
+     * The analysis configuration the index was built with, None when the Schema was assembled
+     * in code and cannot be described declaratively
+     */
+    pub analysis: Option<AnalysisConfig>,
+    /**
+     * The fingerprint of the analysis configuration when the index was built, None for
+     * indexes from before fingerprints were recorded
+     */
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /**
+     * The CRC-64 of each section after the metadata, None for indexes from before checksums
+     * were recorded
+     */
+    #[serde(default)]
+    pub checksums: Option<Vec<u64>>,
+    /**
+     * The ranking configuration the index is searched with, None when it was built without one
+     */
+    #[serde(default)]
+    pub ranking: Option<RankingConfig>,
+    /**
+     * The total number of terms in the titles and in the abstracts of all the documents, None
+     * for indexes from before the lengths were recorded
+     */
+    #[serde(default)]
+    pub total_lengths: Option<(u64, u64)>,
+}
+
+/**
+ * The names of the sections, for reporting problems with them
+ */
+const SECTION_NAMES: [&str; SECTIONS] = [
+    "metadata",
+    "term index",
+    "term dictionary",
+    "postings",
+    "positions",
+    "document table",
+    "document store",
+];
+
+/**
+ * The findings of verifying a persisted index
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub terms: usize,
+    pub postings: usize,
+    pub documents: usize,
+    /**
+     * Whether the index recorded checksums to verify, which older versions did not
+     */
+    pub checksummed: bool,
+    /**
+     * Corruption which would make queries return wrong results
+     */
+    pub errors: Vec<String>,
+    /**
+     * Entries which nothing refers to, which are harmless but wasted space
+     */
+    pub orphans: Vec<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/**
+ * A single entry of the term dictionary
+ */
+struct TermEntry<'a> {
+    field: &'a [u8],
+    term: &'a [u8],
+    postings: Range<usize>,
+}
+
+/**
+ * A single entry of the postings section
+ */
+struct Posting {
+    id: DocumentId,
+    frequency: u32,
+    positions: usize,
+}
+
+/**
+ * A persisted index which is queried in place from a memory-mapped file, or from the bytes of a
+ * segment fetched out of a Storage
+ *
+ * Only the header and metadata are read when opening, the term dictionary, postings and
+ * documents are all read out of the mapped file on demand.
+ */
+pub struct DiskIndex {
+    data: Box<dyn AsRef<[u8]> + Send + Sync>,
+    sections: [Range<usize>; SECTIONS],
+    metadata: Metadata,
+    schema: Schema,
+}
+
+impl std::fmt::Debug for DiskIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "DiskIndex {{ bytes: {}, metadata: {:?} }}",
+            self.bytes().len(),
+            self.metadata
+        )
+    }
+}
+
+/**
+ * Map the index file into memory
+ */
+#[cfg(not(target_arch = "wasm32"))]
+fn map(path: &Path) -> Result<Box<dyn AsRef<[u8]> + Send + Sync>, Error> {
+    let file = File::open(path)?;
+    // Safety: the index files are never modified in place once written
+    let data = unsafe { memmap2::Mmap::map(&file)? };
+    debug!("Mapped index file {:?}", path);
+    Ok(Box::new(data))
+}
+
+/**
+ * Read the index file into memory, there being nothing to map it with on wasm32
+ */
+#[cfg(target_arch = "wasm32")]
+fn map(path: &Path) -> Result<Box<dyn AsRef<[u8]> + Send + Sync>, Error> {
+    Ok(Box::new(std::fs::read(path)?))
+}
+
+impl DiskIndex {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::from_data(map(path)?, OLDEST_READABLE)
+    }
+
+    /**
+     * Open an index from the bytes it was persisted as
+     */
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
+        Self::from_data(Box::new(bytes), OLDEST_READABLE)
+    }
+
+    /**
+     * Open the index persisted as the named segment of the Storage
+     */
+    pub fn load(storage: &dyn Storage, name: &str) -> Result<Self, Error> {
+        match storage.get_segment(name)? {
+            Some(bytes) => Self::from_bytes(bytes),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no segment named `{}`", name),
+            )),
+        }
+    }
+
+    /**
+     * Open an index file encrypted with the key, which is decrypted into memory rather than
+     * being mapped
+     */
+    pub fn open_encrypted(path: &Path, key: &Key) -> Result<Self, Error> {
+        Self::from_bytes(crypto::decrypt(key, &std::fs::read(path)?, b"")?)
+    }
+
+    fn from_data(data: Box<dyn AsRef<[u8]> + Send + Sync>, oldest: u32) -> Result<Self, Error> {
+        let bytes = data.as_ref().as_ref();
+        if crypto::is_encrypted(bytes) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the index is encrypted, so a key is needed to open it",
+            ));
+        }
+        if bytes.len() < HEADER_LEN || &bytes[0..8] != MAGIC {
+            return Err(invalid("not a goedesearch index file"));
+        }
+        let version = read_u32(bytes, 8);
+        if version > VERSION {
+            return Err(invalid(&format!(
+                "the index is format version {}, but this build only reads up to version {}",
+                version, VERSION
+            )));
+        }
+        if version < oldest {
+            return Err(invalid(&format!(
+                "the index is format version {}, which must be rewritten with `goedesearch upgrade` first",
+                version
+            )));
+        }
+
+        let mut sections: [Range<usize>; SECTIONS] = Default::default();
+        for (i, section) in sections.iter_mut().enumerate() {
+            let offset = read_u64(bytes, 16 + i * 16) as usize;
+            let len = read_u64(bytes, 24 + i * 16) as usize;
+            match offset.checked_add(len) {
+                Some(end) if end <= bytes.len() => *section = offset..end,
+                _ => return Err(invalid("index section is out of bounds")),
+            }
+        }
+
+        let mut metadata: Metadata = serde_json::from_slice(&bytes[sections[META].clone()])?;
+        // The header is what decides how the sections are laid out
+        metadata.version = version;
+        let mut schema = match &metadata.analysis {
+            Some(config) => config.schema()?,
+            None => Schema::default(),
+        };
+        if let Some(ranking) = &metadata.ranking {
+            schema = schema.with_ranking(ranking.clone())?;
+        }
+        if let (Some(built), Some(now)) = (&metadata.fingerprint, schema.fingerprint()?) {
+            if *built != now {
+                return Err(invalid(&format!(
+                    "the analysis configuration has changed since the index was built \
+                     (fingerprint {} is now {}), so it has to be rebuilt",
+                    built, now
+                )));
+            }
+        }
+        debug!("Opened index with {:?}", metadata);
+
+        Ok(Self {
+            data,
+            sections,
+            metadata,
+            schema,
+        })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /**
+     * Check that the index was built with the same analysis configuration as the Schema, so
+     * that queries analyzed with the Schema will find what was indexed
+     *
+     * Indexes and Schemas without a fingerprint cannot be checked, and are assumed to match.
+     */
+    pub fn check_schema(&self, schema: &Schema) -> Result<(), Error> {
+        match (&self.metadata.fingerprint, schema.fingerprint()?) {
+            (Some(built), Some(expected)) if *built != expected => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the index was built with analysis configuration {}, not the configured {}",
+                    built, expected
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.data.as_ref().as_ref()
+    }
+
+    fn section(&self, section: usize) -> &[u8] {
+        &self.bytes()[self.sections[section].clone()]
+    }
+
+    fn term_count(&self) -> usize {
+        self.sections[TERM_INDEX].len() / 8
+    }
+
+    fn term_entry(&self, i: usize) -> Option<TermEntry<'_>> {
+        let terms = self.section(TERMS);
+        let mut at = read_u64(self.section(TERM_INDEX), i * 8) as usize;
+
+        let field_len = *terms.get(at)? as usize;
+        let field = terms.get(at + 1..at + 1 + field_len)?;
+        at += 1 + field_len;
+        let term_len = read_u32(terms.get(at..at + 4)?, 0) as usize;
+        let term = terms.get(at + 4..at + 4 + term_len)?;
+        at += 4 + term_len;
+        let meta = terms.get(at..at + 16)?;
+        let start = read_u64(meta, 0) as usize;
+        let count = read_u64(meta, 8) as usize;
+
+        Some(TermEntry {
+            field,
+            term,
+            postings: start..start.checked_add(count)?,
+        })
+    }
+
+    /**
+     * Binary search the term dictionary for the term in the given field
+     */
+    fn find_term(&self, field: &str, term: &str) -> Option<Range<usize>> {
+        let wanted = (field.as_bytes(), term.as_bytes());
+        let (mut low, mut high) = (0, self.term_count());
+
+        while low < high {
+            let middle = (low + high) / 2;
+            let entry = self.term_entry(middle)?;
+            match (entry.field, entry.term).cmp(&wanted) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(entry.postings),
+            }
+        }
+        None
+    }
+
+    fn posting(&self, i: usize) -> Option<Posting> {
+        let bytes = self
+            .section(POSTINGS)
+            .get(i * POSTING_LEN..(i + 1) * POSTING_LEN)?;
+        Some(Posting {
+            id: read_u64(bytes, 0),
+            frequency: read_u32(bytes, 8),
+            positions: read_u64(bytes, 12) as usize,
+        })
+    }
+
+    fn posting_set(&self, postings: Range<usize>) -> HashSet<DocumentId> {
+        postings
+            .filter_map(|i| self.posting(i))
+            .map(|p| p.id)
+            .collect()
+    }
+
+    /**
+     * Binary search the full text postings of the term for the document
+     */
+    fn find_posting(&self, id: DocumentId, term: &str) -> Option<Posting> {
+        let postings = self.find_term("", term)?;
+        let (mut low, mut high) = (postings.start, postings.end);
+
+        while low < high {
+            let middle = (low + high) / 2;
+            let posting = self.posting(middle)?;
+            match posting.id.cmp(&id) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(posting),
+            }
+        }
+        None
+    }
+
+    fn doc_entry_len(&self) -> usize {
+        match self.metadata.version {
+            version if version < 5 => DOC_ENTRY_LEN_V4,
+            5 => DOC_ENTRY_LEN_V5,
+            _ => DOC_ENTRY_LEN,
+        }
+    }
+
+    fn doc_count(&self) -> usize {
+        self.sections[DOC_TABLE].len() / self.doc_entry_len()
+    }
+
+    fn doc_entry_bytes(&self, i: usize) -> Option<&[u8]> {
+        let len = self.doc_entry_len();
+        self.section(DOC_TABLE).get(i * len..(i + 1) * len)
+    }
+
+    fn doc_entry(&self, i: usize) -> Option<(DocumentId, Range<usize>)> {
+        let bytes = self.doc_entry_bytes(i)?;
+        let offset = read_u64(bytes, 8) as usize;
+        let len = read_u32(bytes, 16) as usize;
+        Some((read_u64(bytes, 0), offset..offset.checked_add(len)?))
+    }
+
+    /**
+     * The lengths recorded in the document table entry, or worked out by analyzing the stored
+     * document for indexes from before they were recorded
+     */
+    fn doc_lengths(&self, i: usize) -> Option<FieldLengths> {
+        if self.metadata.version < 5 {
+            let article = self.read_document(i, &mut None).ok()?;
+            return Some(FieldLengths::of(&self.schema, &article));
+        }
+        let bytes = self.doc_entry_bytes(i)?;
+        Some(FieldLengths {
+            title: read_u32(bytes, 20) as f64,
+            r#abstract: read_u32(bytes, 24) as f64,
+            abstract_start: read_u32(bytes, 28) as usize,
+        })
+    }
+
+    /**
+     * Binary search the document table for the document
+     */
+    fn find_document(&self, id: &DocumentId) -> Option<usize> {
+        let (mut low, mut high) = (0, self.doc_count());
+
+        while low < high {
+            let middle = (low + high) / 2;
+            let (entry, _) = self.doc_entry(middle)?;
+            match entry.cmp(id) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+
+    /**
+     * Read the i'th document of the table, reusing the block which was decompressed last when
+     * the document is in the same one, so that reading the documents in order only
+     * decompresses each block once
+     */
+    fn read_document(&self, i: usize, last: &mut LastBlock) -> Result<Article, Error> {
+        let (_, range) = self
+            .doc_entry(i)
+            .ok_or_else(|| invalid("document table entry is out of bounds"))?;
+        let bytes = self
+            .section(STORE)
+            .get(range.clone())
+            .ok_or_else(|| invalid("the stored document is out of bounds"))?;
+        match self.metadata.version {
+            1 => Ok(serde_json::from_slice(bytes)?),
+            version if version < 6 => crate::store::decompress_record(bytes),
+            _ => {
+                let slot = self
+                    .doc_entry_bytes(i)
+                    .map(|entry| read_u32(entry, 32) as usize)
+                    .ok_or_else(|| invalid("document table entry is out of bounds"))?;
+                let block = match last.take() {
+                    Some((at, block)) if at == range => block,
+                    _ => crate::store::decompress_block(bytes)?,
+                };
+                let article = crate::store::read_slot(&block, slot);
+                *last = Some((range, block));
+                article
+            }
+        }
+    }
+
+    /**
+     * Check the whole index for corruption, reading every section
+     *
+     * The checksums are compared, and then every entry is checked to be well formed and in
+     * order, every posting to refer to a stored document, and every stored document to be
+     * readable and referred to by some posting.
+     */
+    pub fn verify(&self) -> Report {
+        let mut report = Report::default();
+
+        match &self.metadata.checksums {
+            Some(checksums) if checksums.len() == SECTIONS - 1 => {
+                report.checksummed = true;
+                for (i, expected) in checksums.iter().enumerate() {
+                    if checksum(self.section(i + 1)) != *expected {
+                        report.errors.push(format!(
+                            "the checksum of the {} does not match",
+                            SECTION_NAMES[i + 1]
+                        ));
+                    }
+                }
+            }
+            Some(_) => report
+                .errors
+                .push("the wrong number of checksums are recorded".to_string()),
+            None => {}
+        }
+
+        let mut stored: Vec<DocumentId> = vec![];
+        let mut last = None;
+        for i in 0..self.doc_count() {
+            match self.doc_entry(i) {
+                Some((id, _)) => {
+                    if stored.last().map(|last| *last >= id).unwrap_or(false) {
+                        report
+                            .errors
+                            .push(format!("document {} is out of order", id));
+                    }
+                    if let Err(e) = self.read_document(i, &mut last) {
+                        report
+                            .errors
+                            .push(format!("document {} cannot be read: {}", id, e));
+                    }
+                    stored.push(id);
+                }
+                None => report
+                    .errors
+                    .push(format!("document table entry {} is out of bounds", i)),
+            }
+        }
+        report.documents = stored.len();
+        if self.metadata.documents != stored.len() as u64 {
+            report.errors.push(format!(
+                "the metadata counts {} documents but {} are stored",
+                self.metadata.documents,
+                stored.len()
+            ));
+        }
+
+        let posting_count = self.sections[POSTINGS].len() / POSTING_LEN;
+        let position_count = self.sections[POSITIONS].len() / 4;
+        let mut referenced: HashSet<DocumentId> = HashSet::new();
+        let mut covered = 0;
+        let mut previous: Option<(Vec<u8>, Vec<u8>)> = None;
+
+        for i in 0..self.term_count() {
+            let entry = match self.term_entry(i) {
+                Some(entry) => entry,
+                None => {
+                    report
+                        .errors
+                        .push(format!("term dictionary entry {} is malformed", i));
+                    continue;
+                }
+            };
+            let name = format!(
+                "`{}{}{}`",
+                String::from_utf8_lossy(entry.field),
+                if entry.field.is_empty() { "" } else { ":" },
+                String::from_utf8_lossy(entry.term)
+            );
+            let key = (entry.field.to_vec(), entry.term.to_vec());
+            if previous.as_ref().map(|p| *p >= key).unwrap_or(false) {
+                report
+                    .errors
+                    .push(format!("the term {} is out of order", name));
+            }
+            previous = Some(key);
+            report.terms += 1;
+
+            if entry.postings.end > posting_count {
+                report
+                    .errors
+                    .push(format!("the postings of {} are out of bounds", name));
+                continue;
+            }
+            if entry.postings.start != covered {
+                report.errors.push(format!(
+                    "the postings of {} do not follow on from the previous term",
+                    name
+                ));
+            }
+            covered = entry.postings.end;
+
+            let mut last: Option<DocumentId> = None;
+            for posting in entry.postings.clone().filter_map(|p| self.posting(p)) {
+                report.postings += 1;
+                if last.map(|last| last >= posting.id).unwrap_or(false) {
+                    report.errors.push(format!(
+                        "the postings of {} are out of order at document {}",
+                        name, posting.id
+                    ));
+                }
+                last = Some(posting.id);
+
+                if stored.binary_search(&posting.id).is_err() {
+                    report.errors.push(format!(
+                        "{} refers to document {} which is not stored",
+                        name, posting.id
+                    ));
+                }
+                let positions = posting.positions.checked_add(posting.frequency as usize);
+                if positions.map(|end| end > position_count).unwrap_or(true) {
+                    report.errors.push(format!(
+                        "the positions of {} in document {} are out of bounds",
+                        name, posting.id
+                    ));
+                }
+                referenced.insert(posting.id);
+            }
+        }
+        if covered != posting_count {
+            report.orphans.push(format!(
+                "{} postings do not belong to any term",
+                posting_count - covered.min(posting_count)
+            ));
+        }
+
+        for id in stored.iter() {
+            if !referenced.contains(id) {
+                report
+                    .orphans
+                    .push(format!("document {} is not referred to by any term", id));
+            }
+        }
+        report
+    }
+
+    fn terms_of(&self, field: &str) -> Vec<String> {
+        (0..self.term_count())
+            .filter_map(|i| self.term_entry(i))
+            .filter(|entry| entry.field == field.as_bytes())
+            .map(|entry| String::from_utf8_lossy(entry.term).into_owned())
+            .collect()
+    }
+}
+
+impl IndexReader for DiskIndex {
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn size(&self) -> u64 {
+        self.doc_count() as u64
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        (0..self.doc_count())
+            .filter_map(|i| self.doc_entry(i))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        match self.read_document(self.find_document(id)?, &mut None) {
+            Ok(article) => Some(Cow::Owned(article)),
+            Err(e) => {
+                error!("Failed to read stored document {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.terms_of("")
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.find_term("", term)
+            .map(|postings| Cow::Owned(self.posting_set(postings)))
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.terms_of(field.name())
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.find_term(field.name(), term)
+            .map(|postings| Cow::Owned(self.posting_set(postings)))
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.find_posting(id, term).map(|p| p.frequency as f64)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        let posting = self.find_posting(id, term)?;
+        let bytes = self
+            .section(POSITIONS)
+            .get(posting.positions * 4..(posting.positions + posting.frequency as usize) * 4)?;
+        Some(Cow::Owned(
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| read_u32(chunk, 0) as usize)
+                .collect(),
+        ))
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.doc_lengths(self.find_document(&id)?)
+    }
+
+    fn average_field_lengths(&self) -> (f64, f64) {
+        match (self.metadata.total_lengths, self.doc_count()) {
+            (_, 0) => (0.0, 0.0),
+            (Some((title, r#abstract)), documents) => (
+                title as f64 / documents as f64,
+                r#abstract as f64 / documents as f64,
+            ),
+            (None, documents) => {
+                let lengths: Vec<FieldLengths> =
+                    (0..documents).filter_map(|i| self.doc_lengths(i)).collect();
+                crate::engine::mean_field_lengths(lengths.iter(), documents)
+            }
+        }
+    }
+
+    fn average_document_length(&self) -> f64 {
+        let (title, r#abstract) = self.average_field_lengths();
+        title + r#abstract
+    }
+}
+
+/**
+ * Persist the contents of the IndexReader to the given path
+ */
+pub fn write<R: IndexReader + ?Sized>(reader: &R, path: &Path) -> Result<(), Error> {
+    let mut file = std::io::BufWriter::new(File::create(path)?);
+    file.write_all(&to_bytes(reader)?)?;
+    file.flush()?;
+    debug!("Wrote the index to {:?}", path);
+    Ok(())
+}
+
+/**
+ * Persist the contents of the IndexReader to the given path, encrypted with the key
+ */
+pub fn write_encrypted<R: IndexReader + ?Sized>(
+    reader: &R,
+    path: &Path,
+    key: &Key,
+) -> Result<(), Error> {
+    std::fs::write(path, crypto::encrypt(key, &to_bytes(reader)?, b"")?)?;
+    debug!("Wrote the encrypted index to {:?}", path);
+    Ok(())
+}
+
+/**
+ * Serialize the contents of the IndexReader into the persisted index format
+ */
+pub fn to_bytes<R: IndexReader + ?Sized>(reader: &R) -> Result<Vec<u8>, Error> {
+    let _span = tracing::debug_span!("to_bytes", documents = reader.size()).entered();
+    let mut sections: Vec<Vec<u8>> = vec![vec![]; SECTIONS];
+
+    let mut terms: Vec<(&str, String)> = reader.terms().into_iter().map(|t| ("", t)).collect();
+    for field in Field::ALL {
+        terms.extend(
+            reader
+                .field_terms(*field)
+                .into_iter()
+                .map(|t| (field.name(), t)),
+        );
+    }
+    terms.sort();
+
+    let mut posting_count = 0u64;
+    let mut position_count = 0u64;
+
+    for (field_name, term) in terms.iter() {
+        let docs = if field_name.is_empty() {
+            reader.postings(term)
+        } else {
+            field_name
+                .parse::<Field>()
+                .ok()
+                .and_then(|field| reader.field_postings(field, term))
+        };
+        let mut docs: Vec<DocumentId> = docs
+            .map(|d| d.iter().copied().collect())
+            .unwrap_or_default();
+        docs.sort_unstable();
+
+        let offset = sections[TERMS].len() as u64;
+        sections[TERM_INDEX].extend_from_slice(&offset.to_le_bytes());
+
+        let entry = &mut sections[TERMS];
+        entry.push(field_name.len() as u8);
+        entry.extend_from_slice(field_name.as_bytes());
+        entry.extend_from_slice(&(term.len() as u32).to_le_bytes());
+        entry.extend_from_slice(term.as_bytes());
+        entry.extend_from_slice(&posting_count.to_le_bytes());
+        entry.extend_from_slice(&(docs.len() as u64).to_le_bytes());
+
+        for id in docs.iter() {
+            let positions = if field_name.is_empty() {
+                reader.positions(*id, term).unwrap_or_default().into_owned()
+            } else {
+                vec![]
+            };
+
+            let postings = &mut sections[POSTINGS];
+            postings.extend_from_slice(&id.to_le_bytes());
+            postings.extend_from_slice(&(positions.len() as u32).to_le_bytes());
+            postings.extend_from_slice(&position_count.to_le_bytes());
+
+            for position in positions.iter() {
+                sections[POSITIONS].extend_from_slice(&(*position as u32).to_le_bytes());
+            }
+            posting_count += 1;
+            position_count += positions.len() as u64;
+        }
+    }
+
+    let mut ids = reader.document_ids();
+    ids.sort_unstable();
+    let mut total_lengths = (0u64, 0u64);
+    let mut blocks = DocumentBlocks::default();
+    for id in ids.iter() {
+        if let Some(article) = reader.document(id) {
+            let lengths = reader.field_lengths(*id).unwrap_or_default();
+            blocks.push(*id, article.as_ref(), lengths)?;
+            total_lengths.0 += lengths.title as u64;
+            total_lengths.1 += lengths.r#abstract as u64;
+        }
+        if blocks.is_full() {
+            let (table, block) = blocks.finish(sections[STORE].len() as u64);
+            sections[DOC_TABLE].extend_from_slice(&table);
+            sections[STORE].extend_from_slice(&block);
+        }
+    }
+    if !blocks.is_empty() {
+        let (table, block) = blocks.finish(sections[STORE].len() as u64);
+        sections[DOC_TABLE].extend_from_slice(&table);
+        sections[STORE].extend_from_slice(&block);
+    }
+
+    let checksums = sections[META + 1..]
+        .iter()
+        .map(|section| checksum(section))
+        .collect();
+    let metadata = Metadata {
+        version: VERSION,
+        documents: reader.size(),
+        analysis: reader.schema().config().cloned(),
+        fingerprint: reader.schema().fingerprint()?,
+        checksums: Some(checksums),
+        ranking: reader.schema().ranking_config().cloned(),
+        total_lengths: Some(total_lengths),
+    };
+    sections[META] = serde_json::to_vec(&metadata)?;
+
+    let header = header(sections.iter().map(|section| section.len() as u64));
+    debug!(
+        "Serialized {} terms and {} postings",
+        terms.len(),
+        posting_count
+    );
+    Ok(header
+        .into_iter()
+        .chain(sections.into_iter().flatten())
+        .collect())
+}
+
+/**
+ * The lengths of a document as they are laid out at the end of its document table entry
+ */
+/**
+ * Gathers documents into the blocks which they are compressed together in, along with the
+ * document table entries of the documents in the block being filled
+ */
+#[derive(Default)]
+struct DocumentBlocks {
+    block: crate::store::Block,
+    entries: Vec<(DocumentId, FieldLengths)>,
+}
+
+impl DocumentBlocks {
+    fn push(
+        &mut self,
+        id: DocumentId,
+        article: &Article,
+        lengths: FieldLengths,
+    ) -> Result<(), Error> {
+        self.block.push(article)?;
+        self.entries.push((id, lengths));
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.block.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        self.block.is_full()
+    }
+
+    /**
+     * Compress the block, returning the document table entries of its documents along with the
+     * block itself, which is to be written at `offset` in the document store
+     */
+    fn finish(&mut self, offset: u64) -> (Vec<u8>, Vec<u8>) {
+        let block = self.block.compress();
+        let mut table = Vec::with_capacity(self.entries.len() * DOC_ENTRY_LEN);
+        for (slot, (id, lengths)) in self.entries.drain(..).enumerate() {
+            table.extend_from_slice(&id.to_le_bytes());
+            table.extend_from_slice(&offset.to_le_bytes());
+            table.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            table.extend_from_slice(&lengths_bytes(&lengths));
+            table.extend_from_slice(&(slot as u32).to_le_bytes());
+        }
+        (table, block)
+    }
+}
+
+fn lengths_bytes(lengths: &FieldLengths) -> [u8; 12] {
+    let mut bytes = [0; 12];
+    bytes[0..4].copy_from_slice(&(lengths.title as u32).to_le_bytes());
+    bytes[4..8].copy_from_slice(&(lengths.r#abstract as u32).to_le_bytes());
+    bytes[8..12].copy_from_slice(&(lengths.abstract_start as u32).to_le_bytes());
+    bytes
+}
+
+/**
+ * The header of an index whose sections, in order, are the given lengths
+ */
+fn header(lengths: impl Iterator<Item = u64>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    let mut offset = HEADER_LEN as u64;
+    for len in lengths {
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+        offset += len;
+    }
+    header
+}
+
+/**
+ * A section of an index being merged, which is written to a temporary file alongside the index
+ * rather than held in memory, and removed once it is dropped
+ */
+struct SectionFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    len: u64,
+    digest: crc64::Digest,
+}
+
+impl SectionFile {
+    fn create(index: &Path, section: usize) -> Result<Self, Error> {
+        let mut path = index.as_os_str().to_owned();
+        path.push(format!(".{}", SECTION_NAMES[section].replace(' ', "-")));
+        let path = PathBuf::from(path);
+        Ok(Self {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            len: 0,
+            digest: crc64::Digest::new(crc64::ECMA),
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(bytes)?;
+        self.digest.write(bytes);
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn copy_to<W: Write>(&mut self, out: &mut W) -> Result<(), Error> {
+        self.writer.flush()?;
+        std::io::copy(&mut File::open(&self.path)?, out)?;
+        Ok(())
+    }
+}
+
+impl Drop for SectionFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/**
+ * Merge persisted indexes which have no documents in common into a single index at the given
+ * path, one term at a time
+ *
+ * The term dictionaries and document tables of the indexes are already sorted, so they are
+ * merged k-way, with only the postings of a single term held in memory at once and the rest
+ * streamed through temporary files. The indexes must all have been built with the same Schema,
+ * which is what the merged index records.
+ */
+pub fn merge(indexes: &[DiskIndex], path: &Path) -> Result<(), Error> {
+    let _span = tracing::debug_span!("merge", indexes = indexes.len()).entered();
+    let mut term_index = SectionFile::create(path, TERM_INDEX)?;
+    let mut terms = SectionFile::create(path, TERMS)?;
+    let mut postings = SectionFile::create(path, POSTINGS)?;
+    let mut positions = SectionFile::create(path, POSITIONS)?;
+    let mut table = SectionFile::create(path, DOC_TABLE)?;
+    let mut store = SectionFile::create(path, STORE)?;
+
+    // The next term of every index, smallest first
+    let mut cursors = vec![0; indexes.len()];
+    let mut next = BinaryHeap::new();
+    let entry = |index: usize, i: usize| -> Result<Option<TermEntry<'_>>, Error> {
+        if i >= indexes[index].term_count() {
+            return Ok(None);
+        }
+        match indexes[index].term_entry(i) {
+            Some(entry) => Ok(Some(entry)),
+            None => Err(invalid("term dictionary entry is malformed")),
+        }
+    };
+    for index in 0..indexes.len() {
+        if let Some(entry) = entry(index, 0)? {
+            next.push(Reverse((entry.field, entry.term, index)));
+        }
+    }
+
+    let (mut term_count, mut posting_count, mut position_count) = (0u64, 0u64, 0u64);
+    while let Some(Reverse((field, term, first))) = next.pop() {
+        let mut sources = vec![first];
+        while let Some(Reverse((f, t, _))) = next.peek() {
+            if (*f, *t) != (field, term) {
+                break;
+            }
+            if let Some(Reverse((_, _, index))) = next.pop() {
+                sources.push(index);
+            }
+        }
+
+        let mut merged = vec![];
+        for index in sources.iter().copied() {
+            let entry = entry(index, cursors[index])?.ok_or_else(|| invalid("term vanished"))?;
+            for i in entry.postings {
+                let posting = indexes[index]
+                    .posting(i)
+                    .ok_or_else(|| invalid("posting is out of bounds"))?;
+                merged.push((posting, index));
+            }
+        }
+        merged.sort_unstable_by_key(|(posting, _)| posting.id);
+
+        term_index.write(&terms.len.to_le_bytes())?;
+        terms.write(&[field.len() as u8])?;
+        terms.write(field)?;
+        terms.write(&(term.len() as u32).to_le_bytes())?;
+        terms.write(term)?;
+        terms.write(&posting_count.to_le_bytes())?;
+        terms.write(&(merged.len() as u64).to_le_bytes())?;
+        for (posting, index) in merged.iter() {
+            let start = posting.positions * 4;
+            let bytes = indexes[*index]
+                .section(POSITIONS)
+                .get(start..start + posting.frequency as usize * 4)
+                .ok_or_else(|| invalid("positions are out of bounds"))?;
+            postings.write(&posting.id.to_le_bytes())?;
+            postings.write(&posting.frequency.to_le_bytes())?;
+            postings.write(&position_count.to_le_bytes())?;
+            positions.write(bytes)?;
+            posting_count += 1;
+            position_count += posting.frequency as u64;
+        }
+        term_count += 1;
+
+        for index in sources {
+            cursors[index] += 1;
+            if let Some(entry) = entry(index, cursors[index])? {
+                next.push(Reverse((entry.field, entry.term, index)));
+            }
+        }
+    }
+
+    let mut cursors = vec![0; indexes.len()];
+    let mut next: BinaryHeap<Reverse<(DocumentId, usize)>> = (0..indexes.len())
+        .filter_map(|index| {
+            indexes[index]
+                .doc_entry(0)
+                .map(|(id, _)| Reverse((id, index)))
+        })
+        .collect();
+    let mut documents = 0u64;
+    let mut total_lengths = (0u64, 0u64);
+    // The documents of every index are read in order, so each of their blocks is decompressed
+    // once before being compressed again into the merged blocks
+    let mut last: Vec<LastBlock> = vec![None; indexes.len()];
+    let mut blocks = DocumentBlocks::default();
+    while let Some(Reverse((id, index))) = next.pop() {
+        let article = indexes[index].read_document(cursors[index], &mut last[index])?;
+        let lengths = indexes[index]
+            .doc_lengths(cursors[index])
+            .ok_or_else(|| invalid("the stored document cannot be read"))?;
+        blocks.push(id, &article, lengths)?;
+        if blocks.is_full() {
+            let (entries, block) = blocks.finish(store.len);
+            table.write(&entries)?;
+            store.write(&block)?;
+        }
+        documents += 1;
+        total_lengths.0 += lengths.title as u64;
+        total_lengths.1 += lengths.r#abstract as u64;
+
+        cursors[index] += 1;
+        if cursors[index] < indexes[index].doc_count() {
+            if let Some((id, _)) = indexes[index].doc_entry(cursors[index]) {
+                next.push(Reverse((id, index)));
+            }
+        }
+    }
+    if !blocks.is_empty() {
+        let (entries, block) = blocks.finish(store.len);
+        table.write(&entries)?;
+        store.write(&block)?;
+    }
+
+    let mut sections = [term_index, terms, postings, positions, table, store];
+    let schema = indexes
+        .first()
+        .map(|index| index.schema.clone())
+        .unwrap_or_default();
+    let metadata = Metadata {
+        version: VERSION,
+        documents,
+        analysis: schema.config().cloned(),
+        fingerprint: schema.fingerprint()?,
+        checksums: Some(
+            sections
+                .iter()
+                .map(|section| section.digest.sum64())
+                .collect(),
+        ),
+        ranking: schema.ranking_config().cloned(),
+        total_lengths: Some(total_lengths),
+    };
+    let metadata = serde_json::to_vec(&metadata)?;
+
+    let lengths = std::iter::once(metadata.len() as u64).chain(sections.iter().map(|s| s.len));
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&header(lengths))?;
+    file.write_all(&metadata)?;
+    for section in sections.iter_mut() {
+        section.copy_to(&mut file)?;
+    }
+    file.flush()?;
+    debug!(
+        "Merged {} terms, {} postings and {} documents into {:?}",
+        term_count, posting_count, documents, path
+    );
+    Ok(())
+}
+
+/**
+ * Rewrite the index file at the given path in the current format, returning the version it was
+ * in before, or None if it was already current
+ *
+ * The upgraded index is written alongside and then renamed over the original, so it is never
+ * left half written.
+ */
+pub fn upgrade(path: &Path) -> Result<Option<u32>, Error> {
+    let bytes = std::fs::read(path)?;
+    let upgraded = match upgrade_bytes(bytes)? {
+        (version, Some(upgraded)) => (version, upgraded),
+        (_, None) => return Ok(None),
+    };
+
+    replace(path, &upgraded.1)?;
+    info!(
+        "Upgraded {:?} from version {} to {}",
+        path, upgraded.0, VERSION
+    );
+    Ok(Some(upgraded.0))
+}
+
+/**
+ * Write the bytes over the file at the given path, by writing them alongside and renaming them
+ * over it so that it is never left half written
+ */
+pub fn replace(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, bytes)?;
+    File::open(&partial)?.sync_all()?;
+    std::fs::rename(&partial, path)
+}
+
+/**
+ * Rewrite every segment of the Storage which is not in the current format, returning the
+ * names of the segments which were upgraded
+ */
+pub fn upgrade_storage(storage: &dyn Storage) -> Result<Vec<String>, Error> {
+    let mut upgraded = vec![];
+    for name in storage.segments()? {
+        let bytes = match storage.get_segment(&name)? {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        if let (version, Some(bytes)) = upgrade_bytes(bytes)? {
+            storage.put_segment(&name, &bytes)?;
+            info!(
+                "Upgraded segment {} from version {} to {}",
+                name, version, VERSION
+            );
+            upgraded.push(name);
+        }
+    }
+    storage.flush()?;
+    Ok(upgraded)
+}
+
+/**
+ * Returns the version the bytes were in, and the upgraded bytes unless they were current
+ */
+fn upgrade_bytes(bytes: Vec<u8>) -> Result<(u32, Option<Vec<u8>>), Error> {
+    let index = DiskIndex::from_data(Box::new(bytes), OLDEST_UPGRADABLE)?;
+    let version = index.metadata.version;
+    if version == VERSION {
+        return Ok((version, None));
+    }
+    Ok((version, Some(to_bytes(&index)?)))
+}
+
+pub(crate) fn checksum(bytes: &[u8]) -> u64 {
+    let mut digest = crc64::Digest::new(crc64::ECMA);
+    digest.write(bytes);
+    digest.sum64()
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_disk_index_matches_memory() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-disk-{}.idx", std::process::id()));
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        index.save(&path)?;
+        let disk = DiskIndex::open(&path)?;
+
+        assert_eq!(IndexReader::size(&disk), index.size());
+        for query in &[
+            "anarchism",
+            "political philosophy",
+            "\"political philosophy\"",
+            "domain:en.wikipedia.org history",
+            "contains:ography",
+            "nonexistentterm",
+        ] {
+            let mut expected = index.query_index(query);
+            let mut actual = disk.query_index(query);
+            expected.sort_unstable();
+            actual.sort_unstable();
+            assert_eq!(actual, expected, "results differ for {}", query);
+        }
+
+        let id = index.query_index("anarchism")[0];
+        assert_eq!(IndexReader::document(&disk, &id), index.document(&id));
+        assert_eq!(disk.field_lengths(id), index.field_lengths(id));
+        assert_eq!(disk.average_field_lengths(), index.average_field_lengths());
+        assert_eq!(
+            disk.average_document_length(),
+            index.average_document_length()
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_rejects_garbage() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-garbage-{}.idx", std::process::id()));
+        std::fs::write(&path, b"this is not an index")?;
+        let result = DiskIndex::open(&path);
+        std::fs::remove_file(&path)?;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    /**
+     * Rewrite current bytes as an older version would have written them
+     */
+    fn downgrade(bytes: &[u8], version: u32) -> Vec<u8> {
+        let index = DiskIndex::from_bytes(bytes.to_vec()).unwrap();
+        let mut sections: Vec<Vec<u8>> = index
+            .sections
+            .iter()
+            .map(|range| bytes[range.clone()].to_vec())
+            .collect();
+
+        let mut metadata = serde_json::to_value(&index.metadata).unwrap();
+        metadata["version"] = version.into();
+        let object = metadata.as_object_mut().unwrap();
+        if version < 5 {
+            object.remove("total_lengths");
+        }
+        if version < 4 {
+            object.remove("checksums");
+        }
+        if version < 3 {
+            object.remove("fingerprint");
+        }
+        sections[META] = serde_json::to_vec(&metadata).unwrap();
+
+        if version < 6 {
+            // Every document was stored on its own, and uncompressed in version 1
+            let entry_len = if version < 5 {
+                DOC_ENTRY_LEN_V4
+            } else {
+                DOC_ENTRY_LEN_V5
+            };
+            sections[DOC_TABLE].clear();
+            sections[STORE].clear();
+            for i in 0..index.doc_count() {
+                let json = serde_json::to_vec(&index.read_document(i, &mut None).unwrap()).unwrap();
+                let record = match version {
+                    1 => json,
+                    _ => lz4_flex::compress_prepend_size(&json),
+                };
+                let mut entry = index.doc_entry_bytes(i).unwrap()[..entry_len].to_vec();
+                entry[8..16].copy_from_slice(&(sections[STORE].len() as u64).to_le_bytes());
+                entry[16..20].copy_from_slice(&(record.len() as u32).to_le_bytes());
+                sections[DOC_TABLE].extend_from_slice(&entry);
+                sections[STORE].extend_from_slice(&record);
+            }
+        }
+
+        let mut out = bytes[..16].to_vec();
+        out[8..12].copy_from_slice(&version.to_le_bytes());
+        let mut offset = HEADER_LEN as u64;
+        for section in sections.iter() {
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(&(section.len() as u64).to_le_bytes());
+            offset += section.len() as u64;
+        }
+        out.extend(sections.into_iter().flatten());
+        out
+    }
+
+    #[test]
+    fn test_upgrade_old_versions() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-upgrade-{}.idx", std::process::id()));
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let current = to_bytes(&index)?;
+        let id = index.query_index("anarchism")[0];
+
+        // Documents compressed one at a time are still readable
+        let v5 = DiskIndex::from_bytes(downgrade(&current, 5))?;
+        assert_eq!(IndexReader::document(&v5, &id), index.document(&id));
+        assert_eq!(v5.field_lengths(id), index.field_lengths(id));
+        assert!(
+            v5.sections[STORE].len()
+                > DiskIndex::from_bytes(current.clone())?.sections[STORE].len()
+        );
+
+        // Version 2 is still readable, just without a fingerprint to check
+        let v2 = DiskIndex::from_bytes(downgrade(&current, 2))?;
+        assert_eq!(v2.metadata().fingerprint, None);
+        assert_eq!(IndexReader::document(&v2, &id), index.document(&id));
+        // Without recorded lengths the documents are analyzed again for them
+        assert_eq!(v2.metadata().total_lengths, None);
+        assert_eq!(v2.field_lengths(id), index.field_lengths(id));
+        assert_eq!(v2.average_field_lengths(), index.average_field_lengths());
+
+        std::fs::write(&path, downgrade(&current, 1))?;
+        let err = DiskIndex::open(&path).unwrap_err();
+        assert!(err.to_string().contains("goedesearch upgrade"));
+        assert_eq!(upgrade(&path)?, Some(1));
+        assert_eq!(upgrade(&path)?, None);
+
+        let upgraded = DiskIndex::open(&path)?;
+        assert_eq!(upgraded.metadata().version, VERSION);
+        assert!(upgraded.metadata().fingerprint.is_some());
+        assert_eq!(IndexReader::document(&upgraded, &id), index.document(&id));
+        assert_eq!(
+            upgraded.query_index("anarchism"),
+            index.query_index("anarchism")
+        );
+
+        let mut future = current;
+        future[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(DiskIndex::from_bytes(future).is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_schema() -> Result<(), Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let disk = DiskIndex::from_bytes(to_bytes(&index)?)?;
+        disk.check_schema(&Schema::default())?;
+
+        let mut config = AnalysisConfig::default();
+        config.analyzer.stemmer = "none".to_string();
+        let err = disk.check_schema(&config.schema()?).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranking_persisted() -> Result<(), Error> {
+        let mut config = RankingConfig::default();
+        config.set("scorer=bm25")?;
+        config.set("k1=2")?;
+        let schema = Schema::default().with_ranking(config.clone())?;
+        let mut index = Index::with_schema(schema);
+        index.index_document(Article::new(
+            "Rain",
+            "rain expected",
+            "https://example.com/",
+        )?)?;
+        assert_eq!(index.ranking(), config.ranking()?);
+
+        let disk = DiskIndex::from_bytes(to_bytes(&index)?)?;
+        assert_eq!(disk.metadata().ranking.as_ref(), Some(&config));
+        assert_eq!(disk.ranking(), index.ranking());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<(), Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let bytes = to_bytes(&index)?;
+        let report = DiskIndex::from_bytes(bytes.clone())?.verify();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.documents, 356);
+        assert!(report.orphans.is_empty(), "{:?}", report.orphans);
+
+        // Point the first posting at a document which does not exist
+        let disk = DiskIndex::from_bytes(bytes.clone())?;
+        let mut corrupt = bytes.clone();
+        let at = disk.sections[POSTINGS].start;
+        corrupt[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let report = DiskIndex::from_bytes(corrupt)?.verify();
+        assert!(!report.is_ok());
+        assert!(report.errors[0].contains("checksum of the postings"));
+        assert!(report
+            .errors
+            .iter()
+            .any(|e| e.contains("refers to document 18446744073709551615")));
+
+        let mut corrupt = bytes;
+        let at = disk.sections[STORE].start + 10;
+        corrupt[at] ^= 0xff;
+        let report = DiskIndex::from_bytes(corrupt)?.verify();
+        assert!(report.errors[0].contains("checksum of the document store"));
+
+        let report = DiskIndex::from_bytes(downgrade(&to_bytes(&index)?, 3))?.verify();
+        assert!(report.is_ok());
+        assert!(!report.checksummed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_index() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-crypt-{}.idx", std::process::id()));
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let key = Key::generate();
+        index.save_encrypted(&path, &key)?;
+
+        assert_eq!(
+            DiskIndex::open(&path).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert!(DiskIndex::open_encrypted(&path, &Key::generate()).is_err());
+        let disk = DiskIndex::open_encrypted(&path, &key)?;
+        assert_eq!(
+            disk.query_index("anarchism"),
+            index.query_index("anarchism")
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+/**
+ * The distributed module contains the Coordinator, which searches a corpus that is too big for
+ * one machine by fanning each query out to goedesearch servers holding a shard each
+ *
+ * Every node returns its own top hits, scored with its own term statistics, and the coordinator
+ * merges them into the overall top hits. A node which cannot be reached only costs the results
+ * from its shard, which are listed as failed in the response.
+ */
+use crate::bulk::Suggestions;
+use crate::server::{bad_response, SearchResponse};
+use log::*;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct Coordinator {
+    nodes: Vec<String>,
+    agent: ureq::Agent,
+}
+
+impl Coordinator {
+    /**
+     * Coordinate searches across the servers at the given base urls, giving up on any which
+     * take longer than the timeout to answer
+     */
+    pub fn new(nodes: Vec<String>, timeout: Duration) -> Result<Self, Error> {
+        if nodes.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a coordinator needs at least one node",
+            ));
+        }
+        let nodes = nodes
+            .into_iter()
+            .map(|node| node.trim_end_matches('/').to_string())
+            .collect();
+        let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        Ok(Self { nodes, agent })
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    fn search_node(&self, node: &str, query: &str, limit: usize) -> Result<SearchResponse, Error> {
+        let body = self
+            .agent
+            .get(&format!("{}/search", node))
+            .query("q", query)
+            .query("limit", &limit.to_string())
+            .call()
+            .map_err(|e| Error::other(format!("searching {} failed: {}", node, e)))?
+            .into_string()?;
+        serde_json::from_str(&body).map_err(|e| bad_response(node, e))
+    }
+
+    /**
+     * Search every node in parallel and merge their results into the top `limit` hits
+     *
+     * This only fails when none of the nodes could be searched.
+     */
+    pub fn search(&self, query: &str, limit: usize) -> Result<SearchResponse, Error> {
+        let responses: Vec<Result<SearchResponse, Error>> = std::thread::scope(|scope| {
+            let searches: Vec<_> = self
+                .nodes
+                .iter()
+                .map(|node| scope.spawn(move || self.search_node(node, query, limit)))
+                .collect();
+            searches
+                .into_iter()
+                .map(|search| {
+                    search
+                        .join()
+                        .unwrap_or_else(|_| Err(Error::other("a node search panicked")))
+                })
+                .collect()
+        });
+
+        let mut merged = SearchResponse::default();
+        let mut succeeded = 0;
+        let mut last_error = None;
+        for (node, response) in self.nodes.iter().zip(responses) {
+            match response {
+                Ok(response) => {
+                    // A term is only missing when no node has it
+                    merged.suggestions = if succeeded == 0 {
+                        response.suggestions
+                    } else {
+                        merge_suggestions(merged.suggestions, response.suggestions)
+                    };
+                    succeeded += 1;
+                    merged.size += response.size;
+                    merged.hits.extend(response.hits);
+                    merged.failed.extend(response.failed);
+                    merged.timings = match (merged.timings, response.timings) {
+                        (Some(merged), Some(timings)) => Some(merged.slowest(timings)),
+                        (merged, timings) => merged.or(timings),
+                    };
+                }
+                Err(e) => {
+                    warn!("Leaving out the results of {}: {}", node, e);
+                    merged.failed.push(node.clone());
+                    last_error = Some(e);
+                }
+            }
+        }
+        if succeeded == 0 {
+            if let Some(e) = last_error {
+                return Err(e);
+            }
+        }
+
+        merged.hits.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Less)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        merged.hits.truncate(limit);
+        Ok(merged)
+    }
+}
+
+/**
+ * Keep the suggestions for the terms which both nodes found in none of their documents, along
+ * with what either suggested for them
+ */
+fn merge_suggestions(merged: Vec<Suggestions>, other: Vec<Suggestions>) -> Vec<Suggestions> {
+    merged
+        .into_iter()
+        .filter_map(|mut suggestions| {
+            let theirs = other.iter().find(|s| s.term == suggestions.term)?;
+            for suggestion in &theirs.suggestions {
+                if !suggestions.suggestions.contains(suggestion) {
+                    suggestions.suggestions.push(suggestion.clone());
+                }
+            }
+            Some(suggestions)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::IndexReader;
+    use crate::schema::Schema;
+    use crate::shard::{self, ShardedIndex};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_coordinate_shards() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("goede-nodes-{}", std::process::id()));
+        let datafile = PathBuf::from("data/simple.xml.gz");
+        shard::build(&datafile, &Schema::default(), 2, &dir, None)?;
+        let local = ShardedIndex::open_dir(&dir, None)?;
+
+        let mut nodes = vec![];
+        for shard in 0..2 {
+            let path = dir.join(format!("shard-{:03}-of-002.idx", shard));
+            let index = crate::disk::DiskIndex::open(&path)?;
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            nodes.push(format!("http://{}/", listener.local_addr()?));
+            std::thread::spawn(move || {
+                crate::server::serve(listener, |q, limit| {
+                    Ok(crate::server::search(&index, q, limit))
+                })
+            });
+        }
+
+        // A node which is down only loses its own shard
+        let down = TcpListener::bind("127.0.0.1:0")?;
+        nodes.push(format!("http://{}", down.local_addr()?));
+        drop(down);
+
+        let coordinator = Coordinator::new(nodes, Duration::from_secs(5))?;
+        let response = coordinator.search("history", 5)?;
+        assert_eq!(response.size, 356);
+        assert_eq!(response.failed.len(), 1);
+        let ids: Vec<_> = response.hits.iter().map(|hit| hit.id).collect();
+        let expected: Vec<_> = local
+            .search("history", 5)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, expected);
+        assert!(response.hits[0].article.is_some());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
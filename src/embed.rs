@@ -0,0 +1,141 @@
+/**
+ * The embed module runs a local ONNX sentence embedding model, such as all-MiniLM-L6-v2, to
+ * compute the embeddings of documents when indexing them and of queries when searching
+ *
+ * The model is expected to take the token ids, attention mask and (optionally) token type ids of
+ * a BERT style tokenizer, and to output the hidden state of every token, which is mean pooled
+ * into a single vector.
+ */
+use crate::engine::IndexReader;
+use crate::vectors::VectorIndex;
+use log::*;
+use std::io::Error;
+use std::path::Path;
+use tokenizers::Tokenizer;
+use tract_onnx::prelude::*;
+
+/**
+ * The number of tokens the model is run over, longer texts are truncated and shorter ones are
+ * padded so that the model only has to be optimized for one shape
+ */
+const SEQUENCE_LEN: usize = 128;
+
+fn model_error(e: impl std::fmt::Display) -> Error {
+    Error::other(format!("the embedding model failed: {}", e))
+}
+
+pub struct Embedder {
+    model: TypedRunnableModel<TypedModel>,
+    tokenizer: Tokenizer,
+    /**
+     * How many of the ids, mask and type ids the model takes
+     */
+    inputs: usize,
+}
+
+impl std::fmt::Debug for Embedder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Embedder {{ inputs: {} }}", self.inputs)
+    }
+}
+
+impl Embedder {
+    /**
+     * Load the model from `model.onnx` and its tokenizer from `tokenizer.json` in the directory
+     */
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json")).map_err(model_error)?;
+        let mut model = tract_onnx::onnx()
+            .model_for_path(dir.join("model.onnx"))
+            .map_err(model_error)?;
+        let inputs = model.input_outlets().map_err(model_error)?.len().min(3);
+        for input in 0..inputs {
+            model = model
+                .with_input_fact(input, i64::fact([1, SEQUENCE_LEN]).into())
+                .map_err(model_error)?;
+        }
+        let model = model
+            .into_optimized()
+            .and_then(|model| model.into_runnable())
+            .map_err(model_error)?;
+        debug!("Loaded the embedding model from {:?}", dir);
+        Ok(Self {
+            model,
+            tokenizer,
+            inputs,
+        })
+    }
+
+    /**
+     * The embedding of the text
+     */
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>, Error> {
+        let encoding = self.tokenizer.encode(text, true).map_err(model_error)?;
+        let len = encoding.get_ids().len().min(SEQUENCE_LEN);
+        let padded = |values: &[u32]| -> Result<Tensor, Error> {
+            let mut padded: Vec<i64> = values[..len].iter().map(|v| *v as i64).collect();
+            padded.resize(SEQUENCE_LEN, 0);
+            Tensor::from_shape(&[1, SEQUENCE_LEN], &padded).map_err(model_error)
+        };
+        let mask = &encoding.get_attention_mask()[..len];
+        let inputs = [
+            padded(encoding.get_ids())?,
+            padded(mask)?,
+            padded(encoding.get_type_ids())?,
+        ];
+        let outputs = self
+            .model
+            .run(
+                inputs[..self.inputs]
+                    .iter()
+                    .cloned()
+                    .map(|t| t.into())
+                    .collect(),
+            )
+            .map_err(model_error)?;
+        let hidden = outputs[0]
+            .to_array_view::<f32>()
+            .map_err(model_error)?
+            .into_dimensionality::<tract_ndarray::Ix3>()
+            .map_err(model_error)?;
+
+        // Mean pool the hidden states of the tokens which are not padding
+        let mut pooled = vec![0.0f32; hidden.shape()[2]];
+        let mut count: f32 = 0.0;
+        for (token, included) in mask.iter().enumerate() {
+            if *included == 0 {
+                continue;
+            }
+            count += 1.0;
+            for (sum, value) in pooled
+                .iter_mut()
+                .zip(hidden.slice(tract_ndarray::s![0, token, ..]))
+            {
+                *sum += value;
+            }
+        }
+        for sum in pooled.iter_mut() {
+            *sum /= count.max(1.0);
+        }
+        Ok(pooled)
+    }
+
+    /**
+     * Embed the full text of every document in the reader
+     */
+    pub fn embed_documents<R: IndexReader + ?Sized>(
+        &self,
+        reader: &R,
+    ) -> Result<VectorIndex, Error> {
+        let mut vectors: Option<VectorIndex> = None;
+        for id in reader.document_ids() {
+            if let Some(article) = reader.document(&id) {
+                let vector = self.embed(&article.fulltext())?;
+                vectors
+                    .get_or_insert_with(|| VectorIndex::new(vector.len()))
+                    .insert(id, vector)?;
+            }
+        }
+        Ok(vectors.unwrap_or_else(|| VectorIndex::new(0)))
+    }
+}
@@ -1,38 +1,78 @@
 /**
  * The engine module contains the bulk of the actual goedesearch engine
  */
+use crate::cache::QueryCache;
+use crate::crypto::Key;
+use crate::filters::{Analyzer, Token};
+use crate::frozen::FrozenIndex;
+use crate::postings::{PostingList, Postings, PostingsRef};
+use crate::query::{Clause, Hits, NormalizedQuery, QueryTimings};
+use crate::schema::{Field, Schema};
+use crate::scoring::Ranking;
+use crate::searcher::Searcher;
+use crate::store::{Documents, FileStorage, Storage};
+use crate::telemetry;
 use flate2::read::GzDecoder;
 use log::*;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug_span, info_span, trace_span};
 use url::Url;
 
 /**
  * Alias to make sure that everything is using the same type for document IDs
  */
-type DocumentId = u64;
+pub type DocumentId = u64;
+
+/**
+ * How far apart the positions of the last title token and the first abstract token are, so that
+ * phrases cannot match across the two
+ */
+const FIELD_POSITION_GAP: usize = 100;
+
+/**
+ * How many of a document's keywords are searched for to find the documents like it
+ */
+const MORE_LIKE_THIS_TERMS: usize = 10;
+
+/**
+ * How many positions apart two terms of a document's full text can be and still co-occur
+ */
+const COOCCURRENCE_WINDOW: usize = 5;
+
+/**
+ * Roughly what a posting costs in memory besides its term, which the frequencies and the
+ * positions each keep a copy of
+ */
+const POSTING_SIZE: usize = 2 * std::mem::size_of::<((DocumentId, String), Vec<usize>)>()
+    + std::mem::size_of::<DocumentId>();
+
+/**
+ * The id of the document at the url
+ */
+pub fn document_id(url: &Url) -> DocumentId {
+    use crc::{crc64, Hasher64};
+
+    let mut digest = crc64::Digest::new(crc64::ECMA);
+    digest.write(url.as_str().as_bytes());
+    digest.sum64()
+}
 
 /**
  * A wikipedia abstract data structure
  */
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct Article {
     id: Option<DocumentId>,
     title: String,
     r#abstract: String,
     url: Option<Url>,
-}
-
-impl Default for Article {
-    fn default() -> Self {
-        Self {
-            id: None,
-            title: String::new(),
-            r#abstract: String::new(),
-            url: None,
-        }
-    }
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
 }
 
 impl std::fmt::Display for Article {
@@ -48,22 +88,79 @@ impl std::fmt::Display for Article {
 }
 
 impl Article {
+    /**
+     * Create an article, whose id is computed from the url just like those loaded from a dump
+     */
+    pub fn new(title: &str, r#abstract: &str, url: &str) -> crate::error::Result<Self> {
+        let mut article = Self {
+            title: title.to_string(),
+            r#abstract: r#abstract.to_string(),
+            ..Default::default()
+        };
+        article
+            .set_url(url)
+            .map_err(|source| crate::error::Error::Url {
+                url: url.to_string(),
+                source,
+            })?;
+        Ok(article)
+    }
+
     /**
      * Return the unique integer ID for the Article computed from the url
      */
-    fn id(&self) -> DocumentId {
+    pub fn id(&self) -> DocumentId {
         self.id.unwrap_or(0)
     }
 
-    fn set_url(&mut self, url: &str) -> Result<(), url::ParseError> {
-        use crc::{crc64, Hasher64};
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
 
-        let url = Url::parse(url).unwrap();
+    pub fn abstract_text(&self) -> &str {
+        &self.r#abstract
+    }
+
+    /**
+     * Attach a piece of metadata to the article, such as its category or author
+     */
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /**
+     * Reassemble an article which was taken apart, e.g. to be written in another format
+     */
+    #[cfg(feature = "protobuf")]
+    pub(crate) fn from_parts(
+        id: DocumentId,
+        title: String,
+        r#abstract: String,
+        url: Option<Url>,
+        metadata: HashMap<String, String>,
+    ) -> Self {
+        Self {
+            id: Some(id),
+            title,
+            r#abstract,
+            url,
+            metadata,
+        }
+    }
 
-        let mut digest = crc64::Digest::new(crc64::ECMA);
-        digest.write(url.as_str().as_bytes());
+    fn set_url(&mut self, url: &str) -> Result<(), url::ParseError> {
+        let url = Url::parse(url)?;
+        self.id = Some(document_id(&url));
         self.url = Some(url);
-        self.id = Some(digest.sum64());
         Ok(())
     }
 
@@ -71,229 +168,2724 @@ impl Article {
      * Return the full text for the abstract which is basically just the
      * title and the brief description
      */
-    fn fulltext(&self) -> String {
+    pub(crate) fn fulltext(&self) -> String {
         format!("{} {}", self.title, self.r#abstract)
     }
+
+    /**
+     * Roughly how many bytes of memory the article takes up
+     */
+    pub(crate) fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.title.len()
+            + self.r#abstract.len()
+            + self.url.as_ref().map(|url| url.as_str().len()).unwrap_or(0)
+            + self
+                .metadata
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum::<usize>()
+    }
+
+    /**
+     * The `key=value` keywords for the metadata which the Schema indexes
+     */
+    fn metadata_terms(&self, schema: &Schema) -> Vec<String> {
+        schema
+            .metadata_keys()
+            .filter_map(|key| {
+                self.metadata
+                    .get(key)
+                    .map(|value| format!("{}={}", key, value))
+            })
+            .collect()
+    }
+
+    /**
+     * Return the text of the given field, if the Article has it
+     */
+    fn field(&self, field: Field) -> Option<String> {
+        match field {
+            Field::Title => Some(self.title.clone()),
+            Field::Abstract => Some(self.r#abstract.clone()),
+            Field::Url => self.url.as_ref().map(|u| u.to_string()),
+            Field::Domain => self
+                .url
+                .as_ref()
+                .and_then(|u| u.host_str())
+                .map(|h| h.to_string()),
+            // Metadata is indexed from its key value pairs instead, see metadata_terms
+            Field::Metadata => None,
+        }
+    }
 }
 
 /**
- * A search index
+ * Statistics about a single term across the whole index
  */
-#[derive(Clone, Debug)]
-pub struct Index {
+#[derive(Clone, Debug, PartialEq)]
+pub struct TermStats {
     /**
-     * Global mapping of each document and its id
+     * The number of documents containing the term
      */
-    documents: HashMap<DocumentId, Article>,
+    pub document_frequency: u64,
     /**
-     * The frequencies of a term in the given document, keyed by the DocumentId
-     * and the term within the document.
+     * The number of times the term occurs across all of the documents
      */
-    freq: HashMap<(DocumentId, String), f64>,
+    pub total_frequency: u64,
     /**
-     * Index containing a mapping of terms to the documents which refer to them
+     * The inverse document frequency of the term, `log10(documents / document_frequency)`
      */
-    index: HashMap<String, HashSet<DocumentId>>,
+    pub idf: f64,
 }
 
-impl Index {
-    fn new() -> Self {
+/**
+ * The occurrences of a single term within a document's full text
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TermVectorEntry {
+    pub term: String,
+    pub frequency: u64,
+    /**
+     * The sorted positions of the term within the full text
+     */
+    pub positions: Vec<usize>,
+}
+
+/**
+ * The number of terms in the title and in the abstract which make up a document's full text
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FieldLengths {
+    pub title: f64,
+    pub r#abstract: f64,
+    /**
+     * The position in the full text which the abstract starts at, every position before it is
+     * in the title
+     */
+    pub abstract_start: usize,
+}
+
+impl FieldLengths {
+    /**
+     * Analyze the title of the article to find where its abstract starts, the rest of the full
+     * text being `length` terms long
+     */
+    fn from_title(schema: &Schema, article: &Article, length: f64) -> Self {
+        let title = schema.text_analyzer().analyze(&article.title);
+        let title_length = title.len() as f64;
         Self {
-            documents: HashMap::default(),
-            index: HashMap::default(),
-            freq: HashMap::default(),
+            title: title_length,
+            r#abstract: length - title_length,
+            abstract_start: title
+                .last()
+                .map(|t| t.position + FIELD_POSITION_GAP)
+                .unwrap_or(0),
         }
     }
 
     /**
-     * Load a Wikipedia XML dump from a gzip file
+     * Analyze the article's full text the same way it is indexed
      */
-    pub fn from_file(path: &PathBuf) -> Result<Self, std::io::Error> {
-        use quick_xml::events::Event;
-        use quick_xml::Reader;
-        use std::io::BufReader;
+    pub fn of(schema: &Schema, article: &Article) -> Self {
+        let length = schema.text_analyzer().terms(&article.title).len()
+            + schema.text_analyzer().terms(&article.r#abstract).len();
+        Self::from_title(schema, article, length as f64)
+    }
 
-        let mut index = Self::new();
-        let file = File::open(path)?;
-        let gz = GzDecoder::new(BufReader::new(file));
-        let mut reader = Reader::from_reader(BufReader::new(gz));
-
-        let mut buf = vec![];
-        let mut article = None;
-
-        loop {
-            match reader.read_event(&mut buf) {
-                // for triggering namespaced events, use this instead:
-                // match reader.read_namespaced_event(&mut buf) {
-                Ok(Event::Start(ref e)) => {
-                    // for namespaced:
-                    // Ok((ref namespace_value, Event::Start(ref e)))
-                    match e.name() {
-                        b"doc" => {
-                            article = Some(Article::default());
-                        }
-                        b"title" => {
-                            if let Some(ref mut article) = article {
-                                article.title =
-                                    reader.read_text(e.name(), &mut Vec::new()).unwrap();
-                            }
-                        }
-                        b"abstract" => {
-                            if let Some(ref mut article) = article {
-                                article.r#abstract =
-                                    reader.read_text(e.name(), &mut Vec::new()).unwrap();
-                            }
-                        }
-                        b"url" => {
-                            if let Some(ref mut article) = article {
-                                let u = reader.read_text(e.name(), &mut Vec::new()).unwrap();
-                                article.set_url(&u).unwrap();
-                            }
-                        }
-                        _ => (),
-                    }
-                }
-                Ok(Event::End(ref e)) => match e.name() {
-                    b"doc" => {
-                        index.index_document(article.unwrap()).unwrap();
-                        article = None
-                    }
-                    _ => (),
-                },
-                // unescape and decode the text event using the reader encoding
-                //Ok(Event::Text(e)) => txt.push(e.unescape_and_decode(&reader).unwrap()),
-                Ok(Event::Eof) => break, // exits the loop when reaching end of file
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                _ => (), // There are several other `Event`s we do not consider here
-            }
+    pub fn total(&self) -> f64 {
+        self.title + self.r#abstract
+    }
+}
 
-            // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
-            buf.clear();
-        }
+/**
+ * Read-only access to the contents of an index, which is everything needed to evaluate queries
+ * against it
+ *
+ * This is implemented by both the in-memory Index and the memory-mapped DiskIndex, so that the
+ * query evaluation does not need to care where the index actually lives.
+ */
+pub trait IndexReader: Send + Sync {
+    /**
+     * The Schema which documents and queries are analyzed with
+     */
+    fn schema(&self) -> &Schema;
+    /**
+     * The number of documents in the index
+     */
+    fn size(&self) -> u64;
+    fn document_ids(&self) -> Vec<DocumentId>;
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>>;
+    /**
+     * Every term in the full text index
+     */
+    fn terms(&self) -> Vec<String>;
+    /**
+     * The documents whose full text contains the term
+     */
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>>;
+    /**
+     * Every term in the index of the given field
+     */
+    fn field_terms(&self, field: Field) -> Vec<String>;
+    /**
+     * The documents whose given field contains the term
+     */
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>>;
+    /**
+     * The documents whose full text contains the term like `postings()`, but borrowed as the
+     * reader keeps them rather than copied into a HashSet where that is what it takes
+     */
+    fn postings_ref(&self, term: &str) -> Option<PostingsRef<'_>> {
+        self.postings(term).map(PostingsRef::Set)
+    }
+    /**
+     * The documents whose given field contains the term, borrowed like `postings_ref()`
+     */
+    fn field_postings_ref(&self, field: Field, term: &str) -> Option<PostingsRef<'_>> {
+        self.field_postings(field, term).map(PostingsRef::Set)
+    }
+    /**
+     * The number of times the term occurs in the document's full text
+     */
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64>;
+    /**
+     * The sorted positions of the term within the document's full text
+     */
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>>;
 
-        debug!("Found {} documents in the file", index.size());
-        Ok(index)
+    /**
+     * The inverse document frequency of the term, zero for terms which are not in the index
+     */
+    fn idf(&self, term: &str) -> f64 {
+        match self.postings_ref(term) {
+            Some(docs) if !docs.is_empty() => (self.size() as f64 / docs.len() as f64).log10(),
+            _ => 0.0,
+        }
     }
 
     /**
-     * The number of documents in the index
+     * The statistics of the (analyzed) term, or None if no document contains it
      */
-    pub fn size(&self) -> u64 {
-        self.documents.len() as u64
+    fn term_stats(&self, term: &str) -> Option<TermStats> {
+        compute_term_stats(self, term)
     }
 
     /**
-     * Attempt to retrieve the given document from the index
+     * The number of terms in the document's full text, or None if it is not in the index
      */
-    pub fn document(&self, id: &DocumentId) -> Option<&Article> {
-        self.documents.get(id)
+    fn document_length(&self, id: DocumentId) -> Option<f64> {
+        self.field_lengths(id).map(|lengths| lengths.total())
     }
 
     /**
-     * Query the index for the given query string
+     * The number of terms in each part of the document's full text, or None if it is not in
+     * the index
      *
-     * The query will be normalized and an ordering of document IDs will be returned
+     * Unless the reader keeps the lengths around this analyzes the document all over again.
      */
-    pub fn query_index(&self, query: &str) -> Vec<DocumentId> {
-        let normalized = crate::filters::filter(query);
-        debug!("Normalized query: {:?}", normalized);
-        let mut sets = vec![];
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        let article = self.document(&id)?;
+        Some(FieldLengths::of(self.schema(), &article))
+    }
+
+    /**
+     * The mean number of terms in the title and in the abstract of the documents
+     */
+    fn average_field_lengths(&self) -> (f64, f64) {
+        let ids = self.document_ids();
+        let lengths: Vec<FieldLengths> = ids
+            .iter()
+            .filter_map(|id| self.field_lengths(*id))
+            .collect();
+        mean_field_lengths(lengths.iter(), ids.len())
+    }
 
-        for token in normalized.iter() {
-            if let Some(doc_ids) = self.index.get(token) {
-                debug!("Docs found for token `{}`: {:?}", token, doc_ids);
-                sets.push(doc_ids);
+    /**
+     * The mean number of terms in the full text of the documents, zero for an empty index
+     */
+    fn average_document_length(&self) -> f64 {
+        let ids = self.document_ids();
+        match ids.len() {
+            0 => 0.0,
+            n => {
+                ids.iter()
+                    .filter_map(|id| self.document_length(*id))
+                    .sum::<f64>()
+                    / n as f64
             }
         }
+    }
 
-        // Depending on how mnay sets were collected, return the intersection
-        let documents = match sets.len() {
-            0 => HashSet::new(),
-            _ => sets[0]
-                .iter()
-                .filter(|b| sets[1..].iter().all(|set| set.contains(*b)))
-                .map(|b| *b)
-                .collect(),
-        };
+    /**
+     * How the documents matching a query are scored
+     */
+    fn ranking(&self) -> Ranking {
+        self.schema().ranking()
+    }
 
-        /*
-         * Time to rank these documents based on query
-         */
-        let mut results = vec![];
-        let total_docs = self.documents.len() as f64;
+    /**
+     * What the scores of the document are multiplied by whatever the query, e.g. to favor
+     * popular documents, which is one unless the reader says otherwise
+     */
+    fn boost(&self, _id: DocumentId) -> f64 {
+        1.0
+    }
 
-        for id in documents.iter() {
-            let mut score = 0.0;
+    /**
+     * Iterate in ascending order over the documents whose full text contains the term, which
+     * must already have been analyzed, e.g. for merging posting lists in custom retrieval
+     *
+     * The documents are borrowed from readers which keep them sorted, the rest have to sort a
+     * copy of them first.
+     */
+    fn posting_list(&self, term: &str) -> PostingList<'_> {
+        PostingList::of(self.postings_ref(term))
+    }
 
-            for token in normalized.iter() {
-                if let Some(term_frequency) = self.freq.get(&(*id, token.to_string())) {
-                    // inverse document frequency
-                    let idf = ((total_docs / term_frequency) as f64).log10();
-                    score += idf * term_frequency;
-                }
-            }
+    /**
+     * The `n` terms most related to the (analyzed) term along with how strongly, which is none
+     * unless the reader keeps track of which terms occur together
+     *
+     * Only an Index built with `enable_cooccurrences()` does, the co-occurrences are not
+     * persisted so a DiskIndex, the segments of a SegmentedIndex and a ShardedIndex have none.
+     */
+    fn related_terms(&self, _term: &str, _n: usize) -> Vec<(String, f64)> {
+        vec![]
+    }
 
-            debug!("Doc: {} has score: {}", id, score);
-            results.push((id, score));
-        }
+    /**
+     * The terms ending with the (analyzed) suffix, or None if the reader has no way of finding
+     * them other than checking every term
+     */
+    fn terms_with_suffix(&self, _suffix: &str) -> Option<Vec<String>> {
+        None
+    }
 
-        /*
-         * Sort the results by whoever has the highest score and return
-         */
-        results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Less));
-        debug!("Document scores: {:?}", results);
-        results.iter().map(|r| *r.0).collect()
+    /**
+     * The documents which might contain the (lowercase) substring, or None if the reader has
+     * no way of narrowing it down and every document must be checked
+     */
+    fn substring_candidates(&self, _needle: &str) -> Option<HashSet<DocumentId>> {
+        None
     }
 
-    fn index_document(&mut self, article: Article) -> Result<(), std::io::Error> {
-        let id = article.id();
-        if !self.documents.contains_key(&id) {
-            let tokens = crate::filters::filter(&article.fulltext());
+    /**
+     * Transform the clauses of a parsed query before it is evaluated, which leaves them be
+     * unless the reader says otherwise
+     */
+    fn rewrite(&self, clauses: Vec<Clause>) -> Vec<Clause> {
+        clauses
+    }
 
-            // Make sure we have each token from the document in the index
-            for token in tokens.iter() {
-                // TODO: Find a way around this clone
-                let freq_tuple = (id, token.clone());
-                if !self.freq.contains_key(&freq_tuple) {
-                    self.freq.insert(freq_tuple, 1.0);
-                } else {
-                    if let Some(freq) = self.freq.get_mut(&freq_tuple) {
-                        *freq += 1.0;
-                    }
-                }
+    /**
+     * Parse, rewrite and analyze the query string, ready to be evaluated against this reader
+     */
+    fn normalize(&self, query: &str) -> NormalizedQuery {
+        let clauses = self.rewrite(crate::query::parse(query));
+        crate::query::normalize_clauses(self.schema(), clauses)
+    }
 
-                if !self.index.contains_key(token) {
-                    self.index.insert(token.to_string(), HashSet::new());
-                }
-                if let Some(set) = self.index.get_mut(token) {
-                    set.insert(id);
-                } else {
-                    warn!(
-                        "Tried to get a mutable version of the index for {} and failed",
-                        token
-                    );
-                }
-            }
+    /**
+     * Query the index for the given query string
+     *
+     * The query will be normalized and an ordering of document IDs will be returned
+     */
+    fn query_index(&self, query: &str) -> Vec<DocumentId> {
+        let normalized = self.normalize(query);
+        debug!("Normalized query: {:?}", normalized);
+        crate::query::execute(self, &normalized)
+    }
 
-            self.documents.insert(id, article);
-        }
-        Ok(())
+    /**
+     * Query the index for at most `limit` of the highest scoring documents, along with their
+     * scores
+     */
+    fn search(&self, query: &str, limit: usize) -> Vec<(DocumentId, f64)> {
+        self.search_timed(query, limit).0
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /**
+     * Search like `search`, along with how long each phase of evaluating the query took
+     */
+    fn search_timed(&self, query: &str, limit: usize) -> (Vec<(DocumentId, f64)>, QueryTimings) {
+        let start = std::time::Instant::now();
+        let normalized = self.normalize(query);
+        let mut timings = QueryTimings {
+            parse: start.elapsed(),
+            ..Default::default()
+        };
+        let mut results =
+            crate::query::execute_timed(self, &normalized, &self.ranking(), &mut timings);
+        results.truncate(limit);
+        (results, timings)
+    }
 
-    #[test]
-    fn test_index_new() {
-        let _index = Index::new();
+    /**
+     * Up to `n` documents picked at random, in no particular order, e.g. for eyeballing what an
+     * ingestion actually put into the index
+     */
+    fn random_documents(&self, n: usize) -> Vec<DocumentId> {
+        use rand::seq::SliceRandom;
+
+        let mut ids = self.document_ids();
+        let n = n.min(ids.len());
+        // Only the part of the ids which is kept has to be shuffled
+        let (picked, _) = ids.partial_shuffle(&mut rand::thread_rng(), n);
+        picked.to_vec()
     }
 
-    #[test]
-    fn test_index_simple_data() -> Result<(), std::io::Error> {
-        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
-        assert_eq!(index.size(), 356);
+    /**
+     * Search for the `k` highest scoring documents, then rescore each of them with the closure,
+     * which is given the document and its score, and order them by their new scores
+     *
+     * This is the place for business rules which only need to reorder the best few documents,
+     * without changing how documents are scored in the first place.
+     */
+    fn query_with_rerank(
+        &self,
+        query: &str,
+        k: usize,
+        rerank: &dyn Fn(&Article, f64) -> f64,
+    ) -> Vec<(DocumentId, f64)> {
+        let mut results: Vec<(DocumentId, f64)> = self
+            .search(query, k)
+            .into_iter()
+            .map(|(id, score)| match self.document(&id) {
+                Some(article) => (id, rerank(&article, score)),
+                None => (id, score),
+            })
+            .collect();
+        crate::query::sort_scored(&mut results);
+        results
+    }
+}
+
+/**
+ * A search index
+ */
+#[derive(Debug)]
+pub struct Index {
+    /**
+     * Global mapping of each document and its id, either held in memory or in a DocumentStore
+     */
+    documents: Arc<Documents>,
+    /**
+     * The id of the document at each url
+     */
+    urls: Arc<HashMap<Url, DocumentId>>,
+    /**
+     * The ids of the documents with each normalized title, in the order they were added
+     */
+    titles: Arc<HashMap<String, Vec<DocumentId>>>,
+    /**
+     * The frequencies of a term in the given document, keyed by the DocumentId
+     * and the term within the document.
+     */
+    freq: Arc<HashMap<(DocumentId, String), f64>>,
+    /**
+     * The sorted positions of a term within the given document's full text, used for matching
+     * phrases
+     */
+    positions: Arc<HashMap<(DocumentId, String), Vec<usize>>>,
+    /**
+     * Index containing a mapping of terms to the documents which refer to them
+     */
+    index: Arc<HashMap<String, Postings>>,
+    /**
+     * Optional index of character trigrams to the documents which contain them, used to answer
+     * `contains:` substring queries without scanning every document
+     */
+    trigrams: Option<Arc<HashMap<String, Postings>>>,
+    /**
+     * Optional sorted set of every full text term spelled backwards, used to answer `*suffix`
+     * wildcard queries without scanning every term
+     */
+    reversed: Option<Arc<BTreeSet<String>>>,
+    /**
+     * Per-field indexes mapping the terms of each individually searchable field to the
+     * documents which contain them
+     */
+    fields: Arc<HashMap<Field, HashMap<String, Postings>>>,
+    /**
+     * The Schema used for turning both documents and queries into terms
+     */
+    schema: Schema,
+    /**
+     * Optional cache of query results, which is cleared whenever the index changes
+     */
+    cache: Option<QueryCache>,
+    /**
+     * Per-term statistics computed by `finalize()`, which are dropped whenever the index
+     * changes since every term's idf depends on the total number of documents
+     */
+    stats: Arc<HashMap<String, TermStats>>,
+    /**
+     * The number of terms in each part of every document's full text, computed by `finalize()`
+     * along with the statistics and dropped whenever the index changes
+     */
+    lengths: Arc<HashMap<DocumentId, FieldLengths>>,
+    /**
+     * The mean number of terms in the titles and in the abstracts, computed by `finalize()`
+     * from the lengths and dropped along with them
+     */
+    average_lengths: Option<(f64, f64)>,
+    /**
+     * Optional count of the documents in which each pair of terms occur within
+     * COOCCURRENCE_WINDOW of each other, kept in both directions, which related terms are
+     * suggested from
+     */
+    cooccurrence: Option<Arc<HashMap<String, HashMap<String, u32>>>>,
+    /**
+     * The most results a query returns, if there is a limit
+     */
+    limit: Option<usize>,
+    /**
+     * A rough estimate of the bytes used by the postings and positions added since the index
+     * was created
+     */
+    postings_size: usize,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self::with_schema(Schema::default())
+    }
+
+    /**
+     * Create an empty index which will analyze the full text with the given Analyzer
+     */
+    pub fn with_analyzer(analyzer: Analyzer) -> Self {
+        Self::with_schema(Schema::default().text(analyzer))
+    }
+
+    /**
+     * Create an empty index which will analyze documents and queries with the given Schema
+     */
+    pub fn with_schema(schema: Schema) -> Self {
+        Self {
+            documents: Arc::default(),
+            urls: Arc::default(),
+            titles: Arc::default(),
+            index: Arc::default(),
+            freq: Arc::default(),
+            positions: Arc::default(),
+            trigrams: None,
+            reversed: None,
+            fields: Arc::default(),
+            schema,
+            cache: None,
+            stats: Arc::default(),
+            lengths: Arc::default(),
+            average_lengths: None,
+            cooccurrence: None,
+            limit: None,
+            postings_size: 0,
+        }
+    }
+
+    /**
+     * Cache the results of up to `capacity` distinct queries
+     */
+    pub fn enable_query_cache(&mut self, capacity: std::num::NonZeroUsize) {
+        self.cache = Some(QueryCache::new(capacity));
+    }
+
+    /**
+     * Only ever return the `limit` highest scoring results of a query
+     */
+    pub fn limit_results(&mut self, limit: usize) {
+        self.limit = Some(limit);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    /**
+     * Keep the stored documents in a file at the given path rather than in memory, reading each
+     * of them back only when it is retrieved
+     */
+    pub fn enable_document_store(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        self.store_documents(Arc::new(FileStorage::create(path)?))
+    }
+
+    /**
+     * Keep the stored documents in the given Storage rather than in memory
+     *
+     * Any documents already in the index are moved into the Storage.
+     */
+    pub fn store_documents(&mut self, storage: Arc<dyn Storage>) -> Result<(), std::io::Error> {
+        let mut documents = Documents::Stored {
+            storage,
+            ids: HashSet::new(),
+        };
+        for id in self.documents.ids() {
+            if let Some(article) = self.documents.get(&id) {
+                documents.insert(article.into_owned())?;
+            }
+        }
+        self.documents = Arc::new(documents);
+        Ok(())
+    }
+
+    /**
+     * The Schema which documents and queries are analyzed with
+     */
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /**
+     * Persist the index to a file which can later be opened with `Index::open` or queried in
+     * place with a `DiskIndex`
+     */
+    pub fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        crate::disk::write(self, path)
+    }
+
+    /**
+     * Persist the index to the given path, encrypted with the key
+     */
+    pub fn save_encrypted(&self, path: &Path, key: &Key) -> Result<(), std::io::Error> {
+        crate::disk::write_encrypted(self, path, key)
+    }
+
+    /**
+     * Persist the index as the named segment of the Storage
+     */
+    pub fn save_to(&self, storage: &dyn Storage, name: &str) -> Result<(), std::io::Error> {
+        storage.put_segment(name, &crate::disk::to_bytes(self)?)?;
+        storage.flush()
+    }
+
+    /**
+     * Load the index persisted as the named segment of the Storage fully into memory
+     */
+    pub fn load_from(storage: &dyn Storage, name: &str) -> Result<Self, std::io::Error> {
+        Self::from_reader(&crate::disk::DiskIndex::load(storage, name)?)
+    }
+
+    /**
+     * Load the index persisted at the given path fully into memory
+     */
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        Self::from_reader(&crate::disk::DiskIndex::open(path)?)
+    }
+
+    /**
+     * Copy the entire contents of any IndexReader into a new in-memory Index
+     */
+    pub fn from_reader<R: IndexReader + ?Sized>(reader: &R) -> Result<Self, std::io::Error> {
+        let mut index = Self::with_schema(reader.schema().clone());
+        index.copy_from(reader)?;
+        index.finalize();
+        Ok(index)
+    }
+
+    /**
+     * Copy the entire contents of the IndexReader into this index, which is assumed not to
+     * contain any of the same documents already
+     */
+    pub(crate) fn copy_from<R: IndexReader + ?Sized>(
+        &mut self,
+        reader: &R,
+    ) -> Result<(), std::io::Error> {
+        for id in reader.document_ids() {
+            if let Some(article) = reader.document(&id) {
+                self.add_lookups(&article);
+                Arc::make_mut(&mut self.documents).insert(article.into_owned())?;
+            }
+        }
+
+        let cooccurrences = self.cooccurrence.is_some();
+        let mut tokens: HashMap<DocumentId, Vec<Token>> = HashMap::new();
+        for term in reader.terms() {
+            let docs = reader
+                .postings(&term)
+                .map(|docs| docs.into_owned())
+                .unwrap_or_default();
+            for id in docs.iter() {
+                if let Some(tf) = reader.term_frequency(*id, &term) {
+                    Arc::make_mut(&mut self.freq).insert((*id, term.clone()), tf);
+                }
+                if let Some(positions) = reader.positions(*id, &term) {
+                    if cooccurrences {
+                        tokens.entry(*id).or_default().extend(
+                            positions
+                                .iter()
+                                .map(|position| Token::new(&term, *position)),
+                        );
+                    }
+                    Arc::make_mut(&mut self.positions)
+                        .insert((*id, term.clone()), positions.into_owned());
+                }
+            }
+            if let Some(reversed) = self.reversed.as_mut().map(Arc::make_mut) {
+                reversed.insert(reverse(&term));
+            }
+            Arc::make_mut(&mut self.index)
+                .entry(term)
+                .or_default()
+                .extend(docs);
+        }
+
+        // The co-occurrences are not read back, but can be worked out again from the positions
+        for (_, mut document) in tokens {
+            document.sort_unstable_by_key(|token| token.position);
+            self.add_cooccurrences(&document);
+        }
+
+        for field in Field::ALL {
+            for term in reader.field_terms(*field) {
+                if let Some(docs) = reader.field_postings(*field, &term) {
+                    Arc::make_mut(&mut self.fields)
+                        .entry(*field)
+                        .or_default()
+                        .entry(term)
+                        .or_default()
+                        .extend(docs.iter().copied());
+                }
+            }
+        }
+
+        self.changed();
+        Ok(())
+    }
+
+    /**
+     * Drop everything worked out from the contents of the index, which have just changed
+     */
+    fn changed(&mut self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        self.stats = Arc::default();
+        self.lengths = Arc::default();
+        self.average_lengths = None;
+    }
+
+    /**
+     * Count the terms of the document's full text which occur near each other, if the
+     * co-occurrences are being kept
+     */
+    fn add_cooccurrences(&mut self, tokens: &[Token]) {
+        let cooccurrence = match self.cooccurrence.as_mut() {
+            Some(cooccurrence) => Arc::make_mut(cooccurrence),
+            None => return,
+        };
+        for (term, other) in cooccurring(tokens) {
+            *cooccurrence
+                .entry(term)
+                .or_default()
+                .entry(other)
+                .or_default() += 1;
+        }
+    }
+
+    /**
+     * Load a Wikipedia XML dump from a gzip file
+     */
+    pub fn from_file(path: &Path) -> crate::error::Result<Self> {
+        let mut index = Self::new();
+        index.load_file(path)?;
+        Ok(index)
+    }
+
+    /**
+     * Index every document in the Wikipedia XML dump at the given path
+     */
+    pub fn load_file(&mut self, path: &Path) -> crate::error::Result<Parsed> {
+        let _span = info_span!("load_file", path = %path.display()).entered();
+        let parsed = read_articles(path, |article| self.index_document(article))?;
+
+        debug!("Found {} documents in the file", self.size());
+        self.finalize();
+        Ok(parsed)
+    }
+
+    /**
+     * Compute the per-term statistics used for scoring once all the documents have been
+     * indexed, rather than on every query
+     *
+     * Indexing more documents afterwards discards the statistics until this is called again,
+     * queries in the meantime compute what they need on the fly.
+     */
+    pub fn finalize(&mut self) {
+        let _span = debug_span!("finalize").entered();
+        let total_docs = self.documents.len() as f64;
+        let mut totals: HashMap<&str, f64> = HashMap::new();
+        let mut lengths: HashMap<DocumentId, f64> = HashMap::new();
+        for ((id, term), frequency) in self.freq.iter() {
+            *totals.entry(term.as_str()).or_default() += frequency;
+            *lengths.entry(*id).or_default() += frequency;
+        }
+        self.stats = Arc::new(
+            self.index
+                .iter()
+                .map(|(term, docs)| {
+                    let document_frequency = docs.len() as u64;
+                    let stats = TermStats {
+                        document_frequency,
+                        total_frequency: totals.get(term.as_str()).copied().unwrap_or(0.0) as u64,
+                        idf: (total_docs / document_frequency as f64).log10(),
+                    };
+                    (term.clone(), stats)
+                })
+                .collect(),
+        );
+        self.lengths = Arc::new(
+            lengths
+                .into_iter()
+                .filter_map(|(id, length)| {
+                    let article = self.documents.get(&id)?;
+                    Some((id, FieldLengths::from_title(&self.schema, &article, length)))
+                })
+                .collect(),
+        );
+        self.average_lengths = Some(mean_field_lengths(
+            self.lengths.values(),
+            self.documents.len(),
+        ));
+        debug!("Computed statistics for {} terms", self.stats.len());
+    }
+
+    /**
+     * Lay the index out for querying once it is done changing, sharing its stored documents
+     *
+     * Call `finalize()` first, otherwise the lengths of the documents are worked out by
+     * analyzing every one of them again.
+     */
+    pub fn freeze(&self) -> FrozenIndex {
+        FrozenIndex::with_documents(self, self.documents.clone())
+    }
+
+    /**
+     * A Searcher over the index as it is now, which shares its postings, documents and
+     * statistics rather than copying them
+     *
+     * The index copies whatever it changes afterwards, so the Searcher never sees documents
+     * added or removed after it was taken. Documents kept in a Storage are the exception, since
+     * the Storage itself is shared. Call `finalize()` first so that the statistics are shared too
+     * rather than worked out on every query.
+     */
+    pub fn snapshot(&self) -> Searcher {
+        Searcher::from(Index {
+            documents: self.documents.clone(),
+            urls: self.urls.clone(),
+            titles: self.titles.clone(),
+            index: self.index.clone(),
+            freq: self.freq.clone(),
+            positions: self.positions.clone(),
+            trigrams: self.trigrams.clone(),
+            reversed: self.reversed.clone(),
+            fields: self.fields.clone(),
+            schema: self.schema.clone(),
+            cache: self.cache.clone(),
+            stats: self.stats.clone(),
+            lengths: self.lengths.clone(),
+            average_lengths: self.average_lengths,
+            cooccurrence: self.cooccurrence.clone(),
+            limit: self.limit,
+            postings_size: 0,
+        })
+    }
+
+    /**
+     * Iterate in order over the full text terms within the range, along with the number of
+     * documents containing each of them
+     *
+     * Unlike `IndexReader::terms` the terms are sorted, which is what building an autocomplete
+     * list or paging through the vocabulary needs, e.g. `index.term_dictionary("an".."ao")`.
+     */
+    pub fn term_dictionary<'r, R: std::ops::RangeBounds<&'r str>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (&str, u64)> {
+        let mut terms: Vec<(&str, u64)> = self
+            .index
+            .iter()
+            .filter(|(term, _)| range.contains(&term.as_str()))
+            .map(|(term, docs)| (term.as_str(), docs.len() as u64))
+            .collect();
+        terms.sort_unstable();
+        terms.into_iter()
+    }
+
+    /**
+     * Iterate in order over the full text terms starting with the prefix, along with the
+     * number of documents containing each of them
+     */
+    pub fn terms_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, u64)> {
+        self.term_dictionary(prefix..)
+            .take_while(move |(term, _)| term.starts_with(prefix))
+    }
+
+    /**
+     * The number of documents in the index
+     */
+    pub fn size(&self) -> u64 {
+        self.documents.len() as u64
+    }
+
+    /**
+     * Whether the document is in the index, without reading it from where it is stored
+     */
+    pub fn contains(&self, id: &DocumentId) -> bool {
+        self.documents.contains(id)
+    }
+
+    /**
+     * Attempt to retrieve the given document from the index
+     */
+    pub fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.documents.get(id)
+    }
+
+    /**
+     * Build the reversed term index for all the terms currently in the index, and keep it up to
+     * date for any documents indexed afterwards
+     */
+    pub fn enable_reversed_terms(&mut self) {
+        let reversed: BTreeSet<String> = self.index.keys().map(|term| reverse(term)).collect();
+        debug!("Built {} reversed terms", reversed.len());
+        self.reversed = Some(Arc::new(reversed));
+    }
+
+    /**
+     * Count which terms occur near each other in all the documents currently in the index, and
+     * keep the counts up to date for any documents indexed afterwards, so that `related_terms`
+     * has something to suggest
+     */
+    pub fn enable_cooccurrences(&mut self) {
+        self.cooccurrence = Some(Arc::default());
+        for id in self.documents.ids() {
+            if let Some(article) = self.documents.get(&id) {
+                let tokens = self.analyze_fulltext(&article);
+                self.add_cooccurrences(&tokens);
+            }
+        }
+        debug!(
+            "Counted the co-occurrences of {} terms",
+            self.cooccurrence.as_ref().map_or(0, |counts| counts.len())
+        );
+    }
+
+    /**
+     * Build the trigram index for all the documents currently in the index, and keep it up to
+     * date for any documents indexed afterwards
+     */
+    pub fn enable_trigrams(&mut self) {
+        let mut trigrams: HashMap<String, Postings> = HashMap::new();
+
+        for id in self.documents.ids() {
+            if let Some(article) = self.documents.get(&id) {
+                for trigram in trigrams_of(&article.fulltext().to_lowercase()) {
+                    trigrams.entry(trigram).or_default().insert(id);
+                }
+            }
+        }
+        debug!("Built {} trigrams", trigrams.len());
+        self.trigrams = Some(Arc::new(trigrams));
+    }
+
+    /**
+     * Query the index for the given query string
+     *
+     * The query will be normalized and an ordering of document IDs will be returned
+     */
+    pub fn query_index(&self, query: &str) -> Vec<DocumentId> {
+        let normalized = IndexReader::normalize(self, query);
+        debug!("Normalized query: {:?}", normalized);
+
+        let execute = || {
+            let mut results = crate::query::execute(self, &normalized);
+            if let Some(limit) = self.limit {
+                results.truncate(limit);
+            }
+            results
+        };
+        if let Some(cache) = &self.cache {
+            let key = normalized.key();
+            if let Some(results) = cache.get(&key) {
+                debug!("Query cache hit for {}", key);
+                return results;
+            }
+            let results = execute();
+            cache.put(key, results.clone());
+            return results;
+        }
+        execute()
+    }
+
+    /**
+     * Iterate over the documents matching the query string, scoring each of them only once it
+     * is reached, in ascending order of their ids or highest score first with `by_score()`
+     *
+     * Unlike `query_index()` the results are neither cached nor cut off at the limit, since the
+     * caller takes as many of them as it wants.
+     */
+    pub fn query_iter(&self, query: &str) -> Hits<'_, Index> {
+        let normalized = IndexReader::normalize(self, query);
+        debug!("Normalized query: {:?}", normalized);
+        crate::query::execute_iter(self, &normalized, &self.ranking())
+    }
+
+    /**
+     * Analyze the title and abstract of the article into the tokens of its full text, with the
+     * abstract's positions following on from the title's
+     */
+    fn analyze_fulltext(&self, article: &Article) -> Vec<Token> {
+        let analyzer = self.schema.text_analyzer();
+        let mut tokens = analyzer.analyze(&article.title);
+        let offset = tokens
+            .last()
+            .map(|t| t.position + FIELD_POSITION_GAP)
+            .unwrap_or(0);
+
+        tokens.extend(
+            analyzer
+                .analyze(&article.r#abstract)
+                .into_iter()
+                .map(|mut t| {
+                    t.position += offset;
+                    t
+                }),
+        );
+        tokens
+    }
+
+    /**
+     * Every term of the document's full text along with where it occurs, sorted by term, or
+     * None if the document is not in the index
+     */
+    pub fn term_vector(&self, id: &DocumentId) -> Option<Vec<TermVectorEntry>> {
+        let article = self.documents.get(id)?;
+        let mut positions: std::collections::BTreeMap<String, Vec<usize>> = Default::default();
+        for token in self.analyze_fulltext(&article) {
+            positions
+                .entry(token.text)
+                .or_default()
+                .push(token.position);
+        }
+        Some(
+            positions
+                .into_iter()
+                .map(|(term, positions)| TermVectorEntry {
+                    term,
+                    frequency: positions.len() as u64,
+                    positions,
+                })
+                .collect(),
+        )
+    }
+
+    /**
+     * The `n` terms of the document with the highest tf-idf, along with their scores
+     *
+     * These are the analyzed terms, so they are stemmed and lowercased like everything else in
+     * the index.
+     */
+    pub fn keywords(&self, id: &DocumentId, n: usize) -> Vec<(String, f64)> {
+        let mut scored: Vec<(String, f64)> = self
+            .tfidf_vector(id)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        crate::query::sort_scored(&mut scored);
+        scored.truncate(n);
+        scored
+    }
+
+    /**
+     * The cosine similarity of the two documents' tf-idf vectors, from zero for documents with
+     * no terms in common up to one, or None if either of them is not in the index
+     */
+    pub fn similarity(&self, a: &DocumentId, b: &DocumentId) -> Option<f64> {
+        Some(cosine(&self.tfidf_vector(a)?, &self.tfidf_vector(b)?))
+    }
+
+    /**
+     * The tf-idf weight of every term in the document's full text
+     */
+    pub(crate) fn tfidf_vector(&self, id: &DocumentId) -> Option<HashMap<String, f64>> {
+        tfidf_vector(self, id)
+    }
+
+    /**
+     * The `limit` highest scoring documents containing any of the document's keywords, other
+     * than the document itself, along with their scores
+     */
+    pub fn more_like_this(&self, id: &DocumentId, limit: usize) -> Vec<(DocumentId, f64)> {
+        let terms: Vec<String> = self
+            .keywords(id, MORE_LIKE_THIS_TERMS)
+            .into_iter()
+            .map(|(term, _)| term)
+            .collect();
+        let mut results: Vec<_> = crate::query::execute_any(self, &terms)
+            .into_iter()
+            .filter(|(other, _)| other != id)
+            .collect();
+        results.truncate(limit);
+        results
+    }
+
+    /**
+     * The `n` terms which most often occur near the (analyzed) term, weighted by their idf so
+     * that rarer terms are favored, e.g. for suggesting related searches
+     */
+    pub fn related_terms(&self, term: &str, n: usize) -> Vec<(String, f64)> {
+        let counts = self
+            .cooccurrence
+            .as_ref()
+            .and_then(|cooccurrence| cooccurrence.get(term));
+        let mut related: Vec<(String, f64)> = match counts {
+            Some(counts) => counts
+                .iter()
+                .map(|(other, count)| (other.clone(), *count as f64 * self.idf(other)))
+                .filter(|(_, weight)| *weight > 0.0)
+                .collect(),
+            None => return vec![],
+        };
+        crate::query::sort_scored(&mut related);
+        related.truncate(n);
+        related
+    }
+
+    /**
+     * Iterate over every document in the index, in no particular order
+     *
+     * Documents held in a Storage are read one at a time as the iterator reaches them.
+     */
+    pub fn documents(&self) -> impl Iterator<Item = (DocumentId, Cow<'_, Article>)> {
+        self.documents.iter()
+    }
+
+    /**
+     * The document at the url, if it has been indexed
+     */
+    pub fn document_by_url(&self, url: &Url) -> Option<Cow<'_, Article>> {
+        self.urls.get(url).and_then(|id| self.documents.get(id))
+    }
+
+    /**
+     * The first document added with the title, ignoring case and differences in whitespace
+     */
+    pub fn document_by_title(&self, title: &str) -> Option<Cow<'_, Article>> {
+        self.titles
+            .get(&normalize_title(title))
+            .and_then(|ids| ids.first())
+            .and_then(|id| self.documents.get(id))
+    }
+
+    /**
+     * Record the article in the maps used for looking documents up by url and title
+     */
+    fn add_lookups(&mut self, article: &Article) {
+        if let Some(url) = article.url() {
+            Arc::make_mut(&mut self.urls).insert(url.clone(), article.id());
+        }
+        Arc::make_mut(&mut self.titles)
+            .entry(normalize_title(&article.title))
+            .or_default()
+            .push(article.id());
+    }
+
+    /**
+     * Add the article to the index, unless a document with the same url is already in it
+     */
+    pub fn index_document(&mut self, article: Article) -> Result<(), std::io::Error> {
+        let id = article.id();
+        let _span = trace_span!("index_document", id).entered();
+        if !self.documents.contains(&id) {
+            let tokens = self.analyze_fulltext(&article);
+
+            // Make sure we have each token from the document in the index
+            let freq = Arc::make_mut(&mut self.freq);
+            let positions = Arc::make_mut(&mut self.positions);
+            let index = Arc::make_mut(&mut self.index);
+            let mut reversed = self.reversed.as_mut().map(Arc::make_mut);
+            for Token {
+                text: token,
+                position,
+            } in tokens.iter()
+            {
+                // TODO: Find a way around this clone
+                let frequency = freq.entry((id, token.clone())).or_insert(0.0);
+                if *frequency == 0.0 {
+                    self.postings_size += POSTING_SIZE + 2 * token.len();
+                }
+                *frequency += 1.0;
+                self.postings_size += std::mem::size_of::<usize>();
+                positions
+                    .entry((id, token.clone()))
+                    .or_default()
+                    .push(*position);
+
+                if !index.contains_key(token) {
+                    index.insert(token.to_string(), Postings::default());
+                    if let Some(reversed) = reversed.as_mut() {
+                        reversed.insert(reverse(token));
+                    }
+                }
+                if let Some(set) = index.get_mut(token) {
+                    set.insert(id);
+                } else {
+                    warn!(
+                        "Tried to get a mutable version of the index for {} and failed",
+                        token
+                    );
+                }
+            }
+
+            for (field, analyzer) in self.schema.fields() {
+                if let Some(text) = article.field(*field) {
+                    let index = Arc::make_mut(&mut self.fields).entry(*field).or_default();
+                    for term in analyzer.terms(&text) {
+                        index.entry(term).or_default().insert(id);
+                    }
+                }
+            }
+            for term in article.metadata_terms(&self.schema) {
+                Arc::make_mut(&mut self.fields)
+                    .entry(Field::Metadata)
+                    .or_default()
+                    .entry(term)
+                    .or_default()
+                    .insert(id);
+            }
+
+            self.add_cooccurrences(&tokens);
+
+            if let Some(trigrams) = self.trigrams.as_mut().map(Arc::make_mut) {
+                for trigram in trigrams_of(&article.fulltext().to_lowercase()) {
+                    trigrams.entry(trigram).or_default().insert(id);
+                }
+            }
+
+            self.add_lookups(&article);
+            Arc::make_mut(&mut self.documents).insert(article)?;
+            metrics::counter!(telemetry::DOCUMENTS_INDEXED).increment(1);
+            self.changed();
+        }
+        Ok(())
+    }
+
+    /**
+     * A rough estimate of the bytes used by the postings and positions added since the index
+     * was created
+     */
+    pub fn postings_size(&self) -> usize {
+        self.postings_size
+    }
+
+    /**
+     * Compact the index once it is done changing, releasing the memory left over from removed
+     * documents and rebuilding the statistics
+     *
+     * Documents are removed from an Index right away, so there are no tombstones to purge
+     * here, but their postings and the containers which held them keep their memory until
+     * they are shrunk.
+     */
+    pub fn optimize(&mut self) {
+        let _span = debug_span!("optimize").entered();
+        let shrink = |postings: &mut HashMap<String, Postings>| {
+            postings.retain(|_, docs| !docs.is_empty());
+            for docs in postings.values_mut() {
+                docs.shrink_to_fit();
+            }
+            postings.shrink_to_fit();
+        };
+        shrink(Arc::make_mut(&mut self.index));
+        let fields = Arc::make_mut(&mut self.fields);
+        for postings in fields.values_mut() {
+            shrink(postings);
+        }
+        fields.retain(|_, postings| !postings.is_empty());
+        if let Some(trigrams) = self.trigrams.as_mut().map(Arc::make_mut) {
+            shrink(trigrams);
+        }
+
+        Arc::make_mut(&mut self.freq).shrink_to_fit();
+        let positions = Arc::make_mut(&mut self.positions);
+        for offsets in positions.values_mut() {
+            offsets.shrink_to_fit();
+        }
+        positions.shrink_to_fit();
+        if let Some(cooccurrence) = self.cooccurrence.as_mut().map(Arc::make_mut) {
+            for counts in cooccurrence.values_mut() {
+                counts.shrink_to_fit();
+            }
+            cooccurrence.shrink_to_fit();
+        }
+        Arc::make_mut(&mut self.titles).shrink_to_fit();
+        Arc::make_mut(&mut self.urls).shrink_to_fit();
+        Arc::make_mut(&mut self.documents).shrink_to_fit();
+
+        self.changed();
+        self.finalize();
+        debug!("Optimized an index of {} documents", self.size());
+    }
+
+    /**
+     * Remove the document and all of its terms from the index, returning whether it was there
+     */
+    pub fn remove_document(&mut self, id: &DocumentId) -> Result<bool, std::io::Error> {
+        let article = match Arc::make_mut(&mut self.documents).remove(id)? {
+            Some(article) => article,
+            None => return Ok(false),
+        };
+        let id = *id;
+        if let Some(url) = article.url() {
+            Arc::make_mut(&mut self.urls).remove(url);
+        }
+        let title = normalize_title(&article.title);
+        let titles = Arc::make_mut(&mut self.titles);
+        if let Some(ids) = titles.get_mut(&title) {
+            ids.retain(|other| *other != id);
+            if ids.is_empty() {
+                titles.remove(&title);
+            }
+        }
+
+        let tokens = self.analyze_fulltext(&article);
+        if let Some(cooccurrence) = self.cooccurrence.as_mut().map(Arc::make_mut) {
+            for (term, other) in cooccurring(&tokens) {
+                if let Some(counts) = cooccurrence.get_mut(&term) {
+                    if let Some(count) = counts.get_mut(&other) {
+                        *count -= 1;
+                        if *count == 0 {
+                            counts.remove(&other);
+                        }
+                    }
+                    if counts.is_empty() {
+                        cooccurrence.remove(&term);
+                    }
+                }
+            }
+        }
+        let freq = Arc::make_mut(&mut self.freq);
+        let positions = Arc::make_mut(&mut self.positions);
+        let index = Arc::make_mut(&mut self.index);
+        let mut reversed = self.reversed.as_mut().map(Arc::make_mut);
+        for token in tokens {
+            let key = (id, token.text);
+            freq.remove(&key);
+            positions.remove(&key);
+            remove_posting(index, &key.1, id);
+            if !index.contains_key(&key.1) {
+                if let Some(reversed) = reversed.as_mut() {
+                    reversed.remove(&reverse(&key.1));
+                }
+            }
+        }
+
+        let fields = Arc::make_mut(&mut self.fields);
+        for (field, analyzer) in self.schema.fields() {
+            if let (Some(text), Some(index)) = (article.field(*field), fields.get_mut(field)) {
+                for term in analyzer.terms(&text) {
+                    remove_posting(index, &term, id);
+                }
+            }
+        }
+        if let Some(index) = fields.get_mut(&Field::Metadata) {
+            for term in article.metadata_terms(&self.schema) {
+                remove_posting(index, &term, id);
+            }
+        }
+
+        if let Some(trigrams) = self.trigrams.as_mut().map(Arc::make_mut) {
+            for trigram in trigrams_of(&article.fulltext().to_lowercase()) {
+                remove_posting(trigrams, &trigram, id);
+            }
+        }
+
+        self.changed();
+        Ok(true)
+    }
+}
+
+/**
+ * Variants of the blocking entry points for use from async code, which run on tokio's blocking
+ * thread pool so that parsing and querying never stall the executor's threads
+ */
+#[cfg(feature = "async")]
+impl Index {
+    /**
+     * Load a Wikipedia XML dump from a gzip file
+     */
+    pub async fn from_file_async(path: std::path::PathBuf) -> crate::error::Result<Self> {
+        blocking(move || Self::from_file(&path)).await?
+    }
+
+    /**
+     * Query the index for the given query string, like `query_index()`
+     */
+    pub async fn query_index_async(
+        self: &std::sync::Arc<Self>,
+        query: &str,
+    ) -> Result<Vec<DocumentId>, std::io::Error> {
+        let index = self.clone();
+        let query = query.to_string();
+        blocking(move || index.query_index(&query)).await
+    }
+}
+
+/**
+ * Run the function on tokio's blocking thread pool, carrying on with its panic if it panics
+ */
+#[cfg(feature = "async")]
+pub(crate) async fn blocking<T, F>(f: F) -> Result<T, std::io::Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.map_err(|e| {
+        if e.is_panic() {
+            std::panic::resume_unwind(e.into_panic());
+        }
+        std::io::Error::other(e)
+    })
+}
+
+/**
+ * The mean title and abstract lengths of `count` documents, zero for no documents
+ */
+pub(crate) fn mean_field_lengths<'a>(
+    lengths: impl Iterator<Item = &'a FieldLengths>,
+    count: usize,
+) -> (f64, f64) {
+    let (title, r#abstract) = lengths.fold((0.0, 0.0), |(title, r#abstract), lengths| {
+        (title + lengths.title, r#abstract + lengths.r#abstract)
+    });
+    match count {
+        0 => (0.0, 0.0),
+        n => (title / n as f64, r#abstract / n as f64),
+    }
+}
+
+/**
+ * Work out the statistics of the term from the reader's postings and term frequencies
+ */
+fn compute_term_stats<R: IndexReader + ?Sized>(reader: &R, term: &str) -> Option<TermStats> {
+    let docs = reader.postings_ref(term).filter(|docs| !docs.is_empty())?;
+    let total_frequency = docs
+        .iter()
+        .filter_map(|id| reader.term_frequency(*id, term))
+        .sum::<f64>() as u64;
+    Some(TermStats {
+        document_frequency: docs.len() as u64,
+        total_frequency,
+        idf: reader.idf(term),
+    })
+}
+
+/**
+ * The tf-idf weight of every term in the document's full text, or None if the document is not
+ * in the index
+ */
+pub(crate) fn tfidf_vector<R: IndexReader + ?Sized>(
+    reader: &R,
+    id: &DocumentId,
+) -> Option<HashMap<String, f64>> {
+    let article = reader.document(id)?;
+    let analyzer = reader.schema().text_analyzer();
+    let mut weights: HashMap<String, f64> = HashMap::new();
+    for text in [article.title(), article.abstract_text()] {
+        for term in analyzer.terms(text) {
+            *weights.entry(term).or_default() += 1.0;
+        }
+    }
+    for (term, weight) in weights.iter_mut() {
+        *weight *= reader.idf(term);
+    }
+    Some(weights)
+}
+
+/**
+ * The cosine of the angle between two sparse vectors, zero if either of them is empty
+ */
+pub(crate) fn cosine(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(term, weight)| b.get(term).map(|other| weight * other))
+        .sum();
+    let norm = |v: &HashMap<String, f64>| v.values().map(|w| w * w).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/**
+ * Normalize a title for exact lookups, so that `anarchism` finds `Anarchism`
+ */
+pub fn normalize_title(title: &str) -> String {
+    use unicode_normalization::UnicodeNormalization;
+
+    title
+        .nfc()
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/**
+ * Every distinct pair of different terms within COOCCURRENCE_WINDOW positions of each other, in
+ * both orders
+ */
+fn cooccurring(tokens: &[Token]) -> HashSet<(String, String)> {
+    let mut pairs = HashSet::new();
+    for (i, token) in tokens.iter().enumerate() {
+        for other in tokens[i + 1..]
+            .iter()
+            .take_while(|other| other.position <= token.position + COOCCURRENCE_WINDOW)
+        {
+            if other.text != token.text {
+                pairs.insert((token.text.clone(), other.text.clone()));
+                pairs.insert((other.text.clone(), token.text.clone()));
+            }
+        }
+    }
+    pairs
+}
+
+/**
+ * Remove the document from the postings of the term, dropping the term once nothing has it
+ */
+fn remove_posting(index: &mut HashMap<String, Postings>, term: &str, id: DocumentId) {
+    if let Some(set) = index.get_mut(term) {
+        set.remove(&id);
+        if set.is_empty() {
+            index.remove(term);
+        }
+    }
+}
+
+impl IndexReader for Index {
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn size(&self) -> u64 {
+        self.documents.len() as u64
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.documents.ids()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.documents.get(id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.index.keys().cloned().collect()
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.index.get(term).map(Postings::to_set)
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.fields
+            .get(&field)
+            .map(|index| index.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.fields
+            .get(&field)
+            .and_then(|index| index.get(term))
+            .map(Postings::to_set)
+    }
+
+    fn postings_ref(&self, term: &str) -> Option<PostingsRef<'_>> {
+        self.index.get(term).map(PostingsRef::Postings)
+    }
+
+    fn field_postings_ref(&self, field: Field, term: &str) -> Option<PostingsRef<'_>> {
+        self.fields
+            .get(&field)
+            .and_then(|index| index.get(term))
+            .map(PostingsRef::Postings)
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        // TODO: Find a way around this clone
+        self.freq.get(&(id, term.to_string())).copied()
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.positions
+            .get(&(id, term.to_string()))
+            .map(|p| Cow::Borrowed(p.as_slice()))
+    }
+
+    fn term_stats(&self, term: &str) -> Option<TermStats> {
+        match self.stats.get(term) {
+            Some(stats) => Some(stats.clone()),
+            None => compute_term_stats(self, term),
+        }
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        if let Some(lengths) = self.lengths.get(&id) {
+            return Some(*lengths);
+        }
+        let article = self.documents.get(&id)?;
+        let length = self.analyze_fulltext(&article).len() as f64;
+        Some(FieldLengths::from_title(&self.schema, &article, length))
+    }
+
+    fn average_field_lengths(&self) -> (f64, f64) {
+        if let Some(averages) = self.average_lengths {
+            return averages;
+        }
+        if self.lengths.is_empty() {
+            let ids = self.documents.ids();
+            let lengths: Vec<FieldLengths> = ids
+                .iter()
+                .filter_map(|id| self.field_lengths(*id))
+                .collect();
+            return mean_field_lengths(lengths.iter(), ids.len());
+        }
+        mean_field_lengths(self.lengths.values(), self.documents.len())
+    }
+
+    /**
+     * The total of every term frequency is the total length of the documents, which is summed
+     * up again unless `finalize()` has computed the averages
+     */
+    fn average_document_length(&self) -> f64 {
+        if let Some((title, r#abstract)) = self.average_lengths {
+            return title + r#abstract;
+        }
+        let total: f64 = match self.lengths.is_empty() {
+            true => self.freq.values().sum(),
+            false => self.lengths.values().map(|lengths| lengths.total()).sum(),
+        };
+        match self.documents.len() {
+            0 => 0.0,
+            n => total / n as f64,
+        }
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        if let Some(stats) = self.stats.get(term) {
+            return stats.idf;
+        }
+        match self.index.get(term) {
+            Some(docs) if !docs.is_empty() => {
+                (self.documents.len() as f64 / docs.len() as f64).log10()
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn related_terms(&self, term: &str, n: usize) -> Vec<(String, f64)> {
+        Index::related_terms(self, term, n)
+    }
+
+    /**
+     * Look the terms up in the reversed term index, when it is enabled
+     */
+    fn terms_with_suffix(&self, suffix: &str) -> Option<Vec<String>> {
+        let reversed = self.reversed.as_ref()?;
+        let prefix = reverse(suffix);
+        Some(
+            reversed
+                .range(prefix.clone()..)
+                .take_while(|term| term.starts_with(&prefix))
+                .map(|term| reverse(term))
+                .collect(),
+        )
+    }
+
+    /**
+     * Narrow the candidates down with the trigram index, when it is enabled
+     */
+    fn substring_candidates(&self, needle: &str) -> Option<HashSet<DocumentId>> {
+        let trigrams = self.trigrams.as_ref()?;
+        let needle_trigrams = trigrams_of(needle);
+        if needle_trigrams.is_empty() {
+            return None;
+        }
+
+        let mut sets = vec![];
+        for trigram in needle_trigrams.iter() {
+            match trigrams.get(trigram) {
+                Some(set) => sets.push(set),
+                None => return Some(HashSet::new()),
+            }
+        }
+        sets.sort_by_key(|set| set.len());
+        Some(
+            sets[0]
+                .iter()
+                .filter(|id| sets[1..].iter().all(|set| set.contains(id)))
+                .copied()
+                .collect(),
+        )
+    }
+
+    fn query_index(&self, query: &str) -> Vec<DocumentId> {
+        Index::query_index(self, query)
+    }
+}
+
+/**
+ * What reading a Wikipedia XML dump came across
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Parsed {
+    pub documents: usize,
+    /**
+     * The `<doc>` entries which were left out for being malformed
+     */
+    pub skipped: usize,
+}
+
+/**
+ * How malformed `<doc>` entries in a Wikipedia XML dump are dealt with
+ */
+#[derive(Clone, Debug, Default)]
+pub struct IngestOptions {
+    /**
+     * Fail on the first malformed entry, rather than skipping it with a warning
+     */
+    pub strict: bool,
+    /**
+     * Write a line of JSON describing every skipped entry to this file
+     */
+    pub rejects: Option<std::path::PathBuf>,
+}
+
+/**
+ * A skipped `<doc>` entry as it is written to the rejects file
+ */
+#[derive(Debug, Serialize)]
+struct Rejected<'a> {
+    position: usize,
+    reason: String,
+    title: &'a str,
+    url: Option<&'a str>,
+    r#abstract: &'a str,
+}
+
+/**
+ * Where malformed entries go while reading a dump
+ */
+struct Rejects {
+    strict: bool,
+    file: Option<std::io::BufWriter<File>>,
+    skipped: usize,
+}
+
+impl Rejects {
+    /**
+     * Skip the article, or fail with the reason in strict mode
+     */
+    fn reject(
+        &mut self,
+        article: &Article,
+        position: usize,
+        reason: crate::error::Error,
+    ) -> crate::error::Result<()> {
+        use std::io::Write;
+
+        if self.strict {
+            return Err(crate::error::Error::Malformed {
+                position,
+                title: article.title.clone(),
+                source: Box::new(reason),
+            });
+        }
+        warn!(
+            "Skipping the <doc> at byte {} titled `{}`: {}",
+            position, article.title, reason
+        );
+        self.skipped += 1;
+        if let Some(file) = &mut self.file {
+            let rejected = Rejected {
+                position,
+                reason: reason.to_string(),
+                title: &article.title,
+                url: article.url.as_ref().map(|url| url.as_str()),
+                r#abstract: &article.r#abstract,
+            };
+            serde_json::to_writer(&mut *file, &rejected).map_err(std::io::Error::from)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Read every Article out of the gzipped Wikipedia XML dump at the given path, handing each of
+ * them to the callback as soon as it has been parsed
+ *
+ * A `<doc>` which is malformed, e.g. with an invalid url or broken markup, is skipped with a
+ * warning rather than failing the whole dump.
+ */
+pub fn read_articles<F>(path: &Path, each: F) -> crate::error::Result<Parsed>
+where
+    F: FnMut(Article) -> Result<(), std::io::Error>,
+{
+    read_articles_with(path, &IngestOptions::default(), each)
+}
+
+/**
+ * Read the text of the element which has just been started onto the end of `out`, like
+ * `Reader::read_text()` but without allocating a buffer and a String for every element
+ *
+ * The text is only copied when it has to be decoded or unescaped, and `buf` is left empty so it
+ * can be used again.
+ */
+fn read_text_into<B: std::io::BufRead>(
+    reader: &mut quick_xml::Reader<B>,
+    end: &[u8],
+    buf: &mut Vec<u8>,
+    out: &mut String,
+) -> quick_xml::Result<()> {
+    use quick_xml::events::Event;
+
+    let read = match reader.read_event(buf) {
+        Ok(Event::Text(text)) => {
+            let decoded = reader.decode(&text);
+            let unescaped = quick_xml::escape::unescape(decoded.as_bytes())
+                .map_err(quick_xml::Error::EscapeError)?;
+            out.push_str(std::str::from_utf8(&unescaped)?);
+            Ok(())
+        }
+        Ok(Event::End(ref e)) if e.name() == end => {
+            buf.clear();
+            return Ok(());
+        }
+        Ok(Event::Eof) => Err(quick_xml::Error::UnexpectedEof("Text".to_string())),
+        Ok(_) => Err(quick_xml::Error::TextNotFound),
+        Err(e) => Err(e),
+    };
+    buf.clear();
+    read?;
+    let skipped = reader.read_to_end(end, buf);
+    buf.clear();
+    skipped
+}
+
+/**
+ * Read every Article out of the gzipped Wikipedia XML dump at the given path like
+ * `read_articles()`, dealing with malformed entries as the options say
+ */
+pub fn read_articles_with<F>(
+    path: &Path,
+    options: &IngestOptions,
+    mut each: F,
+) -> crate::error::Result<Parsed>
+where
+    F: FnMut(Article) -> Result<(), std::io::Error>,
+{
+    use crate::error::Error;
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use std::io::{BufReader, BufWriter, Write};
+
+    let file = File::open(path)?;
+    let gz = GzDecoder::new(BufReader::new(file));
+    let mut reader = Reader::from_reader(BufReader::new(gz));
+    let mut rejects = Rejects {
+        strict: options.strict,
+        file: match &options.rejects {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        },
+        skipped: 0,
+    };
+
+    // Both buffers are reused for every element, and the url for every document
+    let mut buf = vec![];
+    let mut text = vec![];
+    let mut url = String::new();
+    let mut parsed = Parsed::default();
+    // The article being read along with where it started, and the first problem with it
+    let mut article: Option<(Article, usize, Option<Error>)> = None;
+    let mut last_error = None;
+
+    loop {
+        let position = reader.buffer_position();
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => {
+                let name = e.name();
+                if name == b"doc" {
+                    if let Some((unclosed, start, None)) = article.take() {
+                        rejects.reject(&unclosed, start, Error::Unclosed)?;
+                    }
+                    article = Some((Article::default(), position, None));
+                } else if let (Some((current, _, None)), b"title" | b"abstract" | b"url") =
+                    (&mut article, name)
+                {
+                    let out = match name {
+                        b"title" => &mut current.title,
+                        b"abstract" => &mut current.r#abstract,
+                        _ => &mut url,
+                    };
+                    out.clear();
+                    let filled = read_text_into(&mut reader, name, &mut text, out)
+                        .map_err(|source| Error::Xml {
+                            position: reader.buffer_position(),
+                            source,
+                        })
+                        .and_then(|_| match name {
+                            b"url" => current.set_url(&url).map_err(|source| Error::Url {
+                                url: url.clone(),
+                                source,
+                            }),
+                            _ => Ok(()),
+                        });
+                    if let (Err(e), Some((_, _, problem))) = (filled, &mut article) {
+                        *problem = Some(e);
+                    }
+                }
+            }
+            Ok(Event::End(ref e)) if e.name() == b"doc" => match article.take() {
+                Some((current, start, None)) if current.url.is_none() => {
+                    rejects.reject(&current, start, Error::MissingUrl)?;
+                }
+                Some((current, _, None)) => {
+                    each(current)?;
+                    parsed.documents += 1;
+                }
+                Some((current, start, Some(e))) => rejects.reject(&current, start, e)?,
+                None => {}
+            },
+            Ok(Event::Eof) => break,
+            Err(source) => {
+                let position = reader.buffer_position();
+                // Giving up once the reader stops making progress past the broken markup
+                if last_error == Some(position) || (options.strict && article.is_none()) {
+                    return Err(Error::Xml { position, source });
+                }
+                last_error = Some(position);
+                match &mut article {
+                    Some((_, _, problem @ None)) => {
+                        *problem = Some(Error::Xml { position, source });
+                    }
+                    Some(_) => {}
+                    None => warn!("Ignoring malformed XML at byte {}: {}", position, source),
+                }
+            }
+            _ => (),
+        }
+
+        // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
+        buf.clear();
+    }
+    if let Some(file) = &mut rejects.file {
+        file.flush()?;
+    }
+    parsed.skipped = rejects.skipped;
+
+    if parsed.skipped > 0 {
+        warn!(
+            "Skipped {} malformed documents out of {} in {:?}",
+            parsed.skipped,
+            parsed.documents + parsed.skipped,
+            path
+        );
+    }
+    Ok(parsed)
+}
+
+/**
+ * The term spelled backwards, which the reversed term index is keyed by
+ */
+fn reverse(term: &str) -> String {
+    term.chars().rev().collect()
+}
+
+/**
+ * Collect the set of character trigrams in the given text
+ */
+fn trigrams_of(text: &str) -> HashSet<String> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async() -> crate::error::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let path = PathBuf::from("data/simple.xml.gz");
+            let index = std::sync::Arc::new(Index::from_file_async(path.clone()).await?);
+            assert_eq!(index.size(), 356);
+            assert_eq!(
+                index.query_index_async("anarchism").await?,
+                Index::from_file(&path)?.query_index("anarchism")
+            );
+            Ok(())
+        })
+    }
+
+    fn dump(name: &str, xml: &[u8]) -> Result<std::path::PathBuf, std::io::Error> {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let path =
+            std::env::temp_dir().join(format!("goede-{}-{}.xml.gz", name, std::process::id()));
+        let mut gz = GzEncoder::new(File::create(&path)?, flate2::Compression::fast());
+        gz.write_all(xml)?;
+        gz.finish()?;
+        Ok(path)
+    }
+
+    fn malformed_dump(name: &str) -> Result<std::path::PathBuf, std::io::Error> {
+        dump(
+            name,
+            br#"<feed>
+<doc><title>Good</title><url>https://example.com/good</url><abstract>first</abstract></doc>
+<doc><title>Bad url</title><url>not a url</url><abstract>second</abstract></doc>
+<doc><title>No url</title><abstract>third</abstract></doc>
+<doc><title>Broken &bogus; escape</title><url>https://example.com/broken</url></doc>
+<doc><title>Also good</title><url>https://example.com/also</url><abstract>fifth</abstract></doc>
+</feed>"#,
+        )
+    }
+
+    #[test]
+    fn test_decode_entities() -> Result<(), std::io::Error> {
+        let path = dump(
+            "entities",
+            br#"<feed>
+<doc><title>Salt &amp; pepper</title><url>https://example.com/salt?a=1&amp;b=2</url><abstract>&lt;b&gt; caf&#233; &quot;noir&quot;</abstract></doc>
+</feed>"#,
+        )?;
+        let mut articles = vec![];
+        read_articles(&path, |article| {
+            articles.push(article);
+            Ok(())
+        })?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(articles.len(), 1);
+        assert_eq!(articles[0].title(), "Salt & pepper");
+        assert_eq!(articles[0].abstract_text(), "<b> café \"noir\"");
+        assert_eq!(
+            articles[0].url().map(|url| url.as_str()),
+            Some("https://example.com/salt?a=1&b=2")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_malformed_documents() -> Result<(), std::io::Error> {
+        let path = malformed_dump("lenient")?;
+        let rejects = path.with_extension("rejects");
+        let options = IngestOptions {
+            strict: false,
+            rejects: Some(rejects.clone()),
+        };
+        let mut titles = vec![];
+        let parsed = read_articles_with(&path, &options, |article| {
+            titles.push(article.title);
+            Ok(())
+        })?;
+        assert_eq!(titles, vec!["Good", "Also good"]);
+        assert_eq!(
+            parsed,
+            Parsed {
+                documents: 2,
+                skipped: 3
+            }
+        );
+        let rejected: Vec<serde_json::Value> = std::fs::read_to_string(&rejects)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(rejected.len(), 3);
+        assert_eq!(rejected[1]["title"], "No url");
+
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&rejects)?;
+        match Index::from_file(Path::new("data/missing.xml.gz")) {
+            Err(crate::error::Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+            other => panic!("expected an io error, not {:?}", other.map(|i| i.size())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_ingestion() -> Result<(), std::io::Error> {
+        let path = malformed_dump("strict")?;
+        let options = IngestOptions {
+            strict: true,
+            rejects: None,
+        };
+        let result = read_articles_with(&path, &options, |_| Ok(()));
+        std::fs::remove_file(&path)?;
+        match result {
+            Err(crate::error::Error::Malformed {
+                position, title, ..
+            }) => {
+                assert_eq!(title, "Bad url");
+                assert_eq!(position, 99);
+            }
+            other => panic!("expected a malformed document, not {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_article_accessors() {
+        let article = article("Title", "Some text", "https://example.com/title");
+        assert_eq!(article.title(), "Title");
+        assert_eq!(article.abstract_text(), "Some text");
+        assert_eq!(
+            article.url().map(|url| url.as_str()),
+            Some("https://example.com/title")
+        );
+        assert_ne!(article.id(), 0);
+        assert!(Article::new("Title", "Some text", "not a url").is_err());
+    }
+
+    #[test]
+    fn test_index_new() {
+        let _index = Index::new();
+    }
+
+    #[test]
+    fn test_index_simple_data() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        assert_eq!(index.size(), 356);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_contains() -> Result<(), std::io::Error> {
+        let mut index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let scanned = index.query_index("contains:ography");
+        assert!(!scanned.is_empty());
+
+        index.enable_trigrams();
+        let mut indexed = index.query_index("contains:ography");
+        let mut scanned = scanned;
+        indexed.sort();
+        scanned.sort();
+        assert_eq!(indexed, scanned);
+
+        for id in indexed {
+            let article = index.document(&id).unwrap();
+            assert!(article.fulltext().to_lowercase().contains("ography"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_trigrams_of() {
+        let trigrams = trigrams_of("abcd");
+        assert_eq!(trigrams.len(), 2);
+        assert!(trigrams.contains("abc"));
+        assert!(trigrams.contains("bcd"));
+        assert!(trigrams_of("ab").is_empty());
+    }
+
+    #[test]
+    fn test_index_custom_analyzer() -> Result<(), std::io::Error> {
+        use crate::filters::AnalyzerBuilder;
+
+        // Without the stemmer only the exact word will match
+        let analyzer = AnalyzerBuilder::standard().remove_filter(3).build();
+        let mut index = Index::with_analyzer(analyzer);
+        index.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        assert!(index.query_index("anarch").is_empty());
+        assert!(!index.query_index("anarchism").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_field() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let results = index.query_index("url:https://en.wikipedia.org/wiki/Anarchism");
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            index.document(&results[0]).unwrap().title,
+            "Wikipedia: Anarchism"
+        );
+
+        // The keyword analyzer does not tokenize, so partial urls do not match
+        assert!(index.query_index("url:Anarchism").is_empty());
+
+        let everything = index.query_index("domain:en.wikipedia.org");
+        assert_eq!(everything.len() as u64, index.size());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_unsearchable_field() -> Result<(), std::io::Error> {
+        let mut index = Index::with_schema(Schema::new(Analyzer::default()));
+        index.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        assert!(index.query_index("title:anarchism").is_empty());
+        assert!(!index.query_index("anarchism").is_empty());
+        Ok(())
+    }
+
+    fn article(title: &str, r#abstract: &str, url: &str) -> Article {
+        Article::new(title, r#abstract, url).unwrap()
+    }
+
+    #[test]
+    fn test_document_by_url() -> Result<(), std::io::Error> {
+        let url = Url::parse("https://en.wikipedia.org/wiki/Anarchism").unwrap();
+        let mut index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let found = index.document_by_url(&url).map(|a| a.into_owned()).unwrap();
+        assert_eq!(found.title(), "Wikipedia: Anarchism");
+
+        let reopened = Index::from_reader(&crate::disk::DiskIndex::from_bytes(
+            crate::disk::to_bytes(&index)?,
+        )?)?;
+        assert_eq!(reopened.document_by_url(&url).as_deref(), Some(&found));
+
+        index.remove_document(&found.id())?;
+        assert!(index.document_by_url(&url).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_term_dictionary() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let all: Vec<_> = index.term_dictionary(..).collect();
+        assert_eq!(all.len(), index.terms().len());
+        assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let anarch: Vec<_> = index.terms_with_prefix("anarch").collect();
+        assert!(anarch.iter().all(|(term, _)| term.starts_with("anarch")));
+        assert!(anarch.contains(&("anarch", index.postings("anarch").unwrap().len() as u64)));
+        assert_eq!(
+            index
+                .term_dictionary("anarch".."anarci")
+                .collect::<Vec<_>>(),
+            anarch
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_term_vector() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let liberty = article(
+            "Statue of Liberty",
+            "A statue in New York",
+            "https://example.com/liberty",
+        );
+        let id = liberty.id();
+        index.index_document(liberty)?;
+
+        let vector = index.term_vector(&id).unwrap();
+        assert!(vector.windows(2).all(|pair| pair[0].term < pair[1].term));
+        for entry in vector.iter() {
+            assert_eq!(
+                Some(entry.frequency as f64),
+                index.term_frequency(id, &entry.term)
+            );
+            assert_eq!(
+                index.positions(id, &entry.term).as_deref(),
+                Some(&entry.positions[..])
+            );
+        }
+        let statue = vector.iter().find(|entry| entry.term == "statu").unwrap();
+        assert_eq!(statue.frequency, 2);
+        assert_eq!(index.term_vector(&0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keywords() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let url = Url::parse("https://en.wikipedia.org/wiki/Anarchism").unwrap();
+        let id = index.document_by_url(&url).unwrap().id();
+
+        let keywords = index.keywords(&id, 3);
+        assert_eq!(keywords.len(), 3);
+        assert!(keywords.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert!(keywords.iter().any(|(term, _)| term == "anarch"));
+        assert!(index.keywords(&0, 3).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_more_like_this() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let liberty = article(
+            "Statue of Liberty",
+            "A colossal statue in New York harbor",
+            "https://example.com/liberty",
+        );
+        let id = liberty.id();
+        index.index_document(liberty)?;
+        let bell = article(
+            "Liberty Bell",
+            "A bell in Philadelphia",
+            "https://example.com/bell",
+        );
+        let bell_id = bell.id();
+        index.index_document(bell)?;
+        index.index_document(article("Banana", "A fruit", "https://example.com/banana"))?;
+        index.finalize();
+
+        let similar = index.more_like_this(&id, 5);
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].0, bell_id);
+        assert!(index.more_like_this(&0, 5).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_related_terms() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let liberty = article(
+            "Statue of Liberty",
+            "A colossal statue in New York harbor",
+            "https://example.com/liberty",
+        );
+        let id = liberty.id();
+        index.index_document(liberty)?;
+        index.index_document(article(
+            "Liberty Bell",
+            "A cracked bell in Philadelphia",
+            "https://example.com/bell",
+        ))?;
+        index.index_document(article("Banana", "A fruit", "https://example.com/banana"))?;
+        index.finalize();
+        assert!(index.related_terms("liberti", 10).is_empty());
+
+        // Counted for the documents already indexed, and kept up to date from then on
+        index.enable_cooccurrences();
+        let related: Vec<String> = index
+            .related_terms("liberti", 10)
+            .into_iter()
+            .map(|(term, _)| term)
+            .collect();
+        assert!(related.contains(&"statu".to_string()));
+        assert!(related.contains(&"bell".to_string()));
+        assert!(!related.contains(&"harbor".to_string()));
+        assert!(!related.contains(&"banana".to_string()));
+        assert_eq!(index.related_terms("liberti", 1).len(), 1);
+
+        index.remove_document(&id)?;
+        assert!(index.related_terms("statu", 10).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_similarity() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let liberty = article("Statue of Liberty", "A statue", "https://example.com/a");
+        let copy = article("Statue of Liberty", "A statue", "https://example.com/b");
+        let bell = article("Liberty Bell", "A bell", "https://example.com/c");
+        let banana = article("Banana", "A fruit", "https://example.com/d");
+        let ids: Vec<_> = [&liberty, &copy, &bell, &banana]
+            .iter()
+            .map(|a| a.id())
+            .collect();
+        for article in [liberty, copy, bell, banana] {
+            index.index_document(article)?;
+        }
+        index.finalize();
+
+        let same = index.similarity(&ids[0], &ids[1]).unwrap();
+        assert!((same - 1.0).abs() < 1e-9);
+        let related = index.similarity(&ids[0], &ids[2]).unwrap();
+        assert!(related > 0.0 && related < 1.0);
+        assert_eq!(index.similarity(&ids[0], &ids[3]), Some(0.0));
+        assert_eq!(index.similarity(&ids[0], &0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_term_stats() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let stats = index.term_stats("anarch").unwrap();
+        let docs = index.postings("anarch").unwrap();
+        assert_eq!(stats.document_frequency, docs.len() as u64);
+        let total: f64 = docs
+            .iter()
+            .map(|id| index.term_frequency(*id, "anarch").unwrap())
+            .sum();
+        assert_eq!(stats.total_frequency, total as u64);
+        assert!(stats.total_frequency > stats.document_frequency);
+
+        // Readers without precomputed statistics work them out on the fly
+        let disk = crate::disk::DiskIndex::from_bytes(crate::disk::to_bytes(&index)?)?;
+        assert_eq!(disk.term_stats("anarch"), Some(stats));
+        assert_eq!(index.term_stats("anarchism"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_posting_list() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let anarch: Vec<_> = index.posting_list("anarch").collect();
+        assert!(anarch.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(anarch.len(), index.postings("anarch").unwrap().len());
+        assert_eq!(index.posting_list("anarchism").next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_iterate_documents() -> Result<(), std::io::Error> {
+        let mut index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let mut ids: Vec<_> = index.documents().map(|(id, _)| id).collect();
+        ids.sort_unstable();
+        let mut expected = index.document_ids();
+        expected.sort_unstable();
+        assert_eq!(ids, expected);
+
+        index.store_documents(std::sync::Arc::new(crate::store::MemoryStorage::new()))?;
+        assert_eq!(index.documents().count() as u64, index.size());
+        assert!(index.documents().all(|(id, article)| article.id() == id));
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_by_title() -> Result<(), std::io::Error> {
+        let mut index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let found = index
+            .document_by_title("  wikipedia:   ANARCHISM ")
+            .map(|a| a.into_owned())
+            .unwrap();
+        assert_eq!(found.title(), "Wikipedia: Anarchism");
+        assert!(index.document_by_title("Wikipedia: Anarch").is_none());
+
+        index.remove_document(&found.id())?;
+        assert!(index.document_by_title("Wikipedia: Anarchism").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_metadata() -> Result<(), std::io::Error> {
+        let mut index = Index::with_schema(Schema::default().metadata("category"));
+        let philosophy = article("Anarchism", "A philosophy", "https://example.com/a")
+            .with_metadata("category", "Philosophy")
+            .with_metadata("author", "Goede");
+        let id = philosophy.id();
+        index.index_document(philosophy)?;
+        index.index_document(
+            article("Autism", "A condition", "https://example.com/b")
+                .with_metadata("category", "Medicine"),
+        )?;
+
+        assert_eq!(index.query_index("meta:category=Philosophy"), vec![id]);
+        assert!(index.query_index("meta:category=philosophy").is_empty());
+        // Metadata which is not indexed is only stored
+        assert!(index.query_index("meta:author=Goede").is_empty());
+        assert_eq!(index.document(&id).unwrap().metadata()["author"], "Goede");
+
+        let disk = crate::disk::DiskIndex::from_bytes(crate::disk::to_bytes(&index)?)?;
+        assert_eq!(disk.query_index("meta:category=Philosophy"), vec![id]);
+        assert_eq!(IndexReader::document(&disk, &id), index.document(&id));
+
+        index.remove_document(&id)?;
+        assert!(index.query_index("meta:category=Philosophy").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_phrase() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        index.index_document(article(
+            "Statue of Liberty",
+            "A colossal statue in New York",
+            "https://example.com/liberty",
+        ))?;
+        index.index_document(article(
+            "Liberty",
+            "The statue liberty and other things",
+            "https://example.com/other",
+        ))?;
+
+        assert_eq!(index.query_index("statue liberty").len(), 2);
+        let results = index.query_index("\"statue of liberty\"");
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            index.document(&results[0]).unwrap().title,
+            "Statue of Liberty"
+        );
+
+        // Stopwords only hold a place, any word will do
+        assert_eq!(index.query_index("\"statue the liberty\"").len(), 1);
+        // Phrases do not run from the title into the abstract
+        assert!(index.query_index("\"liberty colossal\"").is_empty());
+
+        // With slop other tokens can come between those of the phrase, but only in order
+        assert_eq!(index.query_index("\"statue liberty\"").len(), 1);
+        assert_eq!(index.query_index("\"statue liberty\"~1").len(), 2);
+        assert!(index.query_index("\"liberty statue\"~1").is_empty());
+        assert!(index.query_index("\"liberty colossal\"~100").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_documents() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let ids: HashSet<DocumentId> = index.document_ids().into_iter().collect();
+        let sample = index.random_documents(5);
+        assert_eq!(sample.len(), 5);
+        assert!(sample.iter().all(|id| ids.contains(id)));
+        assert_eq!(sample.iter().collect::<HashSet<_>>().len(), 5);
+
+        let everything: HashSet<DocumentId> =
+            index.random_documents(ids.len() + 10).into_iter().collect();
+        assert_eq!(everything, ids);
+        assert!(Index::new().random_documents(3).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_with_rerank() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let results = index.search("history", 5);
+        let last = results[results.len() - 1].0;
+
+        let reranked =
+            index.query_with_rerank("history", 5, &|article, score| match article.id() == last {
+                true => score * 1000.0,
+                false => score,
+            });
+        assert_eq!(reranked.len(), results.len());
+        assert_eq!(reranked[0].0, last);
+        assert_eq!(reranked[1..], results[..results.len() - 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_cache() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        index.enable_query_cache(std::num::NonZeroUsize::new(8).unwrap());
+        index.index_document(article(
+            "Statue of Liberty",
+            "A colossal statue in New York",
+            "https://example.com/liberty",
+        ))?;
+
+        assert_eq!(index.query_index("statue").len(), 1);
+        // Differently written queries which normalize the same share an entry
+        assert_eq!(index.query_index("Statues!").len(), 1);
+        assert_eq!(index.cache.as_ref().unwrap().len(), 1);
+
+        index.index_document(article(
+            "Statue of Zeus",
+            "At Olympia",
+            "https://example.com/zeus",
+        ))?;
+        assert!(index.cache.as_ref().unwrap().is_empty());
+        assert_eq!(index.query_index("statue").len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_finalize_stats() -> Result<(), std::io::Error> {
+        let mut index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        assert_eq!(index.stats.len(), index.index.len());
+
+        let docs = index.index.get("anarch").unwrap().len();
+        let stats = index.stats.get("anarch").unwrap();
+        assert_eq!(stats.document_frequency, docs as u64);
+        assert_eq!(stats.idf, (356.0 / docs as f64).log10());
+
+        index.index_document(article(
+            "Anarchism",
+            "Again",
+            "https://example.com/anarchism",
+        ))?;
+        assert!(index.stats.is_empty());
+        assert_eq!(
+            IndexReader::idf(&index, "anarch"),
+            (357.0 / (docs + 1) as f64).log10()
+        );
+
+        index.finalize();
+        assert_eq!(
+            IndexReader::idf(&index, "anarch"),
+            index.stats.get("anarch").unwrap().idf
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_open() -> Result<(), std::io::Error> {
+        let path = std::env::temp_dir().join(format!("goede-open-{}.idx", std::process::id()));
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        index.save(&path)?;
+        let reopened = Index::open(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(reopened.size(), index.size());
+        assert_eq!(reopened.index, index.index);
+        assert_eq!(reopened.freq, index.freq);
+        assert_eq!(reopened.positions, index.positions);
+        assert_eq!(reopened.fields, index.fields);
+        for id in index.document_ids() {
+            assert_eq!(reopened.document(&id), index.document(&id));
+        }
+        assert_eq!(
+            reopened.query_index("\"political philosophy\""),
+            index.query_index("\"political philosophy\"")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_to_storage() -> Result<(), std::io::Error> {
+        let storage = crate::store::MemoryStorage::new();
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        index.save_to(&storage, "simple")?;
+
+        let loaded = Index::load_from(&storage, "simple")?;
+        assert_eq!(loaded.size(), index.size());
+        assert_eq!(
+            loaded.query_index("anarchism"),
+            index.query_index("anarchism")
+        );
+        assert!(Index::load_from(&storage, "missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_store() -> Result<(), std::io::Error> {
+        let path = std::env::temp_dir().join(format!("goede-docs-{}.json", std::process::id()));
+        let mut index = Index::new();
+        index.index_document(article(
+            "Statue of Liberty",
+            "A colossal statue in New York",
+            "https://example.com/liberty",
+        ))?;
+        index.enable_document_store(&path)?;
+        index.index_document(article(
+            "Statue of Zeus",
+            "At Olympia",
+            "https://example.com/zeus",
+        ))?;
+
+        assert!(matches!(*index.documents, Documents::Stored { .. }));
+        assert_eq!(index.size(), 2);
+        let results = index.query_index("statue");
+        assert_eq!(results.len(), 2);
+        for id in results {
+            assert!(index.document(&id).unwrap().title.starts_with("Statue"));
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_document() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        index.enable_trigrams();
+        let liberty = article(
+            "Statue of Liberty",
+            "A colossal statue in New York",
+            "https://example.com/liberty",
+        );
+        let id = liberty.id();
+        index.index_document(liberty)?;
+        index.index_document(article(
+            "Statue of Zeus",
+            "At Olympia",
+            "https://example.com/zeus",
+        ))?;
+
+        assert!(index.remove_document(&id)?);
+        assert!(!index.remove_document(&id)?);
+        assert_eq!(index.size(), 1);
+        assert_eq!(index.query_index("statue").len(), 1);
+        assert!(index.query_index("liberty").is_empty());
+        assert!(index.query_index("contains:colossal").is_empty());
+        assert_eq!(index.query_index("domain:example.com").len(), 1);
+        assert!(!index.index.contains_key("liberti"));
+        assert!(index.freq.keys().all(|(doc, _)| *doc != id));
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize() -> Result<(), std::io::Error> {
+        let mut index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let (term, docs) = index
+            .index
+            .iter()
+            .max_by_key(|(_, docs)| docs.len())
+            .unwrap();
+        let term = term.clone();
+        let mut ids: Vec<DocumentId> = docs.iter().copied().collect();
+        ids.sort_unstable();
+        for id in ids[3..].iter() {
+            index.remove_document(id)?;
+        }
+        assert!(matches!(index.index[&term], Postings::Set(_)));
+        assert!(index.stats.is_empty());
+
+        index.optimize();
+        assert!(matches!(
+            index.index[&term],
+            Postings::Inline { len: 3, .. }
+        ));
+        assert_eq!(index.stats.len(), index.index.len());
+        let copied = Index::from_reader(&index)?;
+        assert_eq!(index.search(&term, 10), copied.search(&term, 10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_iter() -> Result<(), std::io::Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let ranked: Vec<(DocumentId, f64)> = index.query_iter("anarchism").by_score().collect();
+        assert_eq!(ranked, index.search("anarchism", usize::MAX));
+
+        let mut hits = index.query_iter("anarchism");
+        assert_eq!(hits.size_hint().0, ranked.len());
+        let first = hits.next().unwrap();
+        assert_eq!(hits.size_hint().0, ranked.len() - 1);
+        assert!(ranked.contains(&first));
+        assert!(hits.all(|(id, _)| id > first.0));
+        assert_eq!(index.query_iter("zzzzzz").count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let rain = article("Rain", "wet weather", "https://example.com/rain");
+        let id = rain.id();
+        index.index_document(rain)?;
+        index.finalize();
+
+        let searcher = index.snapshot();
+        assert!(Arc::ptr_eq(&index.index, &searcher.index().index));
+        assert!(Arc::ptr_eq(&index.stats, &searcher.index().stats));
+
+        index.index_document(article("Snow", "wet weather", "https://example.com/snow"))?;
+        index.remove_document(&id)?;
+        assert!(!Arc::ptr_eq(&index.index, &searcher.index().index));
+        assert_eq!(searcher.size(), 1);
+        assert_eq!(searcher.query_index("rain"), vec![id]);
+        assert!(index.query_index("rain").is_empty());
+        assert_eq!(index.query_index("weather").len(), 1);
         Ok(())
     }
 
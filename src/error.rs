@@ -0,0 +1,52 @@
+/**
+ * The error module contains the Error for everything which can go wrong reading a corpus, as
+ * opposed to the std::io::Error used for reading and writing indexes
+ *
+ * It converts into a std::io::Error, so it can be returned with `?` from anything which returns
+ * one of those.
+ */
+use std::io::ErrorKind;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("malformed XML at byte {position}: {source}")]
+    Xml {
+        position: usize,
+        #[source]
+        source: quick_xml::Error,
+    },
+    /**
+     * A `<doc>` entry which was malformed, when ingesting strictly, along with its byte offset
+     * in the decompressed XML
+     */
+    #[error("the <doc> at byte {position} titled `{title}` is malformed: {source}")]
+    Malformed {
+        position: usize,
+        title: String,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("the <doc> has no <url>")]
+    MissingUrl,
+    #[error("the <doc> was never closed")]
+    Unclosed,
+    #[error("`{url}` is not a valid url: {source}")]
+    Url {
+        url: String,
+        #[source]
+        source: url::ParseError,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => std::io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
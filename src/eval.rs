@@ -0,0 +1,341 @@
+/**
+ * The eval module measures how well queries are ranked against relevance judgements, so that a
+ * change to the ranking can be told apart from noise
+ *
+ * The judgements are read from a TREC style qrels file, with lines of
+ * `QUERY_ID ITERATION DOCUMENT RELEVANCE`, and the queries from lines of `QUERY_ID<TAB>QUERY`,
+ * or of just the query which is then its own id. Documents are given by their id, or by their url which the id is computed from.
+ */
+use crate::engine::{document_id, DocumentId};
+use std::collections::HashMap;
+use std::io::{BufRead, Error, ErrorKind};
+
+/**
+ * The relevance of the judged documents of each query, keyed by the query id
+ *
+ * Documents which were not judged, or judged with zero, are not relevant.
+ */
+pub type Qrels = HashMap<String, HashMap<DocumentId, u32>>;
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("line {}: {}", line + 1, message),
+    )
+}
+
+/**
+ * Parse a document given either by its id or by its url
+ */
+pub(crate) fn parse_document(document: &str) -> Option<DocumentId> {
+    document
+        .parse()
+        .ok()
+        .or_else(|| url::Url::parse(document).ok().map(|url| document_id(&url)))
+}
+
+/**
+ * Read a qrels file, skipping blank lines
+ */
+pub fn read_qrels<R: BufRead>(input: R) -> Result<Qrels, Error> {
+    let mut qrels = Qrels::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => continue,
+            [query, _, document, relevance] => {
+                let document = parse_document(document)
+                    .ok_or_else(|| invalid(number, "the document is neither an id nor a url"))?;
+                let relevance = relevance
+                    .parse()
+                    .map_err(|_| invalid(number, "the relevance is not a whole number"))?;
+                qrels
+                    .entry(query.to_string())
+                    .or_default()
+                    .insert(document, relevance);
+            }
+            _ => {
+                return Err(invalid(
+                    number,
+                    "expected QUERY_ID ITERATION DOCUMENT RELEVANCE",
+                ))
+            }
+        }
+    }
+    Ok(qrels)
+}
+
+/**
+ * Read the `(id, query)` pairs of a queries file, skipping blank lines
+ */
+pub fn read_queries<R: BufRead>(input: R) -> Result<Vec<(String, String)>, Error> {
+    let mut queries = vec![];
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, query) = line.split_once('\t').unwrap_or((&line, &line));
+        queries.push((id.trim().to_string(), query.trim().to_string()));
+    }
+    Ok(queries)
+}
+
+fn relevant(judgements: &HashMap<DocumentId, u32>, id: &DocumentId) -> bool {
+    judgements.get(id).copied().unwrap_or(0) > 0
+}
+
+/**
+ * The fraction of the first k results which are relevant
+ */
+pub fn precision_at(ranked: &[DocumentId], judgements: &HashMap<DocumentId, u32>, k: usize) -> f64 {
+    if k == 0 {
+        return 0.0;
+    }
+    let found = ranked
+        .iter()
+        .take(k)
+        .filter(|id| relevant(judgements, id))
+        .count();
+    found as f64 / k as f64
+}
+
+/**
+ * The fraction of the relevant documents which were found at all
+ */
+pub fn recall(ranked: &[DocumentId], judgements: &HashMap<DocumentId, u32>) -> f64 {
+    let total = judgements.values().filter(|r| **r > 0).count();
+    if total == 0 {
+        return 0.0;
+    }
+    let found = ranked.iter().filter(|id| relevant(judgements, id)).count();
+    found as f64 / total as f64
+}
+
+/**
+ * The mean of the precision at the rank of each relevant document, counting those which were
+ * not found as zero
+ */
+pub fn average_precision(ranked: &[DocumentId], judgements: &HashMap<DocumentId, u32>) -> f64 {
+    let total = judgements.values().filter(|r| **r > 0).count();
+    if total == 0 {
+        return 0.0;
+    }
+    let mut found = 0;
+    let mut sum = 0.0;
+    for (rank, id) in ranked.iter().enumerate() {
+        if relevant(judgements, id) {
+            found += 1;
+            sum += found as f64 / (rank + 1) as f64;
+        }
+    }
+    sum / total as f64
+}
+
+/**
+ * The discounted cumulative gain of the first k results, with a gain of `2^relevance - 1`,
+ * normalized by that of the ideal ranking
+ */
+pub fn ndcg_at(ranked: &[DocumentId], judgements: &HashMap<DocumentId, u32>, k: usize) -> f64 {
+    let dcg = |relevances: &mut dyn Iterator<Item = u32>| -> f64 {
+        relevances
+            .take(k)
+            .enumerate()
+            .map(|(rank, relevance)| {
+                (2f64.powi(relevance as i32) - 1.0) / ((rank + 2) as f64).log2()
+            })
+            .sum()
+    };
+    let mut ideal: Vec<u32> = judgements.values().copied().collect();
+    ideal.sort_unstable_by(|a, b| b.cmp(a));
+    let ideal = dcg(&mut ideal.into_iter());
+    if ideal == 0.0 {
+        return 0.0;
+    }
+    dcg(&mut ranked
+        .iter()
+        .map(|id| judgements.get(id).copied().unwrap_or(0)))
+        / ideal
+}
+
+/**
+ * The metrics of a single query
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryEvaluation {
+    pub id: String,
+    pub query: String,
+    pub precision: f64,
+    pub recall: f64,
+    pub average_precision: f64,
+    pub ndcg: f64,
+}
+
+/**
+ * The metrics of every judged query, with precision and nDCG taken at `k`
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Evaluation {
+    pub k: usize,
+    pub queries: Vec<QueryEvaluation>,
+}
+
+impl Evaluation {
+    fn mean<F: Fn(&QueryEvaluation) -> f64>(&self, metric: F) -> f64 {
+        match self.queries.len() {
+            0 => 0.0,
+            n => self.queries.iter().map(metric).sum::<f64>() / n as f64,
+        }
+    }
+
+    pub fn precision(&self) -> f64 {
+        self.mean(|q| q.precision)
+    }
+
+    pub fn recall(&self) -> f64 {
+        self.mean(|q| q.recall)
+    }
+
+    /**
+     * The mean average precision
+     */
+    pub fn map(&self) -> f64 {
+        self.mean(|q| q.average_precision)
+    }
+
+    pub fn ndcg(&self) -> f64 {
+        self.mean(|q| q.ndcg)
+    }
+}
+
+/**
+ * Rank the documents for every query which has judgements with the function, and measure how
+ * well they were ranked
+ */
+pub fn evaluate<F>(queries: &[(String, String)], qrels: &Qrels, k: usize, rank: F) -> Evaluation
+where
+    F: Fn(&str) -> Vec<DocumentId>,
+{
+    let queries = queries
+        .iter()
+        .filter_map(|(id, query)| {
+            let judgements = qrels.get(id)?;
+            let ranked = rank(query);
+            Some(QueryEvaluation {
+                id: id.clone(),
+                query: query.clone(),
+                precision: precision_at(&ranked, judgements, k),
+                recall: recall(&ranked, judgements),
+                average_precision: average_precision(&ranked, judgements),
+                ndcg: ndcg_at(&ranked, judgements, k),
+            })
+        })
+        .collect();
+    Evaluation { k, queries }
+}
+
+/**
+ * How differently two rankings of the same query order their first k documents
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RankDifference {
+    /**
+     * The number of documents which are in the first k of both rankings
+     */
+    pub overlap: usize,
+    /**
+     * The mean of how many places each of the overlapping documents moved
+     */
+    pub displacement: f64,
+    /**
+     * The overlapping documents which moved, along with their rank in each ranking, counting
+     * from one
+     */
+    pub moved: Vec<(DocumentId, usize, usize)>,
+}
+
+/**
+ * Compare the first k documents of two rankings
+ */
+pub fn rank_difference(a: &[DocumentId], b: &[DocumentId], k: usize) -> RankDifference {
+    let b: HashMap<DocumentId, usize> = b
+        .iter()
+        .take(k)
+        .enumerate()
+        .map(|(rank, id)| (*id, rank + 1))
+        .collect();
+    let mut overlap = 0;
+    let mut total = 0;
+    let mut moved = vec![];
+    for (rank, id) in a.iter().take(k).enumerate() {
+        if let Some(other) = b.get(id) {
+            overlap += 1;
+            total += (rank + 1).abs_diff(*other);
+            if rank + 1 != *other {
+                moved.push((*id, rank + 1, *other));
+            }
+        }
+    }
+    RankDifference {
+        overlap,
+        displacement: match overlap {
+            0 => 0.0,
+            n => total as f64 / n as f64,
+        },
+        moved,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let judgements: HashMap<DocumentId, u32> =
+            vec![(1, 1), (3, 2), (5, 1), (7, 0)].into_iter().collect();
+        let ranked = vec![1, 2, 3, 4, 7];
+        assert_eq!(precision_at(&ranked, &judgements, 2), 0.5);
+        assert!((recall(&ranked, &judgements) - 2.0 / 3.0).abs() < 1e-9);
+        assert!((average_precision(&ranked, &judgements) - (1.0 + 2.0 / 3.0) / 3.0).abs() < 1e-9);
+
+        let ideal = 3.0 + 1.0 / 3f64.log2() + 1.0 / 2.0;
+        let dcg = 1.0 + 3.0 / 2.0;
+        assert!((ndcg_at(&ranked, &judgements, 3) - dcg / ideal).abs() < 1e-9);
+        assert_eq!(ndcg_at(&[3, 1, 5], &judgements, 3), 1.0);
+    }
+
+    #[test]
+    fn test_evaluate() -> Result<(), Error> {
+        let qrels = read_qrels("q1 0 https://example.com/a 1\n\nq1 0 2 2\nq2 0 3 1\n".as_bytes())?;
+        let a = document_id(&url::Url::parse("https://example.com/a").unwrap());
+        assert_eq!(qrels["q1"][&a], 1);
+        assert_eq!(qrels["q1"][&2], 2);
+        assert!(read_qrels("q1 0 nothing 1".as_bytes()).is_err());
+
+        let queries = read_queries("q1\tfirst query\nq3\tunjudged\n".as_bytes())?;
+        let evaluation = evaluate(&queries, &qrels, 2, |_| vec![2, a]);
+        assert_eq!(evaluation.queries.len(), 1);
+        assert_eq!(evaluation.queries[0].query, "first query");
+        assert_eq!(evaluation.precision(), 1.0);
+        assert_eq!(evaluation.map(), 1.0);
+        assert_eq!(evaluation.ndcg(), 1.0);
+
+        let queries = read_queries("q1\tfirst\nsecond query\n".as_bytes())?;
+        assert_eq!(
+            queries[1],
+            ("second query".to_string(), "second query".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rank_difference() {
+        let difference = rank_difference(&[1, 2, 3, 4], &[3, 2, 5, 1], 3);
+        assert_eq!(difference.overlap, 2);
+        assert_eq!(difference.moved, vec![(3, 3, 1)]);
+        assert_eq!(difference.displacement, 1.0);
+        assert_eq!(rank_difference(&[], &[1], 3), RankDifference::default());
+    }
+}
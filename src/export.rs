@@ -0,0 +1,586 @@
+/**
+ * The export module writes the contents of an index out in formats which other tools can read,
+ * for analysing a corpus somewhere other than goedesearch
+ */
+use crate::config::AnalysisConfig;
+use crate::engine::{Article, DocumentId, Index, IndexReader};
+use crate::schema::{Field, Schema};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Error, Read, Write};
+
+/**
+ * What was written by an export
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub documents: u64,
+    /**
+     * The number of (document, term) pairs
+     */
+    pub weights: u64,
+}
+
+/**
+ * A single (document, term) pair of the document-term matrix
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Weight {
+    pub document: DocumentId,
+    pub term: String,
+    pub frequency: f64,
+    /**
+     * The frequency times the idf of the term
+     */
+    pub tfidf: f64,
+}
+
+/**
+ * Call the function with every non-zero entry of the reader's document-term matrix, term by
+ * term in order
+ */
+pub fn each_weight<R, F>(reader: &R, mut each: F) -> Result<(), Error>
+where
+    R: IndexReader + ?Sized,
+    F: FnMut(Weight) -> Result<(), Error>,
+{
+    let mut terms = reader.terms();
+    terms.sort_unstable();
+    for term in terms {
+        let idf = reader.idf(&term);
+        for document in reader.posting_list(&term) {
+            if let Some(frequency) = reader.term_frequency(document, &term) {
+                each(Weight {
+                    document,
+                    term: term.clone(),
+                    frequency,
+                    tfidf: frequency * idf,
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/**
+ * The occurrences of a term in one document
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Posting {
+    pub document: DocumentId,
+    pub frequency: f64,
+    /**
+     * The positions of the term within the document's full text
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub positions: Vec<usize>,
+}
+
+/**
+ * The whole contents of an index in a form which reads naturally as JSON, for debugging, diffing
+ * two indexes or handing them to other tools
+ *
+ * The documents and postings are sorted, by document id and by term, so that exporting the same
+ * index twice gives the same JSON.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Dump {
+    /**
+     * The analysis configuration of the index, None when its Schema was assembled in code
+     */
+    pub analysis: Option<AnalysisConfig>,
+    pub documents: Vec<Article>,
+    /**
+     * The postings of every full text term, sorted by document
+     */
+    pub terms: BTreeMap<String, Vec<Posting>>,
+    /**
+     * The documents containing each term of the individually searchable fields, keyed by the
+     * name of the field
+     */
+    #[serde(default)]
+    pub fields: BTreeMap<String, BTreeMap<String, Vec<DocumentId>>>,
+    #[serde(skip)]
+    pub(crate) schema: Schema,
+}
+
+impl Dump {
+    /**
+     * Copy the entire contents of the IndexReader
+     */
+    pub fn from_reader<R: IndexReader + ?Sized>(reader: &R) -> Self {
+        let mut ids = reader.document_ids();
+        ids.sort_unstable();
+        let documents = ids
+            .iter()
+            .filter_map(|id| reader.document(id).map(|article| article.into_owned()))
+            .collect();
+
+        let mut terms = BTreeMap::new();
+        for term in reader.terms() {
+            let postings = reader
+                .posting_list(&term)
+                .map(|document| Posting {
+                    document,
+                    frequency: reader.term_frequency(document, &term).unwrap_or(0.0),
+                    positions: reader
+                        .positions(document, &term)
+                        .map(|p| p.into_owned())
+                        .unwrap_or_default(),
+                })
+                .collect();
+            terms.insert(term, postings);
+        }
+
+        let mut fields = BTreeMap::new();
+        for field in Field::ALL {
+            let mut postings = BTreeMap::new();
+            for term in reader.field_terms(*field) {
+                if let Some(docs) = reader.field_postings(*field, &term) {
+                    let mut docs: Vec<DocumentId> = docs.iter().copied().collect();
+                    docs.sort_unstable();
+                    postings.insert(term, docs);
+                }
+            }
+            if !postings.is_empty() {
+                fields.insert(field.name().to_string(), postings);
+            }
+        }
+
+        Self {
+            analysis: reader.schema().config().cloned(),
+            documents,
+            terms,
+            fields,
+            schema: reader.schema().clone(),
+        }
+    }
+
+    /**
+     * Read a dump written by `to_json`, rebuilding the Schema from its analysis configuration
+     */
+    pub fn from_json<R: Read>(input: R) -> Result<Self, Error> {
+        let dump: Self = serde_json::from_reader(input)?;
+        dump.validated()
+    }
+
+    /**
+     * Check and sort a dump which was read in from elsewhere, and rebuild its Schema
+     */
+    pub(crate) fn validated(mut self) -> Result<Self, Error> {
+        self.schema = match &self.analysis {
+            Some(config) => config.schema()?,
+            None => Schema::default(),
+        };
+        for name in self.fields.keys() {
+            if name.parse::<Field>().is_err() {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("the dump has postings for an unknown field {:?}", name),
+                ));
+            }
+        }
+        self.documents.sort_unstable_by_key(|article| article.id());
+        for postings in self.terms.values_mut() {
+            postings.sort_unstable_by_key(|posting| posting.document);
+        }
+        Ok(self)
+    }
+
+    pub fn to_json<W: Write>(&self, output: W) -> Result<(), Error> {
+        serde_json::to_writer_pretty(output, self)?;
+        Ok(())
+    }
+
+    /**
+     * Load the dump into a new in-memory Index
+     */
+    pub fn into_index(self) -> Result<Index, Error> {
+        Index::from_reader(&self)
+    }
+
+    fn posting(&self, id: DocumentId, term: &str) -> Option<&Posting> {
+        let postings = self.terms.get(term)?;
+        postings
+            .binary_search_by_key(&id, |posting| posting.document)
+            .ok()
+            .map(|i| &postings[i])
+    }
+}
+
+impl IndexReader for Dump {
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn size(&self) -> u64 {
+        self.documents.len() as u64
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.documents.iter().map(|article| article.id()).collect()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.documents
+            .binary_search_by_key(id, |article| article.id())
+            .ok()
+            .map(|i| Cow::Borrowed(&self.documents[i]))
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.terms.keys().cloned().collect()
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.terms
+            .get(term)
+            .map(|postings| Cow::Owned(postings.iter().map(|p| p.document).collect()))
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.fields
+            .get(field.name())
+            .map(|terms| terms.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.fields
+            .get(field.name())?
+            .get(term)
+            .map(|docs| Cow::Owned(docs.iter().copied().collect()))
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.posting(id, term).map(|posting| posting.frequency)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.posting(id, term)
+            .map(|posting| Cow::Borrowed(posting.positions.as_slice()))
+    }
+}
+
+/**
+ * Write the entire contents of the reader as JSON
+ */
+pub fn to_json<R: IndexReader + ?Sized, W: Write>(reader: &R, output: W) -> Result<(), Error> {
+    Dump::from_reader(reader).to_json(output)
+}
+
+/**
+ * Load an index from JSON written by `to_json`
+ */
+pub fn from_json<R: Read>(input: R) -> Result<Index, Error> {
+    Dump::from_json(input)?.into_index()
+}
+
+#[cfg(feature = "parquet")]
+pub use self::columnar::to_parquet;
+
+#[cfg(feature = "parquet")]
+mod columnar {
+    use super::*;
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt64Array};
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /**
+     * How many rows are buffered before they are written out as a record batch
+     */
+    const BATCH_ROWS: usize = 64 * 1024;
+
+    /**
+     * The reader's documents in order of their ids
+     */
+    fn sorted_documents<R: IndexReader + ?Sized>(
+        reader: &R,
+    ) -> impl Iterator<Item = (DocumentId, Article)> + '_ {
+        let mut ids = reader.document_ids();
+        ids.sort_unstable();
+        ids.into_iter().filter_map(move |id| {
+            reader
+                .document(&id)
+                .map(|article| (id, article.into_owned()))
+        })
+    }
+
+    fn export_error(e: impl std::fmt::Display) -> Error {
+        Error::other(format!("failed to write parquet: {}", e))
+    }
+
+    fn write_batch(
+        writer: &mut ArrowWriter<File>,
+        schema: &Arc<Schema>,
+        columns: Vec<ArrayRef>,
+    ) -> Result<(), Error> {
+        let batch = RecordBatch::try_new(schema.clone(), columns).map_err(export_error)?;
+        writer.write(&batch).map_err(export_error)
+    }
+
+    /**
+     * Write the reader's documents to `documents.parquet` and its document-term weights to
+     * `weights.parquet` in the directory
+     *
+     * The metadata of each document is written as a JSON object, since every document can have
+     * different keys.
+     */
+    pub fn to_parquet<R: IndexReader + ?Sized>(reader: &R, dir: &Path) -> Result<Summary, Error> {
+        std::fs::create_dir_all(dir)?;
+        let mut summary = Summary::default();
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::UInt64, false),
+            Field::new("title", DataType::Utf8, false),
+            Field::new("url", DataType::Utf8, true),
+            Field::new("abstract", DataType::Utf8, false),
+            Field::new("metadata", DataType::Utf8, false),
+        ]));
+        let file = File::create(dir.join("documents.parquet"))?;
+        let mut writer = ArrowWriter::try_new(file, schema.clone(), None).map_err(export_error)?;
+        let mut documents = sorted_documents(reader).peekable();
+        while documents.peek().is_some() {
+            let batch: Vec<(DocumentId, Article)> = documents.by_ref().take(BATCH_ROWS).collect();
+            let mut metadata = vec![];
+            for (_, article) in batch.iter() {
+                metadata.push(serde_json::to_string(article.metadata())?);
+            }
+            summary.documents += batch.len() as u64;
+            write_batch(
+                &mut writer,
+                &schema,
+                vec![
+                    Arc::new(UInt64Array::from_iter_values(
+                        batch.iter().map(|(id, _)| *id),
+                    )),
+                    Arc::new(StringArray::from_iter_values(
+                        batch.iter().map(|(_, a)| a.title()),
+                    )),
+                    Arc::new(
+                        batch
+                            .iter()
+                            .map(|(_, a)| a.url().map(|url| url.as_str()))
+                            .collect::<StringArray>(),
+                    ),
+                    Arc::new(StringArray::from_iter_values(
+                        batch.iter().map(|(_, a)| a.abstract_text()),
+                    )),
+                    Arc::new(StringArray::from(metadata)),
+                ],
+            )?;
+        }
+        writer.close().map_err(export_error)?;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("document", DataType::UInt64, false),
+            Field::new("term", DataType::Utf8, false),
+            Field::new("frequency", DataType::Float64, false),
+            Field::new("tfidf", DataType::Float64, false),
+        ]));
+        let file = File::create(dir.join("weights.parquet"))?;
+        let mut writer = ArrowWriter::try_new(file, schema.clone(), None).map_err(export_error)?;
+        let mut batch: Vec<Weight> = Vec::with_capacity(BATCH_ROWS);
+        let flush = |writer: &mut ArrowWriter<File>, batch: &mut Vec<Weight>| {
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(UInt64Array::from_iter_values(
+                    batch.iter().map(|w| w.document),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    batch.iter().map(|w| w.term.as_str()),
+                )),
+                Arc::new(Float64Array::from_iter_values(
+                    batch.iter().map(|w| w.frequency),
+                )),
+                Arc::new(Float64Array::from_iter_values(
+                    batch.iter().map(|w| w.tfidf),
+                )),
+            ];
+            batch.clear();
+            write_batch(writer, &schema, columns)
+        };
+        each_weight(reader, |weight| {
+            summary.weights += 1;
+            batch.push(weight);
+            if batch.len() == BATCH_ROWS {
+                flush(&mut writer, &mut batch)?;
+            }
+            Ok(())
+        })?;
+        if !batch.is_empty() {
+            flush(&mut writer, &mut batch)?;
+        }
+        writer.close().map_err(export_error)?;
+        Ok(summary)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use std::path::PathBuf;
+
+        #[test]
+        fn test_export_parquet() -> Result<(), Error> {
+            let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+            let dir = std::env::temp_dir().join(format!("goede-parquet-{}", std::process::id()));
+            let summary = to_parquet(&index, &dir)?;
+            assert_eq!(summary.documents, 356);
+
+            let rows = |name: &str| -> Result<i64, Error> {
+                let reader =
+                    SerializedFileReader::new(File::open(dir.join(name))?).map_err(export_error)?;
+                Ok(reader.metadata().file_metadata().num_rows())
+            };
+            assert_eq!(rows("documents.parquet")?, 356);
+            assert_eq!(rows("weights.parquet")? as u64, summary.weights);
+            std::fs::remove_dir_all(&dir)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::to_sqlite;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use rusqlite::{params, Connection};
+    use std::path::Path;
+
+    fn export_error(e: rusqlite::Error) -> Error {
+        Error::other(format!("failed to write the SQLite database: {}", e))
+    }
+
+    /**
+     * Write the reader's documents into a new SQLite database at the path, with a `documents`
+     * table and an FTS5 table named `search` over their titles and abstracts, so that they can
+     * be searched anywhere SQLite is, e.g.
+     * `SELECT title, url FROM search WHERE search MATCH 'anarchism' ORDER BY rank`
+     *
+     * SQLite integers are signed, so the ids are stored as the i64 with the same bits.
+     */
+    pub fn to_sqlite<R: IndexReader + ?Sized>(reader: &R, path: &Path) -> Result<Summary, Error> {
+        if path.exists() {
+            return Err(Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", path),
+            ));
+        }
+        let mut connection = Connection::open(path).map_err(export_error)?;
+        let transaction = connection.transaction().map_err(export_error)?;
+        transaction
+            .execute_batch(
+                "CREATE TABLE documents (
+                    id INTEGER PRIMARY KEY,
+                    title TEXT NOT NULL,
+                    url TEXT,
+                    abstract TEXT NOT NULL,
+                    metadata TEXT NOT NULL
+                );
+                CREATE VIRTUAL TABLE search USING fts5(
+                    title, url UNINDEXED, abstract,
+                    content = 'documents', content_rowid = 'id',
+                    tokenize = 'porter unicode61'
+                );",
+            )
+            .map_err(export_error)?;
+
+        let mut summary = Summary::default();
+        {
+            let mut insert = transaction
+                .prepare(
+                    "INSERT INTO documents (id, title, url, abstract, metadata)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )
+                .map_err(export_error)?;
+            let mut ids = reader.document_ids();
+            ids.sort_unstable();
+            for id in ids {
+                if let Some(article) = reader.document(&id) {
+                    insert
+                        .execute(params![
+                            id as i64,
+                            article.title(),
+                            article.url().map(|url| url.as_str()),
+                            article.abstract_text(),
+                            serde_json::to_string(article.metadata())?,
+                        ])
+                        .map_err(export_error)?;
+                    summary.documents += 1;
+                }
+            }
+        }
+        transaction
+            .execute("INSERT INTO search (search) VALUES ('rebuild')", [])
+            .map_err(export_error)?;
+        transaction.commit().map_err(export_error)?;
+        Ok(summary)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::path::PathBuf;
+
+        #[test]
+        fn test_export_sqlite() -> Result<(), Error> {
+            let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+            let path = std::env::temp_dir().join(format!("goede-{}.db", std::process::id()));
+            let summary = to_sqlite(&index, &path)?;
+            assert_eq!(summary.documents, 356);
+            assert!(to_sqlite(&index, &path).is_err());
+
+            let connection = Connection::open(&path).map_err(export_error)?;
+            let title: String = connection
+                .query_row(
+                    "SELECT title FROM search WHERE search MATCH 'anarchism' ORDER BY rank",
+                    [],
+                    |row| row.get(0),
+                )
+                .map_err(export_error)?;
+            std::fs::remove_file(&path)?;
+            assert!(title.contains("Anarchism"));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_json_round_trip() -> Result<(), Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let mut json = vec![];
+        to_json(&index, &mut json)?;
+        let imported = from_json(json.as_slice())?;
+
+        assert_eq!(imported.size(), index.size());
+        assert_eq!(
+            imported.query_index("anarchism"),
+            index.query_index("anarchism")
+        );
+        assert_eq!(
+            imported.query_index("\"political philosophy\""),
+            index.query_index("\"political philosophy\"")
+        );
+        assert_eq!(
+            imported.query_index("title:history"),
+            index.query_index("title:history")
+        );
+
+        // Exporting what was imported gives back the same JSON
+        let mut again = vec![];
+        to_json(&imported, &mut again)?;
+        assert!(json == again);
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+/**
+ * The ffi module exposes the engine through `extern "C"` functions, so that C and C++
+ * applications can embed it by linking against the cdylib and including `include/goedesearch.h`
+ *
+ * The header is generated from this module with `cbindgen --output include/goedesearch.h`, which
+ * has to be rerun whenever these functions change.
+ *
+ * Functions which can fail return NULL, after which `goede_last_error()` describes what went
+ * wrong. Strings returned by the library are owned by the caller and released with
+ * `goede_string_free()`, indexes with `goede_index_free()`.
+ */
+use crate::disk::DiskIndex;
+use crate::engine::{Index, IndexReader};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::path::PathBuf;
+
+/**
+ * An index opened through the C interface, which C only ever sees a pointer to
+ */
+pub struct GoedeIndex {
+    reader: Box<dyn IndexReader>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/**
+ * The string behind the pointer, or None after recording why it could not be read
+ */
+unsafe fn string<'a>(s: *const c_char, name: &str) -> Option<&'a str> {
+    if s.is_null() {
+        set_error(format!("{} is NULL", name));
+        return None;
+    }
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => Some(s),
+        Err(e) => {
+            set_error(format!("{} is not UTF-8: {}", name, e));
+            None
+        }
+    }
+}
+
+fn boxed<R: IndexReader + 'static>(result: Result<R, impl std::fmt::Display>) -> *mut GoedeIndex {
+    match result {
+        Ok(reader) => Box::into_raw(Box::new(GoedeIndex {
+            reader: Box::new(reader),
+        })),
+        Err(e) => {
+            set_error(e.to_string());
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/**
+ * Build an index in memory from the Wikipedia XML dump at the path
+ *
+ * # Safety
+ *
+ * `path` must be NULL or point to a NUL terminated string.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn goede_index_create(path: *const c_char) -> *mut GoedeIndex {
+    match string(path, "path") {
+        Some(path) => boxed(Index::from_file(&PathBuf::from(path))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/**
+ * Open an index file persisted by goedesearch, which is queried in place
+ *
+ * # Safety
+ *
+ * `path` must be NULL or point to a NUL terminated string.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn goede_index_load(path: *const c_char) -> *mut GoedeIndex {
+    match string(path, "path") {
+        Some(path) => boxed(DiskIndex::open(&PathBuf::from(path))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/**
+ * The number of documents in the index
+ *
+ * # Safety
+ *
+ * `index` must have been returned by this library and not freed yet.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn goede_index_size(index: *const GoedeIndex) -> u64 {
+    index.as_ref().map(|index| index.reader.size()).unwrap_or(0)
+}
+
+/**
+ * Search the index for the `limit` highest scoring documents, returned as the same JSON as the
+ * search server responds with
+ *
+ * # Safety
+ *
+ * `index` must have been returned by this library and not freed yet, and `query` must be NULL or
+ * point to a NUL terminated string.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn goede_search(
+    index: *const GoedeIndex,
+    query: *const c_char,
+    limit: usize,
+) -> *mut c_char {
+    let index = match index.as_ref() {
+        Some(index) => index,
+        None => {
+            set_error("index is NULL".to_string());
+            return std::ptr::null_mut();
+        }
+    };
+    let query = match string(query, "query") {
+        Some(query) => query,
+        None => return std::ptr::null_mut(),
+    };
+    let response = crate::server::search(index.reader.as_ref(), query, limit);
+    match serde_json::to_string(&response) {
+        Ok(json) => CString::new(json).unwrap_or_default().into_raw(),
+        Err(e) => {
+            set_error(e.to_string());
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/**
+ * What went wrong in the last call on this thread which returned NULL, or NULL if nothing has
+ *
+ * The string belongs to the library and stays valid until the next failing call on the thread.
+ */
+#[no_mangle]
+pub extern "C" fn goede_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|message| message.as_ptr())
+            .unwrap_or(std::ptr::null())
+    })
+}
+
+/**
+ * Release a string returned by the library
+ *
+ * # Safety
+ *
+ * `s` must be NULL or have been returned by this library, and must not be used afterwards.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn goede_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/**
+ * Release an index
+ *
+ * # Safety
+ *
+ * `index` must be NULL or have been returned by this library, and must not be used afterwards.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn goede_index_free(index: *mut GoedeIndex) {
+    if !index.is_null() {
+        drop(Box::from_raw(index));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::SearchResponse;
+
+    #[test]
+    fn test_search_through_ffi() {
+        unsafe {
+            let path = CString::new("data/simple.xml.gz").unwrap();
+            let index = goede_index_create(path.as_ptr());
+            assert!(!index.is_null());
+            assert_eq!(goede_index_size(index), 356);
+
+            let query = CString::new("anarchism").unwrap();
+            let json = goede_search(index, query.as_ptr(), 3);
+            let response: SearchResponse =
+                serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+            let article = response.hits[0].article.as_ref().unwrap();
+            assert!(article.title().contains("Anarchism"));
+            goede_string_free(json);
+            goede_index_free(index);
+
+            let missing = CString::new("data/missing.idx").unwrap();
+            assert!(goede_index_load(missing.as_ptr()).is_null());
+            assert!(!goede_last_error().is_null());
+        }
+    }
+}
@@ -1,9 +1,16 @@
 /**
  * The filters module contains all the simple little functions for filtering english text into
  * usable tokens for the search index
+ *
+ * Text is turned into tokens by an Analyzer, which is a Tokenizer followed by a chain of
+ * TokenFilters, each of which can transform, drop, or add tokens.
  */
+use rust_stemmers::{Algorithm, Stemmer};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 
-const STOPWORDS: &'static [&'static str] = &[
+const STOPWORDS: &[&str] = &[
     "the",
     "be",
     "to",
@@ -32,28 +39,628 @@ const STOPWORDS: &'static [&'static str] = &[
     "wikipedia",
 ];
 
-pub fn filter(text: &str) -> Vec<String> {
-    use rust_stemmers::{Algorithm, Stemmer};
+/**
+ * A single token of text along with its position in the original token stream
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub position: usize,
+}
+
+impl Token {
+    pub fn new(text: &str, position: usize) -> Self {
+        Self {
+            text: text.to_string(),
+            position,
+        }
+    }
+}
+
+/**
+ * A Tokenizer is the first stage of an Analyzer, splitting raw text into tokens
+ */
+pub trait Tokenizer: Send + Sync {
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+}
 
-    let mut stems = vec![];
-    // Create a stemmer for the english language
-    let en_stemmer = Stemmer::create(Algorithm::English);
+/**
+ * A TokenFilter is a single stage in an Analyzer's chain, receiving the tokens from the previous
+ * stage and returning the tokens for the next one
+ */
+pub trait TokenFilter: Send + Sync {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token>;
+}
+
+/**
+ * Any closure which splits text into strings can be used as a Tokenizer, each string being
+ * given the next position
+ */
+impl<F> Tokenizer for F
+where
+    F: Fn(&str) -> Vec<String> + Send + Sync,
+{
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        self(text)
+            .iter()
+            .enumerate()
+            .map(|(position, text)| Token::new(text, position))
+            .collect()
+    }
+}
 
-    for token in text.split(' ') {
-        let token: String = token.to_lowercase()
-            .chars()
-            .filter(|ch| ! ch.is_ascii_punctuation())
-            .collect();
+/**
+ * Any closure which maps a single token's text to its replacement can be used as a
+ * TokenFilter, returning None drops the token from the stream entirely
+ */
+impl<F> TokenFilter for F
+where
+    F: Fn(&str) -> Option<String> + Send + Sync,
+{
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token> {
+        tokens
+            .into_iter()
+            .filter_map(|token| {
+                self(&token.text).map(|text| Token {
+                    text,
+                    position: token.position,
+                })
+            })
+            .collect()
+    }
+}
 
-        // only stem the word if it doesn't match a stopword
-        if ! STOPWORDS.contains(&token.as_str()) {
-            stems.push(en_stemmer.stem(&token).to_string());
+/**
+ * Split text on every space character, skipping over the empty pieces between repeated spaces
+ */
+#[derive(Clone, Debug, Default)]
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        text.split(' ')
+            .enumerate()
+            .filter(|(_, text)| !text.is_empty())
+            .map(|(position, text)| Token::new(text, position))
+            .collect()
+    }
+}
+
+/**
+ * Treat the whole of the (trimmed) text as a single token, for values like urls which should
+ * only ever match exactly
+ */
+#[derive(Clone, Debug, Default)]
+pub struct KeywordTokenizer;
+
+impl Tokenizer for KeywordTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let text = text.trim();
+        if text.is_empty() {
+            vec![]
+        } else {
+            vec![Token::new(text, 0)]
         }
     }
-    stems
+}
+
+/**
+ * Split text into words on the unicode word boundaries, which copes with punctuation and
+ * scripts that are not separated by spaces far better than the WhitespaceTokenizer
+ */
+#[derive(Clone, Debug, Default)]
+pub struct UnicodeTokenizer;
+
+impl Tokenizer for UnicodeTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        use unicode_segmentation::UnicodeSegmentation;
+
+        text.unicode_words()
+            .enumerate()
+            .map(|(position, text)| Token::new(text, position))
+            .collect()
+    }
+}
+
+/**
+ * Lowercase every token
+ */
+#[derive(Clone, Debug, Default)]
+pub struct LowercaseFilter;
+
+impl TokenFilter for LowercaseFilter {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token> {
+        tokens
+            .into_iter()
+            .map(|mut token| {
+                token.text = token.text.to_lowercase();
+                token
+            })
+            .collect()
+    }
+}
+
+/**
+ * Strip all ASCII punctuation out of every token, dropping tokens which were only punctuation
+ */
+#[derive(Clone, Debug, Default)]
+pub struct PunctuationFilter;
+
+impl TokenFilter for PunctuationFilter {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token> {
+        tokens
+            .into_iter()
+            .map(|mut token| {
+                token.text = token
+                    .text
+                    .chars()
+                    .filter(|ch| !ch.is_ascii_punctuation())
+                    .collect();
+                token
+            })
+            .filter(|token| !token.text.is_empty())
+            .collect()
+    }
+}
+
+/**
+ * Fold accented characters down to their unaccented ASCII equivalents, e.g. `café` to `cafe`
+ */
+#[derive(Clone, Debug, Default)]
+pub struct AsciiFoldingFilter;
+
+impl TokenFilter for AsciiFoldingFilter {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token> {
+        use unicode_normalization::char::is_combining_mark;
+        use unicode_normalization::UnicodeNormalization;
+
+        tokens
+            .into_iter()
+            .map(|mut token| {
+                token.text = token
+                    .text
+                    .nfkd()
+                    .filter(|ch| !is_combining_mark(*ch))
+                    .collect();
+                token
+            })
+            .collect()
+    }
+}
+
+/**
+ * Drop any token whose length in characters is outside of the given bounds
+ */
+#[derive(Clone, Debug)]
+pub struct LengthFilter {
+    min: usize,
+    max: usize,
+}
+
+impl LengthFilter {
+    pub fn new(min: usize, max: usize) -> Self {
+        Self { min, max }
+    }
+}
+
+impl TokenFilter for LengthFilter {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token> {
+        tokens
+            .into_iter()
+            .filter(|token| {
+                let len = token.text.chars().count();
+                len >= self.min && len <= self.max
+            })
+            .collect()
+    }
+}
+
+/**
+ * Drop any token which matches a stopword
+ */
+#[derive(Clone, Debug)]
+pub struct StopwordFilter {
+    stopwords: HashSet<String>,
+}
+
+impl StopwordFilter {
+    pub fn new(stopwords: &[&str]) -> Self {
+        Self {
+            stopwords: stopwords.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /**
+     * Load the stopwords from a file containing one stopword per line, blank lines and lines
+     * starting with `#` are ignored
+     */
+    pub fn from_file(path: &Path) -> Result<Self, std::io::Error> {
+        Ok(Self::from_words(read_stopwords(path)?))
+    }
+
+    pub fn from_words<I: IntoIterator<Item = String>>(words: I) -> Self {
+        Self {
+            stopwords: words.into_iter().collect(),
+        }
+    }
+}
+
+/**
+ * Read the stopwords listed in a file the way `StopwordFilter::from_file()` does
+ */
+pub fn read_stopwords(path: &Path) -> Result<Vec<String>, std::io::Error> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+impl Default for StopwordFilter {
+    /**
+     * The default StopwordFilter uses the built-in list of english stopwords
+     */
+    fn default() -> Self {
+        Self::new(STOPWORDS)
+    }
+}
+
+impl TokenFilter for StopwordFilter {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token> {
+        tokens
+            .into_iter()
+            .filter(|token| !self.stopwords.contains(&token.text))
+            .collect()
+    }
+}
+
+/**
+ * The most stems a single StemmerFilter will remember, beyond this tokens are still stemmed but
+ * the results are no longer cached
+ */
+const STEM_CACHE_LIMIT: usize = 1_000_000;
+
+/**
+ * Reduce every token to its stem with the snowball stemmer for the given language
+ *
+ * Corpora repeat the same words over and over, so the stems are cached for as long as the
+ * filter lives, which for an Analyzer owned by an Index is the whole indexing run.
+ */
+pub struct StemmerFilter {
+    stemmer: Stemmer,
+    cache: RwLock<HashMap<String, String>>,
+}
+
+impl StemmerFilter {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            stemmer: Stemmer::create(algorithm),
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Stem the word, using the cached stem if it has been seen before
+     */
+    fn stem(&self, word: &str) -> String {
+        if let Ok(cache) = self.cache.read() {
+            if let Some(stem) = cache.get(word) {
+                return stem.clone();
+            }
+        }
+
+        let stem = self.stemmer.stem(word).to_string();
+        if let Ok(mut cache) = self.cache.write() {
+            if cache.len() < STEM_CACHE_LIMIT {
+                cache.insert(word.to_string(), stem.clone());
+            }
+        }
+        stem
+    }
+}
+
+impl Default for StemmerFilter {
+    fn default() -> Self {
+        Self::new(Algorithm::English)
+    }
+}
+
+impl TokenFilter for StemmerFilter {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token> {
+        tokens
+            .into_iter()
+            .map(|mut token| {
+                token.text = self.stem(&token.text);
+                token
+            })
+            .collect()
+    }
+}
+
+/**
+ * An Analyzer turns text into the tokens which are stored in, or looked up from, the index
+ *
+ * The same Analyzer must be used at index and query time for the results to make sense.
+ */
+#[derive(Clone)]
+pub struct Analyzer {
+    tokenizer: Arc<dyn Tokenizer>,
+    filters: Vec<Arc<dyn TokenFilter>>,
+}
+
+impl std::fmt::Debug for Analyzer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Analyzer {{ filters: {} }}", self.filters.len())
+    }
+}
+
+impl Default for Analyzer {
+    /**
+     * The default Analyzer splits on whitespace, lowercases, strips punctuation, drops english
+     * stopwords and then stems what is left over
+     */
+    fn default() -> Self {
+        AnalyzerBuilder::standard().build()
+    }
+}
+
+impl Analyzer {
+    pub fn builder() -> AnalyzerBuilder {
+        AnalyzerBuilder::default()
+    }
+
+    /**
+     * An Analyzer which does no tokenization or filtering at all
+     */
+    pub fn keyword() -> Self {
+        Self::builder().tokenizer(KeywordTokenizer).build()
+    }
+
+    /**
+     * Run the text through the tokenizer and every filter in order
+     */
+    pub fn analyze(&self, text: &str) -> Vec<Token> {
+        let _span = tracing::trace_span!("tokenize").entered();
+        self.filters
+            .iter()
+            .fold(self.tokenizer.tokenize(text), |tokens, filter| {
+                filter.filter(tokens)
+            })
+    }
+
+    /**
+     * Analyze the text and return just the text of the resulting tokens
+     */
+    pub fn terms(&self, text: &str) -> Vec<String> {
+        self.analyze(text).into_iter().map(|t| t.text).collect()
+    }
+}
+
+/**
+ * Builder for assembling an Analyzer out of a Tokenizer and any number of TokenFilters
+ *
+ * An empty builder will use the WhitespaceTokenizer and no filters
+ */
+#[derive(Default)]
+pub struct AnalyzerBuilder {
+    tokenizer: Option<Arc<dyn Tokenizer>>,
+    filters: Vec<Arc<dyn TokenFilter>>,
+}
+
+impl AnalyzerBuilder {
+    /**
+     * Start from the stages of the default english pipeline
+     */
+    pub fn standard() -> Self {
+        Self::default()
+            .tokenizer(WhitespaceTokenizer)
+            .filter(LowercaseFilter)
+            .filter(PunctuationFilter)
+            .filter(StopwordFilter::default())
+            .filter(StemmerFilter::default())
+    }
+
+    /**
+     * Replace the tokenizer
+     */
+    pub fn tokenizer<T: Tokenizer + 'static>(mut self, tokenizer: T) -> Self {
+        self.tokenizer = Some(Arc::new(tokenizer));
+        self
+    }
+
+    /**
+     * Append a filter to the end of the chain
+     */
+    pub fn filter<F: TokenFilter + 'static>(mut self, filter: F) -> Self {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    /**
+     * Insert a filter into the chain before the stage currently at `index`
+     */
+    pub fn insert_filter<F: TokenFilter + 'static>(mut self, index: usize, filter: F) -> Self {
+        self.filters.insert(index, Arc::new(filter));
+        self
+    }
+
+    /**
+     * Remove the stage at `index` from the chain, if there is one
+     */
+    pub fn remove_filter(mut self, index: usize) -> Self {
+        if index < self.filters.len() {
+            self.filters.remove(index);
+        }
+        self
+    }
+
+    /**
+     * Move the stage at `from` so that it sits at `to` in the chain
+     */
+    pub fn move_filter(mut self, from: usize, to: usize) -> Self {
+        if from < self.filters.len() && to < self.filters.len() {
+            let filter = self.filters.remove(from);
+            self.filters.insert(to, filter);
+        }
+        self
+    }
+
+    pub fn build(self) -> Analyzer {
+        Analyzer {
+            tokenizer: self
+                .tokenizer
+                .unwrap_or_else(|| Arc::new(WhitespaceTokenizer)),
+            filters: self.filters,
+        }
+    }
+}
+
+/**
+ * Filter the text with the default Analyzer, returning the terms for the index
+ */
+pub fn filter(text: &str) -> Vec<String> {
+    Analyzer::default().terms(text)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(
+            filter("The History of Anarchism"),
+            vec!["histori", "anarch"]
+        );
+    }
+
+    #[test]
+    fn test_stem_cache() {
+        let stemmer = StemmerFilter::default();
+        let tokens = vec![
+            Token::new("running", 0),
+            Token::new("runs", 1),
+            Token::new("running", 2),
+        ];
+        let stems: Vec<String> = stemmer.filter(tokens).into_iter().map(|t| t.text).collect();
+        assert_eq!(stems, vec!["run", "run", "run"]);
+        assert_eq!(stemmer.cache.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_positions_survive_stopwords() {
+        let tokens = Analyzer::default().analyze("statue of liberty");
+        assert_eq!(
+            tokens,
+            vec![Token::new("statu", 0), Token::new("liberti", 2)]
+        );
+    }
+
+    #[test]
+    fn test_no_empty_tokens() {
+        // Repeated spaces leave no tokens behind, but still count towards the positions
+        assert_eq!(
+            WhitespaceTokenizer.tokenize("Anarchism  -  Overview"),
+            vec![
+                Token::new("Anarchism", 0),
+                Token::new("-", 2),
+                Token::new("Overview", 4)
+            ]
+        );
+        assert!(WhitespaceTokenizer.tokenize("").is_empty());
+        // As do tokens which were nothing but punctuation
+        assert_eq!(
+            PunctuationFilter.filter(vec![Token::new("-", 2), Token::new("(history)", 4)]),
+            vec![Token::new("history", 4)]
+        );
+
+        // Otherwise a trailing space would search for an empty term which is in no document
+        assert_eq!(
+            filter("Anarchism  -  Overview "),
+            vec!["anarch", "overview"]
+        );
+        assert!(filter("").is_empty());
+    }
+
+    #[test]
+    fn test_builder_remove_filter() {
+        // Dropping the stemmer leaves the lowercased, de-punctuated words
+        let analyzer = AnalyzerBuilder::standard().remove_filter(3).build();
+        assert_eq!(analyzer.terms("The Histories!"), vec!["histories"]);
+    }
+
+    #[test]
+    fn test_builder_move_filter() {
+        // Stopwords run before lowercasing, so the capitalized stopword survives
+        let analyzer = AnalyzerBuilder::standard().move_filter(2, 0).build();
+        assert_eq!(analyzer.terms("The end"), vec!["the", "end"]);
+    }
+
+    #[test]
+    fn test_closure_tokenizer() {
+        // Keep ticket IDs like GOEDE-123 together rather than splitting on the dash
+        let analyzer = Analyzer::builder()
+            .tokenizer(|text: &str| {
+                text.split(|c: char| c.is_whitespace() || c == ',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .filter(LowercaseFilter)
+            .build();
+        assert_eq!(
+            analyzer.analyze("GOEDE-123,GOEDE-7"),
+            vec![Token::new("goede-123", 0), Token::new("goede-7", 1)]
+        );
+    }
+
+    #[test]
+    fn test_closure_filter_keeps_positions() {
+        let analyzer = Analyzer::builder()
+            .filter(|token: &str| {
+                if token.len() > 2 {
+                    Some(token.to_uppercase())
+                } else {
+                    None
+                }
+            })
+            .build();
+        assert_eq!(
+            analyzer.analyze("part no 42X"),
+            vec![Token::new("PART", 0), Token::new("42X", 2)]
+        );
+    }
+
+    #[test]
+    fn test_unicode_tokenizer() {
+        let tokens = UnicodeTokenizer.tokenize("Hello, world! (again)");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::new("Hello", 0),
+                Token::new("world", 1),
+                Token::new("again", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ascii_folding() {
+        let analyzer = Analyzer::builder().filter(AsciiFoldingFilter).build();
+        assert_eq!(analyzer.terms("Café Zürich"), vec!["Cafe", "Zurich"]);
+    }
+
+    #[test]
+    fn test_length_filter() {
+        let analyzer = Analyzer::builder().filter(LengthFilter::new(2, 4)).build();
+        assert_eq!(analyzer.terms("a to the tower"), vec!["to", "the"]);
+    }
+
+    #[test]
+    fn test_empty_builder() {
+        let analyzer = Analyzer::builder().build();
+        assert_eq!(analyzer.terms("Hello World"), vec!["Hello", "World"]);
+    }
 }
@@ -0,0 +1,413 @@
+/**
+ * The frozen module contains the FrozenIndex, a read-only layout of an index which is built once
+ * the documents are done changing and is cheaper to query and to hold in memory than the
+ * HashMaps an Index is built with
+ *
+ * Every dictionary is a sorted array of terms, looked up by binary search, with the documents of
+ * each term in one contiguous array sorted by document id. The term frequencies and positions
+ * of the full text line up with its postings, so looking one up is a binary search within the
+ * term's documents rather than hashing a (document, term) pair. The statistics and the lengths
+ * of the documents are all worked out while freezing.
+ *
+ * Nothing can be added to or removed from a FrozenIndex, and it keeps neither the co-occurrences
+ * nor the trigram and reversed term indexes, so related terms, substring queries and leading
+ * wildcards fall back to what any IndexReader does.
+ */
+use crate::engine::{Article, DocumentId, FieldLengths, IndexReader, TermStats};
+use crate::postings::PostingsRef;
+use crate::schema::{Field, Schema};
+use crate::store::Documents;
+use log::*;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+use tracing::debug_span;
+
+/**
+ * Sorted terms, each of which owns a range of the contiguous postings
+ */
+#[derive(Clone, Debug, Default)]
+struct Dictionary {
+    terms: Vec<String>,
+    /**
+     * The start of each term's documents in `postings`, followed by the end of the last one
+     */
+    offsets: Vec<usize>,
+    postings: Vec<DocumentId>,
+}
+
+impl Dictionary {
+    fn build<F: Fn(&str) -> Option<Vec<DocumentId>>>(mut terms: Vec<String>, postings: F) -> Self {
+        terms.sort_unstable();
+        let mut dictionary = Self::default();
+        dictionary.offsets.push(0);
+        for term in terms {
+            let mut ids = match postings(&term) {
+                Some(ids) if !ids.is_empty() => ids,
+                _ => continue,
+            };
+            ids.sort_unstable();
+            dictionary.postings.extend(ids);
+            dictionary.offsets.push(dictionary.postings.len());
+            dictionary.terms.push(term);
+        }
+        dictionary.terms.shrink_to_fit();
+        dictionary.offsets.shrink_to_fit();
+        dictionary.postings.shrink_to_fit();
+        dictionary
+    }
+
+    /**
+     * The position of the term in the dictionary
+     */
+    fn find(&self, term: &str) -> Option<usize> {
+        self.terms
+            .binary_search_by(|other| other.as_str().cmp(term))
+            .ok()
+    }
+
+    /**
+     * Where the documents of the term at the position are in `postings`
+     */
+    fn range(&self, position: usize) -> Range<usize> {
+        self.offsets[position]..self.offsets[position + 1]
+    }
+
+    fn postings(&self, term: &str) -> Option<&[DocumentId]> {
+        self.find(term)
+            .map(|position| &self.postings[self.range(position)])
+    }
+
+    /**
+     * Where the document is in `postings` among the documents of the term
+     */
+    fn posting(&self, id: DocumentId, term: &str) -> Option<usize> {
+        let range = self.range(self.find(term)?);
+        let start = range.start;
+        self.postings[range]
+            .binary_search(&id)
+            .ok()
+            .map(|offset| start + offset)
+    }
+}
+
+/**
+ * An index which can no longer be changed, laid out for querying
+ *
+ * Freeze an Index with `Index::freeze()`, which shares its stored documents, or copy any other
+ * IndexReader with `FrozenIndex::from_reader()`.
+ */
+#[derive(Clone, Debug)]
+pub struct FrozenIndex {
+    schema: Schema,
+    documents: Arc<Documents>,
+    /**
+     * Every document id in ascending order, which `lengths` lines up with
+     */
+    ids: Vec<DocumentId>,
+    lengths: Vec<FieldLengths>,
+    average_lengths: (f64, f64),
+    average_length: f64,
+    terms: Dictionary,
+    /**
+     * The statistics of each full text term, in the order of the dictionary
+     */
+    stats: Vec<TermStats>,
+    /**
+     * The frequency of each full text posting, in the order of the postings
+     */
+    frequencies: Vec<u32>,
+    /**
+     * The start of each full text posting's positions in `positions`, followed by the end of
+     * the last one
+     */
+    position_offsets: Vec<usize>,
+    positions: Vec<usize>,
+    fields: HashMap<Field, Dictionary>,
+}
+
+impl FrozenIndex {
+    /**
+     * Copy the entire contents of any IndexReader, including its documents, into a new
+     * FrozenIndex
+     */
+    pub fn from_reader<R: IndexReader + ?Sized>(reader: &R) -> Result<Self, std::io::Error> {
+        let mut documents = Documents::default();
+        for id in reader.document_ids() {
+            if let Some(article) = reader.document(&id) {
+                documents.insert(article.into_owned())?;
+            }
+        }
+        Ok(Self::with_documents(reader, Arc::new(documents)))
+    }
+
+    /**
+     * Lay out the postings of the reader, whose stored documents are the given ones
+     */
+    pub(crate) fn with_documents<R: IndexReader + ?Sized>(
+        reader: &R,
+        documents: Arc<Documents>,
+    ) -> Self {
+        let _span = debug_span!("freeze").entered();
+        let schema = reader.schema().clone();
+        let mut ids = reader.document_ids();
+        ids.sort_unstable();
+        let lengths = ids
+            .iter()
+            .map(|id| reader.field_lengths(*id).unwrap_or_default())
+            .collect();
+
+        let terms = Dictionary::build(reader.terms(), |term| {
+            reader
+                .postings(term)
+                .map(|docs| docs.iter().copied().collect())
+        });
+        let mut stats = Vec::with_capacity(terms.terms.len());
+        let mut frequencies = Vec::with_capacity(terms.postings.len());
+        let mut position_offsets = Vec::with_capacity(terms.postings.len() + 1);
+        let mut positions = vec![];
+        position_offsets.push(0);
+        for (position, term) in terms.terms.iter().enumerate() {
+            for id in terms.postings[terms.range(position)].iter() {
+                let frequency = reader.term_frequency(*id, term).unwrap_or(0.0);
+                frequencies.push(frequency as u32);
+                if let Some(offsets) = reader.positions(*id, term) {
+                    positions.extend_from_slice(&offsets);
+                }
+                position_offsets.push(positions.len());
+            }
+            stats.push(reader.term_stats(term).unwrap_or(TermStats {
+                document_frequency: 0,
+                total_frequency: 0,
+                idf: 0.0,
+            }));
+        }
+        positions.shrink_to_fit();
+
+        let mut fields = HashMap::new();
+        let searchable = schema.fields().map(|(field, _)| *field);
+        for field in searchable.chain(std::iter::once(Field::Metadata)) {
+            let dictionary = Dictionary::build(reader.field_terms(field), |term| {
+                reader
+                    .field_postings(field, term)
+                    .map(|docs| docs.iter().copied().collect())
+            });
+            if !dictionary.terms.is_empty() {
+                fields.insert(field, dictionary);
+            }
+        }
+
+        debug!(
+            "Froze {} terms with {} postings",
+            terms.terms.len(),
+            terms.postings.len()
+        );
+        Self {
+            average_lengths: reader.average_field_lengths(),
+            average_length: reader.average_document_length(),
+            schema,
+            documents,
+            ids,
+            lengths,
+            terms,
+            stats,
+            frequencies,
+            position_offsets,
+            positions,
+            fields,
+        }
+    }
+
+    /**
+     * The documents whose full text contains the (analyzed) term, in ascending order
+     */
+    pub fn posting_slice(&self, term: &str) -> &[DocumentId] {
+        self.terms.postings(term).unwrap_or_default()
+    }
+}
+
+impl IndexReader for FrozenIndex {
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn size(&self) -> u64 {
+        self.ids.len() as u64
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.ids.clone()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.documents.get(id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.terms.terms.clone()
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.terms
+            .postings(term)
+            .map(|ids| Cow::Owned(ids.iter().copied().collect()))
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.fields
+            .get(&field)
+            .map(|dictionary| dictionary.terms.clone())
+            .unwrap_or_default()
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.fields
+            .get(&field)?
+            .postings(term)
+            .map(|ids| Cow::Owned(ids.iter().copied().collect()))
+    }
+
+    fn postings_ref(&self, term: &str) -> Option<PostingsRef<'_>> {
+        self.terms.postings(term).map(PostingsRef::Sorted)
+    }
+
+    fn field_postings_ref(&self, field: Field, term: &str) -> Option<PostingsRef<'_>> {
+        self.fields
+            .get(&field)?
+            .postings(term)
+            .map(PostingsRef::Sorted)
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.terms
+            .posting(id, term)
+            .map(|posting| self.frequencies[posting] as f64)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        let posting = self.terms.posting(id, term)?;
+        let range = self.position_offsets[posting]..self.position_offsets[posting + 1];
+        Some(Cow::Borrowed(&self.positions[range]))
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        self.terms
+            .find(term)
+            .map_or(0.0, |position| self.stats[position].idf)
+    }
+
+    fn term_stats(&self, term: &str) -> Option<TermStats> {
+        self.terms
+            .find(term)
+            .map(|position| self.stats[position].clone())
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.ids
+            .binary_search(&id)
+            .ok()
+            .map(|position| self.lengths[position])
+    }
+
+    fn average_field_lengths(&self) -> (f64, f64) {
+        self.average_lengths
+    }
+
+    fn average_document_length(&self) -> f64 {
+        self.average_length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Article, Index};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_freeze() -> Result<(), std::io::Error> {
+        let mut index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        index.finalize();
+        let frozen = index.freeze();
+        assert_eq!(frozen.size(), index.size());
+        assert_eq!(frozen.terms().len(), index.terms().len());
+        for query in ["anarchism", "\"united states\"", "title:history", "*ology"] {
+            assert_eq!(
+                frozen.search(query, 10),
+                index.search(query, 10),
+                "{}",
+                query
+            );
+        }
+
+        let slice = frozen.posting_slice("anarch");
+        assert!(slice.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(slice.len(), index.postings("anarch").unwrap().len());
+        // Queries look up the postings where they are rather than copying them
+        assert!(matches!(
+            frozen.postings_ref("anarch"),
+            Some(PostingsRef::Sorted(ids)) if std::ptr::eq(ids, slice)
+        ));
+        let id = slice[0];
+        assert_eq!(
+            frozen.positions(id, "anarch"),
+            index.positions(id, "anarch")
+        );
+        assert_eq!(frozen.term_stats("anarch"), index.term_stats("anarch"));
+        assert_eq!(frozen.term_frequency(id, "zzzz"), None);
+
+        let copied = FrozenIndex::from_reader(&frozen)?;
+        assert_eq!(
+            copied.search("anarchism", 10),
+            index.search("anarchism", 10)
+        );
+        Ok(())
+    }
+
+    /**
+     * Times the same queries against an Index and its FrozenIndex, which takes a corpus far
+     * larger than the sample dump and a release build to mean anything, so run it with
+     * `cargo test --release -- --ignored`
+     */
+    #[test]
+    #[ignore]
+    fn bench_frozen_layout() -> Result<(), std::io::Error> {
+        // Words are drawn from a skewed distribution, so that some of them are in most documents
+        let mut seed: u64 = 42;
+        let mut word = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let uniform = (seed >> 11) as f64 / (1u64 << 53) as f64;
+            format!("w{}", (uniform.powi(3) * 3000.0) as usize)
+        };
+        let mut index = Index::new();
+        for id in 0..20_000 {
+            let text: Vec<String> = (0..60).map(|_| word()).collect();
+            index.index_document(Article::new(
+                &format!("Document {}", id),
+                &text.join(" "),
+                &format!("https://example.com/{}", id),
+            )?)?;
+        }
+        index.finalize();
+        let frozen = index.freeze();
+
+        let queries: Vec<String> = ["w0 w1", "w2 w5 w9", "w0 w3", "w10 w20", "w0 w1 w2 w3"]
+            .iter()
+            .cycle()
+            .take(100)
+            .map(|q| q.to_string())
+            .collect();
+        let before = crate::bench::replay(&index, &queries, 10, 1);
+        let after = crate::bench::replay(&frozen, &queries, 10, 1);
+        println!(
+            "Index: {:.1} queries/s, FrozenIndex: {:.1} queries/s",
+            before.throughput(),
+            after.throughput()
+        );
+        assert!(after.elapsed < before.elapsed);
+        Ok(())
+    }
+}
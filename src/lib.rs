@@ -0,0 +1,71 @@
+/*
+ * Goedesearch is an implementation of Bart's full text search engine as an exercise in Rust
+ *
+ * The library exposes the engine so that it can be embedded in other applications, the
+ * goedesearch binary is just a thin command line interface on top of it.
+ *
+ * Each phase of a query (parsing, analysis, finding the candidates, scoring and sorting) and of
+ * building an index runs in a `tracing` span, so a tracing subscriber can show where the time
+ * goes. Without a subscriber the spans are logged instead, e.g. with
+ * `RUST_LOG=tracing::span=trace`.
+ *
+ * The modules which read and write files of their own or talk over sockets are not compiled for
+ * wasm32, where there are neither, and the wasm module stands in for them.
+ */
+
+pub mod bench;
+pub mod bloom;
+pub mod boost;
+pub mod builder;
+pub mod bulk;
+pub mod cache;
+pub mod cluster;
+pub mod config;
+pub mod crypto;
+#[cfg(unix)]
+pub mod daemon;
+pub mod disk;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod distributed;
+#[cfg(feature = "onnx")]
+pub mod embed;
+pub mod engine;
+pub mod error;
+pub mod eval;
+pub mod export;
+pub mod ffi;
+pub mod filters;
+pub mod frozen;
+pub mod ltr;
+#[cfg(feature = "protobuf")]
+pub mod portable;
+pub mod postings;
+pub mod prior;
+pub mod query;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod querylog;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod remote;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod repl;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod replica;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod rerank;
+pub mod rewrite;
+pub mod schema;
+pub mod scoring;
+pub mod searcher;
+pub mod segment;
+pub mod server;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod shard;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod snapshot;
+pub mod spell;
+pub mod store;
+pub mod telemetry;
+pub mod vectors;
+pub mod wal;
+#[cfg(feature = "wasm")]
+pub mod wasm;
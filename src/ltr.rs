@@ -0,0 +1,271 @@
+/**
+ * The ltr module turns judged queries into feature vectors for training a learning to rank
+ * model offline, such as a reranker for the best few documents
+ *
+ * The vectors are written either in the SVMrank format, with a line of
+ * `RELEVANCE qid:QUERY 1:VALUE 2:VALUE ... # DOCUMENT` for each judged pair, or in the LightGBM
+ * format, which leaves out the `qid:` and instead writes the number of lines of each query to a
+ * separate group file.
+ */
+use crate::engine::{DocumentId, IndexReader};
+use crate::eval::Qrels;
+use crate::query::{self, Clause};
+use crate::schema::Field;
+use crate::scoring::{Averages, Scorer};
+use std::collections::BTreeMap;
+use std::io::{Error, Write};
+
+/**
+ * The name of every feature, in the order of the values of a FeatureVector
+ */
+pub const FEATURES: &[&str] = &[
+    "tf",
+    "idf",
+    "tfidf",
+    "bm25",
+    "bm25f",
+    "lm_dirichlet",
+    "title_matches",
+    "abstract_matches",
+    "length",
+    "title_length",
+    "abstract_length",
+];
+
+/**
+ * The features of a single document for a query, along with how relevant it was judged to be
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeatureVector {
+    pub query: String,
+    pub document: DocumentId,
+    pub relevance: u32,
+    pub features: Vec<f64>,
+}
+
+/**
+ * The scorers whose scores are features, in the order of FEATURES
+ */
+fn scorers() -> [Scorer; 4] {
+    [
+        Scorer::TfIdf,
+        Scorer::bm25(),
+        Scorer::bm25f(),
+        Scorer::dirichlet(),
+    ]
+}
+
+/**
+ * Compute the features of the document for the query, as listed in FEATURES
+ */
+pub fn features<R: IndexReader + ?Sized>(reader: &R, query: &str, id: DocumentId) -> Vec<f64> {
+    let averages = scorers().map(|scorer| scorer.averages(reader));
+    features_with(reader, &averages, query, id)
+}
+
+/**
+ * Compute the features with the averages of each of the scorers, which only need computing
+ * once for the whole index
+ */
+fn features_with<R: IndexReader + ?Sized>(
+    reader: &R,
+    averages: &[Averages; 4],
+    query: &str,
+    id: DocumentId,
+) -> Vec<f64> {
+    let normalized = reader.normalize(query);
+    let terms = &normalized.terms;
+    let scores: Vec<f64> = scorers()
+        .iter()
+        .zip(averages.iter())
+        .map(|(scorer, averages)| {
+            let weights: Vec<(&String, f64)> = terms
+                .iter()
+                .map(|term| (term, scorer.weight(reader, averages, term)))
+                .collect();
+            scorer.score(reader, averages, id, &weights)
+        })
+        .collect();
+
+    let text: Vec<String> = query::parse(query)
+        .into_iter()
+        .filter_map(|clause| match clause {
+            Clause::Text(text) => Some(text),
+            _ => None,
+        })
+        .collect();
+    let matches = |field: Field| {
+        let analyzed = match reader.schema().analyzer(field) {
+            Some(analyzer) => analyzer.terms(&text.join(" ")),
+            None => return 0.0,
+        };
+        if analyzed.is_empty() {
+            return 0.0;
+        }
+        let matched = analyzed
+            .iter()
+            .filter(|term| {
+                reader
+                    .field_postings(field, term)
+                    .is_some_and(|docs| docs.contains(&id))
+            })
+            .count();
+        matched as f64 / analyzed.len() as f64
+    };
+    let lengths = reader.field_lengths(id).unwrap_or_default();
+
+    let mut features = vec![
+        terms
+            .iter()
+            .filter_map(|term| reader.term_frequency(id, term))
+            .sum(),
+        terms.iter().map(|term| reader.idf(term)).sum(),
+    ];
+    features.extend(scores);
+    features.extend([
+        matches(Field::Title),
+        matches(Field::Abstract),
+        lengths.total(),
+        lengths.title,
+        lengths.r#abstract,
+    ]);
+    features
+}
+
+/**
+ * The feature vectors of every judged document of every query, as well as of the `k` highest
+ * scoring documents of each query which were not judged, which count as not relevant
+ */
+pub fn collect<R: IndexReader + ?Sized>(
+    reader: &R,
+    queries: &[(String, String)],
+    qrels: &Qrels,
+    k: usize,
+) -> Vec<FeatureVector> {
+    let averages = scorers().map(|scorer| scorer.averages(reader));
+    let mut vectors = vec![];
+    for (id, query) in queries.iter() {
+        let judgements = match qrels.get(id) {
+            Some(judgements) => judgements,
+            None => continue,
+        };
+        let mut documents: BTreeMap<DocumentId, u32> = judgements
+            .iter()
+            .filter(|(document, _)| reader.document(document).is_some())
+            .map(|(document, relevance)| (*document, *relevance))
+            .collect();
+        for (document, _) in reader.search(query, k) {
+            documents.entry(document).or_insert(0);
+        }
+        vectors.extend(
+            documents
+                .into_iter()
+                .map(|(document, relevance)| FeatureVector {
+                    query: id.clone(),
+                    document,
+                    relevance,
+                    features: features_with(reader, &averages, query, document),
+                }),
+        );
+    }
+    vectors
+}
+
+fn write_features<W: Write + ?Sized>(out: &mut W, features: &[f64]) -> Result<(), Error> {
+    for (number, value) in features.iter().enumerate() {
+        write!(out, " {}:{}", number + 1, value)?;
+    }
+    Ok(())
+}
+
+/**
+ * Write the vectors in the SVMrank format, numbering the queries in the order they first appear
+ */
+pub fn write_svmrank<W: Write + ?Sized>(
+    out: &mut W,
+    vectors: &[FeatureVector],
+) -> Result<(), Error> {
+    let mut numbers: BTreeMap<&str, usize> = BTreeMap::new();
+    for vector in vectors.iter() {
+        let next = numbers.len() + 1;
+        let qid = *numbers.entry(&vector.query).or_insert(next);
+        write!(out, "{} qid:{}", vector.relevance, qid)?;
+        write_features(out, &vector.features)?;
+        writeln!(out, " # {} {}", vector.query, vector.document)?;
+    }
+    out.flush()
+}
+
+/**
+ * Write the vectors in the LightGBM format, and the number of consecutive vectors of each query
+ * to the group file
+ */
+pub fn write_lightgbm<W, G>(
+    out: &mut W,
+    groups: &mut G,
+    vectors: &[FeatureVector],
+) -> Result<(), Error>
+where
+    W: Write + ?Sized,
+    G: Write + ?Sized,
+{
+    let mut group: Option<(&str, usize)> = None;
+    for vector in vectors.iter() {
+        write!(out, "{}", vector.relevance)?;
+        write_features(out, &vector.features)?;
+        writeln!(out)?;
+        group = match group {
+            Some((query, count)) if query == vector.query => Some((query, count + 1)),
+            Some((_, count)) => {
+                writeln!(groups, "{}", count)?;
+                Some((&vector.query, 1))
+            }
+            None => Some((&vector.query, 1)),
+        };
+    }
+    if let Some((_, count)) = group {
+        writeln!(groups, "{}", count)?;
+    }
+    out.flush()?;
+    groups.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+    use crate::eval::read_qrels;
+
+    #[test]
+    fn test_export() -> Result<(), Error> {
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let results = index.search("history", 3);
+        let judged = results[0].0;
+        let qrels = read_qrels(format!("q1 0 {} 2\nq1 0 {} 0\n", judged, results[2].0).as_bytes())?;
+        let queries = vec![
+            ("q1".to_string(), "history".to_string()),
+            ("q2".to_string(), "unjudged".to_string()),
+        ];
+
+        let vectors = collect(&index, &queries, &qrels, 2);
+        assert_eq!(vectors.len(), 3);
+        assert!(vectors.iter().all(|v| v.features.len() == FEATURES.len()));
+        let first = vectors.iter().find(|v| v.document == judged).unwrap();
+        assert_eq!(first.relevance, 2);
+        assert!(first.features[3] > 0.0);
+        assert_eq!(first.features[8], first.features[9] + first.features[10]);
+        assert_eq!(first.features, features(&index, "history", judged));
+
+        let mut out = vec![];
+        write_svmrank(&mut out, &vectors)?;
+        let svmrank = String::from_utf8(out).unwrap();
+        assert_eq!(svmrank.lines().count(), 3);
+        assert!(svmrank.lines().all(|line| line.contains(" qid:1 1:")));
+
+        let (mut out, mut groups) = (vec![], vec![]);
+        write_lightgbm(&mut out, &mut groups, &vectors)?;
+        assert_eq!(String::from_utf8(groups).unwrap(), "3\n");
+        assert!(!String::from_utf8(out).unwrap().contains("qid"));
+        Ok(())
+    }
+}
@@ -5,75 +5,2016 @@
  */
 
 use chrono::prelude::*;
+use goedesearch::bench;
+use goedesearch::boost::{self, BoostedReader, FieldBoost, Recency};
+use goedesearch::builder::IndexBuilder;
+use goedesearch::bulk;
+use goedesearch::cluster::{self, ClusterOptions};
+use goedesearch::config::{Config, RankingConfig};
+use goedesearch::crypto::{EncryptedStorage, Key};
+#[cfg(unix)]
+use goedesearch::daemon;
+use goedesearch::disk::{self, DiskIndex};
+use goedesearch::distributed::Coordinator;
+use goedesearch::engine::{document_id, DocumentId, Index, IndexReader, IngestOptions};
+use goedesearch::eval;
+use goedesearch::ltr;
+use goedesearch::prior::{self, Priors};
+use goedesearch::query;
+use goedesearch::querylog::{self, LoggedReader, QueryLog};
+use goedesearch::remote;
+use goedesearch::repl::{self as meta, Command as MetaCommand, Input, Settings, Vocabulary};
+use goedesearch::replica::{self, Replica};
+use goedesearch::rerank::{self, Reranker};
+use goedesearch::schema::Schema;
+use goedesearch::scoring::Scorer;
+use goedesearch::segment::{SegmentOptions, SegmentedIndex};
+use goedesearch::server::{self, SearchResponse};
+use goedesearch::shard::{self, ShardedIndex};
+use goedesearch::snapshot;
+use goedesearch::store::{open_storage, FileStorage, Storage};
+use goedesearch::vectors::VectorIndex;
 use gumdrop::Options;
 use log::*;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod engine;
-mod filters;
+/**
+ * The name of the segment which the index is saved as in a --storage backend
+ */
+const STORAGE_SEGMENT: &str = "index";
+
+/**
+ * How many related searches are suggested below the results of a query
+ */
+const RELATED_SEARCHES: usize = 5;
 
 #[derive(Debug, Options)]
-struct CLI {
+struct Cli {
     #[options(help = "print help message")]
     help: bool,
-    #[options(required, help = "Specify the data file")]
-    datafile: PathBuf,
+    #[options(help = "Specify the data file")]
+    datafile: Option<PathBuf>,
+    #[options(
+        meta = "PATH|URL",
+        help = "Query a saved index file in place, or fetch one from an http(s):// or s3:// url"
+    )]
+    index: Option<String>,
+    #[options(no_short, help = "Cache indexes fetched from urls in this directory")]
+    cache_dir: Option<PathBuf>,
+    #[options(help = "Save the index built from the data file to this path")]
+    save: Option<PathBuf>,
+    #[options(
+        no_short,
+        help = "Keep the documents in a file at this path instead of in memory"
+    )]
+    store: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "SPEC",
+        help = "Keep the documents and index in a storage backend, e.g. file:/path"
+    )]
+    storage: Option<String>,
     #[options(help = "A string to query for")]
     query: Option<String>,
+    #[options(
+        no_short,
+        help = "Print how long each phase of every query took, bypassing any query cache"
+    )]
+    verbose: bool,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Run every query in this file, one per line, printing the results of each as JSON"
+    )]
+    queries_file: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "The number of results for each of the --queries-file queries (default: 10)"
+    )]
+    limit: Option<usize>,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Append the time, hits and latency of every query to this file as JSON lines"
+    )]
+    query_log: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Favor popular documents by the values in this file, as lines of URL VALUE"
+    )]
+    priors: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "WEIGHT",
+        help = "How strongly the --priors affect the scores (default: 1)"
+    )]
+    prior_weight: Option<f64>,
+    #[options(
+        no_short,
+        meta = "DAYS",
+        help = "Favor recent documents, with a boost which halves every DAYS of their age"
+    )]
+    recency_half_life: Option<f64>,
+    #[options(
+        no_short,
+        meta = "KEY",
+        help = "The metadata key of the dates for --recency-half-life (default: date)"
+    )]
+    recency_key: Option<String>,
+    #[options(
+        no_short,
+        meta = "WEIGHT",
+        help = "How much the newest documents are boosted by with --recency-half-life (default: 1)"
+    )]
+    recency_weight: Option<f64>,
+    #[options(
+        no_short,
+        meta = "KEY",
+        help = "Multiply the scores of documents by the number in this metadata key, e.g. boost"
+    )]
+    boost_key: Option<String>,
+    #[options(help = "Load the configuration from a TOML file")]
+    config: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "KEY=VALUE",
+        help = "Override a ranking parameter of the configuration, e.g. k1=1.2"
+    )]
+    set: Vec<String>,
+    #[options(no_short, help = "Cache the results of this many distinct queries")]
+    cache_size: Option<usize>,
+    #[options(help = "Build a trigram index to speed up contains: substring queries")]
+    trigrams: bool,
+    #[options(
+        no_short,
+        help = "Build a reversed term index to speed up *suffix wildcard queries"
+    )]
+    reversed_terms: bool,
+    #[options(
+        no_short,
+        help = "Suggest related searches from the data file, which a saved --index, segments and shards cannot"
+    )]
+    related_searches: bool,
+    #[options(
+        no_short,
+        help = "Encrypt everything persisted, and decrypt what is opened, with the hex key in this file"
+    )]
+    key_file: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Build N shards into the --save directory, which are searched in parallel"
+    )]
+    shards: Option<usize>,
+    #[options(
+        no_short,
+        meta = "ADDR",
+        help = "Answer searches over HTTP on this address, e.g. 127.0.0.1:8080"
+    )]
+    listen: Option<String>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Answer up to N searches over --listen or --daemon at once (default: the number of CPUs)"
+    )]
+    workers: Option<usize>,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Answer searches over a unix socket at this path with length-prefixed JSON"
+    )]
+    daemon: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "URL",
+        help = "Search the goedesearch servers at these urls instead of a local index"
+    )]
+    node: Vec<String>,
+    #[options(
+        no_short,
+        meta = "SECS",
+        help = "Give up on nodes which take longer than this to answer (default: 10)"
+    )]
+    node_timeout: Option<u64>,
+    #[options(
+        no_short,
+        meta = "URL",
+        help = "Have the service at this url rerank the best hits of searches over --listen"
+    )]
+    reranker: Option<String>,
+    #[options(
+        no_short,
+        meta = "SECS",
+        help = "Keep the original ranking when the --reranker takes longer than this (default: 1)"
+    )]
+    reranker_timeout: Option<f64>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Send the best N hits to the --reranker (default: 50)"
+    )]
+    rerank_candidates: Option<usize>,
+    #[options(
+        no_short,
+        meta = "DIR|URL",
+        help = "Search a replica of the index published to this directory or url"
+    )]
+    replica: Option<String>,
+    #[options(
+        no_short,
+        meta = "SECS",
+        help = "Pull new generations for the --replica this often (default: 30)"
+    )]
+    poll: Option<u64>,
+    #[options(no_short, meta = "N", help = "Index the data file with N threads")]
+    threads: Option<usize>,
+    #[options(
+        no_short,
+        meta = "MB",
+        help = "Move documents out of memory past this many megabytes while indexing, and postings too with --save"
+    )]
+    memory_budget: Option<usize>,
+    #[options(
+        no_short,
+        help = "Stop at the first malformed document in the data file, instead of skipping it"
+    )]
+    strict: bool,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Write the malformed documents which were skipped to this file"
+    )]
+    rejects: Option<PathBuf>,
+    #[options(
+        no_short,
+        help = "Group the results into clusters of similar documents"
+    )]
+    cluster: bool,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Load precomputed document embeddings from this JSON lines file"
+    )]
+    embeddings: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "URL",
+        help = "Print the documents whose --embeddings are nearest to the one at this url"
+    )]
+    nearest: Option<String>,
+    #[options(
+        no_short,
+        meta = "DIR",
+        help = "Rank results with the ONNX embedding model and tokenizer in this directory as well as the text"
+    )]
+    model: Option<PathBuf>,
+    #[options(command)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Options)]
+enum Command {
+    #[options(help = "Archive the index in a storage backend, or restore one from an archive")]
+    Snapshot(SnapshotOptions),
+    #[options(help = "Rewrite indexes saved in older formats in the current one")]
+    Upgrade(MaintenanceOptions),
+    #[options(help = "Check indexes for corruption and orphaned entries")]
+    Verify(MaintenanceOptions),
+    #[options(help = "Compact indexes, purging deleted documents and merging segments")]
+    Optimize(MaintenanceOptions),
+    #[options(help = "Print a new random key to use with --key-file")]
+    Keygen(KeygenOptions),
+    #[options(help = "Publish the last commit in --storage to a directory for --replica to pull")]
+    Publish(PublishOptions),
+    #[options(help = "Write the documents and term weights of an index out for other tools")]
+    Export(ExportOptions),
+    #[options(help = "Build an index from a JSON or protobuf export")]
+    Import(ImportOptions),
+    #[options(help = "Replay the queries in a --query-log against an index and time them")]
+    Bench(BenchOptions),
+    #[options(help = "Measure how well an index ranks queries with relevance judgements")]
+    Eval(EvalOptions),
+    #[options(help = "Compare how two scorers rank the same queries against an index")]
+    Compare(CompareOptions),
+    #[options(help = "Write feature vectors of judged queries for training a ranking model")]
+    Features(FeaturesOptions),
+}
+
+#[derive(Debug, Options)]
+struct EvalOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        required,
+        meta = "PATH",
+        help = "The relevance judgements, as lines of QUERY_ID ITERATION DOCUMENT RELEVANCE"
+    )]
+    qrels: PathBuf,
+    #[options(
+        no_short,
+        required,
+        meta = "PATH",
+        help = "The queries, as lines of QUERY_ID<TAB>QUERY"
+    )]
+    queries: PathBuf,
+    #[options(
+        short = "k",
+        meta = "K",
+        help = "Measure precision and nDCG at K (default: 10)"
+    )]
+    k: Option<usize>,
+    #[options(
+        no_short,
+        meta = "SPEC",
+        help = "Evaluate the index saved in this storage backend, e.g. file:/path"
+    )]
+    storage: Option<String>,
+    #[options(free, help = "The index file to evaluate")]
+    index: Option<PathBuf>,
+}
+
+impl EvalOptions {
+    fn run(&self) -> Result<(), std::io::Error> {
+        let open = |path| std::fs::File::open(path).map(std::io::BufReader::new);
+        let index = open_index(&self.index, &self.storage)?;
+        let qrels = eval::read_qrels(open(&self.qrels)?)?;
+        let queries = eval::read_queries(open(&self.queries)?)?;
+
+        let k = self.k.unwrap_or(10);
+        let evaluation = eval::evaluate(&queries, &qrels, k, |query| index.query_index(query));
+        println!(
+            "{:<12} {:>8} {:>8} {:>8} {:>8}",
+            "query",
+            format!("P@{}", k),
+            "recall",
+            "AP",
+            format!("nDCG@{}", k)
+        );
+        for query in evaluation.queries.iter() {
+            println!(
+                "{:<12} {:>8.4} {:>8.4} {:>8.4} {:>8.4}",
+                query.id, query.precision, query.recall, query.average_precision, query.ndcg
+            );
+        }
+        println!(
+            "{:<12} {:>8.4} {:>8.4} {:>8.4} {:>8.4}",
+            "mean",
+            evaluation.precision(),
+            evaluation.recall(),
+            evaluation.map(),
+            evaluation.ndcg()
+        );
+        println!(
+            "Evaluated {} of {} queries, the others have no judgements",
+            evaluation.queries.len(),
+            queries.len()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Options)]
+struct CompareOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        meta = "SCORER",
+        help = "The first scorer, tfidf, pivoted, bm25, bm25f or dirichlet (default: tfidf)"
+    )]
+    scorer_a: Option<Scorer>,
+    #[options(
+        no_short,
+        meta = "SCORER",
+        help = "The second scorer, tfidf, pivoted, bm25, bm25f or dirichlet (default: bm25)"
+    )]
+    scorer_b: Option<Scorer>,
+    #[options(
+        no_short,
+        required,
+        meta = "PATH",
+        help = "The queries, as lines of QUERY_ID<TAB>QUERY or of just the query"
+    )]
+    queries: PathBuf,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Also compare the metrics of both scorers against these relevance judgements"
+    )]
+    qrels: Option<PathBuf>,
+    #[options(
+        short = "k",
+        meta = "K",
+        help = "Compare the first K results of each query (default: 10)"
+    )]
+    k: Option<usize>,
+    #[options(
+        no_short,
+        meta = "SPEC",
+        help = "Compare with the index saved in this storage backend, e.g. file:/path"
+    )]
+    storage: Option<String>,
+    #[options(free, help = "The index file to compare with")]
+    index: Option<PathBuf>,
 }
 
-impl CLI {
-    fn query(index: &engine::Index, query: &str) {
-        println!("Querying for: `{}`", query);
-        let documents = index.query_index(query);
-        println!("Found {} documents", documents.len());
-        for id in documents {
-            if let Some(document) = index.document(&id) {
-                println!("{}\n-------------------", document);
+impl CompareOptions {
+    fn run(&self) -> Result<(), std::io::Error> {
+        let open = |path| std::fs::File::open(path).map(std::io::BufReader::new);
+        // BM25 needs the length of every document, which the in-memory index keeps
+        let index = Index::from_reader(&open_index(&self.index, &self.storage)?)?;
+        let queries = eval::read_queries(open(&self.queries)?)?;
+        let a = self.scorer_a.unwrap_or(Scorer::TfIdf);
+        let b = self.scorer_b.unwrap_or_else(Scorer::bm25);
+        let k = self.k.unwrap_or(10);
+
+        let rank = |scorer: &Scorer, q: &str| -> Vec<_> {
+            let normalized = query::normalize(index.schema(), q);
+            query::execute_ranked(&index, &normalized, &(*scorer).into())
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect()
+        };
+
+        println!("Comparing {:?} (A) with {:?} (B) at k = {}", a, b, k);
+        println!(
+            "{:<24} {:>8} {:>8} {:>8} {:>8}",
+            "query", "hits A", "hits B", "overlap", "moved"
+        );
+        let mut overlap = 0;
+        for (id, q) in queries.iter() {
+            let (ranked_a, ranked_b) = (rank(&a, q), rank(&b, q));
+            let difference = eval::rank_difference(&ranked_a, &ranked_b, k);
+            println!(
+                "{:<24} {:>8} {:>8} {:>8} {:>8.2}",
+                id,
+                ranked_a.len(),
+                ranked_b.len(),
+                difference.overlap,
+                difference.displacement
+            );
+            for (doc, from, to) in difference.moved.iter() {
+                let title = index
+                    .document(doc)
+                    .map(|article| article.title().to_string())
+                    .unwrap_or_default();
+                println!("    {:>3} -> {:<3} {}", from, to, title);
+            }
+            overlap += difference.overlap;
+        }
+        if !queries.is_empty() {
+            println!(
+                "Mean overlap of the first {} results: {:.2}",
+                k,
+                overlap as f64 / queries.len() as f64
+            );
+        }
+
+        if let Some(qrels) = &self.qrels {
+            let qrels = eval::read_qrels(open(qrels)?)?;
+            let first = eval::evaluate(&queries, &qrels, k, |q| rank(&a, q));
+            let second = eval::evaluate(&queries, &qrels, k, |q| rank(&b, q));
+            println!();
+            println!("{:<12} {:>8} {:>8} {:>8}", "metric", "A", "B", "delta");
+            let metrics = [
+                (format!("P@{}", k), first.precision(), second.precision()),
+                ("recall".to_string(), first.recall(), second.recall()),
+                ("MAP".to_string(), first.map(), second.map()),
+                (format!("nDCG@{}", k), first.ndcg(), second.ndcg()),
+            ];
+            for (metric, a, b) in metrics.iter() {
+                println!("{:<12} {:>8.4} {:>8.4} {:>+8.4}", metric, a, b, b - a);
             }
+            println!(
+                "Evaluated {} of {} queries, the others have no judgements",
+                first.queries.len(),
+                queries.len()
+            );
         }
+        Ok(())
     }
 }
 
-fn main() -> Result<(), std::io::Error> {
+#[derive(Debug, Options)]
+struct FeaturesOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        required,
+        meta = "PATH",
+        help = "The relevance judgements, as lines of QUERY_ID ITERATION DOCUMENT RELEVANCE"
+    )]
+    qrels: PathBuf,
+    #[options(
+        no_short,
+        required,
+        meta = "PATH",
+        help = "The queries, as lines of QUERY_ID<TAB>QUERY"
+    )]
+    queries: PathBuf,
+    #[options(
+        short = "k",
+        meta = "K",
+        help = "Also write the K best unjudged documents of each query as not relevant (default: 10)"
+    )]
+    k: Option<usize>,
+    #[options(
+        no_short,
+        meta = "FORMAT",
+        help = "Either svmrank or lightgbm (default: svmrank)"
+    )]
+    format: Option<String>,
+    #[options(
+        short = "o",
+        meta = "PATH",
+        help = "Write the vectors to this file, and the LightGBM groups to PATH.query"
+    )]
+    output: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "SPEC",
+        help = "Use the index saved in this storage backend, e.g. file:/path"
+    )]
+    storage: Option<String>,
+    #[options(free, help = "The index file to compute the features with")]
+    index: Option<PathBuf>,
+}
+
+impl FeaturesOptions {
+    fn run(&self) -> Result<(), std::io::Error> {
+        let open = |path| std::fs::File::open(path).map(std::io::BufReader::new);
+        // The length normalized scorers need the length of every document
+        let index = Index::from_reader(&open_index(&self.index, &self.storage)?)?;
+        let qrels = eval::read_qrels(open(&self.qrels)?)?;
+        let queries = eval::read_queries(open(&self.queries)?)?;
+        let vectors = ltr::collect(&index, &queries, &qrels, self.k.unwrap_or(10));
+
+        let mut out: Box<dyn Write> = match &self.output {
+            Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+            None => Box::new(std::io::stdout().lock()),
+        };
+        match self.format.as_deref().unwrap_or("svmrank") {
+            "svmrank" => ltr::write_svmrank(&mut out, &vectors)?,
+            "lightgbm" => {
+                let path = match &self.output {
+                    Some(path) => path.with_extension("query"),
+                    None => {
+                        eprintln!("The lightgbm format needs an --output for its group file");
+                        std::process::exit(2);
+                    }
+                };
+                let mut groups = std::io::BufWriter::new(std::fs::File::create(path)?);
+                ltr::write_lightgbm(&mut out, &mut groups, &vectors)?;
+            }
+            other => {
+                eprintln!("Unknown format `{}`, expected svmrank or lightgbm", other);
+                std::process::exit(2);
+            }
+        }
+        if self.output.is_some() {
+            println!(
+                "Wrote {} feature vectors of {}",
+                vectors.len(),
+                ltr::FEATURES.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Options)]
+struct BenchOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        required,
+        meta = "PATH",
+        help = "The query log written with --query-log"
+    )]
+    log: PathBuf,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Run the queries from N threads (default: 1)"
+    )]
+    threads: Option<usize>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "The number of results to search for with each query (default: 10)"
+    )]
+    limit: Option<usize>,
+    #[options(
+        no_short,
+        meta = "SPEC",
+        help = "Benchmark the index saved in this storage backend, e.g. file:/path"
+    )]
+    storage: Option<String>,
+    #[options(
+        no_short,
+        meta = "LAYOUT",
+        help = "Search the index as saved (disk), loaded into an Index (memory) or frozen (frozen)"
+    )]
+    layout: Option<String>,
+    #[options(free, help = "The index file to benchmark")]
+    index: Option<PathBuf>,
+}
+
+impl BenchOptions {
+    fn run(&self) -> Result<(), std::io::Error> {
+        let index = open_index(&self.index, &self.storage)?;
+        let index: Box<dyn IndexReader> = match self.layout.as_deref().unwrap_or("disk") {
+            "disk" => Box::new(index),
+            "memory" => Box::new(Index::from_reader(&index)?),
+            "frozen" => Box::new(Index::from_reader(&index)?.freeze()),
+            other => {
+                eprintln!(
+                    "Unknown layout `{}`, expected disk, memory or frozen",
+                    other
+                );
+                std::process::exit(2);
+            }
+        };
+        let queries: Vec<String> = querylog::read(&self.log)?
+            .into_iter()
+            .map(|entry| entry.query)
+            .collect();
+        println!(
+            "Replaying {} queries against {} documents",
+            queries.len(),
+            index.size()
+        );
+
+        let report = bench::replay(
+            index.as_ref(),
+            &queries,
+            self.limit.unwrap_or(server::DEFAULT_LIMIT),
+            self.threads.unwrap_or(1),
+        );
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        println!(
+            "{} queries in {:.3}s from {} threads: {:.1} queries/s",
+            report.queries(),
+            report.elapsed.as_secs_f64(),
+            report.threads,
+            report.throughput()
+        );
+        println!(
+            "latency: mean {:.3}ms, p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            ms(report.mean()),
+            ms(report.percentile(50.0)),
+            ms(report.percentile(90.0)),
+            ms(report.percentile(99.0)),
+            ms(report.percentile(100.0))
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Options)]
+struct ImportOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(no_short, meta = "FILE", help = "The JSON written by export --json")]
+    json: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "FILE",
+        help = "The protobuf written by export --protobuf, or another tool"
+    )]
+    protobuf: Option<PathBuf>,
+    #[options(free, required, help = "The index file to write")]
+    index: PathBuf,
+}
+
+impl ImportOptions {
+    fn run(&self) -> Result<(), std::io::Error> {
+        let open = |path| std::fs::File::open(path).map(std::io::BufReader::new);
+        let index = match (&self.json, &self.protobuf) {
+            (Some(path), None) => goedesearch::export::from_json(open(path)?)?,
+            (None, Some(path)) => Self::protobuf(open(path)?)?,
+            _ => {
+                eprintln!("Either --json or --protobuf must be given");
+                std::process::exit(2);
+            }
+        };
+        index.save(&self.index)?;
+        println!("Imported {} documents into {:?}", index.size(), self.index);
+        Ok(())
+    }
+
+    #[cfg(feature = "protobuf")]
+    fn protobuf(input: impl std::io::Read) -> Result<Index, std::io::Error> {
+        goedesearch::portable::read(input)
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    fn protobuf(_input: impl std::io::Read) -> Result<Index, std::io::Error> {
+        eprintln!("--protobuf needs goedesearch to be built with the protobuf feature");
+        std::process::exit(2);
+    }
+}
+
+#[derive(Debug, Options)]
+struct ExportOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        meta = "SPEC",
+        help = "Export the index saved in this storage backend, e.g. file:/path"
+    )]
+    storage: Option<String>,
+    #[options(
+        no_short,
+        meta = "DIR",
+        help = "Write documents.parquet and weights.parquet into this directory"
+    )]
+    parquet: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "FILE",
+        help = "Write the documents, postings and frequencies as JSON to this file"
+    )]
+    json: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "FILE",
+        help = "Write the documents into a new SQLite database with an FTS5 table"
+    )]
+    sqlite: Option<PathBuf>,
+    #[options(
+        no_short,
+        meta = "FILE",
+        help = "Write the index in the portable protobuf format to this file"
+    )]
+    protobuf: Option<PathBuf>,
+    #[options(free, help = "The index file to export")]
+    index: Option<PathBuf>,
+}
+
+/**
+ * Open the saved index which a subcommand was given, either as a path or a --storage backend
+ */
+fn open_index(
+    path: &Option<PathBuf>,
+    storage: &Option<String>,
+) -> Result<DiskIndex, std::io::Error> {
+    match (path, storage) {
+        (Some(path), None) => DiskIndex::open(path),
+        (None, Some(spec)) => DiskIndex::load(open_storage(spec)?.as_ref(), STORAGE_SEGMENT),
+        _ => {
+            eprintln!("Either --storage or the path of an index file must be given");
+            std::process::exit(2);
+        }
+    }
+}
+
+impl ExportOptions {
+    fn run(&self) -> Result<(), std::io::Error> {
+        let index = open_index(&self.index, &self.storage)?;
+        let mut exported = false;
+        if let Some(dir) = &self.parquet {
+            Self::parquet(&index, dir)?;
+            exported = true;
+        }
+        if let Some(path) = &self.json {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            goedesearch::export::to_json(&index, &mut file)?;
+            file.flush()?;
+            println!("Exported {} documents to {:?}", index.size(), path);
+            exported = true;
+        }
+        if let Some(path) = &self.sqlite {
+            Self::sqlite(&index, path)?;
+            exported = true;
+        }
+        if let Some(path) = &self.protobuf {
+            Self::protobuf(&index, path)?;
+            exported = true;
+        }
+        if !exported {
+            eprintln!("Nothing to export to, give --parquet, --json, --sqlite or --protobuf");
+            std::process::exit(2);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "parquet")]
+    fn parquet(index: &DiskIndex, dir: &std::path::Path) -> Result<(), std::io::Error> {
+        let summary = goedesearch::export::to_parquet(index, dir)?;
+        println!(
+            "Exported {} documents and {} term weights to {:?}",
+            summary.documents, summary.weights, dir
+        );
+        Ok(())
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    fn parquet(_index: &DiskIndex, _dir: &std::path::Path) -> Result<(), std::io::Error> {
+        eprintln!("--parquet needs goedesearch to be built with the parquet feature");
+        std::process::exit(2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn sqlite(index: &DiskIndex, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let summary = goedesearch::export::to_sqlite(index, path)?;
+        println!("Exported {} documents to {:?}", summary.documents, path);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn sqlite(_index: &DiskIndex, _path: &std::path::Path) -> Result<(), std::io::Error> {
+        eprintln!("--sqlite needs goedesearch to be built with the sqlite feature");
+        std::process::exit(2);
+    }
+
+    #[cfg(feature = "protobuf")]
+    fn protobuf(index: &DiskIndex, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        goedesearch::portable::write(index, &mut file)?;
+        file.flush()?;
+        println!("Exported {} documents to {:?}", index.size(), path);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    fn protobuf(_index: &DiskIndex, _path: &std::path::Path) -> Result<(), std::io::Error> {
+        eprintln!("--protobuf needs goedesearch to be built with the protobuf feature");
+        std::process::exit(2);
+    }
+}
+
+#[derive(Debug, Options)]
+struct PublishOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        required,
+        meta = "SPEC",
+        help = "The storage backend holding the index, e.g. file:/path"
+    )]
+    storage: String,
+    #[options(free, required, help = "The directory to publish into")]
+    dir: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct KeygenOptions {
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+/**
+ * The indexes which a maintenance command applies to
+ */
+#[derive(Debug, Options)]
+struct MaintenanceOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        meta = "SPEC",
+        help = "Every segment in this storage backend, e.g. file:/path"
+    )]
+    storage: Option<String>,
+    #[options(free, help = "Index files")]
+    paths: Vec<PathBuf>,
+}
+
+impl MaintenanceOptions {
+    fn require_some(&self) {
+        if self.storage.is_none() && self.paths.is_empty() {
+            eprintln!("Either --storage or the paths of index files must be given");
+            std::process::exit(2);
+        }
+    }
+}
+
+/**
+ * Print what verifying the index found, returning whether it is free of corruption
+ */
+fn print_report(name: &str, index: Result<DiskIndex, std::io::Error>) -> bool {
+    let report = match index {
+        Ok(index) => index.verify(),
+        Err(e) => {
+            println!("{}: cannot be opened: {}", name, e);
+            return false;
+        }
+    };
+    println!(
+        "{}: {} terms, {} postings, {} documents{}",
+        name,
+        report.terms,
+        report.postings,
+        report.documents,
+        if report.checksummed {
+            ""
+        } else {
+            " (no checksums to verify, upgrade to add them)"
+        }
+    );
+    for error in report.errors.iter() {
+        println!("  error: {}", error);
+    }
+    for orphan in report.orphans.iter() {
+        println!("  orphaned: {}", orphan);
+    }
+    report.is_ok()
+}
+
+#[derive(Debug, Options)]
+struct SnapshotOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(command)]
+    command: Option<SnapshotCommand>,
+}
+
+#[derive(Debug, Options)]
+enum SnapshotCommand {
+    #[options(help = "Write everything in --storage to the archive")]
+    Create(ArchiveOptions),
+    #[options(help = "Unpack the archive into the empty --storage")]
+    Restore(ArchiveOptions),
+}
+
+#[derive(Debug, Options)]
+struct ArchiveOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        required,
+        meta = "SPEC",
+        help = "The storage backend holding the index, e.g. file:/path"
+    )]
+    storage: String,
+    #[options(free, required, help = "The path of the archive")]
+    archive: PathBuf,
+}
+
+impl Command {
+    fn run(&self) -> Result<(), std::io::Error> {
+        match self {
+            Command::Snapshot(SnapshotOptions {
+                command: Some(command),
+                ..
+            }) => match command {
+                SnapshotCommand::Create(opts) => {
+                    let storage = open_storage(&opts.storage)?;
+                    let summary = snapshot::create(storage.as_ref(), &opts.archive)?;
+                    println!(
+                        "Archived {} entries ({} bytes) to {:?}",
+                        summary.entries, summary.bytes, opts.archive
+                    );
+                }
+                SnapshotCommand::Restore(opts) => {
+                    let storage = open_storage(&opts.storage)?;
+                    let summary = snapshot::restore(&opts.archive, storage.as_ref())?;
+                    println!(
+                        "Restored {} entries from {:?} into {}",
+                        summary.entries, opts.archive, opts.storage
+                    );
+                }
+            },
+            Command::Snapshot(_) => {
+                eprintln!("{}", SnapshotOptions::usage());
+                eprintln!(
+                    "\nCommands:\n{}",
+                    SnapshotOptions::command_list().unwrap_or("")
+                );
+                std::process::exit(2);
+            }
+            Command::Upgrade(opts) => {
+                opts.require_some();
+                if let Some(spec) = &opts.storage {
+                    let upgraded = disk::upgrade_storage(open_storage(spec)?.as_ref())?;
+                    println!("Upgraded {} segments in {}", upgraded.len(), spec);
+                }
+                for path in opts.paths.iter() {
+                    match disk::upgrade(path)? {
+                        Some(version) => println!(
+                            "Upgraded {:?} from version {} to {}",
+                            path,
+                            version,
+                            disk::VERSION
+                        ),
+                        None => println!("{:?} is already version {}", path, disk::VERSION),
+                    }
+                }
+            }
+            Command::Optimize(opts) => {
+                opts.require_some();
+                if let Some(spec) = &opts.storage {
+                    let storage = open_storage(spec)?;
+                    match SegmentedIndex::open_committed(
+                        SegmentOptions::default(),
+                        storage.clone(),
+                    )? {
+                        Some(index) => {
+                            let segments = index.segment_sizes().len();
+                            index.optimize()?;
+                            index.commit()?;
+                            println!(
+                                "Optimized {} segments in {} into {}",
+                                segments,
+                                spec,
+                                index.segment_sizes().len()
+                            );
+                        }
+                        None => {
+                            for name in storage.segments()? {
+                                let mut index = Index::load_from(storage.as_ref(), &name)?;
+                                index.optimize();
+                                index.save_to(storage.as_ref(), &name)?;
+                                println!("Optimized segment {} in {}", name, spec);
+                            }
+                        }
+                    }
+                }
+                for path in opts.paths.iter() {
+                    let before = std::fs::metadata(path)?.len();
+                    let mut index = Index::open(path)?;
+                    index.optimize();
+                    let bytes = disk::to_bytes(&index)?;
+                    disk::replace(path, &bytes)?;
+                    println!(
+                        "Optimized {:?} from {} to {} bytes",
+                        path,
+                        before,
+                        bytes.len()
+                    );
+                }
+            }
+            Command::Keygen(_) => println!("{}", Key::generate().to_hex()),
+            Command::Export(opts) => opts.run()?,
+            Command::Import(opts) => opts.run()?,
+            Command::Bench(opts) => opts.run()?,
+            Command::Eval(opts) => opts.run()?,
+            Command::Compare(opts) => opts.run()?,
+            Command::Features(opts) => opts.run()?,
+            Command::Publish(opts) => {
+                let storage = open_storage(&opts.storage)?;
+                let generation = replica::publish(storage.as_ref(), &opts.dir)?;
+                println!("Published generation {} to {:?}", generation, opts.dir);
+            }
+            Command::Verify(opts) => {
+                opts.require_some();
+                let mut ok = true;
+                if let Some(spec) = &opts.storage {
+                    let storage = open_storage(spec)?;
+                    for name in storage.segments()? {
+                        let index = DiskIndex::load(storage.as_ref(), &name);
+                        ok &= print_report(&format!("{} segment {}", spec, name), index);
+                    }
+                }
+                for path in opts.paths.iter() {
+                    ok &= print_report(&path.display().to_string(), DiskIndex::open(path));
+                }
+                if !ok {
+                    std::process::exit(1);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Cli {
+    /**
+     * The ranking configuration of --config with the --set overrides applied, on top of the one
+     * the index was built with when there is no --config, or None when neither was given
+     */
+    fn ranking_config(
+        &self,
+        built: Option<&RankingConfig>,
+    ) -> Result<Option<RankingConfig>, std::io::Error> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?.ranking,
+            None if self.set.is_empty() => return Ok(None),
+            None => built.cloned().unwrap_or_default(),
+        };
+        for setting in self.set.iter() {
+            config.set(setting)?;
+        }
+        Ok(Some(config))
+    }
+
+    /**
+     * The ranking configuration the index is searched with
+     */
+    fn ranking(&self, index: &dyn IndexReader) -> Result<RankingConfig, std::io::Error> {
+        let built = index.schema().ranking_config();
+        Ok(self
+            .ranking_config(built)?
+            .or_else(|| built.cloned())
+            .unwrap_or_default())
+    }
+
+    fn schema(&self) -> Result<Schema, std::io::Error> {
+        let schema = match &self.config {
+            Some(path) => Config::from_file(path)?.analysis.schema()?,
+            None => Schema::default(),
+        };
+        match self.ranking_config(None)? {
+            Some(ranking) => schema.with_ranking(ranking),
+            None => Ok(schema),
+        }
+    }
+
+    /**
+     * Refuse to query an index built differently from the given --config, if one was given
+     */
+    fn check_config(&self, index: &DiskIndex) -> Result<(), std::io::Error> {
+        match &self.config {
+            Some(path) => index.check_schema(&Config::from_file(path)?.analysis.schema()?),
+            None => Ok(()),
+        }
+    }
+
+    fn query(
+        &self,
+        index: &dyn IndexReader,
+        query: &str,
+        settings: &Settings,
+        out: &mut dyn Write,
+    ) -> Result<(), std::io::Error> {
+        writeln!(out, "Querying for: `{}`", query)?;
+        if let Err(e) = query::validate(query) {
+            writeln!(out, "Invalid query: {}", e)?;
+            return Ok(());
+        }
+        let (documents, timings) = match self.verbose {
+            true => {
+                let (results, timings) = index.search_timed(query, usize::MAX);
+                let ids: Vec<DocumentId> = results.into_iter().map(|(id, _)| id).collect();
+                (ids, Some(timings))
+            }
+            false => (index.query_index(query), None),
+        };
+        writeln!(out, "Found {} documents", documents.len())?;
+        let mut documents: Vec<(DocumentId, usize)> = match settings.dedupe {
+            true => query::dedupe_by_title(documents, |id| {
+                index
+                    .document(id)
+                    .map(|document| document.title().to_string())
+            }),
+            false => documents.into_iter().map(|id| (id, 0)).collect(),
+        };
+        if let Some(limit) = settings.limit {
+            documents.truncate(limit);
+        }
+        let collapsed: HashMap<DocumentId, usize> = documents.iter().copied().collect();
+        let documents: Vec<DocumentId> = documents.into_iter().map(|(id, _)| id).collect();
+        let render = |id: &DocumentId, out: &mut dyn Write| -> Result<(), std::io::Error> {
+            if let Some(document) = index.document(id) {
+                writeln!(out, "{}", settings.render(&document))?;
+                match collapsed.get(id) {
+                    Some(0) | None => {}
+                    Some(count) => writeln!(out, "(and {} more with the same title)", count)?,
+                }
+                writeln!(out, "-------------------")?;
+            }
+            Ok(())
+        };
+        if self.cluster {
+            // Only the top hits are clustered, since every hit is compared with every cluster
+            let top = &documents[..documents
+                .len()
+                .min(settings.limit.unwrap_or(cluster::DEFAULT_HITS))];
+            if top.len() < documents.len() {
+                writeln!(out, "Clustering the top {} of them", top.len())?;
+            }
+            for cluster in cluster::cluster(index, top, &ClusterOptions::default()) {
+                writeln!(out, "== {} ==", cluster.label.join(", "))?;
+                for id in cluster.documents.iter() {
+                    render(id, out)?;
+                }
+            }
+        } else {
+            for id in documents.iter() {
+                render(id, out)?;
+            }
+        }
+        let related = meta::related_searches(index, query, RELATED_SEARCHES);
+        if !related.is_empty() {
+            writeln!(out, "Related searches: {}", related.join(", "))?;
+        }
+        if let Some(timings) = timings {
+            writeln!(out, "Took {}", timings)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Run a query or meta-command typed at the prompt against the index
+     */
+    fn run(
+        &self,
+        index: &dyn IndexReader,
+        input: &Input,
+        settings: &Settings,
+        out: &mut dyn Write,
+    ) -> Result<(), std::io::Error> {
+        match input {
+            Input::Query(query) => self.query(index, query, settings, out)?,
+            Input::Command(MetaCommand::Stats) => Cli::print_stats(index, out)?,
+            Input::Command(MetaCommand::Doc(id)) => match index.document(id) {
+                Some(document) => {
+                    writeln!(out, "{}", document)?;
+                    if let Some(url) = document.url() {
+                        writeln!(out, "url: {}", url)?;
+                    }
+                    let mut metadata: Vec<_> = document.metadata().iter().collect();
+                    metadata.sort();
+                    for (key, value) in metadata {
+                        writeln!(out, "{}: {}", key, value)?;
+                    }
+                }
+                None => writeln!(out, "There is no document {}", id)?,
+            },
+            Input::Command(MetaCommand::Random(n)) => {
+                for id in index.random_documents(*n) {
+                    if let Some(document) = index.document(&id) {
+                        writeln!(out, "{}\n-------------------", settings.render(&document))?;
+                    }
+                }
+            }
+            Input::Command(MetaCommand::Explain(query)) => {
+                Cli::explain(index, query, settings, out)?
+            }
+            Input::Command(MetaCommand::Save(path)) => match &settings.last_query {
+                Some(query) => {
+                    let limit = settings.limit.unwrap_or(index.size() as usize);
+                    let response = server::search(index, query, limit);
+                    meta::save_results(&response.hits, path)?;
+                    writeln!(
+                        out,
+                        "Saved {} results for `{}` to {:?}",
+                        response.hits.len(),
+                        query,
+                        path
+                    )?;
+                }
+                None => writeln!(out, "There is no query to save the results of yet")?,
+            },
+            // Everything else only changes the settings, which the repl has already done
+            Input::Command(_) => {}
+        }
+        Ok(())
+    }
+
+    fn print_stats(index: &dyn IndexReader, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        writeln!(out, "{} documents", index.size())?;
+        writeln!(out, "{} terms in the full text", index.terms().len())?;
+        let mut fields: Vec<_> = index.schema().fields().map(|(field, _)| *field).collect();
+        fields.sort_by_key(|field| field.name());
+        for field in fields {
+            writeln!(out, "{} terms in {}", index.field_terms(field).len(), field)?;
+        }
+        Ok(())
+    }
+
+    fn explain(
+        index: &dyn IndexReader,
+        query: &str,
+        settings: &Settings,
+        out: &mut dyn Write,
+    ) -> Result<(), std::io::Error> {
+        let explanation = query::explain(
+            index,
+            query,
+            settings.limit.unwrap_or(server::DEFAULT_LIMIT),
+        );
+        writeln!(out, "Normalized: {:?}", explanation.query)?;
+        for (term, idf) in explanation.idf.iter() {
+            writeln!(out, "  {}: idf {:.4}", term, idf)?;
+        }
+        for hit in explanation.hits.iter() {
+            let title = index
+                .document(&hit.id)
+                .map(|document| document.title().to_string())
+                .unwrap_or_default();
+            writeln!(out, "{:.4}  {}\t({})", hit.score, title, hit.id)?;
+            for (term, tf, score) in hit.terms.iter() {
+                writeln!(out, "    {:.4} = tf {} * idf of {}", score, tf, term)?;
+            }
+            if hit.boost != 1.0 {
+                writeln!(
+                    out,
+                    "    * {:.4} for the title and document boosts",
+                    hit.boost
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_hits(
+        query: &str,
+        response: &SearchResponse,
+        settings: &Settings,
+        out: &mut dyn Write,
+    ) -> Result<(), std::io::Error> {
+        writeln!(out, "Querying for: `{}`", query)?;
+        if !response.failed.is_empty() {
+            writeln!(out, "Could not search: {}", response.failed.join(", "))?;
+        }
+        writeln!(
+            out,
+            "Found {} of {} documents",
+            response.hits.len(),
+            response.size
+        )?;
+        for hit in response
+            .hits
+            .iter()
+            .take(settings.limit.unwrap_or(usize::MAX))
+        {
+            if let Some(document) = &hit.article {
+                writeln!(out, "{}\n-------------------", settings.render(document))?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Print the documents whose embeddings are nearest to that of the document at the url
+     */
+    fn nearest(
+        index: &dyn IndexReader,
+        vectors: &VectorIndex,
+        url: &str,
+    ) -> Result<(), std::io::Error> {
+        let url = url::Url::parse(url)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let id = document_id(&url);
+        let vector = vectors.get(&id).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("there is no embedding for {}", url),
+            )
+        })?;
+        println!("Nearest to: {}", url);
+        for (other, similarity) in vectors.search(vector, server::DEFAULT_LIMIT + 1)? {
+            if other == id {
+                continue;
+            }
+            if let Some(document) = index.document(&other) {
+                println!("{:.3}  {}\n-------------------", similarity, document);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Search with both the text of the query and its embedding, embedding every document
+     * first if their embeddings were not loaded
+     */
+    #[cfg(feature = "onnx")]
+    fn semantic(
+        &self,
+        index: &dyn IndexReader,
+        vectors: Option<VectorIndex>,
+        dir: &std::path::Path,
+    ) -> Result<(), std::io::Error> {
+        use goedesearch::embed::Embedder;
+        use goedesearch::vectors::hybrid_search;
+
+        let fusion = self.ranking(index)?.fusion();
+        let embedder = Embedder::open(dir)?;
+        let vectors = match vectors {
+            Some(vectors) => vectors,
+            None => {
+                let start = Utc::now();
+                let vectors = embedder.embed_documents(index)?;
+                println!(
+                    "Embedded {} documents in {}s",
+                    vectors.len(),
+                    Utc::now() - start
+                );
+                vectors
+            }
+        };
+        let search = |query: &str, settings: &Settings, out: &mut dyn Write| {
+            let results = embedder.embed(query).and_then(|embedding| {
+                hybrid_search(
+                    index,
+                    &vectors,
+                    query,
+                    &embedding,
+                    server::DEFAULT_LIMIT,
+                    fusion,
+                )
+            });
+            match results {
+                Ok(results) => {
+                    writeln!(out, "Querying for: `{}`", query)?;
+                    for (id, _) in results
+                        .into_iter()
+                        .take(settings.limit.unwrap_or(usize::MAX))
+                    {
+                        if let Some(document) = index.document(&id) {
+                            writeln!(out, "{}\n-------------------", settings.render(&document))?;
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to search for {}: {}", query, e),
+            }
+            Ok(())
+        };
+        match &self.query {
+            Some(query) => search(query, &Settings::default(), &mut std::io::stdout())?,
+            None => repl(
+                Vocabulary::new(index.terms()),
+                Some(index),
+                |input, settings, out| match only_queries(input) {
+                    Some(query) => search(query, settings, out),
+                    None => Ok(()),
+                },
+            ),
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "onnx"))]
+    fn semantic(
+        &self,
+        _index: &dyn IndexReader,
+        _vectors: Option<VectorIndex>,
+        _dir: &std::path::Path,
+    ) -> Result<(), std::io::Error> {
+        eprintln!("--model needs goedesearch to be built with the onnx feature");
+        std::process::exit(2);
+    }
+
+    /**
+     * How many searches over --listen or --daemon are answered at once
+     */
+    fn workers(&self) -> usize {
+        self.workers.unwrap_or_else(server::default_workers)
+    }
+
+    /**
+     * Answer searches of the index over the unix socket at the path until it fails
+     */
+    #[cfg(unix)]
+    fn daemon(
+        &self,
+        index: &dyn IndexReader,
+        path: &std::path::Path,
+    ) -> Result<(), std::io::Error> {
+        let listener = daemon::bind(path)?;
+        println!("Answering searches on {:?}", path);
+        daemon::serve(listener, self.workers(), |q, limit| {
+            Ok(server::search(index, q, limit))
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn daemon(
+        &self,
+        _index: &dyn IndexReader,
+        _path: &std::path::Path,
+    ) -> Result<(), std::io::Error> {
+        eprintln!("--daemon needs unix domain sockets, which this platform does not have");
+        std::process::exit(2);
+    }
+
+    /**
+     * Search the nodes given with --node, rather than anything local
+     */
+    fn coordinate(&self) -> Result<(), std::io::Error> {
+        let timeout = Duration::from_secs(self.node_timeout.unwrap_or(10));
+        let coordinator = Coordinator::new(self.node.clone(), timeout)?;
+        println!("Coordinating {} nodes", coordinator.nodes().len());
+
+        if let Some(addr) = &self.listen {
+            return server::serve_with(TcpListener::bind(addr)?, self.workers(), |q, limit| {
+                coordinator.search(q, limit)
+            });
+        }
+        let search = |query: &str, settings: &Settings, out: &mut dyn Write| {
+            match coordinator.search(query, server::DEFAULT_LIMIT) {
+                Ok(response) => {
+                    Cli::print_hits(query, &response, settings, out)?;
+                    if let (true, Some(timings)) = (self.verbose, response.timings) {
+                        writeln!(out, "Took {}", timings)?;
+                    }
+                }
+                Err(e) => error!("Failed to search for `{}`: {}", query, e),
+            }
+            Ok(())
+        };
+        match &self.query {
+            Some(query) => search(query, &Settings::default(), &mut std::io::stdout())?,
+            None => repl(
+                Vocabulary::default(),
+                None,
+                |input, settings, out| match only_queries(input) {
+                    Some(query) => search(query, settings, out),
+                    None => Ok(()),
+                },
+            ),
+        }
+        Ok(())
+    }
+
+    /**
+     * Search the replica of the index published at the source, pulling new generations of it
+     * in the background
+     */
+    fn follow(&self, source: &str) -> Result<(), std::io::Error> {
+        let replica = Arc::new(Replica::open(source, self.schema()?)?);
+        println!(
+            "Replicating generation {:?} from {}",
+            replica.generation(),
+            source
+        );
+        let _follower = replica::follow(&replica, Duration::from_secs(self.poll.unwrap_or(30)));
+
+        if let Some(addr) = &self.listen {
+            return server::serve_with(TcpListener::bind(addr)?, self.workers(), |q, limit| {
+                Ok(server::search(&replica.searcher()?, q, limit))
+            });
+        }
+        match &self.query {
+            Some(query) => match replica.searcher() {
+                Ok(searcher) => self.query(
+                    &searcher,
+                    query,
+                    &Settings::default(),
+                    &mut std::io::stdout(),
+                )?,
+                Err(e) => error!("Failed to search the replica: {}", e),
+            },
+            None => {
+                let vocabulary = Vocabulary::new(replica.searcher()?.terms());
+                repl(vocabulary, None, |input, settings, out| {
+                    match replica.searcher() {
+                        Ok(searcher) => self.run(&searcher, input, settings, out)?,
+                        Err(e) => error!("Failed to search the replica: {}", e),
+                    }
+                    Ok(())
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Completes the word before the cursor at the prompt to the terms in the index, and shows the
+ * top hits for what has been typed so far below it in the `:live` mode
+ */
+struct Prompt<'a> {
+    vocabulary: Vocabulary,
+    index: Option<&'a dyn IndexReader>,
+    live: bool,
+}
+
+/**
+ * The hits shown below the prompt, which unlike a String hint are not inserted into the line
+ * by pressing the right arrow
+ */
+struct LiveHits(String);
+
+impl rustyline::hint::Hint for LiveHits {
+    fn display(&self) -> &str {
+        &self.0
+    }
+
+    fn completion(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl rustyline::completion::Completer for Prompt<'_> {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        // Words can also start after the colon of a field or the quote of a phrase
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || c == ':' || c == '"')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let terms = self.vocabulary.complete(&line[start..pos]);
+        Ok((start, terms.map(String::from).collect()))
+    }
+}
+
+impl rustyline::hint::Hinter for Prompt<'_> {
+    type Hint = LiveHits;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> Option<LiveHits> {
+        let index = self.index.filter(|_| self.live)?;
+        if pos < line.len() || line.trim().is_empty() || line.starts_with(':') {
+            return None;
+        }
+        let hits: String = self
+            .vocabulary
+            .search_as_you_type(index, line, Vocabulary::LIVE_HITS)
+            .into_iter()
+            .filter_map(|(id, _)| index.document(&id))
+            .map(|document| format!("\n  {}", document.title()))
+            .collect();
+        Some(LiveHits(hits))
+    }
+}
+
+impl rustyline::highlight::Highlighter for Prompt<'_> {}
+impl rustyline::validate::Validator for Prompt<'_> {}
+impl rustyline::Helper for Prompt<'_> {}
+
+/**
+ * The query typed at a prompt which has no local index to run the other meta-commands against
+ */
+fn only_queries(input: &Input) -> Option<&str> {
+    match input {
+        Input::Query(query) => Some(query),
+        Input::Command(_) => {
+            println!("That command needs a local index, only queries can be run here");
+            None
+        }
+    }
+}
+
+/**
+ * Print the output, through $PAGER or else the internal pager when it has more lines than the
+ * terminal has rows
+ */
+fn show(output: &[u8], rows: Option<usize>) -> Result<(), std::io::Error> {
+    let text = String::from_utf8_lossy(output);
+    let rows = match rows {
+        Some(rows) if text.lines().count() >= rows => rows,
+        _ => return std::io::stdout().write_all(output),
+    };
+    if let Some(pager) = std::env::var("PAGER").ok().filter(|p| !p.trim().is_empty()) {
+        let mut words = pager.split_whitespace();
+        let spawned = std::process::Command::new(words.next().unwrap_or_default())
+            .args(words)
+            .stdin(std::process::Stdio::piped())
+            .spawn();
+        match spawned {
+            Ok(mut child) => {
+                if let Some(mut stdin) = child.stdin.take() {
+                    // The pager closing early, e.g. by quitting less, is not a failure
+                    match stdin.write_all(output) {
+                        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+                        result => result?,
+                    }
+                }
+                child.wait()?;
+                return Ok(());
+            }
+            Err(e) => warn!("Failed to run the pager `{}`: {}", pager, e),
+        }
+    }
+    meta::page(
+        &text,
+        rows,
+        std::io::stdin().lock(),
+        &mut std::io::stdout().lock(),
+    )
+}
+
+/**
+ * Read queries and meta-commands from the terminal until it is closed, running each one with
+ * the function
+ *
+ * The meta-commands which only change the settings of the session are taken care of here, and
+ * pressing tab completes words to the terms in the vocabulary. Searching as the query is typed
+ * needs the index to search, which remote indexes do not have.
+ *
+ * Whatever the function writes is paged when it does not fit in the terminal.
+ */
+fn repl<F>(vocabulary: Vocabulary, index: Option<&dyn IndexReader>, run: F)
+where
+    F: Fn(&Input, &Settings, &mut dyn Write) -> Result<(), std::io::Error>,
+{
     use rustyline::error::ReadlineError;
     use rustyline::Editor;
 
-    pretty_env_logger::init();
-    let opts = CLI::parse_args_or_exit(gumdrop::ParsingStyle::AllOptions);
-    println!("Loading data file: {:?}", opts.datafile);
+    let history = ".geodesearch-history.txt";
+    let mut rl = Editor::<Prompt>::new();
+    rl.set_helper(Some(Prompt {
+        vocabulary,
+        index,
+        live: false,
+    }));
+    let mut settings = Settings::default();
 
-    let start = Utc::now();
-    let index = engine::Index::from_file(&opts.datafile)?;
-    println!("Parsed and indexed {} entries", index.size());
-    println!(">> took {}s", (Utc::now() - start));
+    if rl.load_history(history).is_err() {
+        info!("No previous history.");
+    }
+    loop {
+        match rl.readline("query> ") {
+            Ok(line) => match line.parse::<Input>() {
+                Ok(Input::Command(MetaCommand::Help)) => println!("{}", meta::HELP),
+                Ok(Input::Command(command)) if settings.apply(&command) => {
+                    if settings.live && index.is_none() {
+                        println!("Searching as you type needs a local index");
+                        settings.live = false;
+                    }
+                    if let Some(prompt) = rl.helper_mut() {
+                        prompt.live = settings.live;
+                    }
+                }
+                Ok(input) => {
+                    if let Input::Query(query) = &input {
+                        settings.last_query = Some(query.clone());
+                    }
+                    let mut output = vec![];
+                    let rows = rl.dimensions().map(|(_, rows)| rows);
+                    if let Err(e) =
+                        run(&input, &settings, &mut output).and_then(|_| show(&output, rows))
+                    {
+                        error!("Failed to print the results: {}", e);
+                    }
+                }
+                Err(e) => println!("{}", e),
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(ReadlineError::Interrupted) => break,
+            Err(err) => {
+                error!("Failed while reading line: {:?}", err);
+                break;
+            }
+        }
+    }
 
-    if let Some(query) = &opts.query {
-        CLI::query(&index, query);
-    } else {
-        let history = ".geodesearch-history.txt";
-        let mut rl = Editor::<()>::new();
+    rl.save_history(history).expect("Failed to save history");
+}
+
+fn main() -> Result<(), std::io::Error> {
+    pretty_env_logger::init();
+    let opts = Cli::parse_args_or_exit(gumdrop::ParsingStyle::AllOptions);
+    if let Some(command) = &opts.command {
+        return command.run();
+    }
+    if !opts.node.is_empty() {
+        return opts.coordinate();
+    }
+    if let Some(source) = &opts.replica {
+        return opts.follow(source);
+    }
+    if opts.nearest.is_some() && opts.embeddings.is_none() {
+        eprintln!("--nearest needs the --embeddings to compare");
+        std::process::exit(2);
+    }
 
-        if rl.load_history(history).is_err() {
-            info!("No previous history.");
+    let start = Utc::now();
+    let key = opts.key_file.as_deref().map(Key::from_file).transpose()?;
+    let mut storage = opts.storage.as_deref().map(open_storage).transpose()?;
+    if let Some(key) = &key {
+        storage = storage
+            .map(|inner| Arc::new(EncryptedStorage::new(inner, key.clone())) as Arc<dyn Storage>);
+    }
+    let index: Box<dyn IndexReader> = match (&opts.datafile, &opts.index, &storage) {
+        (Some(datafile), None, None) if opts.shards.is_some() => {
+            let dir = match &opts.save {
+                Some(dir) => dir,
+                None => {
+                    eprintln!("--shards needs a --save directory to build them into");
+                    std::process::exit(2);
+                }
+            };
+            println!("Loading data file: {:?}", datafile);
+            let shards = opts.shards.unwrap_or(1);
+            shard::build(datafile, &opts.schema()?, shards, dir, key.as_ref())?;
+            let index = ShardedIndex::open_dir(dir, key.as_ref())?;
+            println!(
+                "Parsed and indexed {} entries into {} shards in {:?}",
+                index.size(),
+                shards,
+                dir
+            );
+            Box::new(index)
         }
-        loop {
-            match rl.readline("query> ") {
-                Ok(line) => {
-                    let start = Utc::now();
-                    CLI::query(&index, &line);
-                    println!(">> took {}s", (Utc::now() - start));
+        (Some(datafile), None, _) => {
+            println!("Loading data file: {:?}", datafile);
+            let mut builder = IndexBuilder::new()
+                .schema(opts.schema()?)
+                .trigrams(opts.trigrams)
+                .reversed_terms(opts.reversed_terms)
+                .cooccurrences(opts.related_searches)
+                .parallelism(opts.threads.unwrap_or(1))
+                .ingest(IngestOptions {
+                    strict: opts.strict,
+                    rejects: opts.rejects.clone(),
+                });
+            if let Some(path) = &opts.store {
+                let store: Arc<dyn Storage> = Arc::new(FileStorage::create(path)?);
+                builder = builder.documents(match &key {
+                    Some(key) => Arc::new(EncryptedStorage::new(store, key.clone())),
+                    None => store,
+                });
+            }
+            if let Some(storage) = &storage {
+                builder = builder.documents(storage.clone());
+            }
+            if let Some(megabytes) = opts.memory_budget {
+                builder = builder.memory_budget(megabytes * 1024 * 1024);
+            }
+            if let Some(capacity) = opts.cache_size.and_then(std::num::NonZeroUsize::new) {
+                builder = builder.cache_size(capacity);
+            }
+            // With nowhere else to put them, the postings are flushed to runs merged into --save
+            if let (Some(_), Some(path), None, None) =
+                (opts.memory_budget, &opts.save, &key, &storage)
+            {
+                let index = builder.write(datafile, path)?;
+                println!(
+                    "Parsed and indexed {} entries into {:?}",
+                    index.size(),
+                    path
+                );
+                Box::new(index)
+            } else {
+                let index = builder.from_file(datafile)?;
+                println!("Parsed and indexed {} entries", index.size());
+
+                if let Some(path) = &opts.save {
+                    match &key {
+                        Some(key) => index.save_encrypted(path, key)?,
+                        None => index.save(path)?,
+                    }
+                    println!("Saved the index to {:?}", path);
                 }
-                Err(ReadlineError::Eof) => break,
-                Err(ReadlineError::Interrupted) => break,
-                Err(err) => {
-                    error!("Failed while reading line: {:?}", err);
-                    break;
+                if let Some(storage) = &storage {
+                    index.save_to(storage.as_ref(), STORAGE_SEGMENT)?;
+                    println!(
+                        "Saved the index to {}",
+                        opts.storage.as_deref().unwrap_or_default()
+                    );
                 }
+                Box::new(index)
+            }
+        }
+        (None, Some(location), None) => {
+            println!("Opening index: {}", location);
+            let cache_dir = opts
+                .cache_dir
+                .clone()
+                .unwrap_or_else(remote::default_cache_dir);
+            if std::path::Path::new(location).is_dir() {
+                let index = ShardedIndex::open_dir(location.as_ref(), key.as_ref())?;
+                println!(
+                    "Opened {} shards of {} entries",
+                    index.shard_count(),
+                    index.size()
+                );
+                Box::new(index)
+            } else {
+                let index = remote::open(location, &cache_dir, key.as_ref())?;
+                opts.check_config(&index)?;
+                println!("Opened index of {} entries", index.size());
+                Box::new(index)
             }
         }
+        (None, None, Some(storage)) => {
+            println!(
+                "Opening index from {}",
+                opts.storage.as_deref().unwrap_or_default()
+            );
+            let index = DiskIndex::load(storage.as_ref(), STORAGE_SEGMENT)?;
+            opts.check_config(&index)?;
+            println!("Opened index of {} entries", index.size());
+            Box::new(index)
+        }
+        _ => {
+            eprintln!("Exactly one of --datafile, --index or --storage must be given");
+            std::process::exit(2);
+        }
+    };
+    println!(">> took {}s", (Utc::now() - start));
+    let settings = opts.ranking(index.as_ref())?;
+    let ranking = settings.ranking()?;
+    let mut boosted = match ranking != index.ranking() {
+        true => BoostedReader::new(index).ranking(ranking),
+        false => BoostedReader::new(index),
+    };
+    if let Some(path) = &opts.priors {
+        let weight = opts.prior_weight.or(settings.prior_weight);
+        let priors = Priors::read(std::io::BufReader::new(std::fs::File::open(path)?))?
+            .weight(weight.unwrap_or(prior::DEFAULT_WEIGHT));
+        println!("Loaded the priors of {} documents", priors.len());
+        boosted = boosted.with(priors);
+    }
+    if let Some(days) = opts.recency_half_life.or(settings.recency_half_life) {
+        let half_life = Duration::try_from_secs_f64(days * 24.0 * 60.0 * 60.0)
+            .ok()
+            .filter(|half_life| !half_life.is_zero())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("a recency half-life of {} days is not positive", days),
+                )
+            })?;
+        let key = opts
+            .recency_key
+            .as_deref()
+            .unwrap_or(boost::DEFAULT_DATE_KEY);
+        let weight = opts.recency_weight.or(settings.recency_weight);
+        boosted = boosted.with(Recency::new(key, half_life).weight(weight.unwrap_or(1.0)));
+    }
+    if let Some(key) = &opts.boost_key {
+        boosted = boosted.with(FieldBoost::new(key));
+    }
+    let index = boosted.into_reader();
+    let index: Box<dyn IndexReader> = match &opts.query_log {
+        Some(path) => Box::new(LoggedReader::new(index, QueryLog::open(path)?)),
+        None => index,
+    };
 
-        rl.save_history(history).expect("Failed to save history");
+    let vectors = opts
+        .embeddings
+        .as_deref()
+        .map(VectorIndex::load)
+        .transpose()?;
+    if let Some(vectors) = &vectors {
+        println!(
+            "Loaded {} embeddings of {} dimensions",
+            vectors.len(),
+            vectors.dimensions()
+        );
+        if let Some(url) = &opts.nearest {
+            return Cli::nearest(index.as_ref(), vectors, url);
+        }
+    }
+    if let Some(dir) = &opts.model {
+        return opts.semantic(index.as_ref(), vectors, dir);
+    }
+
+    if let Some(path) = &opts.queries_file {
+        let queries = std::io::BufReader::new(std::fs::File::open(path)?);
+        let limit = opts.limit.unwrap_or(server::DEFAULT_LIMIT);
+        let count = bulk::run(
+            index.as_ref(),
+            queries,
+            limit,
+            &mut std::io::stdout().lock(),
+        )?;
+        info!("Ran {} queries from {:?}", count, path);
+        return Ok(());
+    }
+    if let Some(path) = &opts.daemon {
+        return opts.daemon(index.as_ref(), path);
+    }
+    if let Some(addr) = &opts.listen {
+        let listener = TcpListener::bind(addr)?;
+        if let Some(url) = &opts.reranker {
+            let secs = opts.reranker_timeout.unwrap_or(1.0);
+            let timeout = Duration::try_from_secs_f64(secs)
+                .ok()
+                .filter(|timeout| !timeout.is_zero())
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("a reranker timeout of {}s is not positive", secs),
+                    )
+                })?;
+            let reranker = Reranker::new(url, timeout)
+                .candidates(opts.rerank_candidates.unwrap_or(rerank::DEFAULT_CANDIDATES));
+            return server::serve_with(listener, opts.workers(), |q, limit| {
+                let response = server::search(index.as_ref(), q, reranker.limit(limit));
+                Ok(reranker.rerank(q, response, limit))
+            });
+        }
+        return server::serve_with(listener, opts.workers(), |q, limit| {
+            Ok(server::search(index.as_ref(), q, limit))
+        });
+    }
+    if let Some(query) = &opts.query {
+        opts.query(
+            index.as_ref(),
+            query,
+            &Settings::default(),
+            &mut std::io::stdout(),
+        )?;
+    } else {
+        repl(
+            Vocabulary::new(index.terms()),
+            Some(index.as_ref()),
+            |input, settings, out| opts.run(index.as_ref(), input, settings, out),
+        );
     }
 
     Ok(())
@@ -0,0 +1,249 @@
+/**
+ * The portable module reads and writes indexes in the protobuf format described by
+ * `proto/goedesearch.proto`, so that tools which are not written in Rust can produce or consume
+ * them with nothing more than a protobuf library
+ *
+ * Unlike the persisted format of the disk module, this cannot be queried in place and has to be
+ * loaded into an Index.
+ */
+use crate::engine::{Article, Index, IndexReader};
+use crate::export::{Dump, Posting};
+use prost::Message;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use url::Url;
+
+pub const VERSION: u32 = 1;
+
+/**
+ * The messages of `proto/goedesearch.proto`, which have to be kept in step with it
+ */
+pub mod messages {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Index {
+        #[prost(uint32, tag = "1")]
+        pub version: u32,
+        #[prost(string, tag = "2")]
+        pub analysis: String,
+        #[prost(message, repeated, tag = "3")]
+        pub documents: Vec<Document>,
+        #[prost(message, repeated, tag = "4")]
+        pub terms: Vec<Term>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Document {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(string, tag = "2")]
+        pub title: String,
+        #[prost(string, optional, tag = "3")]
+        pub url: Option<String>,
+        #[prost(string, tag = "4")]
+        pub r#abstract: String,
+        #[prost(map = "string, string", tag = "5")]
+        pub metadata: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Term {
+        #[prost(string, tag = "1")]
+        pub field: String,
+        #[prost(string, tag = "2")]
+        pub term: String,
+        #[prost(message, repeated, tag = "3")]
+        pub postings: Vec<Posting>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Posting {
+        #[prost(uint64, tag = "1")]
+        pub document: u64,
+        #[prost(double, tag = "2")]
+        pub frequency: f64,
+        #[prost(uint32, repeated, tag = "3")]
+        pub positions: Vec<u32>,
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/**
+ * Convert the entire contents of the reader into its protobuf message
+ */
+pub fn to_message<R: IndexReader + ?Sized>(reader: &R) -> Result<messages::Index, Error> {
+    let dump = Dump::from_reader(reader);
+    let analysis = match &dump.analysis {
+        Some(config) => serde_json::to_string(config)?,
+        None => String::new(),
+    };
+    let documents = dump
+        .documents
+        .into_iter()
+        .map(|article| messages::Document {
+            id: article.id(),
+            title: article.title().to_string(),
+            url: article.url().map(|url| url.to_string()),
+            r#abstract: article.abstract_text().to_string(),
+            metadata: article.metadata().clone(),
+        })
+        .collect();
+
+    let mut terms: Vec<messages::Term> = dump
+        .terms
+        .into_iter()
+        .map(|(term, postings)| messages::Term {
+            field: String::new(),
+            term,
+            postings: postings
+                .into_iter()
+                .map(|posting| messages::Posting {
+                    document: posting.document,
+                    frequency: posting.frequency,
+                    positions: posting.positions.iter().map(|p| *p as u32).collect(),
+                })
+                .collect(),
+        })
+        .collect();
+    for (field, postings) in dump.fields {
+        for (term, documents) in postings {
+            terms.push(messages::Term {
+                field: field.clone(),
+                term,
+                postings: documents
+                    .into_iter()
+                    .map(|document| messages::Posting {
+                        document,
+                        ..Default::default()
+                    })
+                    .collect(),
+            });
+        }
+    }
+
+    Ok(messages::Index {
+        version: VERSION,
+        analysis,
+        documents,
+        terms,
+    })
+}
+
+/**
+ * Load the protobuf message into a new in-memory Index
+ */
+pub fn from_message(message: messages::Index) -> Result<Index, Error> {
+    if message.version > VERSION {
+        return Err(invalid(format!(
+            "the index is in version {} of the portable format, newer than {}",
+            message.version, VERSION
+        )));
+    }
+    let analysis = if message.analysis.is_empty() {
+        None
+    } else {
+        Some(serde_json::from_str(&message.analysis)?)
+    };
+
+    let mut documents = Vec::with_capacity(message.documents.len());
+    for document in message.documents {
+        let url = document
+            .url
+            .as_deref()
+            .map(Url::parse)
+            .transpose()
+            .map_err(|e| invalid(format!("document {} has a bad url: {}", document.id, e)))?;
+        documents.push(Article::from_parts(
+            document.id,
+            document.title,
+            document.r#abstract,
+            url,
+            document.metadata,
+        ));
+    }
+
+    let mut terms = BTreeMap::new();
+    let mut fields: BTreeMap<String, BTreeMap<String, Vec<u64>>> = BTreeMap::new();
+    for term in message.terms {
+        if term.field.is_empty() {
+            let postings: Vec<Posting> = term
+                .postings
+                .into_iter()
+                .map(|posting| Posting {
+                    document: posting.document,
+                    frequency: posting.frequency,
+                    positions: posting.positions.iter().map(|p| *p as usize).collect(),
+                })
+                .collect();
+            terms.insert(term.term, postings);
+        } else {
+            let mut documents: Vec<u64> = term.postings.iter().map(|p| p.document).collect();
+            documents.sort_unstable();
+            fields
+                .entry(term.field)
+                .or_default()
+                .insert(term.term, documents);
+        }
+    }
+
+    Dump {
+        analysis,
+        documents,
+        terms,
+        fields,
+        schema: Default::default(),
+    }
+    .validated()?
+    .into_index()
+}
+
+/**
+ * Write the entire contents of the reader in the portable format
+ */
+pub fn write<R: IndexReader + ?Sized, W: Write>(reader: &R, mut output: W) -> Result<(), Error> {
+    output.write_all(&to_message(reader)?.encode_to_vec())
+}
+
+/**
+ * Load an index written in the portable format
+ */
+pub fn read<R: Read>(mut input: R) -> Result<Index, Error> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    let message = messages::Index::decode(bytes.as_slice())
+        .map_err(|e| invalid(format!("the portable index cannot be decoded: {}", e)))?;
+    from_message(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_portable_round_trip() -> Result<(), Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let mut bytes = vec![];
+        write(&index, &mut bytes)?;
+        let imported = read(bytes.as_slice())?;
+
+        assert_eq!(imported.size(), index.size());
+        assert_eq!(
+            imported.query_index("anarchism"),
+            index.query_index("anarchism")
+        );
+        assert_eq!(
+            imported.query_index("title:history"),
+            index.query_index("title:history")
+        );
+        let (mut before, mut after) = (vec![], vec![]);
+        crate::export::to_json(&index, &mut before)?;
+        crate::export::to_json(&imported, &mut after)?;
+        assert!(before == after);
+        Ok(())
+    }
+}
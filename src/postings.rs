@@ -0,0 +1,340 @@
+/**
+ * The postings module contains the compact representation of the documents which contain a
+ * term, which is how the in-memory Index keeps its postings
+ *
+ * Most terms occur in only a handful of documents, and a HashSet for each of them costs an
+ * allocation and far more memory than the ids themselves. Postings keep up to INLINE_POSTINGS
+ * ids inline instead, and only move them into a HashSet once there are more than that.
+ */
+use crate::engine::DocumentId;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::iter::FromIterator;
+
+/**
+ * The most document ids which are kept inline, which keeps Postings no larger than an empty
+ * HashSet
+ */
+pub const INLINE_POSTINGS: usize = 4;
+
+/**
+ * The documents which contain a term
+ *
+ * Postings which have grown into a HashSet stay one when documents are removed again, so that
+ * adding and removing the same document does not move the ids back and forth, until
+ * `shrink_to_fit()` is called.
+ */
+#[derive(Clone, Debug)]
+pub enum Postings {
+    Inline {
+        len: u8,
+        ids: [DocumentId; INLINE_POSTINGS],
+    },
+    Set(HashSet<DocumentId>),
+}
+
+impl Default for Postings {
+    fn default() -> Self {
+        Self::Inline {
+            len: 0,
+            ids: [0; INLINE_POSTINGS],
+        }
+    }
+}
+
+impl Postings {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Inline { len, .. } => *len as usize,
+            Self::Set(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: &DocumentId) -> bool {
+        match self {
+            Self::Inline { len, ids } => ids[..*len as usize].contains(id),
+            Self::Set(set) => set.contains(id),
+        }
+    }
+
+    /**
+     * Add the document, returning whether it was not already there
+     */
+    pub fn insert(&mut self, id: DocumentId) -> bool {
+        match self {
+            Self::Inline { len, ids } => {
+                let count = *len as usize;
+                if ids[..count].contains(&id) {
+                    return false;
+                }
+                if count < INLINE_POSTINGS {
+                    ids[count] = id;
+                    *len += 1;
+                } else {
+                    let mut set: HashSet<DocumentId> = ids.iter().copied().collect();
+                    set.insert(id);
+                    *self = Self::Set(set);
+                }
+                true
+            }
+            Self::Set(set) => set.insert(id),
+        }
+    }
+
+    /**
+     * Remove the document, returning whether it was there
+     */
+    pub fn remove(&mut self, id: &DocumentId) -> bool {
+        match self {
+            Self::Inline { len, ids } => {
+                let count = *len as usize;
+                match ids[..count].iter().position(|other| other == id) {
+                    Some(position) => {
+                        ids.copy_within(position + 1..count, position);
+                        *len -= 1;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Self::Set(set) => set.remove(id),
+        }
+    }
+
+    /**
+     * Move the documents back inline if there are few enough of them, or otherwise release the
+     * memory the HashSet has left over
+     */
+    pub fn shrink_to_fit(&mut self) {
+        if let Self::Set(set) = self {
+            if set.len() <= INLINE_POSTINGS {
+                *self = set.iter().copied().collect();
+            } else {
+                set.shrink_to_fit();
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Self::Inline { len, ids } => Iter::Inline(ids[..*len as usize].iter()),
+            Self::Set(set) => Iter::Set(set.iter()),
+        }
+    }
+
+    /**
+     * The documents as a HashSet, which is only built when they are inline
+     */
+    pub fn to_set(&self) -> Cow<'_, HashSet<DocumentId>> {
+        match self {
+            Self::Inline { .. } => Cow::Owned(self.iter().copied().collect()),
+            Self::Set(set) => Cow::Borrowed(set),
+        }
+    }
+}
+
+/**
+ * The documents which contain a term, borrowed from a reader in whatever form it keeps them
+ *
+ * This is what queries are evaluated with, so that looking up the postings of a term does not
+ * have to copy them into a HashSet first.
+ */
+#[derive(Clone, Debug)]
+pub enum PostingsRef<'a> {
+    /**
+     * Documents in ascending order, which are looked up by binary search
+     */
+    Sorted(&'a [DocumentId]),
+    Postings(&'a Postings),
+    Set(Cow<'a, HashSet<DocumentId>>),
+}
+
+impl<'a> PostingsRef<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Sorted(ids) => ids.len(),
+            Self::Postings(postings) => postings.len(),
+            Self::Set(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: &DocumentId) -> bool {
+        match self {
+            Self::Sorted(ids) => ids.binary_search(id).is_ok(),
+            Self::Postings(postings) => postings.contains(id),
+            Self::Set(set) => set.contains(id),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Self::Sorted(ids) => Iter::Inline(ids.iter()),
+            Self::Postings(postings) => postings.iter(),
+            Self::Set(set) => Iter::Set(set.iter()),
+        }
+    }
+}
+
+/**
+ * An iterator over the documents of a term in ascending order, which borrows them from readers
+ * that keep them sorted and only owns a sorted copy for the rest
+ */
+#[derive(Clone, Debug)]
+pub struct PostingList<'a> {
+    ids: Cow<'a, [DocumentId]>,
+    next: usize,
+}
+
+impl<'a> PostingList<'a> {
+    pub fn new(ids: Cow<'a, [DocumentId]>) -> Self {
+        Self { ids, next: 0 }
+    }
+
+    /**
+     * The documents in ascending order, borrowing them if they already are
+     */
+    pub fn of(postings: Option<PostingsRef<'a>>) -> Self {
+        match postings {
+            Some(PostingsRef::Sorted(ids)) => Self::new(Cow::Borrowed(ids)),
+            Some(postings) => {
+                let mut ids: Vec<DocumentId> = postings.iter().copied().collect();
+                ids.sort_unstable();
+                Self::new(Cow::Owned(ids))
+            }
+            None => Self::new(Cow::Borrowed(&[])),
+        }
+    }
+}
+
+impl<'a> Iterator for PostingList<'a> {
+    type Item = DocumentId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.ids.get(self.next).copied()?;
+        self.next += 1;
+        Some(id)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.ids.len() - self.next;
+        (left, Some(left))
+    }
+}
+
+/**
+ * Postings are equal when they contain the same documents, however they are kept
+ */
+impl PartialEq for Postings {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|id| other.contains(id))
+    }
+}
+
+impl Eq for Postings {}
+
+impl Extend<DocumentId> for Postings {
+    fn extend<I: IntoIterator<Item = DocumentId>>(&mut self, ids: I) {
+        for id in ids {
+            self.insert(id);
+        }
+    }
+}
+
+impl FromIterator<DocumentId> for Postings {
+    fn from_iter<I: IntoIterator<Item = DocumentId>>(ids: I) -> Self {
+        let mut postings = Self::default();
+        postings.extend(ids);
+        postings
+    }
+}
+
+/**
+ * An iterator over the documents of Postings, in no particular order, or over sorted ones in
+ * ascending order
+ */
+pub enum Iter<'a> {
+    Inline(std::slice::Iter<'a, DocumentId>),
+    Set(std::collections::hash_set::Iter<'a, DocumentId>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a DocumentId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Inline(ids) => ids.next(),
+            Self::Set(ids) => ids.next(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Postings {
+    type Item = &'a DocumentId;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_postings() {
+        let mut postings = Postings::default();
+        assert!(postings.is_empty());
+        assert!(std::mem::size_of::<Postings>() <= std::mem::size_of::<HashSet<DocumentId>>());
+
+        for id in 1..=INLINE_POSTINGS as DocumentId {
+            assert!(postings.insert(id));
+        }
+        assert!(!postings.insert(1));
+        assert!(matches!(postings, Postings::Inline { .. }));
+        assert!(matches!(postings.to_set(), Cow::Owned(_)));
+
+        assert!(postings.remove(&2));
+        assert!(!postings.remove(&2));
+        assert_eq!(postings.len(), INLINE_POSTINGS - 1);
+        assert!(postings.contains(&(INLINE_POSTINGS as DocumentId)));
+
+        postings.extend(10..20);
+        assert!(matches!(postings, Postings::Set(_)));
+        assert_eq!(postings.len(), INLINE_POSTINGS - 1 + 10);
+        assert!(matches!(postings.to_set(), Cow::Borrowed(_)));
+        for id in 10..20 {
+            postings.remove(&id);
+        }
+        assert!(matches!(postings, Postings::Set(_)));
+        postings.shrink_to_fit();
+        assert!(matches!(postings, Postings::Inline { len: 3, .. }));
+        postings.extend(10..20);
+
+        let same: Postings = postings.iter().copied().collect();
+        let inline: Postings = vec![3, 1].into_iter().collect();
+        assert_eq!(same, postings);
+        assert_eq!(inline, Postings::Set(vec![1, 3].into_iter().collect()));
+        assert_ne!(inline, postings);
+
+        let borrowed = PostingsRef::Postings(&inline);
+        assert_eq!(borrowed.len(), 2);
+        assert!(borrowed.contains(&3) && !borrowed.contains(&2));
+        let mut ids: Vec<DocumentId> = borrowed.iter().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(PostingList::of(Some(borrowed)).collect::<Vec<_>>(), ids);
+        let sorted = [2, 4];
+        let list = PostingList::of(Some(PostingsRef::Sorted(&sorted)));
+        assert!(matches!(list.ids, Cow::Borrowed(_)));
+        assert_eq!(PostingList::of(None).next(), None);
+    }
+}
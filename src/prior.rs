@@ -0,0 +1,137 @@
+/**
+ * The prior module blends a static rank of each document, known before any query is run, into
+ * the scores of the documents matching a query
+ *
+ * The priors are read from lines of `DOCUMENT VALUE`, where the document is given by its id or
+ * its url and the value is e.g. the number of times the article was viewed, so that popular
+ * articles outrank obscure ones which match an ambiguous query just as well.
+ */
+use crate::boost::Boost;
+use crate::engine::{DocumentId, IndexReader};
+use crate::eval::parse_document;
+use std::collections::HashMap;
+use std::io::{BufRead, Error, ErrorKind};
+
+/**
+ * The weight priors are blended in with by default
+ */
+pub const DEFAULT_WEIGHT: f64 = 1.0;
+
+/**
+ * The static rank of each document
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Priors {
+    values: HashMap<DocumentId, f64>,
+    /**
+     * The logarithm of the largest value, which the others are scaled by
+     */
+    scale: f64,
+    weight: f64,
+}
+
+impl Priors {
+    pub fn new(values: HashMap<DocumentId, f64>) -> Self {
+        let largest = values.values().copied().fold(0.0, f64::max);
+        Self {
+            values,
+            scale: largest.ln_1p(),
+            weight: DEFAULT_WEIGHT,
+        }
+    }
+
+    /**
+     * Read the priors from lines of `DOCUMENT VALUE`, skipping blank ones
+     */
+    pub fn read<R: BufRead>(input: R) -> Result<Self, Error> {
+        let invalid = |number: usize, message: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, message),
+            )
+        };
+        let mut values = HashMap::new();
+        for (number, line) in input.lines().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => continue,
+                [document, value] => {
+                    let document = parse_document(document).ok_or_else(|| {
+                        invalid(number, "the document is neither an id nor a url")
+                    })?;
+                    let value: f64 = value
+                        .parse()
+                        .ok()
+                        .filter(|value: &f64| *value >= 0.0)
+                        .ok_or_else(|| invalid(number, "the value is not a positive number"))?;
+                    values.insert(document, value);
+                }
+                _ => return Err(invalid(number, "expected DOCUMENT VALUE")),
+            }
+        }
+        Ok(Self::new(values))
+    }
+
+    /**
+     * How strongly the priors affect the scores, zero ignores them and with one the most
+     * popular document scores twice what it would without them
+     */
+    pub fn weight(mut self, weight: f64) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /**
+     * What the scores of the document are multiplied by, which grows with the logarithm of its
+     * value, and is one for documents without one
+     */
+    pub fn boost(&self, id: DocumentId) -> f64 {
+        match self.values.get(&id) {
+            Some(value) if self.scale > 0.0 => 1.0 + self.weight * value.ln_1p() / self.scale,
+            _ => 1.0,
+        }
+    }
+}
+
+impl Boost for Priors {
+    fn boost(&self, _reader: &dyn IndexReader, id: DocumentId) -> f64 {
+        Priors::boost(self, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boost::BoostedReader;
+    use crate::engine::Index;
+
+    #[test]
+    fn test_priors() -> Result<(), Error> {
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let results = index.search("history", 10);
+        let (last, _) = results[results.len() - 1];
+
+        let priors = Priors::read(format!("{} 1000\n\n{} 0\n", last, results[0].0).as_bytes())?;
+        assert_eq!(priors.len(), 2);
+        assert_eq!(priors.boost(last), 2.0);
+        assert_eq!(priors.boost(results[0].0), 1.0);
+        assert_eq!(priors.boost(0), 1.0);
+        assert!(Priors::read("1 lots".as_bytes()).is_err());
+        assert!(Priors::read("1 -3".as_bytes()).is_err());
+
+        let reader = BoostedReader::new(Box::new(index)).with(priors.weight(100.0));
+        let boosted = reader.search("history", 10);
+        assert_eq!(boosted[0].0, last);
+        assert_eq!(boosted.len(), results.len());
+        Ok(())
+    }
+}
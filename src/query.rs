@@ -0,0 +1,1422 @@
+/*
+ * The query module is responsible for turning the raw query string typed by the user into the
+ * clauses which the engine knows how to evaluate
+ */
+use crate::engine::{normalize_title, DocumentId, IndexReader};
+use crate::filters::Token;
+use crate::postings::PostingsRef;
+use crate::schema::{Field, Schema};
+use crate::scoring::{Averages, EarlyExit, ProximityBonus, Ranking, Scorer, TitleBonus};
+use crate::spell::edit_distance;
+use crate::telemetry;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tracing::debug_span;
+
+/**
+ * A single clause of a parsed query
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Clause {
+    /**
+     * Free text which will be normalized and looked up in the inverted index
+     */
+    Text(String),
+    /**
+     * A substring which must appear somewhere in the document, e.g. `contains:ography`
+     */
+    Contains(String),
+    /**
+     * A value which must be found in a specific field, e.g. `domain:en.wikipedia.org`
+     */
+    Field(Field, String),
+    /**
+     * Text which must appear in the document as a phrase, e.g. `"statue of liberty"`, with at
+     * most the given number of other tokens between its own, e.g. `"new york city"~2`
+     */
+    Phrase(String, usize),
+    /**
+     * A regular expression which at least one whole term of the document must match, e.g.
+     * `/colou?r/`
+     */
+    Regex(String),
+    /**
+     * A leading wildcard which at least one term of the document must end with, e.g. `*ology`
+     */
+    Suffix(String),
+}
+
+/**
+ * The most terms of the index a single regular expression or wildcard is expanded to, beyond
+ * which the rest are left out
+ */
+pub const MAX_EXPANSIONS: usize = 1024;
+
+/**
+ * The most other tokens a phrase can allow between its own, larger slops being clamped to it so
+ * that phrases do not stretch from the title into the abstract
+ */
+pub const MAX_SLOP: usize = 16;
+
+/**
+ * What is wrong with a query which does not follow the query syntax, along with the (one-based)
+ * column of the character where it went wrong
+ */
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum QueryError {
+    #[error("unbalanced quote at column {column}")]
+    UnbalancedQuote { column: usize },
+    #[error("unknown field '{field}' at column {column}{}", match suggestion {
+        Some(suggestion) => format!(", did you mean '{}'?", suggestion),
+        None => String::new(),
+    })]
+    UnknownField {
+        column: usize,
+        field: String,
+        suggestion: Option<String>,
+    },
+    #[error("missing a value for '{field}' at column {column}")]
+    MissingValue { column: usize, field: String },
+    #[error("invalid regular expression at column {column}: {message}")]
+    InvalidRegex { column: usize, message: String },
+}
+
+impl From<QueryError> for std::io::Error {
+    fn from(e: QueryError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/**
+ * Parse the query string into its clauses
+ *
+ * Everything which is not a recognized operator is gathered up into a single Text clause so
+ * that the normalization of free text behaves the same as it always has
+ */
+pub fn parse(query: &str) -> Vec<Clause> {
+    scan(query, false).unwrap_or_default()
+}
+
+/**
+ * Parse the query string like `parse`, but fail on anything which does not follow the query
+ * syntax instead of treating it as text
+ *
+ * Words such as `titel:history` whose prefix looks like a field are unknown fields, unless the
+ * value starts with `//` like that of a url.
+ */
+pub fn validate(query: &str) -> Result<Vec<Clause>, QueryError> {
+    scan(query, true)
+}
+
+fn scan(query: &str, strict: bool) -> Result<Vec<Clause>, QueryError> {
+    let _span = debug_span!("parse", query).entered();
+    let mut clauses = vec![];
+    let mut text = vec![];
+    let mut rest = query;
+    let column = |rest: &str| query[..query.len() - rest.len()].chars().count() + 1;
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+
+        // An unterminated quote runs until the end of the query
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let (phrase, remainder) = match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None if strict => {
+                    return Err(QueryError::UnbalancedQuote {
+                        column: column(rest),
+                    })
+                }
+                None => (quoted, ""),
+            };
+            // A slop is only recognized right after the closing quote
+            let (slop, remainder) = match remainder.strip_prefix('~') {
+                Some(after) => {
+                    let digits = after.find(|c: char| !c.is_ascii_digit());
+                    let (slop, after) = after.split_at(digits.unwrap_or(after.len()));
+                    match slop.parse::<usize>() {
+                        Ok(slop) => (slop.min(MAX_SLOP), after),
+                        Err(_) => (0, remainder),
+                    }
+                }
+                None => (0, remainder),
+            };
+            if !phrase.trim().is_empty() {
+                clauses.push(Clause::Phrase(phrase.trim().to_string(), slop));
+            }
+            rest = remainder;
+            continue;
+        }
+
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..end];
+        let start = rest;
+        rest = &rest[end..];
+
+        if let Some(suffix) = word
+            .strip_prefix('*')
+            .filter(|suffix| !suffix.is_empty() && !suffix.contains('*'))
+        {
+            clauses.push(Clause::Suffix(suffix.to_string()));
+            continue;
+        }
+
+        if let Some(pattern) = word
+            .strip_prefix('/')
+            .and_then(|word| word.strip_suffix('/'))
+            .filter(|pattern| !pattern.is_empty())
+        {
+            if strict {
+                if let Err(e) = term_regex(pattern) {
+                    return Err(QueryError::InvalidRegex {
+                        column: column(start),
+                        message: e.to_string(),
+                    });
+                }
+            }
+            clauses.push(Clause::Regex(pattern.to_string()));
+            continue;
+        }
+
+        if let Some((prefix, value)) = word.split_once(':') {
+            let known = prefix == "contains" || prefix.parse::<Field>().is_ok();
+            if !value.is_empty() {
+                if prefix == "contains" {
+                    clauses.push(Clause::Contains(value.to_lowercase()));
+                    continue;
+                }
+                if let Ok(field) = prefix.parse::<Field>() {
+                    clauses.push(Clause::Field(field, value.to_string()));
+                    continue;
+                }
+            }
+            if strict && known {
+                return Err(QueryError::MissingValue {
+                    column: column(start),
+                    field: prefix.to_string(),
+                });
+            }
+            let looks_like_field = !prefix.is_empty()
+                && prefix.chars().all(|c| c.is_ascii_alphabetic())
+                && !value.is_empty()
+                && !value.starts_with("//");
+            if strict && looks_like_field {
+                return Err(QueryError::UnknownField {
+                    column: column(start),
+                    field: prefix.to_string(),
+                    suggestion: suggest_field(prefix),
+                });
+            }
+        }
+        text.push(word);
+    }
+
+    if !text.is_empty() {
+        clauses.insert(0, Clause::Text(text.join(" ")));
+    }
+    Ok(clauses)
+}
+
+/**
+ * Compile the pattern of a Regex clause to match whole terms, ignoring case
+ */
+fn term_regex(pattern: &str) -> Result<regex::Regex, regex::Error> {
+    regex::RegexBuilder::new(&format!("^(?:{})$", pattern))
+        .case_insensitive(true)
+        .build()
+}
+
+/**
+ * The terms of the index which the pattern of a Regex clause matches, in order, or none when
+ * the pattern is invalid
+ */
+pub fn expand_regex<R: IndexReader + ?Sized>(reader: &R, pattern: &str) -> Vec<String> {
+    let regex = match term_regex(pattern) {
+        Ok(regex) => regex,
+        Err(e) => {
+            warn!(
+                "Ignoring the invalid regular expression `{}`: {}",
+                pattern, e
+            );
+            return vec![];
+        }
+    };
+    let mut terms: Vec<String> = reader
+        .terms()
+        .into_iter()
+        .filter(|term| regex.is_match(term))
+        .collect();
+    terms.sort_unstable();
+    if terms.len() > MAX_EXPANSIONS {
+        warn!(
+            "`/{}/` matches {} terms, only the first {} are searched for",
+            pattern,
+            terms.len(),
+            MAX_EXPANSIONS
+        );
+        terms.truncate(MAX_EXPANSIONS);
+    }
+    terms
+}
+
+/**
+ * The terms of the index which end with the (analyzed) suffix, in order, from the reversed term
+ * index if the reader has one or else by checking every term
+ */
+pub fn expand_suffix<R: IndexReader + ?Sized>(reader: &R, suffix: &str) -> Vec<String> {
+    let mut terms = reader.terms_with_suffix(suffix).unwrap_or_else(|| {
+        reader
+            .terms()
+            .into_iter()
+            .filter(|term| term.ends_with(suffix))
+            .collect()
+    });
+    terms.sort_unstable();
+    if terms.len() > MAX_EXPANSIONS {
+        warn!(
+            "`*{}` matches {} terms, only the first {} are searched for",
+            suffix,
+            terms.len(),
+            MAX_EXPANSIONS
+        );
+        terms.truncate(MAX_EXPANSIONS);
+    }
+    terms
+}
+
+/**
+ * The operator closest to the misspelled one, if any is close enough to be what was meant
+ */
+fn suggest_field(prefix: &str) -> Option<String> {
+    let prefix = prefix.to_lowercase();
+    Field::ALL
+        .iter()
+        .map(|field| field.name())
+        .chain(["contains"])
+        .map(|name| (edit_distance(&prefix, name), name))
+        .filter(|(distance, name)| *distance <= 2 && *distance < name.len())
+        .min()
+        .map(|(_, name)| name.to_string())
+}
+
+/**
+ * Something which transforms the clauses of a parsed query before it is evaluated, e.g. to
+ * expand acronyms or to add filters
+ *
+ * Closures taking and returning the clauses are Rewriters too.
+ */
+pub trait Rewriter: Send + Sync {
+    fn rewrite(&self, clauses: Vec<Clause>) -> Vec<Clause>;
+}
+
+impl<F> Rewriter for F
+where
+    F: Fn(Vec<Clause>) -> Vec<Clause> + Send + Sync,
+{
+    fn rewrite(&self, clauses: Vec<Clause>) -> Vec<Clause> {
+        self(clauses)
+    }
+}
+
+/**
+ * A query which has been parsed and had all of its text run through the Schema's analyzers,
+ * ready to be evaluated against an index
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NormalizedQuery {
+    pub terms: Vec<String>,
+    pub substrings: Vec<String>,
+    pub fields: Vec<(Field, Vec<String>)>,
+    /**
+     * The analyzed tokens of each phrase, along with how many other tokens can come between them
+     */
+    pub phrases: Vec<(Vec<Token>, usize)>,
+    /**
+     * The patterns of the Regex clauses, which are expanded to the terms they match when the
+     * query is evaluated
+     */
+    pub regexes: Vec<String>,
+    /**
+     * The suffixes of the wildcard clauses, analyzed like the terms they are to match the end
+     * of, which are expanded to those terms when the query is evaluated
+     */
+    pub suffixes: Vec<String>,
+    /**
+     * The free text of the query as analyzed for the title field, which documents get a bonus
+     * for matching
+     */
+    pub title: Vec<String>,
+}
+
+impl NormalizedQuery {
+    /**
+     * The key identifying this query in the QueryCache, queries which normalize to the same
+     * thing always have the same results
+     */
+    pub fn key(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/**
+ * How long each phase of evaluating a query took
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct QueryTimings {
+    /**
+     * Parsing, rewriting and analyzing the query
+     */
+    pub parse: Duration,
+    /**
+     * Finding the documents which match the query
+     */
+    pub candidates: Duration,
+    pub scoring: Duration,
+    pub sort: Duration,
+}
+
+impl QueryTimings {
+    pub fn total(&self) -> Duration {
+        self.parse + self.candidates + self.scoring + self.sort
+    }
+
+    /**
+     * The longest time of each phase of either, for the timings of searches which ran in
+     * parallel
+     */
+    pub fn slowest(self, other: Self) -> Self {
+        Self {
+            parse: self.parse.max(other.parse),
+            candidates: self.candidates.max(other.candidates),
+            scoring: self.scoring.max(other.scoring),
+            sort: self.sort.max(other.sort),
+        }
+    }
+}
+
+impl std::fmt::Display for QueryTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} (parse {:?}, candidates {:?}, scoring {:?}, sort {:?})",
+            self.total(),
+            self.parse,
+            self.candidates,
+            self.scoring,
+            self.sort
+        )
+    }
+}
+
+/**
+ * Parse the query and analyze each of its clauses with the appropriate Analyzer
+ */
+pub fn normalize(schema: &Schema, query: &str) -> NormalizedQuery {
+    normalize_clauses(schema, parse(query))
+}
+
+/**
+ * Analyze each of the already parsed clauses with the appropriate Analyzer
+ */
+pub fn normalize_clauses(schema: &Schema, clauses: Vec<Clause>) -> NormalizedQuery {
+    let _span = debug_span!("analyze").entered();
+    let mut normalized = NormalizedQuery::default();
+    let mut text = vec![];
+
+    for clause in clauses {
+        match clause {
+            Clause::Text(t) => text.push(t),
+            Clause::Contains(needle) => normalized.substrings.push(needle),
+            Clause::Field(field, value) => {
+                // Fields which are not searchable in the Schema never match anything
+                let terms = match schema.analyzer(field) {
+                    Some(analyzer) => analyzer.terms(&value),
+                    None => {
+                        warn!("The field `{}` is not searchable in this index", field);
+                        vec![]
+                    }
+                };
+                normalized.fields.push((field, terms));
+            }
+            Clause::Phrase(phrase, slop) => {
+                let tokens = schema.text_analyzer().analyze(&phrase);
+                if !tokens.is_empty() {
+                    normalized.phrases.push((tokens, slop));
+                }
+            }
+            Clause::Regex(pattern) => normalized.regexes.push(pattern),
+            Clause::Suffix(suffix) => {
+                // A stop word or a suffix the analyzer strips away entirely is left as it is
+                let analyzed = schema.text_analyzer().terms(&suffix).into_iter().next();
+                normalized
+                    .suffixes
+                    .push(analyzed.unwrap_or_else(|| suffix.to_lowercase()));
+            }
+        }
+    }
+
+    let text = text.join(" ");
+    normalized.terms = schema.text_analyzer().terms(&text);
+    normalized.title = schema
+        .analyzer(Field::Title)
+        .map(|analyzer| analyzer.terms(&text))
+        .unwrap_or_default();
+    normalized
+}
+
+/**
+ * Evaluate the normalized query against the index, returning the matching documents ordered by
+ * their score
+ */
+pub fn execute<R: IndexReader + ?Sized>(reader: &R, query: &NormalizedQuery) -> Vec<DocumentId> {
+    execute_scored(reader, query)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/**
+ * Evaluate the normalized query against the index, returning the matching documents along with
+ * their scores as the reader's Ranking has them, highest first
+ */
+pub fn execute_scored<R: IndexReader + ?Sized>(
+    reader: &R,
+    query: &NormalizedQuery,
+) -> Vec<(DocumentId, f64)> {
+    execute_ranked(reader, query, &reader.ranking())
+}
+
+/**
+ * Evaluate the normalized query like `execute_scored`, scoring the matching documents as the
+ * Ranking says to
+ */
+pub fn execute_ranked<R: IndexReader + ?Sized>(
+    reader: &R,
+    query: &NormalizedQuery,
+    ranking: &Ranking,
+) -> Vec<(DocumentId, f64)> {
+    execute_timed(reader, query, ranking, &mut QueryTimings::default())
+}
+
+/**
+ * Evaluate the normalized query like `execute_ranked`, adding how long finding, scoring and
+ * sorting the documents took to the timings
+ */
+pub fn execute_timed<R: IndexReader + ?Sized>(
+    reader: &R,
+    query: &NormalizedQuery,
+    ranking: &Ranking,
+    timings: &mut QueryTimings,
+) -> Vec<(DocumentId, f64)> {
+    let start = Instant::now();
+    let averages = ranking.scorer.averages(reader);
+    let (documents, weights) = candidates(reader, query, &ranking.scorer, &averages);
+    timings.candidates += start.elapsed();
+    let scored: Vec<(&String, f64)> = weights.iter().map(|(term, w)| (term, *w)).collect();
+    let title = TitleMatch::new(reader, query, ranking.title);
+    let proximity = Proximity::new(query, ranking.proximity);
+    rank(
+        reader,
+        documents.into_iter(),
+        &scored,
+        (&ranking.scorer, &averages),
+        Some((&title, &proximity)),
+        ranking.early_exit,
+        timings,
+    )
+}
+
+/**
+ * Evaluate the normalized query lazily, only scoring each matching document once the Hits reach
+ * it, which are in ascending order of document id until `by_score()` is called on them
+ */
+pub fn execute_iter<'a, R: IndexReader + ?Sized>(
+    reader: &'a R,
+    query: &NormalizedQuery,
+    ranking: &Ranking,
+) -> Hits<'a, R> {
+    let averages = ranking.scorer.averages(reader);
+    let (documents, weights) = candidates(reader, query, &ranking.scorer, &averages);
+    let mut documents: Vec<DocumentId> = documents.into_iter().collect();
+    documents.sort_unstable();
+    Hits {
+        reader,
+        documents: documents.into_iter(),
+        weights,
+        scorer: ranking.scorer,
+        averages,
+        title: TitleMatch::new(reader, query, ranking.title),
+        proximity: Proximity::new(query, ranking.proximity),
+    }
+}
+
+/**
+ * Find the documents matching the normalized query, along with the weight of every term they
+ * are scored by
+ */
+fn candidates<R: IndexReader + ?Sized>(
+    reader: &R,
+    query: &NormalizedQuery,
+    scorer: &Scorer,
+    averages: &Averages,
+) -> (HashSet<DocumentId>, Vec<(String, f64)>) {
+    let _span = debug_span!("candidates").entered();
+    let postings: Vec<_> = query
+        .terms
+        .iter()
+        .filter_map(|token| reader.postings_ref(token))
+        .collect();
+    let mut filters = vec![];
+    for (field, terms) in query.fields.iter() {
+        filters.push(field_matches(reader, *field, terms));
+    }
+    // Phrases and substrings are checked against the documents themselves, which is wasted
+    // once a term or field has already ruled every document out
+    let ruled_out = postings.iter().any(|docs| docs.is_empty())
+        || filters
+            .iter()
+            .any(|docs: &HashSet<DocumentId>| docs.is_empty());
+    if !ruled_out {
+        for (tokens, slop) in query.phrases.iter() {
+            if let Some(matches) = phrase_matches(reader, tokens, *slop) {
+                filters.push(matches);
+            }
+        }
+        for needle in query.substrings.iter() {
+            filters.push(substring_matches(reader, needle));
+        }
+    }
+
+    let expansions: Vec<Vec<String>> = query
+        .regexes
+        .iter()
+        .map(|pattern| expand_regex(reader, pattern))
+        .chain(
+            query
+                .suffixes
+                .iter()
+                .map(|suffix| expand_suffix(reader, suffix)),
+        )
+        .collect();
+    for terms in expansions.iter() {
+        filters.push(any_matches(reader, terms));
+    }
+
+    let weights: Vec<(String, f64)> = query
+        .phrases
+        .iter()
+        .flat_map(|(tokens, _)| tokens)
+        .map(|t| &t.text)
+        .chain(query.terms.iter())
+        .chain(expansions.iter().flatten())
+        .map(|term| (term.clone(), scorer.weight(reader, averages, term)))
+        .collect();
+
+    let mut sets: Vec<PostingsRef<'_>> = filters
+        .iter()
+        .map(|docs| PostingsRef::Set(Cow::Borrowed(docs)))
+        .collect();
+    sets.extend(postings);
+
+    let documents = intersection(&sets);
+    metrics::histogram!(telemetry::CANDIDATES).record(documents.len() as f64);
+    (documents, weights)
+}
+
+/**
+ * The documents matching a query, each of which is scored only once it is reached so that
+ * taking the first few does not pay for scoring the rest
+ *
+ * The hits come in ascending order of document id, `by_score()` turns them into the highest
+ * scoring first, which has to score every one of them but only sorts as many as are taken.
+ */
+pub struct Hits<'a, R: IndexReader + ?Sized> {
+    reader: &'a R,
+    documents: std::vec::IntoIter<DocumentId>,
+    weights: Vec<(String, f64)>,
+    scorer: Scorer,
+    averages: Averages,
+    title: TitleMatch<'a>,
+    proximity: Proximity,
+}
+
+impl<'a, R: IndexReader + ?Sized> Hits<'a, R> {
+    /**
+     * The hits highest score first, ties broken by the document id like `sort_scored()` does
+     */
+    pub fn by_score(self) -> ByScore {
+        ByScore(self.map(|(id, score)| Ranked(id, score)).collect())
+    }
+}
+
+impl<'a, R: IndexReader + ?Sized> Iterator for Hits<'a, R> {
+    type Item = (DocumentId, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.documents.next()?;
+        let scored: Vec<(&String, f64)> = self.weights.iter().map(|(t, w)| (t, *w)).collect();
+        let bonuses = Some((&self.title, &self.proximity));
+        let score = score_document(
+            self.reader,
+            &self.scorer,
+            &self.averages,
+            id,
+            &scored,
+            bonuses,
+        );
+        Some((id, score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.documents.size_hint()
+    }
+}
+
+/**
+ * A scored document which orders before another with a higher score, or the same score and a
+ * lower document id
+ */
+#[derive(Debug)]
+struct Ranked(DocumentId, f64);
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.1
+            .total_cmp(&other.1)
+            .then_with(|| other.0.cmp(&self.0))
+    }
+}
+
+/**
+ * Hits in descending order of their scores, popped off a heap as they are taken
+ */
+pub struct ByScore(BinaryHeap<Ranked>);
+
+impl Iterator for ByScore {
+    type Item = (DocumentId, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop().map(|Ranked(id, score)| (id, score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+/**
+ * Find the documents containing any of the (analyzed) terms, scored the same way as the
+ * documents matching a query are, highest first
+ */
+pub fn execute_any<R: IndexReader + ?Sized>(
+    reader: &R,
+    terms: &[String],
+) -> Vec<(DocumentId, f64)> {
+    let documents = any_matches(reader, terms);
+    let scored: Vec<(&String, f64)> = terms.iter().map(|term| (term, reader.idf(term))).collect();
+    rank(
+        reader,
+        documents.into_iter(),
+        &scored,
+        (&Scorer::TfIdf, &Averages::default()),
+        None,
+        EarlyExit::default(),
+        &mut QueryTimings::default(),
+    )
+}
+
+/**
+ * How a query was understood and how its best documents came by their scores
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Explanation {
+    pub query: NormalizedQuery,
+    /**
+     * Every term which contributes to the score, along with its inverse document frequency
+     */
+    pub idf: Vec<(String, f64)>,
+    pub hits: Vec<ExplainedHit>,
+}
+
+/**
+ * A single scored document of an Explanation
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainedHit {
+    pub id: DocumentId,
+    pub score: f64,
+    /**
+     * The frequency of each scored term in the document and the part of the score it made up,
+     * which is that frequency times the idf of the term
+     */
+    pub terms: Vec<(String, f64, f64)>,
+    /**
+     * What the sum of the terms was multiplied by, for matching the title, for having the terms
+     * close together and by any boost of the document
+     */
+    pub boost: f64,
+}
+
+/**
+ * Evaluate the query like `execute_scored` does, explaining the scores of at most `limit` of
+ * the highest scoring documents
+ */
+pub fn explain<R: IndexReader + ?Sized>(reader: &R, query: &str, limit: usize) -> Explanation {
+    let normalized = reader.normalize(query);
+    let idf: Vec<(String, f64)> = normalized
+        .phrases
+        .iter()
+        .flat_map(|(tokens, _)| tokens)
+        .map(|t| &t.text)
+        .chain(normalized.terms.iter())
+        .map(|term| (term.clone(), reader.idf(term)))
+        .collect();
+
+    let mut results = execute_scored(reader, &normalized);
+    results.truncate(limit);
+    let hits = results
+        .into_iter()
+        .map(|(id, score)| {
+            let terms: Vec<(String, f64, f64)> = idf
+                .iter()
+                .filter_map(|(term, idf)| {
+                    reader
+                        .term_frequency(id, term)
+                        .map(|tf| (term.clone(), tf, tf * idf))
+                })
+                .collect();
+            let sum: f64 = terms.iter().map(|(_, _, part)| part).sum();
+            ExplainedHit {
+                id,
+                score,
+                terms,
+                boost: match sum > 0.0 {
+                    true => score / sum,
+                    false => 1.0,
+                },
+            }
+        })
+        .collect();
+
+    Explanation {
+        query: normalized,
+        idf,
+        hits,
+    }
+}
+
+/**
+ * Time to rank these documents based on query
+ */
+fn rank<R: IndexReader + ?Sized>(
+    reader: &R,
+    documents: impl Iterator<Item = DocumentId>,
+    scored: &[(&String, f64)],
+    (scorer, averages): (&Scorer, &Averages),
+    bonuses: Option<(&TitleMatch, &Proximity)>,
+    early_exit: EarlyExit,
+    timings: &mut QueryTimings,
+) -> Vec<(DocumentId, f64)> {
+    let mut results: Vec<(DocumentId, f64)> = vec![];
+    let start = Instant::now();
+    let scoring = debug_span!("score").entered();
+    let mut best = f64::NEG_INFINITY;
+    let mut hits = 0;
+
+    for id in documents {
+        let score = score_document(reader, scorer, averages, id, scored, bonuses);
+        debug!("Doc: {} has score: {}", id, score);
+        results.push((id, score));
+
+        if early_exit.is_enabled() {
+            if score > best {
+                best = score;
+                let threshold = early_exit.threshold(best);
+                hits = results.iter().filter(|(_, s)| *s >= threshold).count();
+            } else if score >= early_exit.threshold(best) {
+                hits += 1;
+            }
+            if hits >= early_exit.hits {
+                debug!("Stopped scoring after {} documents", results.len());
+                break;
+            }
+        }
+    }
+    drop(scoring);
+    timings.scoring += start.elapsed();
+
+    let start = Instant::now();
+    sort_scored(&mut results);
+    timings.sort += start.elapsed();
+    debug!("Document scores: {:?}", results);
+    results
+}
+
+/**
+ * The score of the document for the weighted terms, multiplied by its bonuses and boost
+ */
+fn score_document<R: IndexReader + ?Sized>(
+    reader: &R,
+    scorer: &Scorer,
+    averages: &Averages,
+    id: DocumentId,
+    scored: &[(&String, f64)],
+    bonuses: Option<(&TitleMatch, &Proximity)>,
+) -> f64 {
+    let mut score = scorer.score(reader, averages, id, scored);
+    if let Some((title, proximity)) = bonuses {
+        score *= title.factor(reader, id) * proximity.factor(reader, id);
+    }
+    score * reader.boost(id)
+}
+
+/**
+ * Which documents have the terms of a query in their title, for giving them a TitleBonus
+ */
+struct TitleMatch<'a> {
+    postings: Vec<Option<PostingsRef<'a>>>,
+    terms: Vec<String>,
+    bonus: TitleBonus,
+}
+
+impl<'a> TitleMatch<'a> {
+    fn new<R: IndexReader + ?Sized>(
+        reader: &'a R,
+        query: &NormalizedQuery,
+        bonus: TitleBonus,
+    ) -> Self {
+        let postings = match bonus == TitleBonus::none() {
+            true => vec![],
+            false => query
+                .title
+                .iter()
+                .map(|term| reader.field_postings_ref(Field::Title, term))
+                .collect(),
+        };
+        Self {
+            postings,
+            terms: query.title.clone(),
+            bonus,
+        }
+    }
+
+    /**
+     * Only documents with every term in their title are fetched, to check whether their title
+     * analyzes to exactly the same terms as the query
+     */
+    fn factor<R: IndexReader + ?Sized>(&self, reader: &R, id: DocumentId) -> f64 {
+        if self.postings.is_empty() {
+            return 1.0;
+        }
+        let matched = self
+            .postings
+            .iter()
+            .flatten()
+            .filter(|docs| docs.contains(&id))
+            .count();
+        let exact = matched == self.postings.len()
+            && reader.document(&id).is_some_and(|article| {
+                reader
+                    .schema()
+                    .analyzer(Field::Title)
+                    .is_some_and(|analyzer| analyzer.terms(article.title()) == self.terms)
+            });
+        self.bonus
+            .factor(matched as f64 / self.postings.len() as f64, exact)
+    }
+}
+
+/**
+ * The distinct free text terms of a query, for giving the documents they are close together in
+ * a ProximityBonus
+ */
+struct Proximity {
+    terms: Vec<String>,
+    bonus: ProximityBonus,
+}
+
+impl Proximity {
+    fn new(query: &NormalizedQuery, bonus: ProximityBonus) -> Self {
+        let mut terms: Vec<String> = vec![];
+        if bonus != ProximityBonus::none() {
+            for term in query.terms.iter() {
+                if !terms.contains(term) {
+                    terms.push(term.clone());
+                }
+            }
+        }
+        Self { terms, bonus }
+    }
+
+    fn factor<R: IndexReader + ?Sized>(&self, reader: &R, id: DocumentId) -> f64 {
+        if self.terms.len() < 2 {
+            return 1.0;
+        }
+        match minimal_span(reader, id, &self.terms) {
+            Some(span) => self.bonus.factor(self.terms.len(), span),
+            None => 1.0,
+        }
+    }
+}
+
+/**
+ * The fewest consecutive positions of the document which contain every one of the terms, None
+ * when some of them are not in the document at all
+ */
+fn minimal_span<R: IndexReader + ?Sized, S: AsRef<str>>(
+    reader: &R,
+    id: DocumentId,
+    terms: &[S],
+) -> Option<usize> {
+    let mut positions: Vec<(usize, usize)> = vec![];
+    for (term, text) in terms.iter().enumerate() {
+        let found = reader.positions(id, text.as_ref())?;
+        positions.extend(found.iter().map(|position| (*position, term)));
+    }
+    positions.sort_unstable();
+
+    // Slide a window over the positions, shrinking it from the front whenever it has them all
+    let mut counts = vec![0; terms.len()];
+    let (mut covered, mut start, mut best) = (0, 0, None);
+    for &(position, term) in positions.iter() {
+        if counts[term] == 0 {
+            covered += 1;
+        }
+        counts[term] += 1;
+        while covered == terms.len() {
+            let (first, first_term) = positions[start];
+            let span = position - first + 1;
+            best = Some(best.map_or(span, |best: usize| best.min(span)));
+            counts[first_term] -= 1;
+            if counts[first_term] == 0 {
+                covered -= 1;
+            }
+            start += 1;
+        }
+    }
+    best
+}
+
+/**
+ * Collapse the results whose titles are the same once normalized, such as disambiguation pages
+ * and redirect stubs, into the first of them, along with how many others were collapsed into it
+ *
+ * Results without a title are never collapsed.
+ */
+pub fn dedupe_by_title<T, F>(results: Vec<T>, title: F) -> Vec<(T, usize)>
+where
+    F: Fn(&T) -> Option<String>,
+{
+    let mut kept: Vec<(T, usize)> = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for result in results {
+        match title(&result).map(|title| normalize_title(&title)) {
+            Some(title) => match seen.get(&title) {
+                Some(first) => kept[*first].1 += 1,
+                None => {
+                    seen.insert(title, kept.len());
+                    kept.push((result, 0));
+                }
+            },
+            None => kept.push((result, 0)),
+        }
+    }
+    kept
+}
+
+/**
+ * Sort scored results by whoever has the highest score, ties are broken by the document id (or
+ * whatever else was scored) so that every reader of the same contents returns the same order
+ */
+pub fn sort_scored<T: Ord>(results: &mut [(T, f64)]) {
+    let _span = debug_span!("sort", results = results.len()).entered();
+    results.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(std::cmp::Ordering::Less)
+            .then_with(|| a.0.cmp(&b.0))
+    });
+}
+
+/**
+ * Find all the documents whose full text contains the given (lowercase) substring
+ *
+ * When the reader can narrow down the candidates (e.g. with a trigram index) only those need to
+ * be checked, otherwise every document in the index is scanned
+ */
+fn substring_matches<R: IndexReader + ?Sized>(reader: &R, needle: &str) -> HashSet<DocumentId> {
+    let candidates: Vec<DocumentId> = match reader.substring_candidates(needle) {
+        Some(candidates) => candidates.into_iter().collect(),
+        None => {
+            debug!("Scanning every document for `{}`", needle);
+            reader.document_ids()
+        }
+    };
+
+    candidates
+        .into_iter()
+        .filter(|id| {
+            reader
+                .document(id)
+                .map(|article| article.fulltext().to_lowercase().contains(needle))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/**
+ * The documents containing any of the (analyzed) terms
+ */
+fn any_matches<R: IndexReader + ?Sized>(reader: &R, terms: &[String]) -> HashSet<DocumentId> {
+    let mut documents = HashSet::new();
+    for term in terms.iter() {
+        if let Some(docs) = reader.postings_ref(term) {
+            documents.extend(docs.iter().copied());
+        }
+    }
+    documents
+}
+
+/**
+ * Find all the documents which contain every one of the (analyzed) terms in the given field
+ */
+fn field_matches<R: IndexReader + ?Sized>(
+    reader: &R,
+    field: Field,
+    terms: &[String],
+) -> HashSet<DocumentId> {
+    let mut postings = vec![];
+    for term in terms.iter() {
+        match reader.field_postings_ref(field, term) {
+            Some(docs) => postings.push(docs),
+            None => return HashSet::new(),
+        }
+    }
+    intersection(&postings)
+}
+
+/**
+ * Find all the documents which contain the analyzed phrase, with its tokens in the same order
+ * and at least as far apart as they were in the phrase, but with no more than `slop` other
+ * tokens between them altogether
+ *
+ * Stopwords removed from the phrase leave gaps in the positions, so they will match any single
+ * word in the document. A phrase with no tokens at all does not constrain the query.
+ */
+fn phrase_matches<R: IndexReader + ?Sized>(
+    reader: &R,
+    tokens: &[Token],
+    slop: usize,
+) -> Option<HashSet<DocumentId>> {
+    tokens.first()?;
+
+    let mut postings = vec![];
+    for token in tokens.iter() {
+        match reader.postings_ref(&token.text) {
+            Some(docs) => postings.push(docs),
+            None => return Some(HashSet::new()),
+        }
+    }
+
+    Some(
+        intersection(&postings)
+            .into_iter()
+            .filter(|id| contains_phrase(reader, *id, tokens, slop))
+            .collect(),
+    )
+}
+
+/**
+ * Check the positions of the tokens in the given document for the phrase
+ *
+ * Each token is matched to its earliest position far enough after the one before it, which
+ * leaves the fewest tokens between them and so the most slop for the rest of the phrase.
+ */
+fn contains_phrase<R: IndexReader + ?Sized>(
+    reader: &R,
+    id: DocumentId,
+    tokens: &[Token],
+    slop: usize,
+) -> bool {
+    let first = &tokens[0];
+    let starts = match reader.positions(id, &first.text) {
+        Some(starts) => starts,
+        None => return false,
+    };
+    let positions: Vec<_> = tokens[1..]
+        .iter()
+        .map(|token| reader.positions(id, &token.text))
+        .collect();
+
+    starts.iter().any(|start| {
+        let mut previous = (*start, first.position);
+        tokens[1..]
+            .iter()
+            .zip(positions.iter())
+            .all(|(token, positions)| {
+                let positions = match positions {
+                    Some(positions) => positions,
+                    None => return false,
+                };
+                let earliest = previous.0 + token.position.saturating_sub(previous.1);
+                let found = positions.partition_point(|position| *position < earliest);
+                match positions.get(found) {
+                    Some(position)
+                        if position - start <= token.position - first.position + slop =>
+                    {
+                        previous = (*position, token.position);
+                        true
+                    }
+                    _ => false,
+                }
+            })
+    })
+}
+
+/**
+ * Return the documents which are in every one of the given sets, or none at all if there were
+ * no sets
+ *
+ * The smallest set is walked and the rest are checked from smallest to largest, so that the
+ * candidates shrink as quickly as they can, and the rest are not checked at all once there are
+ * none left.
+ */
+fn intersection(sets: &[PostingsRef<'_>]) -> HashSet<DocumentId> {
+    let mut sets: Vec<&PostingsRef<'_>> = sets.iter().collect();
+    sets.sort_by_key(|set| set.len());
+    let (rarest, rest) = match sets.split_first() {
+        Some(split) => split,
+        None => return HashSet::new(),
+    };
+    let mut candidates: Vec<DocumentId> = rarest.iter().copied().collect();
+    for set in rest {
+        if candidates.is_empty() {
+            break;
+        }
+        candidates.retain(|id| set.contains(id));
+    }
+    candidates.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_text() {
+        assert_eq!(
+            parse("hello world"),
+            vec![Clause::Text("hello world".into())]
+        );
+    }
+
+    #[test]
+    fn test_intersection() {
+        let common: HashSet<DocumentId> = (0..1000).collect();
+        let rare: HashSet<DocumentId> = vec![3, 500, 2000].into_iter().collect();
+        let odd: HashSet<DocumentId> = (0..1000).filter(|id| id % 2 == 1).collect();
+        let expected: HashSet<DocumentId> = vec![3].into_iter().collect();
+        let set = |ids| PostingsRef::Set(Cow::Borrowed(ids));
+        let empty = HashSet::new();
+        assert_eq!(
+            intersection(&[set(&common), set(&rare), set(&odd)]),
+            expected
+        );
+        assert_eq!(
+            intersection(&[set(&odd), set(&common), set(&rare)]),
+            expected
+        );
+        assert!(intersection(&[set(&common), set(&empty), set(&rare)]).is_empty());
+        assert!(intersection(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_contains() {
+        assert_eq!(
+            parse("history contains:OGRAPHY"),
+            vec![
+                Clause::Text("history".into()),
+                Clause::Contains("ography".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_field() {
+        assert_eq!(
+            parse("url:https://en.wikipedia.org/wiki/Anarchism"),
+            vec![Clause::Field(
+                Field::Url,
+                "https://en.wikipedia.org/wiki/Anarchism".into()
+            )]
+        );
+        // Unknown prefixes are just left as text
+        assert_eq!(parse("ratio 2:1"), vec![Clause::Text("ratio 2:1".into())]);
+    }
+
+    #[test]
+    fn test_parse_phrase() {
+        assert_eq!(
+            parse("new \"statue of  liberty\" york"),
+            vec![
+                Clause::Text("new york".into()),
+                Clause::Phrase("statue of  liberty".into(), 0)
+            ]
+        );
+        assert_eq!(
+            parse("\"unterminated phrase"),
+            vec![Clause::Phrase("unterminated phrase".into(), 0)]
+        );
+        assert_eq!(
+            parse("\"new york city\"~2 guide"),
+            vec![
+                Clause::Text("guide".into()),
+                Clause::Phrase("new york city".into(), 2)
+            ]
+        );
+        assert_eq!(
+            parse("\"new york\"~ city"),
+            vec![
+                Clause::Text("~ city".into()),
+                Clause::Phrase("new york".into(), 0)
+            ]
+        );
+        assert_eq!(
+            parse("\"new york\"~1000"),
+            vec![Clause::Phrase("new york".into(), MAX_SLOP)]
+        );
+        assert_eq!(parse("\"\""), vec![]);
+    }
+
+    #[test]
+    fn test_explain() -> Result<(), std::io::Error> {
+        let index = crate::engine::Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let explanation = explain(&index, "history", 3);
+        assert_eq!(explanation.query.terms, vec!["histori".to_string()]);
+        assert_eq!(explanation.hits.len(), 3);
+        let ranked: Vec<_> = explanation
+            .hits
+            .iter()
+            .map(|hit| (hit.id, hit.score))
+            .collect();
+        assert_eq!(ranked, index.search("history", 3));
+        for hit in explanation.hits.iter() {
+            let sum: f64 = hit.terms.iter().map(|(_, _, score)| score).sum();
+            assert!((sum * hit.boost - hit.score).abs() < 1e-9);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(
+            validate("new \"statue of liberty\" domain:example.com"),
+            Ok(parse("new \"statue of liberty\" domain:example.com"))
+        );
+        assert_eq!(
+            validate("see https://example.com 2:1").map(|c| c.len()),
+            Ok(1)
+        );
+
+        let unbalanced = validate("new york \"statue of").unwrap_err();
+        assert_eq!(unbalanced, QueryError::UnbalancedQuote { column: 10 });
+        assert_eq!(unbalanced.to_string(), "unbalanced quote at column 10");
+
+        let unknown = validate("history titel:rome").unwrap_err();
+        assert_eq!(
+            unknown.to_string(),
+            "unknown field 'titel' at column 9, did you mean 'title'?"
+        );
+        assert_eq!(
+            validate("colour:red").unwrap_err().to_string(),
+            "unknown field 'colour' at column 1"
+        );
+        assert_eq!(
+            validate("history contains:"),
+            Err(QueryError::MissingValue {
+                column: 9,
+                field: "contains".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_regex() -> Result<(), std::io::Error> {
+        assert_eq!(
+            parse("history /colou?r/ //"),
+            vec![
+                Clause::Text("history //".into()),
+                Clause::Regex("colou?r".into())
+            ]
+        );
+        assert_eq!(
+            validate("history /colou(r/").unwrap_err(),
+            QueryError::InvalidRegex {
+                column: 9,
+                message: term_regex("colou(r").unwrap_err().to_string()
+            }
+        );
+
+        let index = crate::engine::Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let terms = expand_regex(&index, "anarch.*");
+        assert!(terms.contains(&"anarch".to_string()));
+        assert!(terms.iter().all(|term| term.starts_with("anarch")));
+        assert!(expand_regex(&index, "(").is_empty());
+
+        let mut expected = index.query_index("anarchism");
+        let mut found = index.query_index("/ANARCH.*/");
+        assert!(found.len() >= expected.len());
+        found.sort_unstable();
+        expected.retain(|id| found.binary_search(id).is_err());
+        assert!(expected.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_suffix() -> Result<(), std::io::Error> {
+        assert_eq!(
+            parse("* *ology a*b"),
+            vec![Clause::Text("* a*b".into()), Clause::Suffix("ology".into())]
+        );
+
+        let path = std::path::Path::new("data/simple.xml.gz");
+        let scanned = crate::engine::Index::from_file(path)?;
+        let mut reversed = crate::engine::Index::from_file(path)?;
+        reversed.enable_reversed_terms();
+        let terms = expand_suffix(&reversed, "olog");
+        assert!(!terms.is_empty());
+        assert!(terms.iter().all(|term| term.ends_with("olog")));
+        assert_eq!(terms, expand_suffix(&scanned, "olog"));
+        assert_eq!(
+            scanned.normalize("*ology").suffixes,
+            vec!["olog".to_string()]
+        );
+        assert_eq!(reversed.search("*ology", 5), scanned.search("*ology", 5));
+        assert!(!reversed.query_index("*ology").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_empty_contains() {
+        assert_eq!(parse("contains:"), vec![Clause::Text("contains:".into())]);
+    }
+}
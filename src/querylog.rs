@@ -0,0 +1,282 @@
+/**
+ * The querylog module records every query which is run against an index, with how long it took
+ * and what it found, as lines of JSON appended to a file
+ *
+ * Logging is opt-in, by wrapping a reader in a LoggedReader, and the log is what benchmarks and
+ * analytics of real workloads are built from.
+ */
+use crate::engine::{Article, DocumentId, FieldLengths, IndexReader, TermStats};
+use crate::postings::PostingsRef;
+use crate::query::{Clause, QueryTimings};
+use crate::schema::{Field, Schema};
+use crate::scoring::Ranking;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{BufRead, BufWriter, Error, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * The number of the top documents which are recorded for each query
+ */
+pub const TOP_DOCUMENTS: usize = 10;
+
+/**
+ * A single query in the log
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LogEntry {
+    /**
+     * When the query was run, in RFC 3339 format
+     */
+    pub timestamp: String,
+    pub query: String,
+    /**
+     * The number of documents which were returned, at most the limit of a search
+     */
+    pub hits: usize,
+    pub latency_ms: f64,
+    /**
+     * The highest scoring documents, best first
+     */
+    pub top: Vec<DocumentId>,
+}
+
+impl LogEntry {
+    pub fn new(query: &str, results: &[DocumentId], latency: Duration) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            query: query.to_string(),
+            hits: results.len(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            top: results.iter().take(TOP_DOCUMENTS).copied().collect(),
+        }
+    }
+}
+
+/**
+ * A log file which entries are appended to, from any number of threads
+ */
+#[derive(Debug)]
+pub struct QueryLog {
+    file: Mutex<BufWriter<std::fs::File>>,
+}
+
+impl QueryLog {
+    /**
+     * Open the log at the path for appending, creating it if it does not exist yet
+     */
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /**
+     * Append the entry as a line of JSON, which is flushed straight away so that the log is
+     * complete even if the process is killed
+     */
+    pub fn record(&self, entry: &LogEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().expect("The query log lock was poisoned");
+        file.write_all(&line)?;
+        file.flush()
+    }
+}
+
+/**
+ * Read every entry of the log at the path, oldest first
+ */
+pub fn read(path: &Path) -> Result<Vec<LogEntry>, Error> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut entries = vec![];
+    for line in file.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/**
+ * A reader which records every query run against it in a QueryLog, and otherwise does whatever
+ * the reader it wraps does
+ */
+pub struct LoggedReader {
+    reader: Box<dyn IndexReader>,
+    log: QueryLog,
+}
+
+impl LoggedReader {
+    pub fn new(reader: Box<dyn IndexReader>, log: QueryLog) -> Self {
+        Self { reader, log }
+    }
+
+    fn record(&self, query: &str, results: &[DocumentId], start: Instant) {
+        let entry = LogEntry::new(query, results, start.elapsed());
+        if let Err(e) = self.log.record(&entry) {
+            warn!("Failed to log the query `{}`: {}", query, e);
+        }
+    }
+}
+
+impl IndexReader for LoggedReader {
+    fn schema(&self) -> &Schema {
+        self.reader.schema()
+    }
+
+    fn size(&self) -> u64 {
+        self.reader.size()
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.reader.document_ids()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.reader.document(id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.reader.terms()
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.reader.postings(term)
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.reader.field_terms(field)
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.reader.field_postings(field, term)
+    }
+
+    fn postings_ref(&self, term: &str) -> Option<PostingsRef<'_>> {
+        self.reader.postings_ref(term)
+    }
+
+    fn field_postings_ref(&self, field: Field, term: &str) -> Option<PostingsRef<'_>> {
+        self.reader.field_postings_ref(field, term)
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.reader.term_frequency(id, term)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.reader.positions(id, term)
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        self.reader.idf(term)
+    }
+
+    fn term_stats(&self, term: &str) -> Option<TermStats> {
+        self.reader.term_stats(term)
+    }
+
+    fn document_length(&self, id: DocumentId) -> Option<f64> {
+        self.reader.document_length(id)
+    }
+
+    fn average_document_length(&self) -> f64 {
+        self.reader.average_document_length()
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.reader.field_lengths(id)
+    }
+
+    fn average_field_lengths(&self) -> (f64, f64) {
+        self.reader.average_field_lengths()
+    }
+
+    fn ranking(&self) -> Ranking {
+        self.reader.ranking()
+    }
+
+    fn boost(&self, id: DocumentId) -> f64 {
+        self.reader.boost(id)
+    }
+
+    fn related_terms(&self, term: &str, n: usize) -> Vec<(String, f64)> {
+        self.reader.related_terms(term, n)
+    }
+
+    fn substring_candidates(&self, needle: &str) -> Option<HashSet<DocumentId>> {
+        self.reader.substring_candidates(needle)
+    }
+
+    fn terms_with_suffix(&self, suffix: &str) -> Option<Vec<String>> {
+        self.reader.terms_with_suffix(suffix)
+    }
+
+    fn rewrite(&self, clauses: Vec<Clause>) -> Vec<Clause> {
+        self.reader.rewrite(clauses)
+    }
+
+    fn query_index(&self, query: &str) -> Vec<DocumentId> {
+        let start = Instant::now();
+        let results = self.reader.query_index(query);
+        self.record(query, &results, start);
+        results
+    }
+
+    fn search(&self, query: &str, limit: usize) -> Vec<(DocumentId, f64)> {
+        let start = Instant::now();
+        let results = self.reader.search(query, limit);
+        let ids: Vec<DocumentId> = results.iter().map(|(id, _)| *id).collect();
+        self.record(query, &ids, start);
+        results
+    }
+
+    fn search_timed(&self, query: &str, limit: usize) -> (Vec<(DocumentId, f64)>, QueryTimings) {
+        let start = Instant::now();
+        let (results, timings) = self.reader.search_timed(query, limit);
+        let ids: Vec<DocumentId> = results.iter().map(|(id, _)| *id).collect();
+        self.record(query, &ids, start);
+        (results, timings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+
+    #[test]
+    fn test_log_queries() -> Result<(), Error> {
+        let index = Index::from_file(Path::new("data/simple.xml.gz"))?;
+        let expected = index.query_index("history");
+        let path = std::env::temp_dir().join(format!("goede-{}.querylog", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let reader = LoggedReader::new(Box::new(index), QueryLog::open(&path)?);
+        assert_eq!(reader.query_index("history"), expected);
+        assert_eq!(reader.search("history", 2).len(), 2);
+
+        let entries = read(&path)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].query, "history");
+        assert_eq!(entries[0].hits, expected.len());
+        assert_eq!(
+            entries[0].top,
+            expected[..TOP_DOCUMENTS.min(expected.len())]
+        );
+        assert_eq!(entries[1].top, expected[..2]);
+        assert!(chrono::DateTime::parse_from_rfc3339(&entries[0].timestamp).is_ok());
+
+        std::fs::remove_file(&path)
+    }
+}
@@ -0,0 +1,208 @@
+/**
+ * The remote module fetches persisted indexes from HTTP(S) servers or S3 buckets into a local
+ * cache, so that they can be memory-mapped like any other index file
+ */
+use crate::crypto::Key;
+use crate::disk::DiskIndex;
+use log::*;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/**
+ * Whether the location refers to a remote index rather than a local file
+ */
+pub fn is_remote(location: &str) -> bool {
+    ["http://", "https://", "s3://"]
+        .iter()
+        .any(|scheme| location.starts_with(scheme))
+}
+
+/**
+ * The directory remote indexes are cached in unless another is given
+ */
+pub fn default_cache_dir() -> PathBuf {
+    std::env::temp_dir().join("goedesearch-cache")
+}
+
+/**
+ * Turn an `s3://bucket/key` location into the HTTPS url of the object
+ *
+ * Requests are not signed, so the object must either be public or the url must be presigned.
+ * The endpoint can be overridden with `AWS_ENDPOINT_URL` for S3 compatible object stores, which
+ * are then addressed path-style, otherwise the region is taken from `AWS_REGION`.
+ */
+pub fn s3_url(location: &str) -> Result<String, Error> {
+    let path = location.strip_prefix("s3://").unwrap_or(location);
+    let (bucket, key) = match path.split_once('/') {
+        Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => (bucket, key),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("`{}` is not an s3://bucket/key location", location),
+            ))
+        }
+    };
+
+    Ok(match std::env::var("AWS_ENDPOINT_URL") {
+        Ok(endpoint) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key),
+        Err(_) => {
+            let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+            format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key)
+        }
+    })
+}
+
+/**
+ * Download the index at the location into the cache directory, returning the path of the
+ * cached copy
+ *
+ * A cached copy is revalidated with its ETag, so it is only downloaded again when it has
+ * changed, and it is used as is when the server cannot be reached or fails with a 5xx.
+ */
+pub fn fetch(location: &str, cache_dir: &Path) -> Result<PathBuf, Error> {
+    use crc::{crc64, Hasher64};
+
+    let url = if location.starts_with("s3://") {
+        s3_url(location)?
+    } else {
+        location.to_string()
+    };
+
+    let mut digest = crc64::Digest::new(crc64::ECMA);
+    digest.write(url.as_bytes());
+    std::fs::create_dir_all(cache_dir)?;
+    let cached = cache_dir.join(format!("{:016x}.idx", digest.sum64()));
+    let etag_path = cached.with_extension("etag");
+
+    let mut request = ureq::get(&url);
+    if cached.exists() {
+        if let Ok(etag) = std::fs::read_to_string(&etag_path) {
+            request = request.set("If-None-Match", etag.trim());
+        }
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Transport(e)) if cached.exists() => {
+            warn!(
+                "Using the cached copy of {} since it is unreachable: {}",
+                url, e
+            );
+            return Ok(cached);
+        }
+        Err(ureq::Error::Status(status, _)) if status >= 500 && cached.exists() => {
+            warn!(
+                "Using the cached copy of {} since fetching it failed with {}",
+                url, status
+            );
+            return Ok(cached);
+        }
+        Err(ureq::Error::Status(status, _)) => {
+            return Err(Error::other(format!(
+                "fetching {} failed with {}",
+                url, status
+            )))
+        }
+        Err(e) => return Err(Error::other(e)),
+    };
+
+    if response.status() == 304 {
+        debug!("The cached copy of {} is still fresh", url);
+        return Ok(cached);
+    }
+
+    let etag = response.header("ETag").map(|etag| etag.to_string());
+    let partial = cached.with_extension("partial");
+    let mut file = std::fs::File::create(&partial)?;
+    let bytes = std::io::copy(&mut response.into_reader(), &mut file)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, &cached)?;
+
+    match etag {
+        Some(etag) => std::fs::write(&etag_path, etag)?,
+        None => {
+            let _ = std::fs::remove_file(&etag_path);
+        }
+    }
+    info!("Downloaded {} bytes from {} to {:?}", bytes, url, cached);
+    Ok(cached)
+}
+
+/**
+ * Open the index at the location, fetching it into the cache directory first if it is remote,
+ * and decrypting it with the key if one is given
+ */
+pub fn open(location: &str, cache_dir: &Path, key: Option<&Key>) -> Result<DiskIndex, Error> {
+    let path = if is_remote(location) {
+        fetch(location, cache_dir)?
+    } else {
+        PathBuf::from(location)
+    };
+    match key {
+        Some(key) => DiskIndex::open_encrypted(&path, key),
+        None => DiskIndex::open(&path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_s3_url() -> Result<(), Error> {
+        assert!(s3_url("s3://bucket").is_err());
+        assert!(s3_url("s3:///key").is_err());
+        if std::env::var("AWS_ENDPOINT_URL").is_err() && std::env::var("AWS_REGION").is_err() {
+            assert_eq!(
+                s3_url("s3://bucket/indexes/simple.idx")?,
+                "https://bucket.s3.us-east-1.amazonaws.com/indexes/simple.idx"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_revalidates_cache() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/simple.idx", listener.local_addr()?);
+
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for stream in listener.incoming().take(3) {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+
+                let response = if requests.len() == 2 {
+                    "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"
+                } else if request.contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n"
+                } else {
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\r\nhello"
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let cache_dir = std::env::temp_dir().join(format!("goede-remote-{}", std::process::id()));
+        let first = fetch(&url, &cache_dir)?;
+        assert_eq!(std::fs::read(&first)?, b"hello");
+        let second = fetch(&url, &cache_dir)?;
+        assert_eq!(first, second);
+        // A failing server is no worse than an unreachable one
+        assert_eq!(fetch(&url, &cache_dir)?, first);
+        assert_eq!(std::fs::read(&first)?, b"hello");
+
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains("if-none-match"));
+        // What was fetched is not an index though
+        assert!(DiskIndex::open(&second).is_err());
+        std::fs::remove_dir_all(&cache_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,551 @@
+/**
+ * The repl module understands the meta-commands which can be typed at the interactive prompt
+ * instead of a query, e.g. `:limit 5`, and holds the settings they change for the session
+ *
+ * Reading lines from the terminal is left to the goedesearch binary, anything which does not
+ * start with a colon is passed through as a query. The Vocabulary of the index lets it complete
+ * the words being typed, and search for them as they are typed in the `:live` mode.
+ */
+use crate::engine::{Article, DocumentId, IndexReader};
+use crate::query;
+use crate::schema::Field;
+use crate::server::Hit;
+use std::collections::HashMap;
+use std::io::{BufRead, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+pub const HELP: &str = "\
+:stats              Print the number of documents and terms in the index
+:doc <id>           Print everything about the document with the id
+:random [n]         Print n (or 5) documents picked at random
+:limit <n>          Print at most n results for each query, or all of them with 0
+:explain <query>    Print how the query was analyzed and its top results were scored
+:fields <f1,f2,..>  Print only these fields of each result, or everything with no fields
+:live               Show the top hits below the prompt while typing, or stop showing them
+:dedupe             Collapse results with the same title into the best of them, or stop that
+:save <file>        Write the results of the last query to a .json or .csv file
+:help               Print this help
+Anything else is searched for";
+
+/**
+ * The number of documents `:random` prints when it is not given one
+ */
+pub const RANDOM_DOCUMENTS: usize = 5;
+
+/**
+ * A meta-command typed at the prompt
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Stats,
+    Doc(DocumentId),
+    Random(usize),
+    Limit(Option<usize>),
+    Explain(String),
+    Fields(Vec<Field>),
+    Live,
+    Dedupe,
+    Save(PathBuf),
+    Help,
+}
+
+/**
+ * A line typed at the prompt
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    Query(String),
+    Command(Command),
+}
+
+impl std::str::FromStr for Input {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let command = match line.strip_prefix(':') {
+            Some(command) => command,
+            None => return Ok(Input::Query(line.to_string())),
+        };
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+
+        let command = match name {
+            "stats" => Command::Stats,
+            "help" => Command::Help,
+            "live" => Command::Live,
+            "dedupe" => Command::Dedupe,
+            "save" => match ResultsFormat::of(Path::new(argument)) {
+                Some(_) => Command::Save(argument.into()),
+                None => return Err(":save needs a .json or .csv file to write".into()),
+            },
+            "doc" => Command::Doc(
+                argument
+                    .parse()
+                    .map_err(|_| format!("`{}` is not a document id", argument))?,
+            ),
+            "random" if argument.is_empty() => Command::Random(RANDOM_DOCUMENTS),
+            "random" => Command::Random(
+                argument
+                    .parse()
+                    .map_err(|_| format!("`{}` is not a number of documents", argument))?,
+            ),
+            "limit" => match argument.parse() {
+                Ok(0) => Command::Limit(None),
+                Ok(limit) => Command::Limit(Some(limit)),
+                Err(_) => return Err(format!("`{}` is not a number of results", argument)),
+            },
+            "explain" if argument.is_empty() => return Err(":explain needs a query".into()),
+            "explain" => Command::Explain(argument.to_string()),
+            "fields" => Command::Fields(
+                argument
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|field| !field.is_empty())
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err(format!("unknown command `:{}`, try :help", name)),
+        };
+        Ok(Input::Command(command))
+    }
+}
+
+/**
+ * How the results of queries are printed for the rest of the session, and the last query which
+ * was run
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /**
+     * The most results to print for each query, or all of them when None
+     */
+    pub limit: Option<usize>,
+    /**
+     * The fields of each result to print, or the whole document when empty
+     */
+    pub fields: Vec<Field>,
+    /**
+     * Whether to search as the query is typed
+     */
+    pub live: bool,
+    /**
+     * Whether results with the same title are collapsed into the best of them
+     */
+    pub dedupe: bool,
+    /**
+     * The query whose results `:save` writes
+     */
+    pub last_query: Option<String>,
+}
+
+impl Settings {
+    /**
+     * Apply the command if it changes the settings, returning whether it did
+     */
+    pub fn apply(&mut self, command: &Command) -> bool {
+        match command {
+            Command::Limit(limit) => self.limit = *limit,
+            Command::Fields(fields) => self.fields = fields.clone(),
+            Command::Live => self.live = !self.live,
+            Command::Dedupe => self.dedupe = !self.dedupe,
+            _ => return false,
+        }
+        true
+    }
+
+    /**
+     * Format a result for printing with the fields which were asked for
+     */
+    pub fn render(&self, article: &Article) -> String {
+        if self.fields.is_empty() {
+            return article.to_string();
+        }
+        self.fields
+            .iter()
+            .map(|field| match field {
+                Field::Title => article.title().to_string(),
+                Field::Abstract => article.abstract_text().to_string(),
+                Field::Url => article.url().map(|u| u.to_string()).unwrap_or_default(),
+                Field::Domain => article
+                    .url()
+                    .and_then(|u| u.host_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                Field::Metadata => {
+                    let mut metadata: Vec<String> = article
+                        .metadata()
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect();
+                    metadata.sort();
+                    metadata.join(" ")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\t")
+    }
+}
+
+/**
+ * Every term in an index, sorted so that the prompt can complete words to the terms which
+ * start with them
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Vocabulary {
+    terms: Vec<String>,
+}
+
+impl Vocabulary {
+    /**
+     * The most completions offered for a single word
+     */
+    pub const MAX_COMPLETIONS: usize = 100;
+    /**
+     * How many hits are shown while searching as the query is typed
+     */
+    pub const LIVE_HITS: usize = 5;
+
+    pub fn new(mut terms: Vec<String>) -> Self {
+        terms.sort_unstable();
+        terms.dedup();
+        Self { terms }
+    }
+
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /**
+     * The terms starting with the (lowercased) word, in order
+     */
+    pub fn complete(&self, word: &str) -> impl Iterator<Item = &str> {
+        let prefix = word.to_lowercase();
+        let start = if prefix.is_empty() {
+            self.terms.len()
+        } else {
+            self.terms
+                .partition_point(|term| term.as_str() < prefix.as_str())
+        };
+        self.terms[start..]
+            .iter()
+            .take_while(move |term| term.starts_with(&prefix))
+            .take(Self::MAX_COMPLETIONS)
+            .map(String::as_str)
+    }
+
+    /**
+     * The `limit` highest scoring documents for a query which is still being typed
+     *
+     * The last word may not have been finished, so documents containing any of its completions
+     * match it, while the words before it are matched like any other query.
+     */
+    pub fn search_as_you_type<R: IndexReader + ?Sized>(
+        &self,
+        reader: &R,
+        query: &str,
+        limit: usize,
+    ) -> Vec<(DocumentId, f64)> {
+        let (head, partial) = match query.rfind(char::is_whitespace) {
+            Some(end) => query.split_at(end),
+            None => ("", query),
+        };
+        let normalized = reader.normalize(head);
+        let mut completions = reader.schema().text_analyzer().terms(partial);
+        completions.extend(self.complete(partial.trim()).map(String::from));
+        completions.sort_unstable();
+        completions.dedup();
+
+        let mut results = if completions.is_empty() {
+            query::execute_scored(reader, &normalized)
+        } else if normalized == query::NormalizedQuery::default() {
+            query::execute_any(reader, &completions)
+        } else {
+            let matched: HashMap<DocumentId, f64> = query::execute_scored(reader, &normalized)
+                .into_iter()
+                .collect();
+            let mut results: Vec<_> = query::execute_any(reader, &completions)
+                .into_iter()
+                .filter_map(|(id, score)| matched.get(&id).map(|head| (id, head + score)))
+                .collect();
+            query::sort_scored(&mut results);
+            results
+        };
+        results.truncate(limit);
+        results
+    }
+}
+
+/**
+ * The terms most related to those of the query, other than the query's own, to suggest as
+ * related searches below its results
+ */
+pub fn related_searches<R: IndexReader + ?Sized>(reader: &R, query: &str, n: usize) -> Vec<String> {
+    let terms = reader.normalize(query).terms;
+    let mut weights: HashMap<String, f64> = HashMap::new();
+    for term in terms.iter() {
+        for (related, weight) in reader.related_terms(term, n) {
+            if !terms.contains(&related) {
+                *weights.entry(related).or_default() += weight;
+            }
+        }
+    }
+    let mut related: Vec<(String, f64)> = weights.into_iter().collect();
+    query::sort_scored(&mut related);
+    related.into_iter().take(n).map(|(term, _)| term).collect()
+}
+
+/**
+ * The formats which `:save` can write results in, chosen by the extension of the file
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResultsFormat {
+    Json,
+    Csv,
+}
+
+impl ResultsFormat {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(ResultsFormat::Json),
+            "csv" => Some(ResultsFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Quote a CSV field if it has anything in it which would otherwise be misread
+ */
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/**
+ * Write the hits to the file, as a JSON array or as CSV with a header row depending on its
+ * extension
+ */
+pub fn save_results(hits: &[Hit], path: &Path) -> Result<(), Error> {
+    let format = ResultsFormat::of(path).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is neither a .json nor a .csv file", path),
+        )
+    })?;
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    match format {
+        ResultsFormat::Json => serde_json::to_writer_pretty(&mut file, hits)?,
+        ResultsFormat::Csv => {
+            writeln!(file, "id,score,title,url,abstract")?;
+            for hit in hits.iter() {
+                let article = hit.article.clone().unwrap_or_default();
+                writeln!(
+                    file,
+                    "{},{},{},{},{}",
+                    hit.id,
+                    hit.score,
+                    csv_field(article.title()),
+                    csv_field(article.url().map(|u| u.as_str()).unwrap_or_default()),
+                    csv_field(article.abstract_text())
+                )?;
+            }
+        }
+    }
+    file.flush()
+}
+
+/**
+ * Write the text a screenful of `rows` at a time, reading a line from the keys before each of
+ * the following screenfuls until the text runs out or `q` is typed
+ *
+ * This is the pager used when $PAGER is not set.
+ */
+pub fn page<R: BufRead, W: Write + ?Sized>(
+    text: &str,
+    rows: usize,
+    mut keys: R,
+    out: &mut W,
+) -> Result<(), std::io::Error> {
+    // One row is left over for the prompt
+    let height = rows.saturating_sub(1).max(1);
+    let lines: Vec<&str> = text.lines().collect();
+    for (page, screenful) in lines.chunks(height).enumerate() {
+        if page > 0 {
+            write!(
+                out,
+                "--More-- ({} of {} lines, q to quit) ",
+                page * height,
+                lines.len()
+            )?;
+            out.flush()?;
+            let mut key = String::new();
+            if keys.read_line(&mut key)? == 0 || key.trim() == "q" {
+                break;
+            }
+        }
+        for line in screenful {
+            writeln!(out, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!("  anarchism ".parse(), Ok(Input::Query("anarchism".into())));
+        assert_eq!(":stats".parse(), Ok(Input::Command(Command::Stats)));
+        assert_eq!(":live".parse(), Ok(Input::Command(Command::Live)));
+        assert_eq!(":dedupe".parse(), Ok(Input::Command(Command::Dedupe)));
+        assert_eq!(":doc 42".parse(), Ok(Input::Command(Command::Doc(42))));
+        assert_eq!(
+            ":random".parse(),
+            Ok(Input::Command(Command::Random(RANDOM_DOCUMENTS)))
+        );
+        assert_eq!(":random 3".parse(), Ok(Input::Command(Command::Random(3))));
+        assert!(":random some".parse::<Input>().is_err());
+        assert_eq!(
+            ":limit 5".parse(),
+            Ok(Input::Command(Command::Limit(Some(5))))
+        );
+        assert_eq!(":limit 0".parse(), Ok(Input::Command(Command::Limit(None))));
+        assert_eq!(
+            ":explain  new york".parse(),
+            Ok(Input::Command(Command::Explain("new york".into())))
+        );
+        assert_eq!(
+            ":fields title, url".parse(),
+            Ok(Input::Command(Command::Fields(vec![
+                Field::Title,
+                Field::Url
+            ])))
+        );
+        assert_eq!(
+            ":save hits.csv".parse(),
+            Ok(Input::Command(Command::Save("hits.csv".into())))
+        );
+        assert!(":save hits.txt".parse::<Input>().is_err());
+        assert!(":limit lots".parse::<Input>().is_err());
+        assert!(":fields title,colour".parse::<Input>().is_err());
+        assert!(":explain".parse::<Input>().is_err());
+        assert!(":frobnicate".parse::<Input>().is_err());
+    }
+
+    #[test]
+    fn test_render_fields() -> crate::error::Result<()> {
+        let article = Article::new(
+            "Banana",
+            "A long yellow fruit",
+            "https://example.com/banana",
+        )?;
+        let mut settings = Settings::default();
+        assert_eq!(settings.render(&article), article.to_string());
+        assert!(settings.apply(&Command::Fields(vec![Field::Title, Field::Domain])));
+        assert!(!settings.apply(&Command::Stats));
+        assert_eq!(settings.render(&article), "Banana\texample.com");
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete() {
+        let vocabulary = Vocabulary::new(vec![
+            "histori".into(),
+            "hist".into(),
+            "anarch".into(),
+            "histori".into(),
+            "hit".into(),
+        ]);
+        assert_eq!(vocabulary.len(), 4);
+        let completed: Vec<_> = vocabulary.complete("HIST").collect();
+        assert_eq!(completed, vec!["hist", "histori"]);
+        assert_eq!(vocabulary.complete("z").count(), 0);
+        assert_eq!(vocabulary.complete("").count(), 0);
+    }
+
+    #[test]
+    fn test_search_as_you_type() -> Result<(), std::io::Error> {
+        let index = crate::engine::Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let vocabulary = Vocabulary::new(index.terms());
+        let typing = vocabulary.search_as_you_type(&index, "histo", 5);
+        assert!(!typing.is_empty() && typing.len() <= 5);
+        for (id, _) in typing.iter() {
+            let article = index.document(id).expect("Failed to find a hit");
+            assert!(article.fulltext().to_lowercase().contains("histo"));
+        }
+        // Once the word is finished it is searched for like any other query
+        let typed = vocabulary.search_as_you_type(&index, "history ", 5);
+        assert_eq!(typed, index.search("history", 5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_related_searches() -> Result<(), std::io::Error> {
+        let index = crate::builder::IndexBuilder::new()
+            .cooccurrences(true)
+            .from_file(Path::new("data/simple.xml.gz"))?;
+        let related = related_searches(&index, "history", 5);
+        assert_eq!(related.len(), 5);
+        assert!(!related.contains(&"histori".to_string()));
+        assert!(related_searches(&index, "zzyzx", 5).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_page() -> Result<(), std::io::Error> {
+        let text = "one\ntwo\nthree\nfour\nfive\n";
+        let mut out = vec![];
+        page(text, 3, "\nq\n".as_bytes(), &mut out)?;
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "one\ntwo\n--More-- (2 of 5 lines, q to quit) three\nfour\n\
+             --More-- (4 of 5 lines, q to quit) "
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_results() -> Result<(), Error> {
+        let article = Article::new(
+            "Banana, the fruit",
+            "A long \"yellow\" fruit",
+            "https://example.com/banana",
+        )?;
+        let hits = vec![Hit {
+            id: article.id(),
+            score: 1.5,
+            article: Some(article.clone()),
+            collapsed: 0,
+        }];
+        let dir = std::env::temp_dir().join(format!("goede-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        save_results(&hits, &dir.join("hits.csv"))?;
+        assert_eq!(
+            std::fs::read_to_string(dir.join("hits.csv"))?,
+            format!(
+                "id,score,title,url,abstract\n{},1.5,\"Banana, the fruit\",\
+                 https://example.com/banana,\"A long \"\"yellow\"\" fruit\"\n",
+                article.id()
+            )
+        );
+        save_results(&hits, &dir.join("hits.json"))?;
+        let saved: Vec<Hit> = serde_json::from_reader(std::fs::File::open(dir.join("hits.json"))?)?;
+        assert_eq!(saved, hits);
+        assert!(save_results(&hits, &dir.join("hits.txt")).is_err());
+
+        std::fs::remove_dir_all(&dir)
+    }
+}
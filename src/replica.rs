@@ -0,0 +1,305 @@
+/**
+ * The replica module scales out read traffic by copying the committed segments of a primary's
+ * Storage to read replicas, which swap them in without interrupting searches
+ *
+ * The primary publishes into a directory, which replicas pull from either directly or through
+ * any static HTTP server. A publication is a file per segment plus a `manifest.json` listing the
+ * segments of the latest generation. Segments are immutable, so a replica only downloads the
+ * segments which it does not already have, and only the deletions are new for the rest.
+ */
+use crate::disk::{self, DiskIndex};
+use crate::engine::{DocumentId, Index};
+use crate::schema::Schema;
+use crate::segment::{self, ManifestEntry, Segment, SegmentSearcher};
+use crate::store::{lock, read, write, Storage};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Published {
+    name: String,
+    deleted: Vec<DocumentId>,
+    checksum: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct Manifest {
+    /**
+     * Incremented every time the published segments change
+     */
+    generation: u64,
+    segments: Vec<Published>,
+}
+
+fn segment_file(name: &str) -> String {
+    format!("segment-{}.idx", name)
+}
+
+/**
+ * Publish the last commit in the Storage into the directory, returning its generation
+ *
+ * A Storage which was saved to without a SegmentedIndex has no commits, so all of its segments
+ * are published as they are. Nothing is written if the commit has already been published, and
+ * the segments of the previous generation are kept around for replicas still pulling it.
+ */
+pub fn publish(storage: &dyn Storage, dir: &Path) -> Result<u64, Error> {
+    let entries = match segment::committed(storage)? {
+        Some(entries) => entries,
+        None => storage
+            .segments()?
+            .into_iter()
+            .map(|name| ManifestEntry {
+                name,
+                deleted: vec![],
+            })
+            .collect(),
+    };
+    std::fs::create_dir_all(dir)?;
+    let previous: Manifest = match std::fs::read(dir.join(MANIFEST)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Manifest::default(),
+        Err(e) => return Err(e),
+    };
+
+    let mut segments = vec![];
+    for entry in entries.into_iter() {
+        let already = previous.segments.iter().find(|p| p.name == entry.name);
+        let checksum = match already {
+            Some(published) => published.checksum,
+            None => {
+                let bytes = storage.get_segment(&entry.name)?.ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("the committed segment `{}` is missing", entry.name),
+                    )
+                })?;
+                let path = dir.join(segment_file(&entry.name));
+                let partial = path.with_extension("partial");
+                std::fs::write(&partial, &bytes)?;
+                std::fs::rename(&partial, &path)?;
+                disk::checksum(&bytes)
+            }
+        };
+        segments.push(Published {
+            name: entry.name,
+            deleted: entry.deleted,
+            checksum,
+        });
+    }
+    if segments == previous.segments && dir.join(MANIFEST).exists() {
+        return Ok(previous.generation);
+    }
+
+    let manifest = Manifest {
+        generation: previous.generation + 1,
+        segments,
+    };
+    let path = dir.join(MANIFEST);
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, serde_json::to_vec(&manifest)?)?;
+    std::fs::rename(&partial, &path)?;
+
+    for entry in std::fs::read_dir(dir)? {
+        let file = entry?.file_name().to_string_lossy().into_owned();
+        let in_use = [&previous, &manifest].iter().any(|m| {
+            m.segments
+                .iter()
+                .any(|published| segment_file(&published.name) == file)
+        });
+        if file.starts_with("segment-") && !in_use {
+            std::fs::remove_file(dir.join(&file))?;
+        }
+    }
+    info!(
+        "Published generation {} of {} segments to {:?}",
+        manifest.generation,
+        manifest.segments.len(),
+        dir
+    );
+    Ok(manifest.generation)
+}
+
+/**
+ * A read-only copy of a published index, which is kept up to date by pulling from the source
+ */
+#[derive(Debug)]
+pub struct Replica {
+    source: String,
+    schema: Schema,
+    generation: Mutex<Option<u64>>,
+    /**
+     * The indexes of the segments pulled so far, by name
+     */
+    loaded: Mutex<HashMap<String, Arc<Index>>>,
+    current: RwLock<SegmentSearcher>,
+}
+
+impl Replica {
+    /**
+     * Replicate the publication at the source, a directory or the http(s):// url it is served
+     * from, pulling the latest generation before returning
+     */
+    pub fn open(source: &str, schema: Schema) -> Result<Self, Error> {
+        let replica = Self {
+            source: source.trim_end_matches('/').to_string(),
+            current: RwLock::new(SegmentSearcher::new(schema.clone(), vec![])),
+            schema,
+            generation: Mutex::default(),
+            loaded: Mutex::default(),
+        };
+        replica.pull()?;
+        Ok(replica)
+    }
+
+    fn fetch(&self, file: &str) -> Result<Vec<u8>, Error> {
+        if !crate::remote::is_remote(&self.source) {
+            return std::fs::read(Path::new(&self.source).join(file));
+        }
+        let url = format!("{}/{}", self.source, file);
+        let response = ureq::get(&url)
+            .call()
+            .map_err(|e| Error::other(format!("fetching {} failed: {}", url, e)))?;
+        let mut bytes = vec![];
+        response.into_reader().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /**
+     * The generation being searched
+     */
+    pub fn generation(&self) -> Option<u64> {
+        lock(&self.generation).ok().and_then(|g| *g)
+    }
+
+    /**
+     * Fetch the latest generation from the source and swap it in if it is new, returning
+     * whether it was
+     *
+     * Searchers which are already running carry on with the generation they started with.
+     */
+    pub fn pull(&self) -> Result<bool, Error> {
+        let mut generation = lock(&self.generation)?;
+        let manifest: Manifest = serde_json::from_slice(&self.fetch(MANIFEST)?)?;
+        if *generation == Some(manifest.generation) {
+            return Ok(false);
+        }
+
+        let mut loaded = lock(&self.loaded)?;
+        let mut segments = vec![];
+        let mut pulled = HashMap::new();
+        for published in manifest.segments.iter() {
+            let index = match loaded.get(&published.name).cloned() {
+                Some(index) => index,
+                None => {
+                    let bytes = self.fetch(&segment_file(&published.name))?;
+                    if disk::checksum(&bytes) != published.checksum {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("segment {} failed its checksum", published.name),
+                        ));
+                    }
+                    let disk = DiskIndex::from_bytes(bytes)?;
+                    disk.check_schema(&self.schema)?;
+                    Arc::new(Index::from_reader(&disk)?)
+                }
+            };
+            segments.push(Segment::new(
+                published.name.clone(),
+                index.clone(),
+                published.deleted.iter().copied().collect(),
+            ));
+            pulled.insert(published.name.clone(), index);
+        }
+        *loaded = pulled;
+        *write(&self.current)? = SegmentSearcher::new(self.schema.clone(), segments);
+        info!(
+            "Swapped in generation {} of {} segments",
+            manifest.generation,
+            manifest.segments.len()
+        );
+        *generation = Some(manifest.generation);
+        Ok(true)
+    }
+
+    /**
+     * A view of the latest generation which has been pulled
+     */
+    pub fn searcher(&self) -> Result<SegmentSearcher, Error> {
+        Ok(read(&self.current)?.clone())
+    }
+}
+
+/**
+ * Pull from the source every interval on a background thread, until the replica is dropped
+ */
+pub fn follow(replica: &Arc<Replica>, interval: Duration) -> JoinHandle<()> {
+    let replica: Weak<Replica> = Arc::downgrade(replica);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        match replica.upgrade() {
+            Some(replica) => {
+                if let Err(e) = replica.pull() {
+                    warn!("Failed to pull from {}: {}", replica.source, e);
+                }
+            }
+            None => break,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::IndexReader;
+    use crate::segment::{SegmentOptions, SegmentedIndex};
+    use crate::store::MemoryStorage;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_replicate_commits() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("goede-replica-{}", std::process::id()));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let options = SegmentOptions {
+            segment_size: 100,
+            merge_factor: 100,
+        };
+        let primary = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        primary.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        primary.commit()?;
+        assert_eq!(publish(storage.as_ref(), &dir)?, 1);
+        assert_eq!(publish(storage.as_ref(), &dir)?, 1);
+
+        let replica = Replica::open(&dir.to_string_lossy(), Schema::default())?;
+        let before = replica.searcher()?;
+        assert_eq!(before.size(), 356);
+        assert_eq!(
+            before.query_index("anarchism"),
+            primary.query_index("anarchism")
+        );
+        assert!(!replica.pull()?);
+
+        let deleted = primary.query_index("anarchism")[0];
+        primary.delete(deleted)?;
+        primary.commit()?;
+        assert_eq!(publish(storage.as_ref(), &dir)?, 2);
+
+        assert!(replica.pull()?);
+        assert_eq!(replica.generation(), Some(2));
+        let after = replica.searcher()?;
+        assert_eq!(after.size(), 355);
+        assert!(!after.query_index("anarchism").contains(&deleted));
+        // Searchers from before the pull still see the generation they started with
+        assert_eq!(before.size(), 356);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
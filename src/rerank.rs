@@ -0,0 +1,243 @@
+/**
+ * The rerank module hands the best candidates of a search to an external reranking service,
+ * such as a cross-encoder, and orders the results the way it says to
+ *
+ * The service is sent a `POST` with a JSON RerankRequest of the query and the candidates, and
+ * answers with a JSON RerankResponse of the candidates' ids and new scores, best first. When the
+ * service cannot be reached, is too slow or answers with anything else, the original ranking is
+ * returned instead, so a broken reranker never breaks searching.
+ */
+use crate::engine::DocumentId;
+use crate::server::{Hit, SearchResponse};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Error;
+use std::time::Duration;
+
+/**
+ * The number of candidates sent to the reranker by default, when that is more than asked for
+ */
+pub const DEFAULT_CANDIDATES: usize = 50;
+
+/**
+ * A single candidate for the reranker to score
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RerankDocument {
+    pub id: DocumentId,
+    pub title: String,
+    pub text: String,
+    /**
+     * The score of the candidate in the original ranking
+     */
+    pub score: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RerankRequest {
+    pub query: String,
+    pub documents: Vec<RerankDocument>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RerankResult {
+    pub id: DocumentId,
+    pub score: f64,
+}
+
+/**
+ * The candidates in their new order, best first
+ *
+ * Candidates which are left out keep their original order after all of those which are not.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RerankResponse {
+    pub results: Vec<RerankResult>,
+}
+
+#[derive(Debug)]
+pub struct Reranker {
+    url: String,
+    agent: ureq::Agent,
+    candidates: usize,
+}
+
+impl Reranker {
+    /**
+     * Rerank with the service at the url, falling back to the original ranking when it takes
+     * longer than the timeout to answer
+     */
+    pub fn new(url: &str, timeout: Duration) -> Self {
+        Self {
+            url: url.to_string(),
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+            candidates: DEFAULT_CANDIDATES,
+        }
+    }
+
+    /**
+     * Send this many of the best hits to the reranker, or as many as were asked for if that is
+     * more
+     */
+    pub fn candidates(mut self, candidates: usize) -> Self {
+        self.candidates = candidates;
+        self
+    }
+
+    /**
+     * How many hits to search for so that there are enough candidates for `limit` results
+     */
+    pub fn limit(&self, limit: usize) -> usize {
+        limit.max(self.candidates)
+    }
+
+    fn call(&self, query: &str, hits: &[Hit]) -> Result<RerankResponse, Error> {
+        let request = RerankRequest {
+            query: query.to_string(),
+            documents: hits
+                .iter()
+                .map(|hit| RerankDocument {
+                    id: hit.id,
+                    title: hit
+                        .article
+                        .as_ref()
+                        .map(|a| a.title().to_string())
+                        .unwrap_or_default(),
+                    text: hit
+                        .article
+                        .as_ref()
+                        .map(|a| a.abstract_text().to_string())
+                        .unwrap_or_default(),
+                    score: hit.score,
+                })
+                .collect(),
+        };
+        let body = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_bytes(&serde_json::to_vec(&request)?)
+            .map_err(|e| Error::other(format!("reranking with {} failed: {}", self.url, e)))?
+            .into_string()?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /**
+     * Reorder the hits of the response as the reranker says to, keeping at most `limit` of
+     * them, or just keep the best `limit` of them if the reranker fails
+     */
+    pub fn rerank(
+        &self,
+        query: &str,
+        mut response: SearchResponse,
+        limit: usize,
+    ) -> SearchResponse {
+        if response.hits.is_empty() {
+            return response;
+        }
+        match self.call(query, &response.hits) {
+            Ok(reranked) => {
+                let mut hits: HashMap<DocumentId, Hit> = response
+                    .hits
+                    .iter()
+                    .map(|hit| (hit.id, hit.clone()))
+                    .collect();
+                let mut ordered = vec![];
+                for result in reranked.results {
+                    if let Some(mut hit) = hits.remove(&result.id) {
+                        hit.score = result.score;
+                        ordered.push(hit);
+                    }
+                }
+                ordered.extend(
+                    response
+                        .hits
+                        .into_iter()
+                        .filter(|hit| hits.contains_key(&hit.id)),
+                );
+                response.hits = ordered;
+            }
+            Err(e) => warn!("Keeping the original ranking of `{}`: {}", query, e),
+        }
+        response.hits.truncate(limit);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /**
+     * Answer a single request by reversing the order of the candidates
+     */
+    fn reverse(listener: TcpListener) -> Result<(), Error> {
+        let (stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        let request: RerankRequest = serde_json::from_slice(&body)?;
+        let response = RerankResponse {
+            results: request
+                .documents
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(rank, document)| RerankResult {
+                    id: document.id,
+                    score: 1.0 / (rank + 1) as f64,
+                })
+                .collect(),
+        };
+        let body = serde_json::to_vec(&response)?;
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)
+    }
+
+    #[test]
+    fn test_rerank() -> Result<(), Error> {
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let original = crate::server::search(&index, "history", 4);
+        let ids: Vec<_> = original.hits.iter().map(|hit| hit.id).collect();
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/rerank", listener.local_addr()?);
+        let server = std::thread::spawn(move || reverse(listener));
+        let reranker = Reranker::new(&url, Duration::from_secs(5)).candidates(4);
+        assert_eq!(reranker.limit(2), 4);
+        let reranked = reranker.rerank("history", original.clone(), 2);
+        server.join().unwrap()?;
+        let reranked: Vec<_> = reranked.hits.iter().map(|hit| hit.id).collect();
+        assert_eq!(reranked, vec![ids[3], ids[2]]);
+
+        // A reranker which never answers leaves the original ranking
+        let silent = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/rerank", silent.local_addr()?);
+        let reranker = Reranker::new(&url, Duration::from_millis(200));
+        let fallback = reranker.rerank("history", original.clone(), 2);
+        assert_eq!(fallback.hits, original.hits[..2]);
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+/**
+ * The rewrite module transforms the clauses of queries before they are evaluated, through any
+ * number of Rewriters registered with a RewritingReader
+ *
+ * The Rewriters are applied in the order they were added, each one being handed the clauses the
+ * one before it returned, so that e.g. acronyms can be expanded before a tenant's filter is
+ * added.
+ */
+use crate::engine::{Article, DocumentId, FieldLengths, IndexReader, TermStats};
+use crate::postings::PostingsRef;
+use crate::query::{Clause, Rewriter};
+use crate::schema::{Field, Schema};
+use crate::scoring::Ranking;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/**
+ * Add the clause to every query, e.g. `Filter::new(Clause::Field(Field::Domain, tenant))` to
+ * only ever find the documents of a single tenant
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    clause: Clause,
+}
+
+impl Filter {
+    pub fn new(clause: Clause) -> Self {
+        Self { clause }
+    }
+}
+
+impl Rewriter for Filter {
+    fn rewrite(&self, mut clauses: Vec<Clause>) -> Vec<Clause> {
+        clauses.push(self.clause.clone());
+        clauses
+    }
+}
+
+/**
+ * A reader which rewrites the queries evaluated against the reader it wraps
+ *
+ * Like the BoostedReader, queries are evaluated by this reader itself so that the rewriters are
+ * applied whichever reader is wrapped.
+ */
+pub struct RewritingReader {
+    reader: Box<dyn IndexReader>,
+    rewriters: Vec<Box<dyn Rewriter>>,
+}
+
+impl RewritingReader {
+    pub fn new(reader: Box<dyn IndexReader>) -> Self {
+        Self {
+            reader,
+            rewriters: vec![],
+        }
+    }
+
+    /**
+     * Apply the rewriter after those already added
+     */
+    pub fn with<W: Rewriter + 'static>(mut self, rewriter: W) -> Self {
+        self.rewriters.push(Box::new(rewriter));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rewriters.is_empty()
+    }
+
+    /**
+     * The wrapped reader, or this one when there are any rewriters
+     */
+    pub fn into_reader(self) -> Box<dyn IndexReader> {
+        match self.is_empty() {
+            true => self.reader,
+            false => Box::new(self),
+        }
+    }
+}
+
+impl IndexReader for RewritingReader {
+    fn schema(&self) -> &Schema {
+        self.reader.schema()
+    }
+
+    fn size(&self) -> u64 {
+        self.reader.size()
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.reader.document_ids()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.reader.document(id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.reader.terms()
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.reader.postings(term)
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.reader.field_terms(field)
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.reader.field_postings(field, term)
+    }
+
+    fn postings_ref(&self, term: &str) -> Option<PostingsRef<'_>> {
+        self.reader.postings_ref(term)
+    }
+
+    fn field_postings_ref(&self, field: Field, term: &str) -> Option<PostingsRef<'_>> {
+        self.reader.field_postings_ref(field, term)
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.reader.term_frequency(id, term)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.reader.positions(id, term)
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        self.reader.idf(term)
+    }
+
+    fn term_stats(&self, term: &str) -> Option<TermStats> {
+        self.reader.term_stats(term)
+    }
+
+    fn document_length(&self, id: DocumentId) -> Option<f64> {
+        self.reader.document_length(id)
+    }
+
+    fn average_document_length(&self) -> f64 {
+        self.reader.average_document_length()
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.reader.field_lengths(id)
+    }
+
+    fn average_field_lengths(&self) -> (f64, f64) {
+        self.reader.average_field_lengths()
+    }
+
+    fn ranking(&self) -> Ranking {
+        self.reader.ranking()
+    }
+
+    fn boost(&self, id: DocumentId) -> f64 {
+        self.reader.boost(id)
+    }
+
+    fn related_terms(&self, term: &str, n: usize) -> Vec<(String, f64)> {
+        self.reader.related_terms(term, n)
+    }
+
+    fn substring_candidates(&self, needle: &str) -> Option<HashSet<DocumentId>> {
+        self.reader.substring_candidates(needle)
+    }
+
+    fn terms_with_suffix(&self, suffix: &str) -> Option<Vec<String>> {
+        self.reader.terms_with_suffix(suffix)
+    }
+
+    fn rewrite(&self, clauses: Vec<Clause>) -> Vec<Clause> {
+        self.rewriters
+            .iter()
+            .fold(self.reader.rewrite(clauses), |clauses, rewriter| {
+                rewriter.rewrite(clauses)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+
+    #[test]
+    fn test_rewriters() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let nasa = Article::new(
+            "NASA",
+            "the national aeronautics and space administration",
+            "https://example.com/nasa",
+        )?;
+        let mirror = Article::new(
+            "Mirror",
+            "the national aeronautics and space administration",
+            "https://mirror.example.org/nasa",
+        )?;
+        let other = Article::new("Sports", "a win", "https://example.com/sports")?;
+        let ids = [nasa.id(), mirror.id()];
+        for article in [nasa, mirror, other] {
+            index.index_document(article)?;
+        }
+        assert_eq!(index.query_index("nasa"), vec![ids[0]]);
+
+        let expand = |clauses: Vec<Clause>| -> Vec<Clause> {
+            clauses
+                .into_iter()
+                .map(|clause| match clause {
+                    Clause::Text(text) if text.eq_ignore_ascii_case("nasa") => {
+                        Clause::Text("national aeronautics space administration".to_string())
+                    }
+                    clause => clause,
+                })
+                .collect()
+        };
+        let reader = RewritingReader::new(Box::new(index)).with(expand);
+        let mut results = reader.query_index("nasa");
+        results.sort_unstable();
+        let mut expected = ids.to_vec();
+        expected.sort_unstable();
+        assert_eq!(results, expected);
+
+        let reader = reader.with(Filter::new(Clause::Field(
+            Field::Domain,
+            "example.com".to_string(),
+        )));
+        assert_eq!(reader.query_index("nasa"), vec![ids[0]]);
+        assert_eq!(reader.search("nasa", 10).len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+/**
+ * The schema module describes the fields of a document and how each of them should be analyzed
+ */
+use crate::config::{AnalysisConfig, RankingConfig};
+use crate::filters::Analyzer;
+use crate::scoring::Ranking;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Error;
+
+/**
+ * The fields of an Article which can be searched individually with `field:value` clauses
+ */
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Field {
+    Title,
+    Abstract,
+    Url,
+    /**
+     * The host portion of the url, e.g. `en.wikipedia.org`
+     */
+    Domain,
+    /**
+     * The metadata of the document, searched as `meta:key=value` for the keys which the
+     * Schema indexes
+     */
+    Metadata,
+}
+
+impl Field {
+    pub const ALL: &'static [Field] = &[
+        Field::Title,
+        Field::Abstract,
+        Field::Url,
+        Field::Domain,
+        Field::Metadata,
+    ];
+
+    /**
+     * The name of the field as used in queries
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Abstract => "abstract",
+            Field::Url => "url",
+            Field::Domain => "domain",
+            Field::Metadata => "meta",
+        }
+    }
+}
+
+impl std::str::FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Field::ALL
+            .iter()
+            .find(|field| field.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown field `{}`", s))
+    }
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.name())
+    }
+}
+
+/**
+ * The Schema assigns an Analyzer to the full text of the document, which is what unqualified
+ * query text is matched against, and to each of the fields which should be searchable on
+ * their own.
+ *
+ * The same Schema is used when indexing and querying so that a `field:value` clause is always
+ * analyzed the same way as the field was.
+ */
+#[derive(Clone, Debug)]
+pub struct Schema {
+    text: Analyzer,
+    fields: HashMap<Field, Analyzer>,
+    /**
+     * The metadata keys whose values are indexed as keywords
+     */
+    metadata: BTreeSet<String>,
+    /**
+     * The declarative configuration this Schema was built from, if it was built from one,
+     * which is what allows the same pipeline to be rebuilt when the index is reopened
+     */
+    config: Option<AnalysisConfig>,
+    /**
+     * The ranking configuration the index is searched with, which is remembered along with the
+     * analysis although it does not affect it
+     */
+    ranking_config: Option<RankingConfig>,
+    ranking: Ranking,
+}
+
+impl Default for Schema {
+    /**
+     * The default Schema analyzes the title and abstract as full text, and treats the url and
+     * domain as keywords which must match exactly
+     *
+     * The text fields share a single Analyzer so that they also share its stem cache. This is
+     * the same Schema as the default AnalysisConfig describes, so it is recorded as such.
+     */
+    fn default() -> Self {
+        let text = Analyzer::default();
+        Self::new(text.clone())
+            .field(Field::Title, text.clone())
+            .field(Field::Abstract, text)
+            .field(Field::Url, Analyzer::keyword())
+            .field(Field::Domain, Analyzer::keyword())
+            .with_config(AnalysisConfig::default())
+    }
+}
+
+impl Schema {
+    /**
+     * Create a Schema using the given Analyzer for the full text and without any individually
+     * searchable fields
+     */
+    pub fn new(text: Analyzer) -> Self {
+        Self {
+            text,
+            fields: HashMap::new(),
+            metadata: BTreeSet::new(),
+            config: None,
+            ranking_config: None,
+            ranking: Ranking::default(),
+        }
+    }
+
+    /**
+     * Record the configuration which this Schema was built from
+     */
+    pub fn with_config(mut self, config: AnalysisConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn config(&self) -> Option<&AnalysisConfig> {
+        self.config.as_ref()
+    }
+
+    /**
+     * Search with the ranking configuration, failing if it does not describe a Ranking
+     */
+    pub fn with_ranking(mut self, config: RankingConfig) -> Result<Self, Error> {
+        self.ranking = config.ranking()?;
+        self.ranking_config = Some(config);
+        Ok(self)
+    }
+
+    pub fn ranking_config(&self) -> Option<&RankingConfig> {
+        self.ranking_config.as_ref()
+    }
+
+    pub fn ranking(&self) -> Ranking {
+        self.ranking
+    }
+
+    /**
+     * The fingerprint of the configuration this Schema was built from, None if it was
+     * assembled in code and so cannot be fingerprinted
+     */
+    pub fn fingerprint(&self) -> Result<Option<String>, Error> {
+        self.config.as_ref().map(|c| c.fingerprint()).transpose()
+    }
+
+    /**
+     * Make the field individually searchable, analyzed with the given Analyzer
+     *
+     * The Schema no longer matches any configuration it was built from afterwards.
+     */
+    pub fn field(mut self, field: Field, analyzer: Analyzer) -> Self {
+        self.fields.insert(field, analyzer);
+        self.config = None;
+        self
+    }
+
+    /**
+     * Index the values of the metadata key as keywords, so that they can be searched with
+     * `meta:key=value`
+     *
+     * Metadata which is not indexed is still stored and returned with the document. The key
+     * is added to any configuration the Schema was built from, so that it is remembered by the
+     * indexes built with it.
+     */
+    pub fn metadata(mut self, key: &str) -> Self {
+        self.metadata.insert(key.to_string());
+        self.fields.insert(Field::Metadata, Analyzer::keyword());
+        if let Some(config) = self.config.as_mut() {
+            if !config.metadata.iter().any(|k| k == key) {
+                config.metadata.push(key.to_string());
+            }
+        }
+        self
+    }
+
+    /**
+     * The metadata keys which are indexed
+     */
+    pub fn metadata_keys(&self) -> impl Iterator<Item = &str> {
+        self.metadata.iter().map(|key| key.as_str())
+    }
+
+    /**
+     * Replace the Analyzer used for the full text
+     */
+    pub fn text(mut self, analyzer: Analyzer) -> Self {
+        self.text = analyzer;
+        self.config = None;
+        self
+    }
+
+    pub fn text_analyzer(&self) -> &Analyzer {
+        &self.text
+    }
+
+    /**
+     * The Analyzer for the field, or None if the field is not individually searchable
+     */
+    pub fn analyzer(&self, field: Field) -> Option<&Analyzer> {
+        self.fields.get(&field)
+    }
+
+    /**
+     * Iterate over the individually searchable fields and their Analyzers
+     */
+    pub fn fields(&self) -> impl Iterator<Item = (&Field, &Analyzer)> {
+        self.fields.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_from_str() {
+        assert_eq!("title".parse::<Field>(), Ok(Field::Title));
+        assert!("titel".parse::<Field>().is_err());
+    }
+
+    #[test]
+    fn test_default_schema_keyword_url() {
+        let schema = Schema::default();
+        let analyzer = schema.analyzer(Field::Url).unwrap();
+        assert_eq!(
+            analyzer.terms("https://en.wikipedia.org/wiki/Anarchism"),
+            vec!["https://en.wikipedia.org/wiki/Anarchism"]
+        );
+    }
+}
@@ -0,0 +1,673 @@
+/**
+ * The scoring module holds the functions which documents matching a query can be ranked with,
+ * so that different ranking functions can be compared against the same index
+ */
+use crate::engine::{DocumentId, IndexReader};
+use std::str::FromStr;
+
+/**
+ * How much each occurrence of a query term in a document adds to its score
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Scorer {
+    /**
+     * The frequency of the term in the document times its inverse document frequency
+     */
+    #[default]
+    TfIdf,
+    /**
+     * TF-IDF with the term frequencies divided by the length of the document pivoted around
+     * the average, as much as `slope` says to, so that long documents do not win just for
+     * repeating the terms more often
+     */
+    PivotedTfIdf { slope: f64 },
+    /**
+     * Okapi BM25, which saturates the term frequency by `k1` and normalizes it by the length of
+     * the document relative to the average, as much as `b` says to
+     */
+    Bm25 { k1: f64, b: f64 },
+    /**
+     * BM25F, which is BM25 over a term frequency combined from the title and the abstract, each
+     * weighted and normalized by its own length relative to the average
+     */
+    Bm25F {
+        k1: f64,
+        title: FieldWeight,
+        r#abstract: FieldWeight,
+    },
+    /**
+     * Query likelihood, the probability of the document's language model generating the query,
+     * with the model smoothed by that of the whole index with a Dirichlet prior of `mu`
+     */
+    Dirichlet { mu: f64 },
+}
+
+/**
+ * How much the occurrences of a term in one field of a document count for with BM25F, and how
+ * much they are normalized by the length of the field
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FieldWeight {
+    pub weight: f64,
+    pub b: f64,
+}
+
+impl FieldWeight {
+    /**
+     * What the frequency in a field is divided by, one when the field is of average length
+     */
+    fn normalization(&self, length: f64, average: f64) -> f64 {
+        match average > 0.0 {
+            true => 1.0 - self.b + self.b * length / average,
+            false => 1.0,
+        }
+    }
+}
+
+/**
+ * The average lengths of the documents in the index, which a Scorer needs to normalize the
+ * frequencies of terms in a document by its length
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Averages {
+    pub length: f64,
+    pub title: f64,
+    pub r#abstract: f64,
+}
+
+impl Scorer {
+    /**
+     * TF-IDF with pivoted length normalization of `slope = 0.75`, which normalizes as much as
+     * BM25 does with its usual parameters
+     */
+    pub fn pivoted_tfidf() -> Self {
+        Scorer::PivotedTfIdf { slope: 0.75 }
+    }
+
+    /**
+     * BM25 with the usual parameters of `k1 = 1.2` and `b = 0.75`
+     */
+    pub fn bm25() -> Self {
+        Scorer::Bm25 { k1: 1.2, b: 0.75 }
+    }
+
+    /**
+     * BM25F with the title weighing twice as much as the abstract
+     */
+    pub fn bm25f() -> Self {
+        Scorer::Bm25F {
+            k1: 1.2,
+            title: FieldWeight {
+                weight: 2.0,
+                b: 0.75,
+            },
+            r#abstract: FieldWeight {
+                weight: 1.0,
+                b: 0.75,
+            },
+        }
+    }
+
+    /**
+     * Query likelihood with the usual `mu = 2000`
+     */
+    pub fn dirichlet() -> Self {
+        Scorer::Dirichlet { mu: 2000.0 }
+    }
+
+    /**
+     * The averages which this scorer needs, fetched once for every query and handed to
+     * `weight()` and `score()` rather than fetched for every term or document
+     */
+    pub fn averages<R: IndexReader + ?Sized>(&self, reader: &R) -> Averages {
+        match self {
+            Scorer::TfIdf => Averages::default(),
+            Scorer::PivotedTfIdf { .. } | Scorer::Bm25 { .. } | Scorer::Dirichlet { .. } => {
+                Averages {
+                    length: reader.average_document_length(),
+                    ..Default::default()
+                }
+            }
+            Scorer::Bm25F { .. } => {
+                let (title, r#abstract) = reader.average_field_lengths();
+                Averages {
+                    length: title + r#abstract,
+                    title,
+                    r#abstract,
+                }
+            }
+        }
+    }
+
+    /**
+     * The weight of the (analyzed) term across the whole index, zero for terms which are not
+     * in it
+     *
+     * For query likelihood this is the probability of the term in the whole index.
+     */
+    pub fn weight<R: IndexReader + ?Sized>(
+        &self,
+        reader: &R,
+        averages: &Averages,
+        term: &str,
+    ) -> f64 {
+        match self {
+            Scorer::TfIdf | Scorer::PivotedTfIdf { .. } => reader.idf(term),
+            Scorer::Dirichlet { .. } => {
+                let total = averages.length * reader.size() as f64;
+                match reader.term_stats(term) {
+                    Some(stats) if total > 0.0 => stats.total_frequency as f64 / total,
+                    _ => 0.0,
+                }
+            }
+            Scorer::Bm25 { .. } | Scorer::Bm25F { .. } => {
+                let documents = reader.size() as f64;
+                match reader.postings(term) {
+                    Some(docs) if !docs.is_empty() => {
+                        let frequency = docs.len() as f64;
+                        (1.0 + (documents - frequency + 0.5) / (frequency + 0.5)).ln()
+                    }
+                    _ => 0.0,
+                }
+            }
+        }
+    }
+
+    /**
+     * The score of the document for the terms of a query, along with their weights
+     */
+    pub fn score<R: IndexReader + ?Sized>(
+        &self,
+        reader: &R,
+        averages: &Averages,
+        id: DocumentId,
+        terms: &[(&String, f64)],
+    ) -> f64 {
+        match self {
+            Scorer::TfIdf => terms
+                .iter()
+                .filter_map(|(term, weight)| Some(weight * reader.term_frequency(id, term)?))
+                .sum(),
+            Scorer::PivotedTfIdf { slope } => {
+                let length = reader.document_length(id).unwrap_or(averages.length);
+                let norm = FieldWeight {
+                    weight: 1.0,
+                    b: *slope,
+                }
+                .normalization(length, averages.length);
+                terms
+                    .iter()
+                    .filter_map(|(term, weight)| {
+                        Some(weight * reader.term_frequency(id, term)? / norm)
+                    })
+                    .sum()
+            }
+            Scorer::Bm25 { k1, b } => {
+                let length = reader.document_length(id).unwrap_or(averages.length);
+                let norm =
+                    FieldWeight { weight: 1.0, b: *b }.normalization(length, averages.length);
+                terms
+                    .iter()
+                    .filter_map(|(term, weight)| {
+                        let frequency = reader.term_frequency(id, term)?;
+                        Some(saturate(*k1, frequency / norm) * weight)
+                    })
+                    .sum()
+            }
+            Scorer::Bm25F {
+                k1,
+                title,
+                r#abstract,
+            } => {
+                let lengths = match reader.field_lengths(id) {
+                    Some(lengths) => lengths,
+                    None => return 0.0,
+                };
+                let title_norm = title.normalization(lengths.title, averages.title);
+                let abstract_norm =
+                    r#abstract.normalization(lengths.r#abstract, averages.r#abstract);
+                terms
+                    .iter()
+                    .filter_map(|(term, weight)| {
+                        let positions = reader.positions(id, term)?;
+                        let in_title = positions
+                            .iter()
+                            .filter(|p| **p < lengths.abstract_start)
+                            .count() as f64;
+                        let in_abstract = positions.len() as f64 - in_title;
+                        let frequency = title.weight * in_title / title_norm
+                            + r#abstract.weight * in_abstract / abstract_norm;
+                        Some(saturate(*k1, frequency) * weight)
+                    })
+                    .sum()
+            }
+            Scorer::Dirichlet { mu } => {
+                // The geometric mean of the smoothed probabilities of the terms ranks the same
+                // as their log likelihood does, but stays positive for boosts to multiply
+                let length = reader.document_length(id).unwrap_or(averages.length);
+                let terms: Vec<f64> = terms
+                    .iter()
+                    .filter(|(_, probability)| *probability > 0.0)
+                    .map(|(term, probability)| {
+                        let frequency = reader.term_frequency(id, term).unwrap_or(0.0);
+                        ((frequency + mu * probability) / (length + mu)).ln()
+                    })
+                    .collect();
+                match terms.len() {
+                    0 => 0.0,
+                    n => (terms.iter().sum::<f64>() / n as f64).exp(),
+                }
+            }
+        }
+    }
+}
+
+/**
+ * The (normalized) frequency of a term saturated by `k1`, so that repeating a term has
+ * diminishing returns
+ */
+fn saturate(k1: f64, frequency: f64) -> f64 {
+    frequency * (k1 + 1.0) / (frequency + k1)
+}
+
+/**
+ * The bonus for documents whose title matches the free text of a query, which the score of the
+ * document is multiplied by
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TitleBonus {
+    /**
+     * The bonus when every term of the query is in the title, and a part of it for some of them
+     */
+    pub matched: f64,
+    /**
+     * The further bonus when the title is the query, ignoring case and whitespace
+     */
+    pub exact: f64,
+}
+
+impl Default for TitleBonus {
+    fn default() -> Self {
+        Self {
+            matched: 0.5,
+            exact: 1.0,
+        }
+    }
+}
+
+impl TitleBonus {
+    /**
+     * No bonus at all, so that titles only count as part of the full text
+     */
+    pub fn none() -> Self {
+        Self {
+            matched: 0.0,
+            exact: 0.0,
+        }
+    }
+
+    /**
+     * What the score is multiplied by when the title has `fraction` of the query's terms, and
+     * whether it is exactly the query
+     */
+    pub fn factor(&self, fraction: f64, exact: bool) -> f64 {
+        let exact = match exact {
+            true => self.exact,
+            false => 0.0,
+        };
+        1.0 + self.matched * fraction + exact
+    }
+}
+
+/**
+ * The bonus for documents in which the terms of a query are close together, which the score of
+ * the document is multiplied by
+ *
+ * Terms next to each other are much more likely to be about the same thing than terms from
+ * opposite ends of an abstract, even when the query did not quote them as a phrase.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProximityBonus {
+    /**
+     * The bonus when the terms are next to each other, which shrinks as the span of the document
+     * they are all found within grows
+     */
+    pub weight: f64,
+}
+
+impl Default for ProximityBonus {
+    fn default() -> Self {
+        Self { weight: 0.5 }
+    }
+}
+
+impl ProximityBonus {
+    /**
+     * No bonus at all, so that where the terms are does not matter
+     */
+    pub fn none() -> Self {
+        Self { weight: 0.0 }
+    }
+
+    /**
+     * What the score is multiplied by when the number of distinct terms are all found within a
+     * span of that many positions
+     */
+    pub fn factor(&self, terms: usize, span: usize) -> f64 {
+        match terms > 1 {
+            true => 1.0 + self.weight * terms as f64 / span.max(terms) as f64,
+            false => 1.0,
+        }
+    }
+}
+
+/**
+ * When to stop scoring the documents matching a query, trading the accuracy of the results for
+ * how quickly they come back
+ *
+ * Once `hits` of the documents scored so far are within `ratio` of the best score so far, the
+ * rest are not scored at all, so a better document which had not been reached yet is missed.
+ * The threshold rises along with the best score, so the hits have to keep up with it. No hits,
+ * the default, scores every document.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EarlyExit {
+    pub hits: usize,
+    pub ratio: f64,
+}
+
+impl EarlyExit {
+    pub fn new(hits: usize, ratio: f64) -> Self {
+        Self { hits, ratio }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.hits > 0
+    }
+
+    /**
+     * The score which counts as a hit when the best score so far is `best`, which works for
+     * scorers whose scores are negative too
+     */
+    pub fn threshold(&self, best: f64) -> f64 {
+        best - (1.0 - self.ratio) * best.abs()
+    }
+}
+
+/**
+ * Everything about how the documents matching a query are scored
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ranking {
+    pub scorer: Scorer,
+    pub title: TitleBonus,
+    pub proximity: ProximityBonus,
+    pub early_exit: EarlyExit,
+}
+
+impl From<Scorer> for Ranking {
+    fn from(scorer: Scorer) -> Self {
+        Self {
+            scorer,
+            ..Default::default()
+        }
+    }
+}
+
+impl FromStr for Scorer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tfidf" | "tf-idf" => Ok(Scorer::TfIdf),
+            "pivoted" | "tfidf-pivoted" => Ok(Scorer::pivoted_tfidf()),
+            "bm25" => Ok(Scorer::bm25()),
+            "bm25f" => Ok(Scorer::bm25f()),
+            "dirichlet" | "lm" => Ok(Scorer::dirichlet()),
+            _ => Err(format!(
+                "unknown scorer `{}`, expected tfidf, pivoted, bm25, bm25f or dirichlet",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Article, Index};
+    use crate::query::{execute_ranked, normalize};
+
+    #[test]
+    fn test_bm25() -> Result<(), std::io::Error> {
+        assert_eq!("BM25".parse(), Ok(Scorer::bm25()));
+        assert_eq!("tfidf".parse(), Ok(Scorer::TfIdf));
+        assert!("cosine".parse::<Scorer>().is_err());
+
+        let bm25 = Scorer::bm25();
+        // Repeating a term has diminishing returns
+        assert!(saturate(1.2, 2.0) < 2.0 * saturate(1.2, 1.0));
+
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let query = normalize(index.schema(), "history");
+        let tfidf = execute_ranked(&index, &query, &Scorer::TfIdf.into());
+        let ranked = execute_ranked(&index, &query, &bm25.into());
+        assert_eq!(tfidf, index.search("history", usize::MAX));
+
+        let mut ids: Vec<_> = ranked.iter().map(|(id, _)| *id).collect();
+        let mut expected: Vec<_> = tfidf.iter().map(|(id, _)| *id).collect();
+        ids.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(ids, expected);
+        assert!(ranked.windows(2).all(|w| w[0].1 >= w[1].1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pivoted_tfidf() -> Result<(), std::io::Error> {
+        assert_eq!("pivoted".parse(), Ok(Scorer::pivoted_tfidf()));
+
+        let mut index = Index::new();
+        let short = Article::new("Jazz", "jazz music", "https://example.com/short")?;
+        let long = Article::new(
+            "Music",
+            "jazz is one of many kinds of music played all over the world, and jazz clubs are \
+             found in most of the cities where music is played late into the night",
+            "https://example.com/long",
+        )?;
+        let other = Article::new("Cooking", "a pie", "https://example.com/cooking")?;
+        let ids = [short.id(), long.id()];
+        for article in [short, long, other] {
+            index.index_document(article)?;
+        }
+        index.finalize();
+
+        let query = normalize(index.schema(), "jazz");
+        let plain = Ranking {
+            scorer: Scorer::TfIdf,
+            title: TitleBonus::none(),
+            ..Default::default()
+        };
+        let pivoted = Ranking {
+            scorer: Scorer::pivoted_tfidf(),
+            ..plain
+        };
+        assert_eq!(execute_ranked(&index, &query, &plain)[0].0, ids[1]);
+        assert_eq!(execute_ranked(&index, &query, &pivoted)[0].0, ids[0]);
+
+        // Without any slope it is plain TF-IDF
+        let flat = Ranking {
+            scorer: Scorer::PivotedTfIdf { slope: 0.0 },
+            ..plain
+        };
+        assert_eq!(
+            execute_ranked(&index, &query, &flat),
+            execute_ranked(&index, &query, &plain)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bm25f() -> Result<(), std::io::Error> {
+        let mut index = Index::new();
+        let titled = Article::new("Jazz", "music from new orleans", "https://example.com/jazz")?;
+        let mentions = Article::new(
+            "New Orleans",
+            "a city known for jazz music",
+            "https://example.com/orleans",
+        )?;
+        let other = Article::new("Cooking", "a pie", "https://example.com/cooking")?;
+        let ids = [titled.id(), mentions.id()];
+        for article in [titled, mentions, other] {
+            index.index_document(article)?;
+        }
+        index.finalize();
+
+        let lengths = index.field_lengths(ids[1]).unwrap();
+        assert_eq!((lengths.title, lengths.r#abstract), (2.0, 4.0));
+        assert_eq!(index.document_length(ids[1]), Some(6.0));
+
+        let query = normalize(index.schema(), "jazz");
+        let ranking = Ranking {
+            scorer: Scorer::bm25f(),
+            title: TitleBonus::none(),
+            ..Default::default()
+        };
+        let ranked = execute_ranked(&index, &query, &ranking);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].0, ids[0]);
+        assert!(ranked[0].1 > ranked[1].1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dirichlet() -> Result<(), std::io::Error> {
+        assert_eq!("lm".parse(), Ok(Scorer::dirichlet()));
+
+        let mut index = Index::new();
+        let short = Article::new("Jazz", "jazz music", "https://example.com/short")?;
+        let long = Article::new(
+            "Music",
+            "jazz is one of many kinds of music played all over the world",
+            "https://example.com/long",
+        )?;
+        let other = Article::new("Cooking", "a pie", "https://example.com/cooking")?;
+        let ids = [short.id(), long.id()];
+        for article in [short, long, other] {
+            index.index_document(article)?;
+        }
+        index.finalize();
+
+        let scorer = Scorer::Dirichlet { mu: 10.0 };
+        let probability = scorer.weight(&index, &scorer.averages(&index), "jazz");
+        let total = index.average_document_length() * 3.0;
+        assert!((probability - 3.0 / total).abs() < 1e-9);
+
+        let query = normalize(index.schema(), "jazz music");
+        let ranked = execute_ranked(&index, &query, &scorer.into());
+        assert_eq!(ranked[0].0, ids[0]);
+        assert!(ranked.iter().all(|(_, score)| *score > 0.0 && *score < 1.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_title_bonus() -> Result<(), std::io::Error> {
+        let bonus = TitleBonus::default();
+        assert_eq!(bonus.factor(0.0, false), 1.0);
+        assert_eq!(bonus.factor(1.0, true), 2.5);
+        assert_eq!(TitleBonus::none().factor(1.0, true), 1.0);
+
+        let mut index = Index::new();
+        let mentions = Article::new(
+            "Sports",
+            "football is played on a field, and football is popular",
+            "https://example.com/sports",
+        )?;
+        let titled = Article::new(
+            "Football",
+            "a game played with a ball",
+            "https://example.com/football",
+        )?;
+        let other = Article::new("Cooking", "a pie", "https://example.com/cooking")?;
+        let ids = [mentions.id(), titled.id()];
+        for article in [mentions, titled, other] {
+            index.index_document(article)?;
+        }
+
+        let query = normalize(index.schema(), "football");
+        let plain = Ranking {
+            title: TitleBonus::none(),
+            ..Default::default()
+        };
+        assert_eq!(execute_ranked(&index, &query, &plain)[0].0, ids[0]);
+        assert_eq!(index.search("football", 1)[0].0, ids[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_early_exit() -> Result<(), std::io::Error> {
+        let early_exit = EarlyExit::new(3, 0.5);
+        assert_eq!(early_exit.threshold(4.0), 2.0);
+        assert_eq!(early_exit.threshold(-4.0), -6.0);
+
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let query = normalize(index.schema(), "world");
+        let exact = execute_ranked(&index, &query, &Ranking::default());
+        assert!(exact.len() > 3);
+
+        // Every score is within no ratio at all of the best, so the first few are all it takes
+        let approximate = Ranking {
+            early_exit: EarlyExit::new(3, 0.0),
+            ..Default::default()
+        };
+        let ranked = execute_ranked(&index, &query, &approximate);
+        assert_eq!(ranked.len(), 3);
+        assert!(ranked.iter().all(|hit| exact.contains(hit)));
+
+        let strict = Ranking {
+            early_exit: EarlyExit::new(exact.len(), 1.0),
+            ..Default::default()
+        };
+        assert_eq!(execute_ranked(&index, &query, &strict), exact);
+        Ok(())
+    }
+
+    #[test]
+    fn test_proximity_bonus() -> Result<(), std::io::Error> {
+        let bonus = ProximityBonus::default();
+        assert_eq!(bonus.factor(1, 1), 1.0);
+        assert_eq!(bonus.factor(2, 2), 1.5);
+        assert_eq!(bonus.factor(2, 4), 1.25);
+        assert_eq!(ProximityBonus::none().factor(2, 2), 1.0);
+
+        let mut index = Index::new();
+        let apart = Article::new(
+            "Travel",
+            "new guide to a city york",
+            "https://example.com/a",
+        )?;
+        let close = Article::new(
+            "Travel",
+            "a guide to new york city",
+            "https://example.com/b",
+        )?;
+        let other = Article::new("Cooking", "a pie", "https://example.com/cooking")?;
+        let ids = [apart.id(), close.id()];
+        for article in [apart, close, other] {
+            index.index_document(article)?;
+        }
+
+        let query = normalize(index.schema(), "new york");
+        let ranked = execute_ranked(&index, &query, &Ranking::default());
+        assert_eq!(ranked[0].0, ids[1]);
+        assert!(ranked[0].1 > ranked[1].1);
+
+        let plain = Ranking {
+            proximity: ProximityBonus::none(),
+            ..Default::default()
+        };
+        let ranked = execute_ranked(&index, &query, &plain);
+        assert_eq!(ranked[0].1, ranked[1].1);
+        Ok(())
+    }
+}
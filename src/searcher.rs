@@ -0,0 +1,249 @@
+/**
+ * The searcher module splits an index's life into its build phase, in an IndexWriter, and the
+ * phase where it is only searched, through a Searcher
+ *
+ * A Searcher can never be changed, so it is shared behind an Arc and cloning it only bumps a
+ * reference count, which lets a server hand the same loaded index to every request thread.
+ * `Index::snapshot()` hands out Searchers of an index which is still changing instead.
+ */
+use crate::engine::{Article, DocumentId, FieldLengths, Index, IndexReader, Parsed, TermStats};
+use crate::postings::PostingsRef;
+use crate::query::Clause;
+use crate::schema::{Field, Schema};
+use crate::scoring::Ranking;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+/**
+ * An index which is still having documents added to and removed from it
+ */
+#[derive(Debug, Default)]
+pub struct IndexWriter {
+    index: Index,
+}
+
+impl From<Index> for IndexWriter {
+    fn from(index: Index) -> Self {
+        Self { index }
+    }
+}
+
+impl IndexWriter {
+    pub fn new(schema: Schema) -> Self {
+        Self::from(Index::with_schema(schema))
+    }
+
+    /**
+     * Index every document in the Wikipedia XML dump at the given path
+     */
+    pub fn load_file(&mut self, path: &Path) -> crate::error::Result<Parsed> {
+        self.index.load_file(path)
+    }
+
+    pub fn add(&mut self, article: Article) -> Result<(), Error> {
+        self.index.index_document(article)
+    }
+
+    /**
+     * Remove the document, returning whether it was in the index
+     */
+    pub fn remove(&mut self, id: &DocumentId) -> Result<bool, Error> {
+        self.index.remove_document(id)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.index.size()
+    }
+
+    /**
+     * Finish building the index, which cannot be changed from then on
+     */
+    pub fn searcher(mut self) -> Searcher {
+        self.index.finalize();
+        Searcher::from(self.index)
+    }
+}
+
+/**
+ * A finished index which is cheap to clone and can be searched from any number of threads
+ */
+#[derive(Clone, Debug)]
+pub struct Searcher {
+    index: Arc<Index>,
+}
+
+impl From<Index> for Searcher {
+    fn from(index: Index) -> Self {
+        Self {
+            index: Arc::new(index),
+        }
+    }
+}
+
+impl Searcher {
+    /**
+     * The index being searched, e.g. to save it
+     */
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /**
+     * Query the index for the given query string on tokio's blocking thread pool, so that
+     * evaluating it never stalls the executor's threads
+     */
+    #[cfg(feature = "async")]
+    pub async fn query_index_async(&self, query: &str) -> Result<Vec<DocumentId>, Error> {
+        let searcher = self.clone();
+        let query = query.to_string();
+        crate::engine::blocking(move || searcher.query_index(&query)).await
+    }
+}
+
+impl IndexReader for Searcher {
+    fn schema(&self) -> &Schema {
+        self.index.schema()
+    }
+
+    fn size(&self) -> u64 {
+        self.index.size()
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.index.document_ids()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.index.document(id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.index.terms()
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.index.postings(term)
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.index.field_terms(field)
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.index.field_postings(field, term)
+    }
+
+    fn postings_ref(&self, term: &str) -> Option<PostingsRef<'_>> {
+        self.index.postings_ref(term)
+    }
+
+    fn field_postings_ref(&self, field: Field, term: &str) -> Option<PostingsRef<'_>> {
+        self.index.field_postings_ref(field, term)
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.index.term_frequency(id, term)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.index.positions(id, term)
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        self.index.idf(term)
+    }
+
+    fn term_stats(&self, term: &str) -> Option<TermStats> {
+        self.index.term_stats(term)
+    }
+
+    fn document_length(&self, id: DocumentId) -> Option<f64> {
+        self.index.document_length(id)
+    }
+
+    fn average_document_length(&self) -> f64 {
+        self.index.average_document_length()
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.index.field_lengths(id)
+    }
+
+    fn average_field_lengths(&self) -> (f64, f64) {
+        self.index.average_field_lengths()
+    }
+
+    fn ranking(&self) -> Ranking {
+        self.index.ranking()
+    }
+
+    fn boost(&self, id: DocumentId) -> f64 {
+        self.index.boost(id)
+    }
+
+    fn related_terms(&self, term: &str, n: usize) -> Vec<(String, f64)> {
+        self.index.related_terms(term, n)
+    }
+
+    fn substring_candidates(&self, needle: &str) -> Option<HashSet<DocumentId>> {
+        self.index.substring_candidates(needle)
+    }
+
+    fn terms_with_suffix(&self, suffix: &str) -> Option<Vec<String>> {
+        self.index.terms_with_suffix(suffix)
+    }
+
+    fn rewrite(&self, clauses: Vec<Clause>) -> Vec<Clause> {
+        self.index.rewrite(clauses)
+    }
+
+    fn query_index(&self, query: &str) -> Vec<DocumentId> {
+        self.index.query_index(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_write_then_search() -> Result<(), Error> {
+        let path = PathBuf::from("data/simple.xml.gz");
+        let mut writer = IndexWriter::new(Schema::default());
+        writer.load_file(&path)?;
+        let anarchism = writer.index.query_index("anarchism");
+        assert!(writer.remove(&anarchism[0])?);
+
+        let searcher = writer.searcher();
+        let shared = searcher.clone();
+        assert!(Arc::ptr_eq(&searcher.index, &shared.index));
+
+        let results = std::thread::spawn(move || shared.query_index("anarchism"))
+            .join()
+            .unwrap();
+        assert_eq!(results, &anarchism[1..]);
+        assert_eq!(searcher.size(), 355);
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async() -> Result<(), Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let path = PathBuf::from("data/simple.xml.gz");
+            let index = Index::from_file_async(path.clone()).await?;
+            assert_eq!(index.size(), 356);
+            let searcher = Searcher::from(index);
+            assert_eq!(
+                searcher.query_index_async("anarchism").await?,
+                Index::from_file(&path)?.query_index("anarchism")
+            );
+            Ok(())
+        })
+    }
+}
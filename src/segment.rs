@@ -0,0 +1,1085 @@
+/**
+ * The segment module contains the segmented index, which is made up of immutable segments that
+ * can be searched while new documents are still being written
+ *
+ * New documents are buffered in an in-memory writer, which is sealed into a new segment once it
+ * is full. Nothing which has been added is searchable until `refresh()` is called, and nothing
+ * is durable until `commit()` has persisted the segments to a Storage. Lots of small segments
+ * make for slow searches, so a background thread merges them together into larger ones.
+ *
+ * Segments are never rewritten, so deleting a document only marks it as deleted in the segment
+ * which holds it, and it is purged for good when that segment is next merged. Mutations since the
+ * last commit can optionally be recorded in a WriteAheadLog to be replayed after a crash.
+ */
+use crate::bloom::BloomFilter;
+use crate::disk::DiskIndex;
+use crate::engine::{read_articles, Article, DocumentId, FieldLengths, Index, IndexReader, Parsed};
+use crate::schema::{Field, Schema};
+use crate::store::{lock, read, write, Storage};
+use crate::wal::{Operation, WriteAheadLog};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+
+/**
+ * The key of the list of committed segments in the Storage, which is only ever replaced in a
+ * single put so that a crash part way through a commit leaves the previous commit intact
+ */
+const MANIFEST: &[u8] = b"manifest";
+
+/**
+ * A committed segment as it is listed in the manifest
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ManifestEntry {
+    pub(crate) name: String,
+    pub(crate) deleted: Vec<DocumentId>,
+}
+
+/**
+ * The segments in the last commit to the Storage, which is none if nothing has been committed
+ */
+pub(crate) fn committed(storage: &dyn Storage) -> Result<Option<Vec<ManifestEntry>>, Error> {
+    match storage.get(MANIFEST)? {
+        Some(manifest) => Ok(Some(serde_json::from_slice(&manifest)?)),
+        None => Ok(None),
+    }
+}
+
+/**
+ * Tuning for when segments are created and merged
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentOptions {
+    /**
+     * The number of documents the writer buffers before sealing them into a segment
+     */
+    pub segment_size: usize,
+    /**
+     * Once there are this many segments the smallest of them are merged into one
+     */
+    pub merge_factor: usize,
+}
+
+impl Default for SegmentOptions {
+    fn default() -> Self {
+        Self {
+            segment_size: 10_000,
+            merge_factor: 8,
+        }
+    }
+}
+
+/**
+ * A sealed segment along with the name it is persisted under, and the documents in it which
+ * have since been deleted
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct Segment {
+    name: String,
+    index: Arc<Index>,
+    /**
+     * Every document of the segment, deleted or not, so that finding which segment holds a
+     * document does not read it from each of them
+     */
+    ids: Arc<HashSet<DocumentId>>,
+    deleted: Arc<HashSet<DocumentId>>,
+    /**
+     * Every full text term of the segment, and every (field, term) of its fields, so that the
+     * segments which cannot contain a term are skipped without looking it up
+     */
+    terms: Arc<BloomFilter>,
+}
+
+impl Segment {
+    pub(crate) fn new(name: String, index: Arc<Index>, deleted: HashSet<DocumentId>) -> Self {
+        let mut fields: Vec<Field> = index.schema().fields().map(|(field, _)| *field).collect();
+        fields.push(Field::Metadata);
+        let field_terms: Vec<(Field, String)> = fields
+            .into_iter()
+            .flat_map(|field| {
+                index
+                    .field_terms(field)
+                    .into_iter()
+                    .map(move |term| (field, term))
+            })
+            .collect();
+        let terms = index.terms();
+        let mut filter = BloomFilter::with_capacity(terms.len() + field_terms.len());
+        for term in terms.iter() {
+            filter.insert(term);
+        }
+        for key in field_terms.iter() {
+            filter.insert(key);
+        }
+        Self {
+            name,
+            ids: Arc::new(index.document_ids().into_iter().collect()),
+            index,
+            deleted: Arc::new(deleted),
+            terms: Arc::new(filter),
+        }
+    }
+
+    fn is_live(&self, id: &DocumentId) -> bool {
+        self.ids.contains(id) && !self.deleted.contains(id)
+    }
+
+    /**
+     * Mark the document as deleted, if this segment holds it
+     */
+    fn delete(&mut self, id: DocumentId) -> bool {
+        if !self.is_live(&id) {
+            return false;
+        }
+        Arc::make_mut(&mut self.deleted).insert(id);
+        true
+    }
+
+    fn size(&self) -> u64 {
+        self.index.size() - self.deleted.len() as u64
+    }
+}
+
+struct Inner {
+    schema: Schema,
+    options: SegmentOptions,
+    storage: Option<Arc<dyn Storage>>,
+    writer: Mutex<Index>,
+    /**
+     * Segments which have been sealed but not yet refreshed into view
+     */
+    pending: Mutex<Vec<Segment>>,
+    segments: RwLock<Vec<Segment>>,
+    /**
+     * The names of the segments in the last commit, held for the duration of a commit
+     */
+    committed: Mutex<HashSet<String>>,
+    next_segment: AtomicU64,
+    /**
+     * Held for the duration of a merge, so that only one runs at a time
+     */
+    merging: Mutex<()>,
+    /**
+     * Where mutations are logged until they are committed, if anywhere
+     */
+    log: Mutex<Option<WriteAheadLog>>,
+}
+
+/**
+ * An index made up of immutable segments plus an in-memory writer
+ */
+pub struct SegmentedIndex {
+    inner: Arc<Inner>,
+    merges: Option<Sender<()>>,
+    merger: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for SegmentedIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "SegmentedIndex {{ options: {:?}, segments: {:?} }}",
+            self.inner.options,
+            self.segment_sizes()
+        )
+    }
+}
+
+impl SegmentedIndex {
+    /**
+     * Create an empty index which only lives in memory, and so cannot be committed
+     */
+    pub fn new(schema: Schema, options: SegmentOptions) -> Self {
+        Self::start(Inner::new(schema, options, None))
+    }
+
+    /**
+     * Open the index committed to the Storage, or an empty one if nothing has been committed
+     *
+     * Segments left behind by a commit which never finished are removed.
+     */
+    pub fn open(
+        schema: Schema,
+        options: SegmentOptions,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self, Error> {
+        let entries = committed(storage.as_ref())?.unwrap_or_default();
+
+        let mut segments = vec![];
+        for entry in entries.iter() {
+            let disk = DiskIndex::load(storage.as_ref(), &entry.name)?;
+            disk.check_schema(&schema)?;
+            let index = Index::from_reader(&disk)?;
+            segments.push(Segment::new(
+                entry.name.clone(),
+                Arc::new(index),
+                entry.deleted.iter().copied().collect(),
+            ));
+        }
+        let names: Vec<String> = entries.into_iter().map(|entry| entry.name).collect();
+        for name in storage.segments()? {
+            if !names.contains(&name) {
+                warn!("Removing the uncommitted segment {}", name);
+                storage.delete_segment(&name)?;
+            }
+        }
+        debug!("Opened {} committed segments", segments.len());
+
+        let inner = Inner::new(schema, options, Some(storage));
+        let next = names.iter().filter_map(|n| n.parse::<u64>().ok()).max();
+        inner
+            .next_segment
+            .store(next.map(|n| n + 1).unwrap_or(0), Ordering::SeqCst);
+        *write(&inner.segments)? = segments;
+        *lock(&inner.committed)? = names.into_iter().collect();
+        Ok(Self::start(inner))
+    }
+
+    /**
+     * Open the index committed to the Storage with the Schema its segments were built with, or
+     * None if nothing has been committed to it
+     */
+    pub fn open_committed(
+        options: SegmentOptions,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Option<Self>, Error> {
+        let schema = match committed(storage.as_ref())? {
+            Some(entries) => match entries.first() {
+                Some(entry) => DiskIndex::load(storage.as_ref(), &entry.name)?
+                    .schema()
+                    .clone(),
+                None => Schema::default(),
+            },
+            None => return Ok(None),
+        };
+        Self::open(schema, options, storage).map(Some)
+    }
+
+    fn start(inner: Inner) -> Self {
+        let inner = Arc::new(inner);
+        let (merges, requests) = channel::<()>();
+        let background = inner.clone();
+        let merger = std::thread::Builder::new()
+            .name("segment-merger".to_string())
+            .spawn(move || {
+                // Exits once the SegmentedIndex has been dropped along with the sender
+                while requests.recv().is_ok() {
+                    if let Err(e) = background.merge() {
+                        error!("Failed to merge segments: {}", e);
+                    }
+                }
+            })
+            .expect("Failed to spawn the segment merger");
+
+        Self {
+            inner,
+            merges: Some(merges),
+            merger: Some(merger),
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.inner.schema
+    }
+
+    /**
+     * Record every mutation from now on in the write-ahead log at the given path, after first
+     * replaying whatever it already holds, returning the number of operations replayed
+     *
+     * The log is truncated by every commit, so anything left in it was added or deleted after
+     * the last commit and would otherwise have been lost.
+     */
+    pub fn enable_log(&self, path: &Path) -> Result<usize, Error> {
+        let mut writer = lock(&self.inner.writer)?;
+        let (log, operations) = WriteAheadLog::open(path)?;
+        let replayed = operations.len();
+        for operation in operations.into_iter() {
+            self.inner.apply(&mut writer, operation, false)?;
+        }
+        if replayed > 0 {
+            info!("Replayed {} operations from {:?}", replayed, path);
+        }
+        *lock(&self.inner.log)? = Some(log);
+        Ok(replayed)
+    }
+
+    /**
+     * Buffer the article in the writer, sealing the writer into a pending segment once it is
+     * full
+     *
+     * Articles which are already in the index are ignored.
+     */
+    pub fn add(&self, article: Article) -> Result<(), Error> {
+        let mut writer = lock(&self.inner.writer)?;
+        self.inner
+            .apply(&mut writer, Operation::Add(article), true)
+            .map(|_| ())
+    }
+
+    /**
+     * Delete the document from the index, returning whether it was in there
+     *
+     * Unlike additions, deletions do not wait for a refresh, every searcher taken afterwards
+     * leaves the document out.
+     */
+    pub fn delete(&self, id: DocumentId) -> Result<bool, Error> {
+        let mut writer = lock(&self.inner.writer)?;
+        self.inner.apply(&mut writer, Operation::Delete(id), true)
+    }
+
+    /**
+     * Add every document in the Wikipedia XML dump at the given path
+     */
+    pub fn load_file(&self, path: &Path) -> crate::error::Result<Parsed> {
+        read_articles(path, |article| self.add(article))
+    }
+
+    /**
+     * Make everything which has been added so far visible to searches
+     */
+    pub fn refresh(&self) -> Result<(), Error> {
+        let mut writer = lock(&self.inner.writer)?;
+        self.refresh_writer(&mut writer)
+    }
+
+    fn refresh_writer(&self, writer: &mut Index) -> Result<(), Error> {
+        let mut pending = lock(&self.inner.pending)?;
+        let sealed = self.inner.seal(writer);
+
+        let mut refreshed: Vec<Segment> = pending.drain(..).collect();
+        refreshed.extend(sealed);
+        if refreshed.is_empty() {
+            return Ok(());
+        }
+        debug!("Refreshed {} segments into view", refreshed.len());
+        write(&self.inner.segments)?.extend(refreshed);
+
+        if let Some(merges) = &self.merges {
+            let _ = merges.send(());
+        }
+        Ok(())
+    }
+
+    /**
+     * Refresh, and then durably persist every searchable segment to the Storage
+     *
+     * Nothing can be added or deleted while a commit is running, so that once it has finished
+     * the write-ahead log holds nothing which is not in the commit and can be truncated.
+     */
+    pub fn commit(&self) -> Result<(), Error> {
+        let storage = match &self.inner.storage {
+            Some(storage) => storage,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "an index without a Storage cannot be committed",
+                ))
+            }
+        };
+        let mut writer = lock(&self.inner.writer)?;
+        self.refresh_writer(&mut writer)?;
+
+        let mut committed = lock(&self.inner.committed)?;
+        let segments = read(&self.inner.segments)?.clone();
+        for segment in segments.iter() {
+            if !committed.contains(&segment.name) {
+                storage.put_segment(
+                    &segment.name,
+                    &crate::disk::to_bytes(segment.index.as_ref())?,
+                )?;
+            }
+        }
+
+        let entries: Vec<ManifestEntry> = segments
+            .iter()
+            .map(|segment| {
+                let mut deleted: Vec<DocumentId> = segment.deleted.iter().copied().collect();
+                deleted.sort_unstable();
+                ManifestEntry {
+                    name: segment.name.clone(),
+                    deleted,
+                }
+            })
+            .collect();
+        storage.put(MANIFEST, &serde_json::to_vec(&entries)?)?;
+        storage.flush()?;
+        if let Some(log) = lock(&self.inner.log)?.as_mut() {
+            log.truncate()?;
+        }
+
+        for name in committed.iter() {
+            if !entries.iter().any(|entry| &entry.name == name) {
+                storage.delete_segment(name)?;
+            }
+        }
+        *committed = entries.into_iter().map(|entry| entry.name).collect();
+        debug!("Committed {} segments", committed.len());
+        drop(writer);
+        Ok(())
+    }
+
+    /**
+     * Run the merge policy right away rather than waiting for the background thread
+     */
+    pub fn merge(&self) -> Result<(), Error> {
+        self.inner.merge()
+    }
+
+    /**
+     * Refresh, and then merge every segment into a single optimized one which leaves out the
+     * deleted documents for good, e.g. before the index is published
+     *
+     * The merged segment is only persisted by the next `commit()`.
+     */
+    pub fn optimize(&self) -> Result<(), Error> {
+        self.refresh()?;
+        self.inner.optimize()
+    }
+
+    /**
+     * The number of documents in each of the searchable segments
+     */
+    pub fn segment_sizes(&self) -> Vec<u64> {
+        self.searcher()
+            .segments
+            .iter()
+            .map(|segment| segment.size())
+            .collect()
+    }
+
+    /**
+     * A point in time view of the segments to run searches against, which is unaffected by
+     * any documents added or segments merged afterwards
+     */
+    pub fn searcher(&self) -> SegmentSearcher {
+        let segments = self
+            .inner
+            .segments
+            .read()
+            .map(|segments| segments.clone())
+            .unwrap_or_default();
+        SegmentSearcher::new(self.inner.schema.clone(), segments)
+    }
+
+    pub fn query_index(&self, query: &str) -> Vec<DocumentId> {
+        self.searcher().query_index(query)
+    }
+}
+
+impl Drop for SegmentedIndex {
+    fn drop(&mut self) {
+        self.merges.take();
+        if let Some(merger) = self.merger.take() {
+            let _ = merger.join();
+        }
+    }
+}
+
+impl Inner {
+    fn new(schema: Schema, options: SegmentOptions, storage: Option<Arc<dyn Storage>>) -> Self {
+        Self {
+            writer: Mutex::new(Index::with_schema(schema.clone())),
+            schema,
+            options,
+            storage,
+            pending: Mutex::default(),
+            segments: RwLock::default(),
+            committed: Mutex::default(),
+            next_segment: AtomicU64::new(0),
+            merging: Mutex::default(),
+            log: Mutex::default(),
+        }
+    }
+
+    /**
+     * Whether the document is in the index, searchable or not
+     */
+    fn contains(&self, writer: &Index, id: &DocumentId) -> Result<bool, Error> {
+        Ok(writer.contains(id)
+            || lock(&self.pending)?.iter().any(|s| s.is_live(id))
+            || read(&self.segments)?.iter().any(|s| s.is_live(id)))
+    }
+
+    /**
+     * Apply the operation, logging it first if asked to, and return whether it changed anything
+     */
+    fn apply(&self, writer: &mut Index, operation: Operation, logged: bool) -> Result<bool, Error> {
+        let (id, add) = match &operation {
+            Operation::Add(article) => (article.id(), true),
+            Operation::Delete(id) => (*id, false),
+        };
+        // Replaying a log which was already committed just finds nothing left to do
+        if self.contains(writer, &id)? == add {
+            return Ok(false);
+        }
+        if logged {
+            if let Some(log) = lock(&self.log)?.as_mut() {
+                log.append(&operation)?;
+            }
+        }
+
+        match operation {
+            Operation::Add(article) => {
+                writer.index_document(article)?;
+                if writer.size() as usize >= self.options.segment_size {
+                    let segment = self.seal(writer);
+                    lock(&self.pending)?.extend(segment);
+                }
+            }
+            Operation::Delete(id) => {
+                writer.remove_document(&id)?;
+                for segment in lock(&self.pending)?.iter_mut() {
+                    segment.delete(id);
+                }
+                for segment in write(&self.segments)?.iter_mut() {
+                    segment.delete(id);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn segment(&self, mut index: Index) -> Segment {
+        index.finalize();
+        let id = self.next_segment.fetch_add(1, Ordering::SeqCst);
+        Segment::new(format!("{:08}", id), Arc::new(index), HashSet::new())
+    }
+
+    /**
+     * Take whatever is buffered in the writer as a new segment
+     */
+    fn seal(&self, writer: &mut Index) -> Option<Segment> {
+        if writer.size() == 0 {
+            return None;
+        }
+        let index = std::mem::replace(writer, Index::with_schema(self.schema.clone()));
+        debug!("Sealed a segment of {} documents", index.size());
+        Some(self.segment(index))
+    }
+
+    /**
+     * Merge the smallest segments together for as long as there are too many of them
+     */
+    fn merge(&self) -> Result<(), Error> {
+        let _merging = lock(&self.merging)?;
+        let factor = self.options.merge_factor.max(2);
+
+        loop {
+            let mut candidates = read(&self.segments)?.clone();
+            if candidates.len() < factor {
+                return Ok(());
+            }
+            candidates.sort_by_key(|segment| segment.size());
+            candidates.truncate(factor);
+            self.merge_segments(&candidates, false)?;
+        }
+    }
+
+    /**
+     * Merge every segment into one, unless there is only one already with nothing to purge
+     */
+    fn optimize(&self) -> Result<(), Error> {
+        let _merging = lock(&self.merging)?;
+        let candidates = read(&self.segments)?.clone();
+        match &candidates[..] {
+            [] => Ok(()),
+            [segment] if segment.deleted.is_empty() => Ok(()),
+            _ => self.merge_segments(&candidates, true),
+        }
+    }
+
+    /**
+     * Replace the candidates with a single segment holding all of their live documents
+     */
+    fn merge_segments(&self, candidates: &[Segment], optimize: bool) -> Result<(), Error> {
+        // The merge itself happens without holding the lock, so searches carry on, and it
+        // leaves out the deleted documents for good
+        let mut merged = Index::with_schema(self.schema.clone());
+        for segment in candidates.iter() {
+            merged.copy_from(segment.index.as_ref())?;
+            for id in segment.deleted.iter() {
+                merged.remove_document(id)?;
+            }
+        }
+        if optimize {
+            merged.optimize();
+        }
+        let mut merged = self.segment(merged);
+        debug!(
+            "Merged {} segments into {} of {} documents",
+            candidates.len(),
+            merged.name,
+            merged.index.size()
+        );
+
+        let mut segments = write(&self.segments)?;
+        // Anything deleted from the candidates while they were being merged is still in the
+        // merged segment
+        for segment in segments.iter() {
+            if let Some(candidate) = candidates.iter().find(|c| c.name == segment.name) {
+                for id in segment.deleted.difference(&candidate.deleted) {
+                    merged.delete(*id);
+                }
+            }
+        }
+        segments.retain(|segment| !candidates.iter().any(|c| c.name == segment.name));
+        segments.push(merged);
+        Ok(())
+    }
+}
+
+/**
+ * A searchable snapshot of the segments of a SegmentedIndex
+ */
+#[derive(Clone, Debug)]
+pub struct SegmentSearcher {
+    schema: Schema,
+    segments: Vec<Segment>,
+}
+
+impl SegmentSearcher {
+    pub(crate) fn new(schema: Schema, segments: Vec<Segment>) -> Self {
+        Self { schema, segments }
+    }
+
+    fn segment_of(&self, id: DocumentId) -> Option<&Index> {
+        self.segments
+            .iter()
+            .find(|segment| segment.is_live(&id))
+            .map(|segment| segment.index.as_ref())
+    }
+
+    /**
+     * Combine the postings for the term from every segment which has any, leaving out the
+     * deleted documents, and skipping the segments whose bloom filter rules the key out
+     */
+    fn union<'a, K, F>(&'a self, key: &K, postings: F) -> Option<Cow<'a, HashSet<DocumentId>>>
+    where
+        K: Hash + ?Sized,
+        F: Fn(&'a Index) -> Option<Cow<'a, HashSet<DocumentId>>>,
+    {
+        let mut found: Vec<_> = self
+            .segments
+            .iter()
+            .filter(|segment| segment.terms.might_contain(key))
+            .filter_map(|segment| {
+                let docs = postings(segment.index.as_ref())?;
+                if segment.deleted.is_empty() {
+                    return Some(docs);
+                }
+                let live: HashSet<DocumentId> =
+                    docs.difference(&segment.deleted).copied().collect();
+                match live.is_empty() {
+                    true => None,
+                    false => Some(Cow::Owned(live)),
+                }
+            })
+            .collect();
+
+        match found.len() {
+            0 => None,
+            1 => found.pop(),
+            _ => Some(Cow::Owned(
+                found.iter().flat_map(|d| d.iter()).copied().collect(),
+            )),
+        }
+    }
+
+    /**
+     * The number of live documents containing the term, summed over the segments rather than
+     * counted from the union of their postings
+     */
+    fn document_frequency(&self, term: &str) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| segment.terms.might_contain(term))
+            .filter_map(|segment| {
+                let docs = segment.index.postings_ref(term)?;
+                let deleted = segment.deleted.iter().filter(|id| docs.contains(id));
+                Some(docs.len() - deleted.count())
+            })
+            .sum()
+    }
+
+    fn distinct(&self, terms: impl Fn(&Index) -> Vec<String>) -> Vec<String> {
+        let terms: HashSet<String> = self
+            .segments
+            .iter()
+            .flat_map(|segment| terms(segment.index.as_ref()))
+            .collect();
+        terms.into_iter().collect()
+    }
+}
+
+impl IndexReader for SegmentSearcher {
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size()).sum()
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.segments
+            .iter()
+            .flat_map(|segment| {
+                segment
+                    .ids
+                    .iter()
+                    .filter(move |id| !segment.deleted.contains(id))
+                    .copied()
+            })
+            .collect()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        IndexReader::document(self.segment_of(*id)?, id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.distinct(|segment| segment.terms())
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.union(term, |segment| segment.postings(term))
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.distinct(|segment| segment.field_terms(field))
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        match self.document_frequency(term) {
+            0 => 0.0,
+            documents => (self.size() as f64 / documents as f64).log10(),
+        }
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.union(&(field, term), |segment| {
+            segment.field_postings(field, term)
+        })
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.segment_of(id)?.term_frequency(id, term)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.segment_of(id)?.positions(id, term)
+    }
+
+    fn document_length(&self, id: DocumentId) -> Option<f64> {
+        self.segment_of(id)?.document_length(id)
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.segment_of(id)?.field_lengths(id)
+    }
+
+    /**
+     * The averages of every segment weighted by its size, less the lengths of the documents
+     * deleted from it
+     */
+    fn average_field_lengths(&self) -> (f64, f64) {
+        let (mut title, mut r#abstract) = (0.0, 0.0);
+        for segment in self.segments.iter() {
+            let documents = segment.index.size() as f64;
+            let averages = segment.index.average_field_lengths();
+            title += averages.0 * documents;
+            r#abstract += averages.1 * documents;
+            for id in segment.deleted.iter() {
+                if let Some(lengths) = segment.index.field_lengths(*id) {
+                    title -= lengths.title;
+                    r#abstract -= lengths.r#abstract;
+                }
+            }
+        }
+        match self.size() {
+            0 => (0.0, 0.0),
+            n => (title / n as f64, r#abstract / n as f64),
+        }
+    }
+
+    fn average_document_length(&self) -> f64 {
+        let (title, r#abstract) = self.average_field_lengths();
+        title + r#abstract
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStorage;
+    use std::path::PathBuf;
+
+    fn sorted(mut results: Vec<DocumentId>) -> Vec<DocumentId> {
+        results.sort_unstable();
+        results
+    }
+
+    #[test]
+    fn test_segments_match_index() -> Result<(), Error> {
+        let path = PathBuf::from("data/simple.xml.gz");
+        let index = Index::from_file(&path)?;
+        let options = SegmentOptions {
+            segment_size: 50,
+            merge_factor: 100,
+        };
+        let segmented = SegmentedIndex::new(Schema::default(), options);
+        segmented.load_file(&path)?;
+        segmented.refresh()?;
+
+        assert_eq!(segmented.segment_sizes().len(), 8);
+        assert_eq!(segmented.searcher().size(), index.size());
+        let expected = index.average_document_length();
+        assert!((segmented.searcher().average_document_length() - expected).abs() < 1e-9);
+        let searcher = segmented.searcher();
+        for term in &["histori", "anarch", "qqqqqqqq"] {
+            assert!((searcher.idf(term) - index.idf(term)).abs() < 1e-9);
+        }
+        for query in &["anarchism", "\"political philosophy\"", "title:history"] {
+            assert_eq!(
+                segmented.query_index(query),
+                index.query_index(query),
+                "results differ for {}",
+                query
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_keeps_searcher_snapshot() -> Result<(), Error> {
+        let path = PathBuf::from("data/simple.xml.gz");
+        let options = SegmentOptions {
+            segment_size: 50,
+            merge_factor: 3,
+        };
+        let segmented = SegmentedIndex::new(Schema::default(), options);
+        let before = segmented.searcher();
+        segmented.load_file(&path)?;
+        segmented.refresh()?;
+        let loaded = segmented.searcher();
+        let results = sorted(loaded.query_index("anarchism"));
+
+        segmented.merge()?;
+        assert!(segmented.segment_sizes().len() < 3);
+        assert_eq!(segmented.segment_sizes().iter().sum::<u64>(), 356);
+
+        // Searchers see the segments as they were when they were taken
+        assert_eq!(before.size(), 0);
+        assert_eq!(sorted(loaded.query_index("anarchism")), results);
+        assert_eq!(sorted(segmented.query_index("anarchism")), results);
+
+        // Adding a document which is already indexed is a no-op
+        let id = results[0];
+        let article = loaded.document(&id).unwrap().into_owned();
+        segmented.add(article)?;
+        segmented.refresh()?;
+        assert_eq!(segmented.searcher().size(), 356);
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_makes_documents_visible() -> Result<(), Error> {
+        let options = SegmentOptions {
+            segment_size: 50,
+            merge_factor: 100,
+        };
+        let segmented = SegmentedIndex::new(Schema::default(), options);
+        segmented.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+
+        // Even the segments which filled up are not searchable until a refresh
+        assert!(segmented.query_index("anarchism").is_empty());
+        segmented.refresh()?;
+        assert!(!segmented.query_index("anarchism").is_empty());
+        assert!(segmented.commit().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloom_filters() -> Result<(), Error> {
+        let options = SegmentOptions {
+            segment_size: 1,
+            merge_factor: 100,
+        };
+        let segmented = SegmentedIndex::new(Schema::default(), options);
+        for (title, text, url) in [
+            ("Rain", "wet weather", "https://example.com/rain"),
+            ("Snow", "cold weather", "https://example.org/snow"),
+        ] {
+            segmented.add(Article::new(title, text, url)?)?;
+        }
+        segmented.refresh()?;
+
+        let searcher = segmented.searcher();
+        let holding = |key: &dyn Fn(&BloomFilter) -> bool| {
+            searcher
+                .segments
+                .iter()
+                .filter(|segment| key(&segment.terms))
+                .count()
+        };
+        assert_eq!(searcher.segments.len(), 2);
+        assert_eq!(holding(&|terms| terms.might_contain("weather")), 2);
+        assert_eq!(holding(&|terms| terms.might_contain("rain")), 1);
+        assert_eq!(
+            holding(&|terms| terms.might_contain(&(Field::Domain, "example.org"))),
+            1
+        );
+        assert_eq!(searcher.query_index("snow").len(), 1);
+        assert_eq!(searcher.query_index("weather").len(), 2);
+        assert!(searcher.postings("hail").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_and_reopen() -> Result<(), Error> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let options = SegmentOptions {
+            segment_size: 100,
+            merge_factor: 100,
+        };
+        let segmented = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        segmented.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        segmented.commit()?;
+        let results = segmented.query_index("anarchism");
+        assert_eq!(storage.segments()?.len(), 4);
+
+        drop(segmented);
+
+        // A segment written by a commit which never finished is not part of the index
+        storage.put_segment("99999999", b"half written")?;
+        let reopened = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        assert_eq!(reopened.searcher().size(), 356);
+        assert_eq!(reopened.query_index("anarchism"), results);
+        assert_eq!(storage.segments()?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_until_merged() -> Result<(), Error> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let options = SegmentOptions {
+            segment_size: 50,
+            merge_factor: 100,
+        };
+        let segmented = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        segmented.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        segmented.refresh()?;
+        let before = segmented.searcher();
+        let results = sorted(before.query_index("anarchism"));
+
+        let id = results[0];
+        assert!(segmented.delete(id)?);
+        assert!(!segmented.delete(id)?);
+        assert!(!segmented.delete(0)?);
+
+        let after = segmented.searcher();
+        assert_eq!(after.size(), 355);
+        assert!(after.document(&id).is_none());
+        assert!(!after.query_index("anarchism").contains(&id));
+        assert_eq!(before.size(), 356);
+        // Deleted documents no longer count towards how common a term is
+        assert_eq!(results.len(), 1);
+        assert!(before.idf("anarch") > 0.0);
+        assert_eq!(after.idf("anarch"), 0.0);
+
+        // Deletions survive a commit, and merging purges them for good
+        segmented.commit()?;
+        drop(segmented);
+        let reopened = SegmentedIndex::open(Schema::default(), options, storage)?;
+        assert_eq!(reopened.searcher().size(), 355);
+        reopened.merge()?;
+        assert_eq!(reopened.segment_sizes().iter().sum::<u64>(), 355);
+
+        let options = SegmentOptions {
+            segment_size: 50,
+            merge_factor: 2,
+        };
+        let merged = SegmentedIndex::new(Schema::default(), options);
+        merged.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        merged.refresh()?;
+        merged.delete(id)?;
+        merged.merge()?;
+        assert_eq!(merged.segment_sizes(), vec![355]);
+        assert!(merged.searcher().document(&id).is_none());
+
+        // Once deleted, a document can be added again
+        let article = before.document(&id).unwrap().into_owned();
+        merged.add(article)?;
+        merged.refresh()?;
+        assert!(merged.query_index("anarchism").contains(&id));
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_replays_uncommitted() -> Result<(), Error> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let path = std::env::temp_dir().join(format!("goede-segment-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = SegmentOptions {
+            segment_size: 100,
+            merge_factor: 100,
+        };
+
+        let segmented = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        assert_eq!(segmented.enable_log(&path)?, 0);
+        segmented.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        segmented.commit()?;
+        let id = sorted(segmented.query_index("anarchism"))[0];
+        segmented.delete(id)?;
+        drop(segmented);
+
+        // The deletion was never committed, so only the log has it
+        let reopened = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        assert_eq!(reopened.searcher().size(), 356);
+        assert_eq!(reopened.enable_log(&path)?, 1);
+        assert_eq!(reopened.searcher().size(), 355);
+        reopened.commit()?;
+        drop(reopened);
+
+        let reopened = SegmentedIndex::open(Schema::default(), options, storage)?;
+        assert_eq!(reopened.enable_log(&path)?, 0);
+        assert_eq!(reopened.searcher().size(), 355);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize() -> Result<(), Error> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let options = SegmentOptions {
+            segment_size: 100,
+            merge_factor: 100,
+        };
+        assert!(SegmentedIndex::open_committed(options, storage.clone())?.is_none());
+        let segmented = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        segmented.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        segmented.commit()?;
+        let results = sorted(segmented.query_index("anarchism"));
+        segmented.delete(results[0])?;
+        segmented.commit()?;
+        drop(segmented);
+
+        let reopened = SegmentedIndex::open_committed(options, storage.clone())?.unwrap();
+        assert_eq!(reopened.segment_sizes().len(), 4);
+        reopened.optimize()?;
+        reopened.commit()?;
+        assert_eq!(reopened.segment_sizes(), vec![355]);
+        assert_eq!(storage.segments()?.len(), 1);
+        assert_eq!(sorted(reopened.query_index("anarchism")), results[1..]);
+        Ok(())
+    }
+}
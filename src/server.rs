@@ -0,0 +1,634 @@
+/**
+ * The server module answers searches over HTTP, so that an index can be queried by other
+ * processes and machines, including a coordinator fanning queries out across shards
+ *
+ * There is a single endpoint, `GET /search?q=QUERY&limit=N`, which responds with a JSON
+ * SearchResponse. Adding `&dedupe=true` collapses the hits with the same title into the best of
+ * them. Requests are answered by a pool of worker threads which all search the same reader, so
+ * the number of workers is how many searches run at once.
+ *
+ * Only the searching is compiled for wasm32, which has no sockets to serve it on.
+ */
+use crate::bulk::{self, Suggestions};
+use crate::engine::{Article, DocumentId, IndexReader};
+use crate::query::QueryTimings;
+use crate::telemetry;
+#[cfg(not(target_arch = "wasm32"))]
+use log::*;
+use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::{TcpListener, TcpStream};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+
+/**
+ * The number of results returned when a request does not ask for a limit
+ */
+pub const DEFAULT_LIMIT: usize = 10;
+
+/**
+ * Request lines longer than this are rejected rather than buffered
+ */
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+
+/**
+ * Request heads longer than this are rejected rather than buffered
+ */
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/**
+ * How long a client has to send the whole head of its request, or to take each write of the
+ * response, before it is hung up on
+ */
+#[cfg(not(target_arch = "wasm32"))]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * How long to wait after failing to accept a connection, so that running out of file
+ * descriptors does not spin
+ */
+#[cfg(not(target_arch = "wasm32"))]
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+/**
+ * How many times as many hits are searched for when deduplicating them, so that there are
+ * usually still enough once the duplicates have been collapsed
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const DEDUPE_OVERFETCH: usize = 3;
+
+/**
+ * A single matching document
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Hit {
+    pub id: DocumentId,
+    pub score: f64,
+    pub article: Option<Article>,
+    /**
+     * The number of other hits with the same title which were collapsed into this one
+     */
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub collapsed: usize,
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SearchResponse {
+    /**
+     * The number of documents which were searched
+     */
+    pub size: u64,
+    pub hits: Vec<Hit>,
+    /**
+     * The nodes which could not be searched, when the response was merged from several
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<String>,
+    /**
+     * How long each phase of the search took, on the slowest node when the response was merged
+     * from several
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<QueryTimings>,
+    /**
+     * Suggestions for each of the (analyzed) terms of the query which are in no document, so
+     * that a "did you mean" can be shown without searching again
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<Suggestions>,
+}
+
+impl SearchResponse {
+    /**
+     * Collapse the hits whose titles are the same once normalized into the highest scoring of
+     * them, keeping at most `limit` hits
+     */
+    pub fn dedupe(mut self, limit: usize) -> Self {
+        self.hits = crate::query::dedupe_by_title(self.hits, |hit| {
+            hit.article
+                .as_ref()
+                .map(|article| article.title().to_string())
+        })
+        .into_iter()
+        .take(limit)
+        .map(|(hit, collapsed)| Hit {
+            collapsed: hit.collapsed + collapsed,
+            ..hit
+        })
+        .collect();
+        self
+    }
+}
+
+/**
+ * Search the reader for the highest scoring hits, along with the documents themselves
+ */
+pub fn search<R: IndexReader + ?Sized>(reader: &R, query: &str, limit: usize) -> SearchResponse {
+    let (hits, timings) = reader.search_timed(query, limit);
+    metrics::counter!(telemetry::QUERIES_SERVED).increment(1);
+    metrics::histogram!(telemetry::QUERY_LATENCY).record(timings.total().as_secs_f64());
+    let hits = hits
+        .into_iter()
+        .map(|(id, score)| Hit {
+            id,
+            score,
+            article: reader.document(&id).map(|article| article.into_owned()),
+            collapsed: 0,
+        })
+        .collect();
+    SearchResponse {
+        size: reader.size(),
+        hits,
+        failed: vec![],
+        timings: Some(timings),
+        suggestions: bulk::suggest(reader, query),
+    }
+}
+
+/**
+ * The number of searches answered at once unless told otherwise, one for each CPU
+ */
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+}
+
+/**
+ * Answer requests on the listener, with the given function doing the searching from
+ * `default_workers()` threads
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn serve<F>(listener: TcpListener, search: F) -> Result<(), Error>
+where
+    F: Fn(&str, usize) -> Result<SearchResponse, Error> + Sync,
+{
+    serve_with(listener, default_workers(), search)
+}
+
+/**
+ * Answer requests on the listener, with the given function doing the searching from `workers`
+ * threads at once
+ *
+ * Connections accepted while every worker is busy wait in a queue as long as there are
+ * workers, and once that is full in the listener's backlog.
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn serve_with<F>(listener: TcpListener, workers: usize, search: F) -> Result<(), Error>
+where
+    F: Fn(&str, usize) -> Result<SearchResponse, Error> + Sync,
+{
+    info!(
+        "Listening for searches on {} with {} workers",
+        listener.local_addr()?,
+        workers.max(1)
+    );
+    pool(listener.incoming(), workers, |stream: TcpStream| {
+        let peer = stream.peer_addr().ok();
+        if let Err(e) = handle(stream, &search, CLIENT_TIMEOUT) {
+            warn!("Failed to answer the request from {:?}: {}", peer, e);
+        }
+    })
+}
+
+/**
+ * Hand every connection to one of `workers` threads, for as long as there are connections
+ */
+#[cfg(not(target_arch = "wasm32"))]
+fn pool<S, I, H>(incoming: I, workers: usize, handle: H) -> Result<(), Error>
+where
+    S: Send,
+    I: Iterator<Item = Result<S, Error>>,
+    H: Fn(S) + Sync,
+{
+    let workers = workers.max(1);
+    let (streams, queue) = crossbeam::channel::bounded::<S>(workers);
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let queue = queue.clone();
+            let handle = &handle;
+            scope.spawn(move || {
+                for stream in queue {
+                    handle(stream);
+                }
+            });
+        }
+        drop(queue);
+
+        for stream in accepted(incoming) {
+            streams
+                .send(stream)
+                .map_err(|_| Error::other("every worker stopped"))?;
+        }
+        // Hanging up lets the workers answer what is already queued and then stop
+        drop(streams);
+        Ok(())
+    })
+}
+
+/**
+ * The connections which were accepted, logging the failures to accept one rather than giving up
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn accepted<S, I>(incoming: I) -> impl Iterator<Item = S>
+where
+    I: Iterator<Item = Result<S, Error>>,
+{
+    incoming.filter_map(|stream| match stream {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            warn!("Failed to accept a connection: {}", e);
+            std::thread::sleep(ACCEPT_BACKOFF);
+            None
+        }
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/**
+ * Reads from the stream until the deadline, however slowly the bytes trickle in before it
+ */
+#[cfg(not(target_arch = "wasm32"))]
+struct Deadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::new(ErrorKind::TimedOut, "the request took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn handle<F>(stream: TcpStream, search: &F, timeout: Duration) -> Result<(), Error>
+where
+    F: Fn(&str, usize) -> Result<SearchResponse, Error>,
+{
+    stream.set_write_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(Deadline {
+        stream: stream.try_clone()?,
+        deadline: Instant::now() + timeout,
+    });
+    let request_line = match read_head(&mut reader) {
+        Ok(Ok(request_line)) => request_line,
+        Ok(Err((status, reason))) => return respond(stream, status, reason, b""),
+        Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            return respond(stream, 408, "Request Timeout", b"")
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return respond(stream, 400, "Bad Request", b""),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/search" {
+        return respond(stream, 404, "Not Found", b"");
+    }
+    if method != "GET" {
+        return respond(stream, 405, "Method Not Allowed", b"");
+    }
+
+    let mut q = None;
+    let mut limit = DEFAULT_LIMIT;
+    let mut dedupe = false;
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "q" => q = Some(value.into_owned()),
+            "limit" => match value.parse() {
+                Ok(value) => limit = value,
+                Err(_) => return respond(stream, 400, "Bad Request", b"invalid limit"),
+            },
+            "dedupe" => match value.parse() {
+                Ok(value) => dedupe = value,
+                Err(_) => return respond(stream, 400, "Bad Request", b"invalid dedupe"),
+            },
+            _ => {}
+        }
+    }
+    let q = match q {
+        Some(q) => q,
+        None => return respond(stream, 400, "Bad Request", b"missing q"),
+    };
+
+    if let Err(e) = crate::query::validate(&q) {
+        return respond(stream, 400, "Bad Request", e.to_string().as_bytes());
+    }
+
+    debug!("Searching for `{}` with a limit of {}", q, limit);
+    let results = match dedupe {
+        true => search(&q, limit.saturating_mul(DEDUPE_OVERFETCH)).map(|r| r.dedupe(limit)),
+        false => search(&q, limit),
+    };
+    match results {
+        Ok(response) => respond(stream, 200, "OK", &serde_json::to_vec(&response)?),
+        Err(e) => {
+            error!("Failed to search for `{}`: {}", q, e);
+            respond(
+                stream,
+                500,
+                "Internal Server Error",
+                e.to_string().as_bytes(),
+            )
+        }
+    }
+}
+
+/**
+ * Read the head of a request, returning its request line, or the status to reject it with when
+ * it is too long
+ */
+#[cfg(not(target_arch = "wasm32"))]
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Result<String, (u16, &'static str)>, Error> {
+    let mut request_line = String::new();
+    reader
+        .take(MAX_REQUEST_LINE as u64)
+        .read_line(&mut request_line)?;
+    if request_line.len() >= MAX_REQUEST_LINE && !request_line.ends_with('\n') {
+        return Ok(Err((414, "URI Too Long")));
+    }
+
+    // The rest of the head is not needed, but has to be read before responding
+    let mut head = reader.take(MAX_HEAD_LEN as u64);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = head.read_line(&mut line)?;
+        if line == "\r\n" || line == "\n" {
+            break;
+        }
+        if read == 0 {
+            if head.limit() == 0 {
+                return Ok(Err((431, "Request Header Fields Too Large")));
+            }
+            break;
+        }
+    }
+    Ok(Ok(request_line))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn respond(mut stream: TcpStream, status: u16, reason: &str, body: &[u8]) -> Result<(), Error> {
+    let content_type = if status == 200 {
+        "application/json"
+    } else {
+        "text/plain"
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/**
+ * The error for a response from a node which is not a SearchResponse
+ */
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn bad_response(node: &str, e: impl std::fmt::Display) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{} did not answer with search results: {}", node, e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_serve_search() -> Result<(), Error> {
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let expected = search(&index, "history", 3);
+        assert_eq!(expected.hits.len(), 3);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        std::thread::spawn(move || serve(listener, |q, limit| Ok(search(&index, q, limit))));
+
+        let body = ureq::get(&format!("{}/search", url))
+            .query("q", "history")
+            .query("limit", "3")
+            .call()
+            .map_err(Error::other)?
+            .into_string()?;
+        let response: SearchResponse = serde_json::from_str(&body)?;
+        // Only how long the search took differs
+        assert!(response.timings.is_some_and(|t| t.total() > Duration::ZERO));
+        assert_eq!(
+            SearchResponse {
+                timings: expected.timings,
+                ..response
+            },
+            expected
+        );
+        assert!(!body.contains("suggestions"));
+
+        // Terms in no document come back with what they might have been meant to be
+        let body = ureq::get(&format!("{}/search", url))
+            .query("q", "histry")
+            .call()
+            .map_err(Error::other)?
+            .into_string()?;
+        let response: SearchResponse = serde_json::from_str(&body)?;
+        assert!(response.hits.is_empty());
+        assert_eq!(response.suggestions[0].term, "histri");
+        assert_eq!(response.suggestions[0].suggestions[0], "histori");
+
+        match ureq::get(&format!("{}/search?limit=x&q=a", url)).call() {
+            Err(ureq::Error::Status(400, _)) => {}
+            other => panic!(
+                "expected a bad request, not {:?}",
+                other.map(|r| r.status())
+            ),
+        }
+        match ureq::get(&format!("{}/search", url))
+            .query("q", "\"history")
+            .call()
+        {
+            Err(ureq::Error::Status(400, response)) => {
+                assert_eq!(response.into_string()?, "unbalanced quote at column 1")
+            }
+            other => panic!(
+                "expected a bad request, not {:?}",
+                other.map(|r| r.status())
+            ),
+        }
+        match ureq::get(&format!("{}/elsewhere", url)).call() {
+            Err(ureq::Error::Status(404, _)) => {}
+            other => panic!("expected not found, not {:?}", other.map(|r| r.status())),
+        }
+
+        // A request line which never ends is cut off rather than buffered
+        let mut stream = TcpStream::connect(url.trim_start_matches("http://"))?;
+        let mut request = b"GET /search?q=".to_vec();
+        request.resize(MAX_REQUEST_LINE, b'a');
+        stream.write_all(&request)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 414 "), "{}", response);
+        Ok(())
+    }
+
+    #[test]
+    fn test_workers() -> Result<(), Error> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let busy = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/search?q=history", listener.local_addr()?);
+        std::thread::spawn(move || {
+            serve_with(listener, 2, |q, limit| {
+                let now = busy.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(200));
+                let response = search(&index, q, limit);
+                busy.fetch_sub(1, Ordering::SeqCst);
+                // Report the most searches which were ever running at once in the size
+                Ok(SearchResponse {
+                    size: most.load(Ordering::SeqCst) as u64,
+                    ..response
+                })
+            })
+        });
+
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                let url = url.clone();
+                std::thread::spawn(move || -> Result<SearchResponse, Error> {
+                    let body = ureq::get(&url)
+                        .call()
+                        .map_err(Error::other)?
+                        .into_string()?;
+                    Ok(serde_json::from_str(&body)?)
+                })
+            })
+            .collect();
+        let mut most = 0;
+        for client in clients {
+            let response = client.join().unwrap()?;
+            assert!(!response.hits.is_empty());
+            most = most.max(response.size);
+        }
+        assert_eq!(most, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_misbehaving_clients() -> Result<(), Error> {
+        use std::sync::Mutex;
+
+        // Failing to accept one connection does not stop the rest being answered
+        let answered = Mutex::new(vec![]);
+        let incoming = vec![Err(Error::other("too many open files")), Ok(1), Ok(2)];
+        pool(incoming.into_iter(), 1, |n| {
+            answered.lock().unwrap().push(n)
+        })?;
+        assert_eq!(answered.into_inner().unwrap(), vec![1, 2]);
+
+        // A client sending its request a byte at a time runs out of time for all of it, rather
+        // than for each byte
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+        let slow = std::thread::spawn(move || {
+            for byte in b"GET /search?q=history HTTP/1.1\r\n\r\n".iter() {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            response
+        });
+        let unreachable = |_: &str, _| Err(Error::other("unreachable"));
+        handle(stream, &unreachable, Duration::from_millis(200))?;
+        let response = slow.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe() -> Result<(), Error> {
+        let mut index = Index::new();
+        for (title, text, url) in [
+            (
+                "Mercury",
+                "the planet closest to the sun",
+                "https://example.com/planet",
+            ),
+            (
+                "Mercury",
+                "may refer to a planet or an element",
+                "https://example.com/mercury",
+            ),
+            (
+                " mercury",
+                "a planet, see Mercury",
+                "https://example.com/redirect",
+            ),
+            (
+                "Venus",
+                "the second planet from the sun",
+                "https://example.com/venus",
+            ),
+        ] {
+            index.index_document(Article::new(title, text, url)?)?;
+        }
+        index.index_document(Article::new("Cooking", "a pie", "https://example.com/pie")?)?;
+
+        let response = search(&index, "planet", 10);
+        assert_eq!(response.hits.len(), 4);
+        let deduped = response.clone().dedupe(10);
+        assert_eq!(deduped.hits.len(), 2);
+        assert_eq!(deduped.hits[0].id, response.hits[0].id);
+        let collapsed: Vec<_> = deduped.hits.iter().map(|hit| hit.collapsed).collect();
+        let mercury = deduped
+            .hits
+            .iter()
+            .position(|hit| hit.article.as_ref().unwrap().title() == "Mercury")
+            .unwrap();
+        assert_eq!(collapsed[mercury], 2);
+        assert_eq!(collapsed.iter().sum::<usize>(), 2);
+        assert_eq!(response.dedupe(1).hits.len(), 1);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        std::thread::spawn(move || serve(listener, |q, limit| Ok(search(&index, q, limit))));
+        let body = ureq::get(&format!("{}/search", url))
+            .query("q", "planet")
+            .query("limit", "2")
+            .query("dedupe", "true")
+            .call()
+            .map_err(Error::other)?
+            .into_string()?;
+        let served: SearchResponse = serde_json::from_str(&body)?;
+        assert_eq!(served.hits, deduped.hits);
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+/**
+ * The shard module splits a corpus into a number of independent indexes by the hash of each
+ * document's id, so that the shards can be built in parallel and then searched in parallel
+ *
+ * Each shard is an ordinary index file, named for its position among the shards, e.g.
+ * `shard-001-of-004.idx`, so that a directory of them can be opened without any other metadata.
+ * Building shards into a directory replaces any which were built into it before, so that the
+ * directory only ever holds one set of them.
+ */
+use crate::crypto::Key;
+use crate::disk::DiskIndex;
+use crate::engine::{read_articles, Article, DocumentId, FieldLengths, Index, IndexReader};
+use crate::query::QueryTimings;
+use crate::schema::{Field, Schema};
+use log::*;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/**
+ * The number of parsed articles which can be queued up for each shard's builder
+ */
+const QUEUE_LEN: usize = 1024;
+
+/**
+ * The shard which the document belongs in, the ids are already hashes of the url so they are
+ * spread evenly
+ */
+pub fn shard_of(id: DocumentId, shards: usize) -> usize {
+    (id % shards as u64) as usize
+}
+
+fn shard_name(shard: usize, shards: usize) -> String {
+    format!("shard-{:03}-of-{:03}.idx", shard, shards)
+}
+
+/**
+ * The number of shards in the set which the file is one of, if it is named like a shard
+ */
+fn shard_count(name: &str) -> Option<usize> {
+    let (_, count) = name
+        .strip_prefix("shard-")?
+        .strip_suffix(".idx")?
+        .split_once("-of-")?;
+    count.parse().ok()
+}
+
+/**
+ * The paths of the shard files in the directory, along with the number of shards in the set
+ * each is one of
+ */
+fn shard_files(dir: &Path) -> Result<Vec<(PathBuf, usize)>, Error> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(count) = shard_count(&entry.file_name().to_string_lossy()) {
+            files.push((entry.path(), count));
+        }
+    }
+    Ok(files)
+}
+
+/**
+ * Build the given number of shards of the Wikipedia XML dump into the directory, returning the
+ * paths of the shard files
+ *
+ * The dump is parsed on the calling thread and each shard is indexed on a thread of its own,
+ * then saved, encrypted with the key if one is given. Any shards already in the directory are
+ * removed first, so that shards left over from building a different number of them cannot be
+ * opened along with the new ones.
+ */
+pub fn build(
+    datafile: &Path,
+    schema: &Schema,
+    shards: usize,
+    dir: &Path,
+    key: Option<&Key>,
+) -> Result<Vec<PathBuf>, Error> {
+    if shards == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "there must be at least one shard",
+        ));
+    }
+    std::fs::create_dir_all(dir)?;
+    for (path, _) in shard_files(dir)? {
+        debug!("Removing the old shard {:?}", path);
+        std::fs::remove_file(path)?;
+    }
+
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..shards)
+        .map(|_| crossbeam::channel::bounded::<Article>(QUEUE_LEN))
+        .unzip();
+
+    std::thread::scope(|scope| {
+        let builders: Vec<_> = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(shard, articles)| {
+                scope.spawn(move || -> Result<PathBuf, Error> {
+                    let mut index = Index::with_schema(schema.clone());
+                    for article in articles {
+                        index.index_document(article)?;
+                    }
+                    index.finalize();
+
+                    let path = dir.join(shard_name(shard, shards));
+                    match key {
+                        Some(key) => index.save_encrypted(&path, key)?,
+                        None => index.save(&path)?,
+                    }
+                    debug!("Built shard {:?} of {} documents", path, index.size());
+                    Ok(path)
+                })
+            })
+            .collect();
+
+        let parsed = read_articles(datafile, |article| {
+            senders[shard_of(article.id(), shards)]
+                .send(article)
+                .map_err(|_| Error::other("a shard builder stopped early"))
+        });
+        // Hanging up lets the builders know that every article has been sent
+        drop(senders);
+
+        let built = builders
+            .into_iter()
+            .map(|builder| {
+                builder
+                    .join()
+                    .unwrap_or_else(|_| Err(Error::other("a shard builder panicked")))
+            })
+            .collect::<Result<Vec<_>, _>>();
+        // A builder failing makes the parsing fail as well, so its error is the useful one
+        let built = built?;
+        parsed?;
+        Ok(built)
+    })
+}
+
+/**
+ * A set of shards which are searched together, each query running on every shard in parallel
+ *
+ * Every shard scores its documents with its own term statistics, which are close to those of
+ * the whole corpus as long as the shards are not tiny, so the merged ranking can differ
+ * slightly from that of a single index.
+ */
+#[derive(Debug)]
+pub struct ShardedIndex {
+    shards: Vec<DiskIndex>,
+}
+
+impl ShardedIndex {
+    /**
+     * Search the given shards, which must be in order and built with the same configuration
+     */
+    pub fn new(shards: Vec<DiskIndex>) -> Result<Self, Error> {
+        let first = shards.first().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "there must be at least one shard")
+        })?;
+        for shard in shards.iter() {
+            shard.check_schema(first.schema())?;
+        }
+        Ok(Self { shards })
+    }
+
+    /**
+     * Open the shards in the directory, decrypting them with the key if one is given
+     *
+     * The directory must hold a single set of shards, since there is no telling which of
+     * several sets is the right one.
+     */
+    pub fn open_dir(dir: &Path, key: Option<&Key>) -> Result<Self, Error> {
+        let mut counts: Vec<_> = shard_files(dir)?
+            .into_iter()
+            .map(|(_, count)| count)
+            .collect();
+        counts.sort_unstable();
+        counts.dedup();
+        let count = match counts[..] {
+            [count] => count,
+            [] => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("there are no shards in {:?}", dir),
+                ))
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("there are sets of {:?} shards in {:?}", counts, dir),
+                ))
+            }
+        };
+
+        let mut shards = vec![];
+        for shard in 0..count {
+            let path = dir.join(shard_name(shard, count));
+            shards.push(match key {
+                Some(key) => DiskIndex::open_encrypted(&path, key)?,
+                None => DiskIndex::open(&path)?,
+            });
+        }
+        debug!("Opened {} shards from {:?}", count, dir);
+        Self::new(shards)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /**
+     * Run the query against every shard in parallel, and merge the top results of each
+     *
+     * Each phase took as long as it did on the slowest shard, with merging the results counting
+     * towards sorting them. The search fails if any shard's search panicked, rather than
+     * quietly leaving out that shard's results.
+     */
+    pub fn try_search_timed(
+        &self,
+        query: &str,
+        limit: usize,
+    ) -> Result<(Vec<(DocumentId, f64)>, QueryTimings), Error> {
+        // Every search is joined before any is looked at, so that none is left running
+        let searched: Vec<_> = std::thread::scope(|scope| {
+            let searches: Vec<_> = self
+                .shards
+                .iter()
+                .map(|shard| scope.spawn(move || shard.search_timed(query, limit)))
+                .collect();
+            searches.into_iter().map(|search| search.join()).collect()
+        });
+        let mut results = vec![];
+        let mut timings = QueryTimings::default();
+        for (shard, searched) in searched.into_iter().enumerate() {
+            let (found, took) = searched.map_err(|panic| {
+                let reason = panic
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Error::other(format!("searching shard {} panicked: {}", shard, reason))
+            })?;
+            results.extend(found);
+            timings = timings.slowest(took);
+        }
+        let start = std::time::Instant::now();
+        crate::query::sort_scored(&mut results);
+        results.truncate(limit);
+        timings.sort += start.elapsed();
+        Ok((results, timings))
+    }
+
+    fn shard(&self, id: DocumentId) -> &DiskIndex {
+        &self.shards[shard_of(id, self.shards.len())]
+    }
+
+    fn union<'a, F>(&'a self, postings: F) -> Option<Cow<'a, HashSet<DocumentId>>>
+    where
+        F: Fn(&'a DiskIndex) -> Option<Cow<'a, HashSet<DocumentId>>>,
+    {
+        let mut found: Vec<_> = self.shards.iter().filter_map(postings).collect();
+        match found.len() {
+            0 => None,
+            1 => found.pop(),
+            _ => Some(Cow::Owned(
+                found.iter().flat_map(|d| d.iter()).copied().collect(),
+            )),
+        }
+    }
+
+    fn distinct(&self, terms: impl Fn(&DiskIndex) -> Vec<String>) -> Vec<String> {
+        let terms: HashSet<String> = self.shards.iter().flat_map(terms).collect();
+        terms.into_iter().collect()
+    }
+}
+
+impl IndexReader for ShardedIndex {
+    fn schema(&self) -> &Schema {
+        self.shards[0].schema()
+    }
+
+    fn size(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.size()).sum()
+    }
+
+    fn document_ids(&self) -> Vec<DocumentId> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.document_ids())
+            .collect()
+    }
+
+    fn document(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        self.shard(*id).document(id)
+    }
+
+    fn terms(&self) -> Vec<String> {
+        self.distinct(|shard| shard.terms())
+    }
+
+    fn postings(&self, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.union(|shard| shard.postings(term))
+    }
+
+    fn field_terms(&self, field: Field) -> Vec<String> {
+        self.distinct(|shard| shard.field_terms(field))
+    }
+
+    fn field_postings(&self, field: Field, term: &str) -> Option<Cow<'_, HashSet<DocumentId>>> {
+        self.union(|shard| shard.field_postings(field, term))
+    }
+
+    fn term_frequency(&self, id: DocumentId, term: &str) -> Option<f64> {
+        self.shard(id).term_frequency(id, term)
+    }
+
+    fn positions(&self, id: DocumentId, term: &str) -> Option<Cow<'_, [usize]>> {
+        self.shard(id).positions(id, term)
+    }
+
+    fn document_length(&self, id: DocumentId) -> Option<f64> {
+        self.shard(id).document_length(id)
+    }
+
+    fn field_lengths(&self, id: DocumentId) -> Option<FieldLengths> {
+        self.shard(id).field_lengths(id)
+    }
+
+    /**
+     * The averages of every shard weighted by its size
+     */
+    fn average_field_lengths(&self) -> (f64, f64) {
+        let (title, r#abstract) = self.shards.iter().fold((0.0, 0.0), |totals, shard| {
+            let documents = shard.size() as f64;
+            let averages = shard.average_field_lengths();
+            (
+                totals.0 + averages.0 * documents,
+                totals.1 + averages.1 * documents,
+            )
+        });
+        match self.size() {
+            0 => (0.0, 0.0),
+            n => (title / n as f64, r#abstract / n as f64),
+        }
+    }
+
+    fn average_document_length(&self) -> f64 {
+        let (title, r#abstract) = self.average_field_lengths();
+        title + r#abstract
+    }
+
+    fn query_index(&self, query: &str) -> Vec<DocumentId> {
+        self.search(query, usize::MAX)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    fn search(&self, query: &str, limit: usize) -> Vec<(DocumentId, f64)> {
+        self.search_timed(query, limit).0
+    }
+
+    /**
+     * Search the shards with `try_search_timed`, carrying on with the panic of a shard's search
+     * on the calling thread since there is no way to return it as an error here
+     */
+    fn search_timed(&self, query: &str, limit: usize) -> (Vec<(DocumentId, f64)>, QueryTimings) {
+        self.try_search_timed(query, limit)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_matches_index() -> Result<(), Error> {
+        let datafile = PathBuf::from("data/simple.xml.gz");
+        let dir = std::env::temp_dir().join(format!("goede-shards-{}", std::process::id()));
+        let index = Index::from_file(&datafile)?;
+
+        // Shards of an earlier build are replaced rather than left next to the new ones
+        build(&datafile, &Schema::default(), 3, &dir, None)?;
+        let paths = build(&datafile, &Schema::default(), 4, &dir, None)?;
+        assert_eq!(paths.len(), 4);
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 4);
+        let sharded = ShardedIndex::open_dir(&dir, None)?;
+        assert_eq!(sharded.shard_count(), 4);
+        assert_eq!(sharded.size(), index.size());
+        let (title, r#abstract) = index.average_field_lengths();
+        let averages = sharded.average_field_lengths();
+        assert!((averages.0 - title).abs() < 1e-9 && (averages.1 - r#abstract).abs() < 1e-9);
+
+        for query in &["anarchism", "\"political philosophy\"", "title:history"] {
+            let mut expected = index.query_index(query);
+            let mut actual = sharded.query_index(query);
+            expected.sort_unstable();
+            actual.sort_unstable();
+            assert_eq!(actual, expected, "results differ for {}", query);
+        }
+
+        let top = sharded.search("history", 3);
+        assert_eq!(top.len(), 3);
+        assert!(top.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        let id = top[0].0;
+        assert_eq!(sharded.document(&id), index.document(&id));
+        assert_eq!(sharded.try_search_timed("history", 3)?.0, top);
+
+        // Which set of shards to open is ambiguous once there are several
+        std::fs::copy(&paths[0], dir.join(shard_name(0, 2)))?;
+        let e = ShardedIndex::open_dir(&dir, None).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        std::fs::remove_dir_all(&dir)?;
+        assert!(ShardedIndex::open_dir(&dir, None).is_err());
+        Ok(())
+    }
+}
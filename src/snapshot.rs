@@ -0,0 +1,243 @@
+/**
+ * The snapshot module packs everything in a Storage into a single portable archive file, and
+ * unpacks it again, for backups and for shipping prebuilt indexes to other machines
+ *
+ * An archive is the magic bytes, a format version and the number of entries, followed by each
+ * key and value in the Storage with their lengths, and finally a CRC-64 of everything before it.
+ * Since it holds the raw entries, a snapshot of one backend can be restored into any other.
+ */
+use crate::store::Storage;
+use crc::{crc64, Hasher64};
+use log::*;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"GOEDESNP";
+pub const VERSION: u32 = 1;
+
+/**
+ * What was packed into or unpacked from an archive
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+struct Checksummed<T> {
+    inner: T,
+    digest: crc64::Digest,
+    bytes: u64,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            digest: crc64::Digest::new(crc64::ECMA),
+            bytes: 0,
+        }
+    }
+}
+
+impl<W: Write> Checksummed<W> {
+    fn put(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.digest.write(buf);
+        self.bytes += buf.len() as u64;
+        self.inner.write_all(buf)
+    }
+}
+
+impl<R: Read> Checksummed<R> {
+    fn take(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; len];
+        self.inner.read_exact(&mut buf)?;
+        self.digest.write(&buf);
+        self.bytes += buf.len() as u64;
+        Ok(buf)
+    }
+
+    fn take_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?[..].try_into().unwrap()))
+    }
+
+    fn take_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?[..].try_into().unwrap()))
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/**
+ * Write everything in the Storage to an archive at the given path
+ */
+pub fn create(storage: &dyn Storage, path: &Path) -> Result<Summary, Error> {
+    let keys = storage.keys(b"")?;
+    let partial = path.with_extension("partial");
+    let mut out = Checksummed::new(BufWriter::new(File::create(&partial)?));
+
+    out.put(MAGIC)?;
+    out.put(&VERSION.to_le_bytes())?;
+    out.put(&(keys.len() as u64).to_le_bytes())?;
+    for key in keys.iter() {
+        // A key can disappear between listing and reading it if something else is writing
+        let value = storage
+            .get(key)?
+            .ok_or_else(|| Error::other("the storage changed while it was being archived"))?;
+        out.put(&(key.len() as u32).to_le_bytes())?;
+        out.put(&(value.len() as u64).to_le_bytes())?;
+        out.put(key)?;
+        out.put(&value)?;
+    }
+
+    let checksum = out.digest.sum64();
+    let bytes = out.bytes + 8;
+    let mut file = out.inner;
+    file.write_all(&checksum.to_le_bytes())?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&partial, path)?;
+
+    info!("Archived {} entries to {:?}", keys.len(), path);
+    Ok(Summary {
+        entries: keys.len() as u64,
+        bytes,
+    })
+}
+
+/**
+ * Read through the archive, handing each entry to the callback, and then check the checksum
+ */
+fn read_archive<F>(path: &Path, mut each: F) -> Result<Summary, Error>
+where
+    F: FnMut(Vec<u8>, Vec<u8>) -> Result<(), Error>,
+{
+    let file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut input = Checksummed::new(BufReader::new(file));
+    if input.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a goedesearch snapshot"));
+    }
+    let version = input.take_u32()?;
+    if version != VERSION {
+        return Err(invalid(&format!(
+            "unsupported snapshot version {}, expected {}",
+            version, VERSION
+        )));
+    }
+
+    let entries = input.take_u64()?;
+    for _ in 0..entries {
+        let key_len = input.take_u32()? as u64;
+        let value_len = input.take_u64()?;
+        // Lengths which run past the end would otherwise be allocated before failing
+        if input
+            .bytes
+            .saturating_add(key_len)
+            .saturating_add(value_len)
+            + 8
+            > length
+        {
+            return Err(invalid("the snapshot is truncated"));
+        }
+        let key = input.take(key_len as usize)?;
+        let value = input.take(value_len as usize)?;
+        each(key, value)?;
+    }
+
+    let expected = input.digest.sum64();
+    let mut checksum = [0; 8];
+    input.inner.read_exact(&mut checksum)?;
+    if u64::from_le_bytes(checksum) != expected {
+        return Err(invalid(
+            "the snapshot is corrupt, its checksum does not match",
+        ));
+    }
+    Ok(Summary {
+        entries,
+        bytes: input.bytes + 8,
+    })
+}
+
+/**
+ * Check that the archive at the given path is complete and intact
+ */
+pub fn verify(path: &Path) -> Result<Summary, Error> {
+    read_archive(path, |_, _| Ok(()))
+}
+
+/**
+ * Unpack the archive at the given path into the Storage, which must be empty
+ *
+ * The whole archive is verified before anything is written, so a corrupt archive never leaves
+ * half an index behind.
+ */
+pub fn restore(path: &Path, storage: &dyn Storage) -> Result<Summary, Error> {
+    if !storage.keys(b"")?.is_empty() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "snapshots can only be restored into an empty storage",
+        ));
+    }
+    verify(path)?;
+
+    let summary = read_archive(path, |key, value| storage.put(&key, &value))?;
+    storage.flush()?;
+    info!("Restored {} entries from {:?}", summary.entries, path);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+    use crate::schema::Schema;
+    use crate::segment::{SegmentOptions, SegmentedIndex};
+    use crate::store::MemoryStorage;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_create_and_restore() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-{}.snapshot", std::process::id()));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let options = SegmentOptions {
+            segment_size: 100,
+            merge_factor: 100,
+        };
+        let segmented = SegmentedIndex::open(Schema::default(), options, storage.clone())?;
+        segmented.load_file(&PathBuf::from("data/simple.xml.gz"))?;
+        segmented.commit()?;
+        let results = segmented.query_index("anarchism");
+        drop(segmented);
+
+        let created = create(storage.as_ref(), &path)?;
+        assert_eq!(created.entries, 5);
+        assert_eq!(created.bytes, std::fs::metadata(&path)?.len());
+        assert_eq!(verify(&path)?, created);
+
+        assert!(restore(&path, storage.as_ref()).is_err());
+        let restored: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        assert_eq!(restore(&path, restored.as_ref())?, created);
+        let reopened = SegmentedIndex::open(Schema::default(), options, restored)?;
+        assert_eq!(reopened.query_index("anarchism"), results);
+
+        // Flipping a single byte anywhere is caught before anything is restored
+        let mut bytes = std::fs::read(&path)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&path, &bytes)?;
+        let empty = MemoryStorage::new();
+        assert!(restore(&path, &empty).is_err());
+        assert!(empty.keys(b"")?.is_empty());
+
+        let index = Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        index.save(&path)?;
+        assert!(verify(&path).is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+/**
+ * The spell module suggests the terms of the index which a misspelled term was probably meant
+ * to be, so that a query with no hits can be answered with a "did you mean"
+ *
+ * Suggestions are the terms within MAX_DISTANCE edits of the misspelled one, closest first, and
+ * the more documents contain them the more likely they were meant among those as close.
+ */
+use crate::engine::IndexReader;
+
+/**
+ * The most edits a term can be from the misspelled one and still be suggested
+ */
+pub const MAX_DISTANCE: usize = 2;
+
+/**
+ * The number of single character insertions, deletions, substitutions and transpositions of
+ * neighbouring characters it takes to turn one string into the other
+ */
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (rows[i - 1][j] + 1)
+                .min(row[j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+/**
+ * Up to `n` terms of the index which the (analyzed) term might be a misspelling of, best first
+ */
+pub fn suggestions<R: IndexReader + ?Sized>(reader: &R, term: &str, n: usize) -> Vec<String> {
+    let length = term.chars().count();
+    let mut candidates: Vec<(usize, usize, String)> = reader
+        .terms()
+        .into_iter()
+        .filter(|candidate| candidate != term)
+        .filter(|candidate| candidate.chars().count().abs_diff(length) <= MAX_DISTANCE)
+        .filter_map(|candidate| {
+            let distance = edit_distance(term, &candidate);
+            // Every short term is a couple of edits from every other
+            if distance > MAX_DISTANCE || distance >= length {
+                return None;
+            }
+            let documents = reader.postings(&candidate).map_or(0, |docs| docs.len());
+            Some((distance, usize::MAX - documents, candidate))
+        })
+        .collect();
+    candidates.sort_unstable();
+    candidates
+        .into_iter()
+        .take(n)
+        .map(|(_, _, candidate)| candidate)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Index;
+
+    #[test]
+    fn test_suggestions() -> Result<(), std::io::Error> {
+        assert_eq!(edit_distance("titel", "title"), 1);
+        assert_eq!(edit_distance("", "url"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+
+        let index = Index::from_file(std::path::Path::new("data/simple.xml.gz"))?;
+        let suggested = suggestions(&index, "histry", 3);
+        assert_eq!(suggested[0], "histori");
+        assert!(suggested.len() <= 3);
+        assert!(suggestions(&index, "qqqqqqqq", 3).is_empty());
+        Ok(())
+    }
+}
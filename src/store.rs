@@ -0,0 +1,862 @@
+/**
+ * The store module contains the Storage abstraction which persisted segments, postings and
+ * documents are kept in, along with where the Index keeps the documents themselves
+ */
+use crate::engine::{Article, DocumentId};
+use log::*;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+const SEGMENT_PREFIX: &[u8] = b"segment/";
+const POSTINGS_PREFIX: &[u8] = b"postings/";
+const DOCUMENT_PREFIX: &[u8] = b"document/";
+
+/**
+ * The most bytes of serialized documents which are compressed together as one block, bigger
+ * blocks compress better but every document read has to decompress the whole of its block
+ */
+pub const BLOCK_LEN: usize = 64 * 1024;
+/**
+ * The most bytes a block may decompress to, the size prefixed to a block claiming more than
+ * this is corrupt and is rejected before anything is allocated for it
+ */
+pub const MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+/**
+ * A backend which persists opaque values under byte string keys
+ *
+ * Backends only need to provide this small key-value interface, the segments, postings and
+ * documents of an index are laid out on top of it with the helpers on `dyn Storage`.
+ */
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+    fn delete(&self, key: &[u8]) -> Result<(), Error>;
+    /**
+     * Every key which starts with the prefix, in sorted order
+     */
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error>;
+
+    /**
+     * Make sure everything which has been put is durable
+     */
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a> dyn Storage + 'a {
+    /**
+     * Fetch the named segment, which is a whole index in the persisted format
+     */
+    pub fn get_segment(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.get(&key(SEGMENT_PREFIX, name.as_bytes()))
+    }
+
+    pub fn put_segment(&self, name: &str, bytes: &[u8]) -> Result<(), Error> {
+        self.put(&key(SEGMENT_PREFIX, name.as_bytes()), bytes)
+    }
+
+    pub fn delete_segment(&self, name: &str) -> Result<(), Error> {
+        self.delete(&key(SEGMENT_PREFIX, name.as_bytes()))
+    }
+
+    pub fn segments(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .keys(SEGMENT_PREFIX)?
+            .iter()
+            .map(|k| String::from_utf8_lossy(&k[SEGMENT_PREFIX.len()..]).into_owned())
+            .collect())
+    }
+
+    /**
+     * Fetch the documents containing the term
+     */
+    pub fn get_postings(&self, term: &str) -> Result<Option<HashSet<DocumentId>>, Error> {
+        Ok(self
+            .get(&key(POSTINGS_PREFIX, term.as_bytes()))?
+            .map(|bytes| {
+                bytes
+                    .chunks_exact(8)
+                    .map(|id| DocumentId::from_le_bytes(id.try_into().unwrap()))
+                    .collect()
+            }))
+    }
+
+    pub fn put_postings(&self, term: &str, documents: &HashSet<DocumentId>) -> Result<(), Error> {
+        let mut ids: Vec<&DocumentId> = documents.iter().collect();
+        ids.sort_unstable();
+        let bytes: Vec<u8> = ids.into_iter().flat_map(|id| id.to_le_bytes()).collect();
+        self.put(&key(POSTINGS_PREFIX, term.as_bytes()), &bytes)
+    }
+
+    pub fn get_document(&self, id: &DocumentId) -> Result<Option<Article>, Error> {
+        match self.get(&key(DOCUMENT_PREFIX, &id.to_be_bytes()))? {
+            Some(block) => Ok(Some(decompress(&block, 0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Store the document as a block of its own, so that it can be replaced or deleted without
+     * touching any other
+     */
+    pub fn put_document(&self, article: &Article) -> Result<(), Error> {
+        self.put(
+            &key(DOCUMENT_PREFIX, &article.id().to_be_bytes()),
+            &compress(std::iter::once(article))?,
+        )
+    }
+
+    pub fn delete_document(&self, id: &DocumentId) -> Result<(), Error> {
+        self.delete(&key(DOCUMENT_PREFIX, &id.to_be_bytes()))
+    }
+
+    pub fn document_ids(&self) -> Result<Vec<DocumentId>, Error> {
+        Ok(self
+            .keys(DOCUMENT_PREFIX)?
+            .iter()
+            .filter_map(|k| k[DOCUMENT_PREFIX.len()..].try_into().ok())
+            .map(DocumentId::from_be_bytes)
+            .collect())
+    }
+}
+
+fn key(prefix: &[u8], name: &[u8]) -> Vec<u8> {
+    [prefix, name].concat()
+}
+
+/**
+ * Storage which only lives as long as the process
+ */
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(read(&self.entries)?.get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        write(&self.entries)?.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        write(&self.entries)?.remove(key);
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(read(&self.entries)?
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+}
+
+/**
+ * A value which has been deleted is recorded with this length
+ */
+const TOMBSTONE: u32 = u32::MAX;
+
+/**
+ * Storage in a single append-only log file, of which only the offset and length of each value
+ * is kept in memory
+ *
+ * Every record is a u32 key length, a u32 value length, the key and then the value. Reopening
+ * the file replays the log, so the last record for each key wins.
+ */
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    file: Mutex<File>,
+    offsets: RwLock<BTreeMap<Vec<u8>, (u64, u32)>>,
+    /**
+     * Whether the file is removed once the storage is dropped
+     */
+    temporary: bool,
+}
+
+impl FileStorage {
+    /**
+     * Create an empty storage at the given path, truncating whatever was there before
+     */
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            offsets: RwLock::default(),
+            temporary: false,
+        })
+    }
+
+    /**
+     * Create an empty storage at the given path like `create()`, which removes the file again
+     * once it is dropped
+     */
+    pub fn temporary(path: &Path) -> Result<Self, Error> {
+        let mut storage = Self::create(path)?;
+        storage.temporary = true;
+        Ok(storage)
+    }
+
+    /**
+     * Open the storage at the given path, creating it if it does not exist yet
+     *
+     * Only the headers and keys of the log are read, seeking past every value. A partially
+     * written record at the end, from a crash part way through appending it, is discarded.
+     */
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = file.metadata()?.len();
+
+        let mut offsets = BTreeMap::new();
+        let mut reader = BufReader::new(&file);
+        let mut valid = 0;
+        let mut header = [0; 8];
+        while valid + 8 <= len {
+            reader.read_exact(&mut header)?;
+            let key_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+            let value_len = u32::from_le_bytes(header[4..].try_into().unwrap());
+            let start = valid + 8 + key_len;
+            let end = match value_len {
+                TOMBSTONE => start,
+                value_len => start + value_len as u64,
+            };
+            if end > len {
+                break;
+            }
+
+            let mut key = vec![0; key_len as usize];
+            reader.read_exact(&mut key)?;
+            match value_len {
+                TOMBSTONE => offsets.remove(&key),
+                value_len => {
+                    reader.seek_relative(value_len as i64)?;
+                    offsets.insert(key, (start, value_len))
+                }
+            };
+            valid = end;
+        }
+        drop(reader);
+
+        if valid < len {
+            warn!(
+                "Discarding the partially written record at the end of {:?}",
+                path
+            );
+            file.set_len(valid)?;
+        }
+        debug!("Opened {:?} with {} keys", path, offsets.len());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            offsets: RwLock::new(offsets),
+            temporary: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Append a record to the end of the log, returning the offset of its value
+     */
+    fn append(&self, key: &[u8], value: &[u8], value_len: u32) -> Result<u64, Error> {
+        let mut file = lock(&self.file)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+
+        let mut record = Vec::with_capacity(8 + key.len() + value.len());
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&value_len.to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(value);
+        file.write_all(&record)?;
+        Ok(offset + 8 + key.len() as u64)
+    }
+}
+
+impl Drop for FileStorage {
+    fn drop(&mut self) {
+        if self.temporary {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!(
+                    "Failed to remove the temporary storage {:?}: {}",
+                    self.path, e
+                );
+            }
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let (offset, len) = match read(&self.offsets)?.get(key) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+
+        let mut value = vec![0; len as usize];
+        let mut file = lock(&self.file)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut value)?;
+        Ok(Some(value))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let len = match u32::try_from(value.len()) {
+            Ok(len) if len < TOMBSTONE => len,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("a value of {} bytes is too long to store", value.len()),
+                ))
+            }
+        };
+        // Holding the offsets until the value is written keeps `get` from reading it early
+        let mut offsets = write(&self.offsets)?;
+        let offset = self.append(key, value, len)?;
+        offsets.insert(key.to_vec(), (offset, len));
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        let mut offsets = write(&self.offsets)?;
+        if offsets.contains_key(key) {
+            self.append(key, &[], TOMBSTONE)?;
+            offsets.remove(key);
+        }
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(read(&self.offsets)?
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        lock(&self.file)?.sync_data()
+    }
+}
+
+/**
+ * Storage in an embedded sled database, which can grow far beyond the available memory and
+ * recovers whatever was flushed after a crash
+ */
+#[cfg(feature = "sled")]
+pub struct SledStorage {
+    path: PathBuf,
+    db: sled::Db,
+}
+
+#[cfg(feature = "sled")]
+impl std::fmt::Debug for SledStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "SledStorage {{ path: {:?} }}", self.path)
+    }
+}
+
+#[cfg(feature = "sled")]
+impl SledStorage {
+    /**
+     * Open the database at the given path, creating it if it does not exist yet
+     */
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            db: sled::open(path).map_err(sled_error)?,
+        })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl Storage for SledStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.db.get(key).map_err(sled_error)?.map(|v| v.to_vec()))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.db.insert(key, value).map_err(sled_error)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        self.db.remove(key).map_err(sled_error)?;
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        self.db
+            .scan_prefix(prefix)
+            .keys()
+            .map(|k| k.map(|k| k.to_vec()).map_err(sled_error))
+            .collect()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush().map_err(sled_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sled")]
+fn sled_error(e: sled::Error) -> Error {
+    match e {
+        sled::Error::Io(e) => e,
+        e => Error::other(e),
+    }
+}
+
+/**
+ * Open the Storage described by the spec, which is the backend optionally followed by a colon
+ * and its path, e.g. `memory`, `file:index.log` or `sled:/var/lib/goedesearch`
+ */
+pub fn open_storage(spec: &str) -> Result<Arc<dyn Storage>, Error> {
+    let (backend, path) = match spec.split_once(':') {
+        Some((backend, path)) => (backend, Some(Path::new(path))),
+        None => (spec, None),
+    };
+
+    match (backend, path) {
+        ("memory", None) => Ok(Arc::new(MemoryStorage::new())),
+        ("file", Some(path)) => Ok(Arc::new(FileStorage::open(path)?)),
+        #[cfg(feature = "sled")]
+        ("sled", Some(path)) => Ok(Arc::new(SledStorage::open(path)?)),
+        #[cfg(not(feature = "sled"))]
+        ("sled", Some(_)) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "sled storage needs goedesearch built with `--features sled`",
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported storage `{}`", spec),
+        )),
+    }
+}
+
+fn poisoned() -> Error {
+    Error::other("a lock was poisoned")
+}
+
+/*
+ * Locking which turns a poisoned lock into an io::Error like every other failure
+ */
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>, Error> {
+    mutex.lock().map_err(|_| poisoned())
+}
+
+pub(crate) fn read<T>(lock: &RwLock<T>) -> Result<std::sync::RwLockReadGuard<'_, T>, Error> {
+    lock.read().map_err(|_| poisoned())
+}
+
+pub(crate) fn write<T>(lock: &RwLock<T>) -> Result<std::sync::RwLockWriteGuard<'_, T>, Error> {
+    lock.write().map_err(|_| poisoned())
+}
+
+/**
+ * The stored documents of an Index
+ */
+#[derive(Clone, Debug)]
+pub enum Documents {
+    Memory(HashMap<DocumentId, Article>),
+    /**
+     * Documents kept in a Storage, only their ids are held in memory
+     */
+    Stored {
+        storage: Arc<dyn Storage>,
+        ids: HashSet<DocumentId>,
+    },
+}
+
+impl Default for Documents {
+    fn default() -> Self {
+        Documents::Memory(HashMap::default())
+    }
+}
+
+impl Documents {
+    pub fn len(&self) -> usize {
+        match self {
+            Documents::Memory(documents) => documents.len(),
+            Documents::Stored { ids, .. } => ids.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: &DocumentId) -> bool {
+        match self {
+            Documents::Memory(documents) => documents.contains_key(id),
+            Documents::Stored { ids, .. } => ids.contains(id),
+        }
+    }
+
+    pub fn ids(&self) -> Vec<DocumentId> {
+        match self {
+            Documents::Memory(documents) => documents.keys().copied().collect(),
+            Documents::Stored { ids, .. } => ids.iter().copied().collect(),
+        }
+    }
+
+    /**
+     * Iterate over the documents in no particular order, reading each of them from the
+     * Storage as it is reached when they are stored
+     */
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (DocumentId, Cow<'_, Article>)> + '_> {
+        match self {
+            Documents::Memory(documents) => Box::new(
+                documents
+                    .iter()
+                    .map(|(id, article)| (*id, Cow::Borrowed(article))),
+            ),
+            Documents::Stored { ids, .. } => Box::new(
+                ids.iter()
+                    .filter_map(move |id| self.get(id).map(|article| (*id, article))),
+            ),
+        }
+    }
+
+    pub fn get(&self, id: &DocumentId) -> Option<Cow<'_, Article>> {
+        match self {
+            Documents::Memory(documents) => documents.get(id).map(Cow::Borrowed),
+            Documents::Stored { storage, ids } if ids.contains(id) => {
+                match storage.get_document(id) {
+                    Ok(article) => article.map(Cow::Owned),
+                    Err(e) => {
+                        error!("Failed to read document {} from {:?}: {}", id, storage, e);
+                        None
+                    }
+                }
+            }
+            Documents::Stored { .. } => None,
+        }
+    }
+
+    /**
+     * Release whatever memory is left over from documents which have been removed
+     */
+    pub fn shrink_to_fit(&mut self) {
+        match self {
+            Documents::Memory(documents) => documents.shrink_to_fit(),
+            Documents::Stored { ids, .. } => ids.shrink_to_fit(),
+        }
+    }
+
+    /**
+     * Remove the document, returning it if it was there
+     */
+    pub fn remove(&mut self, id: &DocumentId) -> Result<Option<Article>, Error> {
+        match self {
+            Documents::Memory(documents) => Ok(documents.remove(id)),
+            Documents::Stored { storage, ids } => {
+                if !ids.remove(id) {
+                    return Ok(None);
+                }
+                let article = storage.get_document(id)?;
+                storage.delete_document(id)?;
+                Ok(article)
+            }
+        }
+    }
+
+    pub fn insert(&mut self, article: Article) -> Result<(), Error> {
+        match self {
+            Documents::Memory(documents) => {
+                documents.insert(article.id(), article);
+            }
+            Documents::Stored { storage, ids } => {
+                storage.put_document(&article)?;
+                ids.insert(article.id());
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Documents which are compressed together into a single LZ4 block, each serialized as JSON and
+ * preceded by its u32 length
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Block {
+    bytes: Vec<u8>,
+    count: usize,
+}
+
+impl Block {
+    /**
+     * Add the article to the block, returning its slot within it
+     */
+    pub fn push(&mut self, article: &Article) -> Result<usize, Error> {
+        let json = serde_json::to_vec(article)?;
+        if self.bytes.len() + 4 + json.len() > MAX_BLOCK_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("document {} is too big to be stored", article.id()),
+            ));
+        }
+        self.bytes
+            .extend_from_slice(&(json.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(&json);
+        self.count += 1;
+        Ok(self.count - 1)
+    }
+
+    /**
+     * The number of documents in the block
+     */
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /**
+     * Whether the block has grown to `BLOCK_LEN` and should be compressed
+     */
+    pub fn is_full(&self) -> bool {
+        self.bytes.len() >= BLOCK_LEN
+    }
+
+    /**
+     * Compress the documents which were added, leaving the block empty
+     */
+    pub fn compress(&mut self) -> Vec<u8> {
+        self.count = 0;
+        lz4_flex::compress_prepend_size(&std::mem::take(&mut self.bytes))
+    }
+}
+
+/**
+ * Serialize the articles and compress them together into one block
+ */
+pub fn compress<'a>(articles: impl IntoIterator<Item = &'a Article>) -> Result<Vec<u8>, Error> {
+    let mut block = Block::default();
+    for article in articles {
+        block.push(article)?;
+    }
+    Ok(block.compress())
+}
+
+/**
+ * Decompress a block written by `compress()` or a `Block`, so that its documents can be read
+ * with `read_slot()` without decompressing it again for each of them
+ */
+pub fn decompress_block(block: &[u8]) -> Result<Vec<u8>, Error> {
+    let size = block
+        .get(..4)
+        .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "the block is truncated"))?;
+    if size > MAX_BLOCK_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("the block claims to decompress to {} bytes", size),
+        ));
+    }
+    lz4_flex::decompress_size_prepended(block).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/**
+ * Deserialize the article in the given slot of a decompressed block
+ */
+pub fn read_slot(bytes: &[u8], slot: usize) -> Result<Article, Error> {
+    let (mut json, mut rest) = (&bytes[..0], bytes);
+    for _ in 0..=slot {
+        let len = rest
+            .get(..4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("the block has no slot {}", slot),
+                )
+            })?;
+        json = rest
+            .get(4..4 + len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "the block is truncated"))?;
+        rest = &rest[4 + len..];
+    }
+    Ok(serde_json::from_slice(json)?)
+}
+
+/**
+ * Decompress the block and deserialize the article in the given slot of it
+ */
+pub fn decompress(block: &[u8], slot: usize) -> Result<Article, Error> {
+    read_slot(&decompress_block(block)?, slot)
+}
+
+/**
+ * Decompress and deserialize an article which was compressed on its own, as documents were
+ * stored before they were compressed in blocks
+ */
+pub fn decompress_record(record: &[u8]) -> Result<Article, Error> {
+    Ok(serde_json::from_slice(&decompress_block(record)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() -> Result<(), Error> {
+        let article: Article = serde_json::from_str(
+            r#"{"id": 1, "title": "Echo", "abstract": "echo echo echo echo echo echo", "url": null}"#,
+        )?;
+        let block = compress(std::iter::once(&article))?;
+        assert!(block.len() < serde_json::to_vec(&article)?.len());
+        assert_eq!(decompress(&block, 0)?, article);
+        assert!(decompress(&block, 1).is_err());
+        assert!(decompress(b"garbage", 0).is_err());
+
+        // Documents compressed together take up less than they would one at a time
+        let articles: Vec<Article> = (0..20)
+            .map(|id| {
+                serde_json::from_str(&format!(
+                    r#"{{"id": {}, "title": "Echo", "abstract": "echo echo echo echo echo echo", "url": null}}"#,
+                    id
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+        let block = compress(&articles)?;
+        let alone: usize = articles
+            .iter()
+            .map(|article| compress(std::iter::once(article)).map(|block| block.len()))
+            .sum::<Result<_, Error>>()?;
+        assert!(block.len() * 4 < alone);
+        assert_eq!(decompress(&block, 13)?, articles[13]);
+
+        // A corrupt size is rejected rather than allocated
+        let mut corrupt = block;
+        corrupt[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let e = decompress(&corrupt, 0).unwrap_err();
+        assert!(e.to_string().contains("claims to decompress"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_storage() -> Result<(), Error> {
+        let storage: &dyn Storage = &MemoryStorage::new();
+        let postings: HashSet<DocumentId> = vec![3, 1, 2].into_iter().collect();
+        storage.put_postings("anarch", &postings)?;
+        storage.put_segment("b", b"second")?;
+        storage.put_segment("a", b"first")?;
+
+        assert_eq!(storage.get_postings("anarch")?, Some(postings));
+        assert_eq!(storage.get_postings("missing")?, None);
+        assert_eq!(storage.segments()?, vec!["a", "b"]);
+        storage.delete_segment("a")?;
+        assert_eq!(storage.segments()?, vec!["b"]);
+        Ok(())
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_storage() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-sled-{}", std::process::id()));
+        let storage = open_storage(&format!("sled:{}", path.display()))?;
+        storage.put_document(&Article::default())?;
+        storage.put_segment("a", b"first")?;
+        storage.flush()?;
+
+        assert_eq!(storage.document_ids()?, vec![0]);
+        assert_eq!(storage.get_document(&0)?, Some(Article::default()));
+        assert_eq!(storage.segments()?, vec!["a"]);
+        drop(storage);
+
+        // What was flushed is still there once the database is opened again
+        let storage = open_storage(&format!("sled:{}", path.display()))?;
+        assert_eq!(storage.document_ids()?, vec![0]);
+        assert_eq!(storage.get_document(&0)?, Some(Article::default()));
+        assert_eq!(storage.segments()?, vec!["a"]);
+        drop(storage);
+        std::fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_storage_spec() {
+        assert!(open_storage("memory").is_ok());
+        assert!(open_storage("memory:/tmp").is_err());
+        assert!(open_storage("file").is_err());
+        assert!(open_storage("rocksdb:/tmp/db").is_err());
+        #[cfg(not(feature = "sled"))]
+        assert!(open_storage("sled:/tmp/db").is_err());
+    }
+
+    #[test]
+    fn test_file_storage_reopen() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-storage-{}.log", std::process::id()));
+        {
+            let storage: &dyn Storage = &FileStorage::create(&path)?;
+            storage.put_document(&Article::default())?;
+            storage.put_segment("a", b"first")?;
+            storage.put_segment("a", b"replaced")?;
+            storage.put_segment("b", b"deleted")?;
+            storage.delete_segment("b")?;
+            storage.flush()?;
+        }
+
+        let storage: &dyn Storage = &FileStorage::open(&path)?;
+        assert_eq!(storage.document_ids()?, vec![0]);
+        assert_eq!(storage.get_document(&0)?, Some(Article::default()));
+        assert_eq!(storage.get_segment("a")?, Some(b"replaced".to_vec()));
+        assert_eq!(storage.segments()?, vec!["a"]);
+
+        // A record torn part way through is dropped, so the next one is not appended after it
+        let intact = std::fs::metadata(&path)?.len();
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[3, 0, 0, 0, 100, 0, 0, 0, b'k', b'e', b'y', 1, 2])?;
+        {
+            let storage: &dyn Storage = &FileStorage::open(&path)?;
+            assert_eq!(std::fs::metadata(&path)?.len(), intact);
+            storage.put_segment("c", b"after")?;
+        }
+        let storage: &dyn Storage = &FileStorage::open(&path)?;
+        assert_eq!(storage.get_segment("c")?, Some(b"after".to_vec()));
+        assert_eq!(storage.segments()?, vec!["a", "c"]);
+        std::fs::remove_file(&path)?;
+
+        let temporary = FileStorage::temporary(&path)?;
+        assert!(path.exists());
+        drop(temporary);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
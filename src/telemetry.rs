@@ -0,0 +1,155 @@
+/**
+ * The telemetry module names the metrics which the engine emits through the `metrics` facade,
+ * so that an embedder can attach whichever exporter they like (Prometheus, statsd, ...) and
+ * see how much is being indexed and searched
+ *
+ * Nothing is recorded anywhere until a recorder is installed, which is left to the embedder.
+ * Calling `describe()` after installing it gives the exporter the units and descriptions of the
+ * metrics.
+ */
+use metrics::{describe_counter, describe_histogram, Unit};
+
+/**
+ * Counter of the documents added to an index
+ */
+pub const DOCUMENTS_INDEXED: &str = "goedesearch_documents_indexed_total";
+
+/**
+ * Counter of the searches answered with a SearchResponse
+ */
+pub const QUERIES_SERVED: &str = "goedesearch_queries_served_total";
+
+/**
+ * Histogram of the number of documents which matched each query, before they were scored
+ */
+pub const CANDIDATES: &str = "goedesearch_query_candidates";
+
+/**
+ * Histogram of how long each search took to evaluate
+ */
+pub const QUERY_LATENCY: &str = "goedesearch_query_latency_seconds";
+
+/**
+ * Describe every metric to the installed recorder
+ */
+pub fn describe() {
+    describe_counter!(
+        DOCUMENTS_INDEXED,
+        Unit::Count,
+        "The documents added to an index"
+    );
+    describe_counter!(
+        QUERIES_SERVED,
+        Unit::Count,
+        "The searches answered with search results"
+    );
+    describe_histogram!(
+        CANDIDATES,
+        Unit::Count,
+        "The documents matching each query, before they were scored"
+    );
+    describe_histogram!(
+        QUERY_LATENCY,
+        Unit::Seconds,
+        "How long each search took to evaluate"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Article, Index};
+    use metrics::{
+        Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+        SharedString,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /**
+     * Remembers the value of every counter and everything recorded into every histogram
+     */
+    #[derive(Default)]
+    struct Remembered {
+        values: Arc<Mutex<HashMap<String, Vec<f64>>>>,
+    }
+
+    struct Metric {
+        name: String,
+        values: Arc<Mutex<HashMap<String, Vec<f64>>>>,
+    }
+
+    impl CounterFn for Metric {
+        fn increment(&self, value: u64) {
+            self.record(value as f64);
+        }
+
+        fn absolute(&self, value: u64) {
+            self.record(value as f64);
+        }
+    }
+
+    impl HistogramFn for Metric {
+        fn record(&self, value: f64) {
+            let mut values = self.values.lock().unwrap();
+            values.entry(self.name.clone()).or_default().push(value);
+        }
+    }
+
+    impl Remembered {
+        fn metric(&self, key: &Key) -> Arc<Metric> {
+            Arc::new(Metric {
+                name: key.name().to_string(),
+                values: self.values.clone(),
+            })
+        }
+
+        fn values(&self, name: &str) -> Vec<f64> {
+            let values = self.values.lock().unwrap();
+            values.get(name).cloned().unwrap_or_default()
+        }
+    }
+
+    impl Recorder for Remembered {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.metric(key))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.metric(key))
+        }
+    }
+
+    #[test]
+    fn test_metrics() -> Result<(), std::io::Error> {
+        let recorder = Remembered::default();
+        metrics::with_local_recorder(&recorder, || -> Result<(), std::io::Error> {
+            describe();
+            let mut index = Index::new();
+            for (title, url) in [
+                ("Rain", "https://example.com/rain"),
+                ("Snow", "https://example.com/snow"),
+            ] {
+                index.index_document(Article::new(title, "weather", url)?)?;
+            }
+            // Indexing the same document again adds nothing
+            index.index_document(Article::new("Rain", "weather", "https://example.com/rain")?)?;
+            crate::server::search(&index, "rain", 10);
+            Ok(())
+        })?;
+
+        assert_eq!(recorder.values(DOCUMENTS_INDEXED), vec![1.0, 1.0]);
+        assert_eq!(recorder.values(QUERIES_SERVED), vec![1.0]);
+        assert_eq!(recorder.values(CANDIDATES), vec![1.0]);
+        assert_eq!(recorder.values(QUERY_LATENCY).len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+/**
+ * The vectors module stores an embedding for each document, so that documents can be found by
+ * what they mean rather than by the words in them
+ *
+ * Searching compares the query with every stored vector. That is exact, and quick enough for
+ * corpora in the hundreds of thousands of documents, since the comparisons are just dot
+ * products over normalized vectors which the compiler turns into SIMD instructions.
+ */
+use crate::engine::{document_id, DocumentId, IndexReader};
+use log::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+use url::Url;
+
+/**
+ * The embeddings of documents, all with the same number of dimensions
+ */
+#[derive(Clone, Debug)]
+pub struct VectorIndex {
+    dimensions: usize,
+    /**
+     * The embeddings, scaled to unit length so that their dot product is their cosine
+     */
+    vectors: HashMap<DocumentId, Vec<f32>>,
+}
+
+/**
+ * A line of an embeddings file
+ */
+#[derive(Deserialize)]
+struct Embedding {
+    url: String,
+    vector: Vec<f32>,
+}
+
+impl VectorIndex {
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions,
+            vectors: HashMap::new(),
+        }
+    }
+
+    /**
+     * Load precomputed embeddings from a file with one JSON object per line, each holding the
+     * url of a document and its vector, e.g. `{"url": "https://...", "vector": [0.1, 0.2]}`
+     *
+     * The number of dimensions is taken from the first vector.
+     */
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut index: Option<Self> = None;
+        for (number, line) in BufReader::new(std::fs::File::open(path)?)
+            .lines()
+            .enumerate()
+        {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |e: &dyn std::fmt::Display| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {} of {:?}: {}", number + 1, path, e),
+                )
+            };
+            let embedding: Embedding = serde_json::from_str(&line).map_err(|e| invalid(&e))?;
+            let url = Url::parse(&embedding.url).map_err(|e| invalid(&e))?;
+            index
+                .get_or_insert_with(|| Self::new(embedding.vector.len()))
+                .insert(document_id(&url), embedding.vector)
+                .map_err(|e| invalid(&e))?;
+        }
+        let index = index.unwrap_or_else(|| Self::new(0));
+        debug!(
+            "Loaded {} embeddings of {} dimensions from {:?}",
+            index.len(),
+            index.dimensions,
+            path
+        );
+        Ok(index)
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    /**
+     * The embedding of the document, scaled to unit length
+     */
+    pub fn get(&self, id: &DocumentId) -> Option<&[f32]> {
+        self.vectors.get(id).map(|vector| vector.as_slice())
+    }
+
+    /**
+     * Store the embedding of the document, replacing any it already had
+     */
+    pub fn insert(&mut self, id: DocumentId, vector: Vec<f32>) -> Result<(), Error> {
+        self.check(&vector)?;
+        self.vectors.insert(id, normalize(vector));
+        Ok(())
+    }
+
+    /**
+     * Remove the embedding of the document, returning whether it had one
+     */
+    pub fn remove(&mut self, id: &DocumentId) -> bool {
+        self.vectors.remove(id).is_some()
+    }
+
+    fn check(&self, vector: &[f32]) -> Result<(), Error> {
+        if vector.len() != self.dimensions {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the vector has {} dimensions instead of {}",
+                    vector.len(),
+                    self.dimensions
+                ),
+            ));
+        }
+        if vector.iter().all(|x| *x == 0.0) || vector.iter().any(|x| !x.is_finite()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the vector must be finite and not zero",
+            ));
+        }
+        Ok(())
+    }
+
+    /**
+     * The `k` documents whose embeddings are the most similar to the query vector, along with
+     * their cosine similarity, highest first
+     */
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<(DocumentId, f64)>, Error> {
+        self.check(query)?;
+        let query = normalize(query.to_vec());
+        let mut results: Vec<(DocumentId, f64)> = self
+            .vectors
+            .iter()
+            .map(|(id, vector)| (*id, dot(&query, vector) as f64))
+            .collect();
+        crate::query::sort_scored(&mut results);
+        results.truncate(k);
+        Ok(results)
+    }
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let length = dot(&vector, &vector).sqrt();
+    for x in vector.iter_mut() {
+        *x /= length;
+    }
+    vector
+}
+
+/**
+ * The dot product, summed in independent lanes so that it can be vectorized
+ */
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    const LANES: usize = 8;
+    let mut sums = [0.0f32; LANES];
+    let (chunks_a, chunks_b) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+    let rest: f32 = chunks_a
+        .remainder()
+        .iter()
+        .zip(chunks_b.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    for (x, y) in chunks_a.zip(chunks_b) {
+        for lane in 0..LANES {
+            sums[lane] += x[lane] * y[lane];
+        }
+    }
+    sums.iter().sum::<f32>() + rest
+}
+
+/**
+ * How many of the best lexical and vector matches are fused by a hybrid search, when it is
+ * asked for fewer results than this
+ */
+const HYBRID_CANDIDATES: usize = 100;
+
+/**
+ * How the lexical and vector rankings of a hybrid search are combined
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fusion {
+    /**
+     * Score each document by the sum of `1 / (k + rank)` over both rankings, which only looks
+     * at where a document ranks and so needs no tuning between the two kinds of score
+     */
+    ReciprocalRank { k: f64 },
+    /**
+     * Score each document by `weight * lexical + (1 - weight) * similarity`, with the lexical
+     * scores scaled so that the best one is one
+     */
+    Weighted { lexical_weight: f64 },
+}
+
+impl Default for Fusion {
+    fn default() -> Self {
+        Fusion::ReciprocalRank { k: 60.0 }
+    }
+}
+
+/**
+ * Search for the query text in the reader and for its embedding in the vectors, combining the
+ * two rankings into the `limit` best documents by the fusion
+ */
+pub fn hybrid_search<R: IndexReader + ?Sized>(
+    reader: &R,
+    vectors: &VectorIndex,
+    query: &str,
+    embedding: &[f32],
+    limit: usize,
+    fusion: Fusion,
+) -> Result<Vec<(DocumentId, f64)>, Error> {
+    let candidates = limit.max(HYBRID_CANDIDATES);
+    let lexical = reader.search(query, candidates);
+    let semantic = vectors.search(embedding, candidates)?;
+
+    let mut scores: HashMap<DocumentId, f64> = HashMap::new();
+    match fusion {
+        Fusion::ReciprocalRank { k } => {
+            for ranking in [&lexical, &semantic].iter() {
+                for (rank, (id, _)) in ranking.iter().enumerate() {
+                    *scores.entry(*id).or_default() += 1.0 / (k + rank as f64 + 1.0);
+                }
+            }
+        }
+        Fusion::Weighted { lexical_weight } => {
+            let best = lexical.first().map(|(_, score)| *score).unwrap_or(0.0);
+            for (id, score) in lexical.iter() {
+                if best > 0.0 {
+                    *scores.entry(*id).or_default() += lexical_weight * score / best;
+                }
+            }
+            for (id, similarity) in semantic.iter() {
+                *scores.entry(*id).or_default() += (1.0 - lexical_weight) * similarity;
+            }
+        }
+    }
+    let mut results: Vec<(DocumentId, f64)> = scores.into_iter().collect();
+    crate::query::sort_scored(&mut results);
+    results.truncate(limit);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nearest_neighbours() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-vectors-{}.jsonl", std::process::id()));
+        let mut lines = vec![];
+        for (i, vector) in [[1.0, 0.0, 0.0], [0.9, 0.1, 0.0], [0.0, 0.0, 2.0]]
+            .iter()
+            .enumerate()
+        {
+            lines.push(format!(
+                r#"{{"url": "https://example.com/{}", "vector": {:?}}}"#,
+                i, vector
+            ));
+        }
+        std::fs::write(&path, lines.join("\n"))?;
+        let index = VectorIndex::load(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.dimensions(), 3);
+
+        let id = |i| document_id(&Url::parse(&format!("https://example.com/{}", i)).unwrap());
+        let nearest = index.search(&[1.0, 0.0, 0.1], 2)?;
+        assert_eq!(nearest[0].0, id(0));
+        assert_eq!(nearest[1].0, id(1));
+        assert_eq!(index.search(&[0.0, 0.0, 1.0], 1)?[0], (id(2), 1.0));
+        assert!(index.search(&[1.0, 0.0], 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hybrid_search() -> Result<(), Error> {
+        let mut index = crate::engine::Index::new();
+        let mut vectors = VectorIndex::new(2);
+        let mut ids = vec![];
+        for (i, (title, vector)) in [
+            ("Statue of Liberty", [0.0, 1.0]),
+            ("Liberty Bell", [1.0, 0.0]),
+            ("Banana", [0.1, 1.0]),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let article =
+                crate::engine::Article::new(title, "", &format!("https://example.com/{}", i))?;
+            ids.push(article.id());
+            vectors.insert(article.id(), vector.to_vec())?;
+            index.index_document(article)?;
+        }
+        index.finalize();
+
+        // Only the first document matches both, the others are ranked by their embeddings
+        let rrf = hybrid_search(
+            &index,
+            &vectors,
+            "statue",
+            &[0.0, 1.0],
+            3,
+            Fusion::default(),
+        )?;
+        assert_eq!(rrf.len(), 3);
+        let ranked: Vec<_> = rrf.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ranked, vec![ids[0], ids[2], ids[1]]);
+
+        // Only the text counts without any weight on the embedding
+        let lexical = Fusion::Weighted {
+            lexical_weight: 1.0,
+        };
+        let statue = hybrid_search(&index, &vectors, "statue", &[1.0, 0.0], 3, lexical)?;
+        assert_eq!(statue[0], (ids[0], 1.0));
+        Ok(())
+    }
+}
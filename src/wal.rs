@@ -0,0 +1,136 @@
+/**
+ * The wal module contains the write-ahead log, which records every mutation of a segmented
+ * index until it has been committed so that they can be replayed after a crash
+ */
+use crate::engine::{Article, DocumentId};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/**
+ * A single logged mutation
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Add(Article),
+    Delete(DocumentId),
+}
+
+/**
+ * An append-only file of operations, one JSON document per line
+ *
+ * Every operation is written straight through to the operating system, so it survives the
+ * process crashing, and synced to disk whenever the log is truncated after a commit.
+ */
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl WriteAheadLog {
+    /**
+     * Open the log at the given path, returning it along with every operation it already holds
+     *
+     * A partially written operation at the end, from a crash part way through appending it, is
+     * discarded.
+     */
+    pub fn open(path: &Path) -> Result<(Self, Vec<Operation>), Error> {
+        let mut operations = vec![];
+        let mut valid = 0;
+
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut line = String::new();
+            while reader.read_line(&mut line)? > 0 {
+                if !line.ends_with('\n') {
+                    warn!("Discarding the partially written operation in {:?}", path);
+                    break;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(operation) => operations.push(operation),
+                    Err(e) => {
+                        warn!("Discarding the unreadable operation in {:?}: {}", path, e);
+                        break;
+                    }
+                }
+                valid += line.len() as u64;
+                line.clear();
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(valid)?;
+        debug!("Opened {:?} with {} operations", path, operations.len());
+
+        let log = Self {
+            path: path.to_path_buf(),
+            file,
+        };
+        Ok((log, operations))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&mut self, operation: &Operation) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(operation)?;
+        line.push(b'\n');
+        // The file is not opened for appending so that it can be truncated, but every write
+        // still goes on the end
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&line)
+    }
+
+    /**
+     * Discard every logged operation, once they are all durable elsewhere
+     */
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_discards_torn_write() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("goede-wal-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let (mut log, operations) = WriteAheadLog::open(&path)?;
+            assert!(operations.is_empty());
+            log.append(&Operation::Add(Article::default()))?;
+            log.append(&Operation::Delete(42))?;
+        }
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"add\": {\"ti")?;
+
+        let (mut log, operations) = WriteAheadLog::open(&path)?;
+        assert_eq!(
+            operations,
+            vec![Operation::Add(Article::default()), Operation::Delete(42)]
+        );
+        log.append(&Operation::Delete(7))?;
+        let (mut log, operations) = WriteAheadLog::open(&path)?;
+        assert_eq!(operations.len(), 3);
+
+        log.truncate()?;
+        let (_, operations) = WriteAheadLog::open(&path)?;
+        assert!(operations.is_empty());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
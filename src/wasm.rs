@@ -0,0 +1,86 @@
+/**
+ * The wasm module exposes the engine to JavaScript through wasm-bindgen, so that a prebuilt
+ * index can be searched entirely client-side in a browser
+ *
+ * A browser has no filesystem, so the page fetches the index file itself and hands over the
+ * bytes, e.g.
+ *
+ *   const bytes = new Uint8Array(await (await fetch("index.goede")).arrayBuffer());
+ *   const index = Index.load(bytes);
+ *   const articles = JSON.parse(index.queryIndex("anarchism"));
+ *
+ * Results are returned as JSON. Document ids are larger than JavaScript numbers can hold
+ * exactly, so documents are better told apart by their urls.
+ */
+use crate::disk::DiskIndex;
+use crate::engine::IndexReader;
+use wasm_bindgen::prelude::*;
+
+fn js_error(e: impl std::fmt::Display) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+#[wasm_bindgen]
+pub struct Index {
+    index: DiskIndex,
+}
+
+#[wasm_bindgen]
+impl Index {
+    /**
+     * Load an index from the bytes of a file written by `Index::save`
+     */
+    pub fn load(bytes: Vec<u8>) -> Result<Index, JsError> {
+        Ok(Self {
+            index: DiskIndex::from_bytes(bytes).map_err(js_error)?,
+        })
+    }
+
+    /**
+     * The number of documents in the index
+     */
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> f64 {
+        self.index.size() as f64
+    }
+
+    /**
+     * The documents matching the query as a JSON array, best first
+     */
+    #[wasm_bindgen(js_name = queryIndex)]
+    pub fn query_index(&self, query: &str) -> Result<String, JsError> {
+        serde_json::to_string(&articles(&self.index, query)).map_err(js_error)
+    }
+
+    /**
+     * The `limit` highest scoring hits for the query, as the same JSON as the search server
+     * responds with
+     */
+    pub fn search(&self, query: &str, limit: usize) -> Result<String, JsError> {
+        serde_json::to_string(&crate::server::search(&self.index, query, limit)).map_err(js_error)
+    }
+}
+
+fn articles<R: IndexReader + ?Sized>(reader: &R, query: &str) -> Vec<crate::engine::Article> {
+    reader
+        .query_index(query)
+        .iter()
+        .filter_map(|id| reader.document(id).map(|article| article.into_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_articles_from_bytes() -> Result<(), std::io::Error> {
+        let index = crate::engine::Index::from_file(&PathBuf::from("data/simple.xml.gz"))?;
+        let loaded = DiskIndex::from_bytes(crate::disk::to_bytes(&index)?)?;
+        let found = articles(&loaded, "anarchism");
+        assert_eq!(found.len(), index.query_index("anarchism").len());
+        assert!(found[0].title().contains("Anarchism"));
+        Ok(())
+    }
+}